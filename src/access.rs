@@ -0,0 +1,146 @@
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::{Serialize, Serializer};
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+use crate::AppState;
+
+// An address range such as 10.0.0.0/8 or 2001:db8::/32; a bare address matches only itself
+#[derive(Clone, Debug)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u32,
+}
+
+impl FromStr for Cidr {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (address, prefix) = match value.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (value, None),
+        };
+
+        let network = IpAddr::from_str(address).map_err(|_| ())?.to_canonical();
+        let max_prefix = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().map_err(|_| ())?,
+            None => max_prefix,
+        };
+        if prefix > max_prefix {
+            return Err(());
+        }
+
+        Ok(Cidr { network, prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+impl Serialize for Cidr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+fn matches_any(ranges: &[Cidr], ip: IpAddr) -> bool {
+    ranges.iter().any(|range| range.contains(ip))
+}
+
+// The real client address: the peer itself, or when the peer is a trusted proxy, the
+// nearest X-Forwarded-For hop that isn't one of our proxies
+fn client_ip(state: &AppState, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+    let proxies = &state.config.trusted_proxies;
+    if !matches_any(proxies, peer) {
+        return peer;
+    }
+
+    let forwarded = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|hop| IpAddr::from_str(hop.trim()).ok())
+        .collect::<Vec<_>>();
+
+    forwarded
+        .into_iter()
+        .rev()
+        .find(|hop| !matches_any(proxies, *hop))
+        .unwrap_or(peer)
+}
+
+// Whether a WebSocket upgrade arrived over TLS, as far as the policy requires it: the peer
+// must be a trusted proxy and every X-Forwarded-Proto value it passes on must be https.
+// Plaintext upgrades that reach us without going through a proxy are refused.
+pub fn is_secure_upgrade(state: &AppState, peer: IpAddr, headers: &HeaderMap) -> bool {
+    if !state.config.require_secure_upgrades {
+        return true;
+    }
+    if !matches_any(&state.config.trusted_proxies, peer) {
+        return false;
+    }
+
+    let mut protos = headers
+        .get_all("x-forwarded-proto")
+        .iter()
+        .map(|value| value.to_str().unwrap_or_default())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .peekable();
+    protos.peek().is_some() && protos.all(|proto| proto.eq_ignore_ascii_case("https"))
+}
+
+// Whether an address may connect: the denylist wins, then the allowlist if one is set
+fn is_allowed(state: &AppState, ip: IpAddr) -> bool {
+    let config = &state.config;
+    !matches_any(&config.deny_cidrs, ip) && (config.allow_cidrs.is_empty() || matches_any(&config.allow_cidrs, ip))
+}
+
+// Reject disallowed source addresses before any handler (or WebSocket upgrade) runs
+pub async fn enforce(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let ip = client_ip(&state, peer.ip(), request.headers());
+    if !is_allowed(&state, ip) {
+        println!("🚫 Rejected request from {} to {}", ip, request.uri().path());
+        return (
+            StatusCode::FORBIDDEN,
+            serde_json::json!({ "error": "address not allowed" }).to_string(),
+        )
+            .into_response();
+    }
+
+    next.run(request).await
+}
@@ -0,0 +1,640 @@
+use axum::{
+    extract::{Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Deserialize;
+use std::{collections::HashSet, sync::atomic::Ordering};
+
+use crate::{
+    broadcast_all, broadcast_event, channel_config::{self, ChannelOverrides}, clock, close::CloseReason, config::LiveConfig,
+    epoch, lifecycle, migration, presence, retention, send_direct, AppState,
+};
+
+type AdminResult = Result<String, (StatusCode, String)>;
+
+// Reject requests that don't carry the configured admin bearer token
+pub fn authorize(state: &AppState, headers: &HeaderMap) -> Result<(), (StatusCode, String)> {
+    let Some(expected) = state.config.admin_token.as_deref() else {
+        return Err(admin_error(StatusCode::FORBIDDEN, "admin API is disabled"));
+    };
+
+    let provided = headers
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+
+    if constant_time_eq(provided.as_bytes(), expected.as_bytes()) {
+        Ok(())
+    } else {
+        Err(admin_error(StatusCode::UNAUTHORIZED, "invalid admin token"))
+    }
+}
+
+pub fn admin_error(status: StatusCode, message: &str) -> (StatusCode, String) {
+    (status, serde_json::json!({ "error": message }).to_string())
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// Effective configuration, split into what PATCH /admin/config can change and what is
+// fixed until a restart
+pub async fn get_config(State(state): State<AppState>, headers: HeaderMap) -> AdminResult {
+    authorize(&state, &headers)?;
+
+    let live = serde_json::to_value(state.live()).unwrap_or_default();
+    let mut read_only = serde_json::to_value(&*state.config).unwrap_or_default();
+    if let (Some(read_only), Some(live)) = (read_only.as_object_mut(), live.as_object()) {
+        read_only.retain(|key, _| !live.contains_key(key));
+    }
+
+    Ok(serde_json::json!({ "live": live, "read_only": read_only }).to_string())
+}
+
+// Change runtime-adjustable settings; the whole patch is refused if any field is read-only or invalid
+pub async fn update_config(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(patch): Json<serde_json::Map<String, serde_json::Value>>,
+) -> AdminResult {
+    authorize(&state, &headers)?;
+
+    let mut live_config = state.live_config.write().unwrap_or_else(|e| e.into_inner());
+    let previous = serde_json::to_value(*live_config).unwrap_or_default();
+    let mut merged = previous.as_object().cloned().unwrap_or_default();
+
+    for (key, value) in &patch {
+        if !merged.contains_key(key) {
+            let is_setting = serde_json::to_value(&*state.config)
+                .ok()
+                .is_some_and(|config| config.get(key).is_some());
+            let message = if is_setting {
+                format!("{} is read-only at runtime", key)
+            } else {
+                format!("unknown setting {}", key)
+            };
+            return Err(admin_error(StatusCode::BAD_REQUEST, &message));
+        }
+        merged.insert(key.clone(), value.clone());
+    }
+
+    let updated: LiveConfig = serde_json::from_value(serde_json::Value::Object(merged))
+        .map_err(|e| admin_error(StatusCode::BAD_REQUEST, &format!("rejected patch: {}", e)))?;
+    updated.validate().map_err(|message| admin_error(StatusCode::BAD_REQUEST, message))?;
+    *live_config = updated;
+    drop(live_config);
+
+    let current = serde_json::to_value(updated).unwrap_or_default();
+    let mut changed = Vec::new();
+    for key in patch.keys() {
+        if previous.get(key) != current.get(key) {
+            println!(
+                "⚙️ Config {} changed from {} to {} via the admin API",
+                key,
+                previous.get(key).unwrap_or(&serde_json::Value::Null),
+                current.get(key).unwrap_or(&serde_json::Value::Null)
+            );
+            changed.push(key.clone());
+        }
+    }
+    let changes: serde_json::Map<_, _> = changed
+        .iter()
+        .map(|key| (key.clone(), serde_json::json!({ "from": previous.get(key), "to": current.get(key) })))
+        .collect();
+    state.audit.record(&headers, "update_config", None, serde_json::json!({ "changed": changes }));
+
+    Ok(serde_json::json!({ "live": current, "changed": changed }).to_string())
+}
+
+#[derive(Deserialize)]
+pub struct MaintenanceRequest {
+    enabled: bool,
+    message: Option<String>,
+}
+
+// Current maintenance mode
+pub async fn get_maintenance(State(state): State<AppState>, headers: HeaderMap) -> AdminResult {
+    authorize(&state, &headers)?;
+
+    Ok(serde_json::json!({ "enabled": state.maintenance.load(Ordering::Relaxed) }).to_string())
+}
+
+// Toggle read-only maintenance mode and tell every channel
+pub async fn set_maintenance(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<MaintenanceRequest>,
+) -> AdminResult {
+    authorize(&state, &headers)?;
+
+    let previous = state.maintenance.swap(request.enabled, Ordering::Relaxed);
+    if previous != request.enabled {
+        let notified = broadcast_all(
+            &state,
+            "maintenance_mode",
+            serde_json::json!({ "enabled": request.enabled, "message": request.message }),
+        );
+        println!(
+            "🚧 Maintenance mode {} ({} channels notified)",
+            if request.enabled { "enabled" } else { "disabled" },
+            notified
+        );
+    }
+
+    state.audit.record(
+        &headers,
+        "set_maintenance",
+        None,
+        serde_json::json!({ "enabled": request.enabled, "message": request.message, "changed": previous != request.enabled }),
+    );
+
+    Ok(serde_json::json!({ "enabled": request.enabled, "changed": previous != request.enabled }).to_string())
+}
+
+#[derive(Deserialize)]
+pub struct AnnouncementRequest {
+    message: String,
+    data: Option<serde_json::Value>,
+    #[serde(default)]
+    include_unsubscribed: bool,
+}
+
+// Push a notice to every channel, and optionally to connections that aren't in any
+pub async fn broadcast_announcement(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<AnnouncementRequest>,
+) -> AdminResult {
+    authorize(&state, &headers)?;
+
+    let now = clock::now().timestamp();
+    let interval = state.live().announcement_interval_secs as i64;
+    let last = state.last_announcement.load(Ordering::Relaxed);
+    if now - last < interval
+        || state
+            .last_announcement
+            .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+            .is_err()
+    {
+        return Err(admin_error(StatusCode::TOO_MANY_REQUESTS, "announcement sent too recently"));
+    }
+
+    let data = serde_json::json!({ "message": request.message, "data": request.data });
+    let channels = broadcast_all(&state, "announcement", data.clone());
+
+    let mut direct = 0;
+    if request.include_unsubscribed {
+        let subscribed: HashSet<String> = state
+            .channel_presence
+            .iter()
+            .flat_map(|channel_map| channel_map.iter().map(|entry| entry.key().clone()).collect::<Vec<_>>())
+            .collect();
+
+        for client in state.clients.iter().filter(|client| !subscribed.contains(client.key())) {
+            send_direct(&client.outgoing, None, "", "announcement", data.clone());
+            direct += 1;
+        }
+    }
+
+    println!("📢 Announcement sent to {} channels and {} unsubscribed clients", channels, direct);
+    state.audit.record(
+        &headers,
+        "broadcast",
+        None,
+        serde_json::json!({ "message": request.message, "channels": channels, "direct": direct }),
+    );
+
+    Ok(serde_json::json!({ "channels": channels, "direct": direct }).to_string())
+}
+
+// Declared channels and the active creation policy
+pub async fn list_declared_channels(State(state): State<AppState>, headers: HeaderMap) -> AdminResult {
+    authorize(&state, &headers)?;
+
+    let channels = state
+        .declared_channels
+        .iter()
+        .map(|entry| serde_json::json!({ "channel": entry.key(), "declared_at": entry.value() }))
+        .collect::<Vec<_>>();
+
+    Ok(serde_json::json!({
+        "creation_policy": state.config.channel_creation.as_str(),
+        "channels": channels
+    })
+    .to_string())
+}
+
+// Allow subscribing to a channel under the "declared" creation policy
+pub async fn declare_channel(
+    Path(channel_id): Path<String>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> AdminResult {
+    authorize(&state, &headers)?;
+
+    let declared_at = *state
+        .declared_channels
+        .entry(channel_id.clone())
+        .or_insert_with(|| clock::now().timestamp());
+
+    println!("📌 Declared channel {}", channel_id);
+    state.audit.record(&headers, "declare_channel", Some(&channel_id), serde_json::json!({}));
+
+    Ok(serde_json::json!({ "channel": channel_id, "declared_at": declared_at }).to_string())
+}
+
+// Change a channel's overrides: a field set to null falls back to the global value again
+pub async fn update_channel_config(
+    Path(channel_id): Path<String>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(patch): Json<serde_json::Map<String, serde_json::Value>>,
+) -> AdminResult {
+    authorize(&state, &headers)?;
+    let channel_id = resolve_channel(&state, &channel_id);
+
+    let previous = channel_config::overrides(&state, &channel_id);
+    let mut merged = serde_json::to_value(previous)
+        .ok()
+        .and_then(|value| value.as_object().cloned())
+        .unwrap_or_default();
+    for (key, value) in patch {
+        if value.is_null() {
+            merged.remove(&key);
+        } else {
+            merged.insert(key, value);
+        }
+    }
+
+    let updated: ChannelOverrides = serde_json::from_value(serde_json::Value::Object(merged))
+        .map_err(|e| admin_error(StatusCode::BAD_REQUEST, &format!("rejected patch: {}", e)))?;
+    updated.validate().map_err(|message| admin_error(StatusCode::BAD_REQUEST, message))?;
+
+    if updated.is_empty() {
+        state.channel_overrides.remove(&channel_id);
+    } else {
+        state.channel_overrides.insert(channel_id.clone(), updated);
+    }
+    println!(
+        "⚙️ Channel {} overrides changed from {} to {} via the admin API",
+        channel_id,
+        serde_json::to_value(previous).unwrap_or_default(),
+        serde_json::to_value(updated).unwrap_or_default()
+    );
+    state.audit.record(
+        &headers,
+        "update_channel_config",
+        Some(&channel_id),
+        serde_json::json!({ "from": previous, "to": updated }),
+    );
+
+    Ok(serde_json::json!({
+        "channel": channel_id,
+        "overrides": updated,
+        "effective": channel_config::effective(&state, &channel_id),
+    })
+    .to_string())
+}
+
+// Drop all of a channel's overrides
+pub async fn clear_channel_config(
+    Path(channel_id): Path<String>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> AdminResult {
+    authorize(&state, &headers)?;
+    let channel_id = resolve_channel(&state, &channel_id);
+
+    let cleared = state.channel_overrides.remove(&channel_id).map(|(_, overrides)| overrides);
+    if let Some(overrides) = cleared {
+        println!("⚙️ Channel {} overrides cleared via the admin API", channel_id);
+        state.audit.record(&headers, "clear_channel_config", Some(&channel_id), serde_json::json!({ "from": overrides }));
+    }
+    let cleared = cleared.is_some();
+
+    Ok(serde_json::json!({ "channel": channel_id, "cleared": cleared }).to_string())
+}
+
+// Stop accepting new subscribers to a declared channel; existing subscribers stay
+pub async fn undeclare_channel(
+    Path(channel_id): Path<String>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> AdminResult {
+    authorize(&state, &headers)?;
+
+    match state.declared_channels.remove(&channel_id) {
+        Some(_) => {
+            println!("📌 Undeclared channel {}", channel_id);
+            state.audit.record(&headers, "undeclare_channel", Some(&channel_id), serde_json::json!({}));
+            Ok(serde_json::json!({ "channel": channel_id, "removed": true }).to_string())
+        }
+        None => Err(admin_error(StatusCode::NOT_FOUND, "channel not declared")),
+    }
+}
+
+// Remove roster entries that claim to be online but have no live connection, for when a
+// roster has drifted
+pub async fn probe_presence(
+    Path(channel_id): Path<String>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> AdminResult {
+    authorize(&state, &headers)?;
+
+    let channel_id = resolve_channel(&state, &channel_id);
+    let (checked, pruned) = presence::probe(&state, &channel_id);
+    println!(
+        "🩺 Presence probe of channel {} checked {} entries and pruned {}",
+        channel_id,
+        checked,
+        pruned.len()
+    );
+    state.audit.record(
+        &headers,
+        "probe_presence",
+        Some(&channel_id),
+        serde_json::json!({ "checked": checked, "pruned": pruned.len() }),
+    );
+
+    Ok(serde_json::json!({
+        "channel": channel_id,
+        "checked": checked,
+        "pruned": pruned.len(),
+        "pruned_entries": pruned,
+    })
+    .to_string())
+}
+
+// Make a channel read-only while keeping its history and transcript
+pub async fn archive_channel(
+    Path(channel_id): Path<String>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> AdminResult {
+    authorize(&state, &headers)?;
+
+    let channel_id = resolve_channel(&state, &channel_id);
+    let changed = lifecycle::archive(&state, &channel_id);
+    if changed {
+        println!("🗃️ Archived channel {}", channel_id);
+    }
+    state.audit.record(&headers, "archive_channel", Some(&channel_id), serde_json::json!({ "changed": changed }));
+
+    Ok(serde_json::json!({ "channel": channel_id, "archived": true, "changed": changed }).to_string())
+}
+
+// Accept publishes on an archived channel again
+pub async fn revive_channel(
+    Path(channel_id): Path<String>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> AdminResult {
+    authorize(&state, &headers)?;
+
+    let channel_id = resolve_channel(&state, &channel_id);
+    if !lifecycle::revive(&state, &channel_id) {
+        return Err(admin_error(StatusCode::NOT_FOUND, "channel not archived"));
+    }
+
+    println!("🗃️ Revived channel {}", channel_id);
+    state.audit.record(&headers, "revive_channel", Some(&channel_id), serde_json::json!({}));
+
+    Ok(serde_json::json!({ "channel": channel_id, "archived": false }).to_string())
+}
+
+// Restart a channel's seq numbering in a new epoch, e.g. before reusing it for another session
+pub async fn reset_channel_epoch(
+    Path(channel_id): Path<String>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> AdminResult {
+    authorize(&state, &headers)?;
+
+    let channel_id = resolve_channel(&state, &channel_id);
+    let epoch = epoch::reset(&state, &channel_id);
+    println!("🔢 Channel {} numbering reset in epoch {}", channel_id, epoch);
+    state.audit.record(&headers, "reset_channel_epoch", Some(&channel_id), serde_json::json!({ "epoch": epoch }));
+
+    Ok(serde_json::json!({ "channel": channel_id, "epoch": epoch }).to_string())
+}
+
+// Full transcript of a retained channel, as JSON lines
+pub async fn export_channel(
+    Path(channel_id): Path<String>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    authorize(&state, &headers)?;
+
+    let channel_id = resolve_channel(&state, &channel_id);
+    if !retention::is_retained(&state, &channel_id) {
+        return Err(admin_error(StatusCode::NOT_FOUND, "channel is not retained"));
+    }
+
+    state.audit.record(&headers, "export_channel", Some(&channel_id), serde_json::json!({}));
+    match retention::export(&state, channel_id).await {
+        Ok(transcript) => Ok((
+            [(header::CONTENT_TYPE, "application/x-ndjson")],
+            transcript.unwrap_or_default(),
+        )
+            .into_response()),
+        Err(e) => {
+            eprintln!("❌ Failed to export transcript: {}", e);
+            Err(admin_error(StatusCode::INTERNAL_SERVER_ERROR, "export failed"))
+        }
+    }
+}
+
+// A connected client's round-trip time
+pub async fn get_client(
+    Path(client_id): Path<String>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> AdminResult {
+    authorize(&state, &headers)?;
+
+    let Some(client) = state.clients.get(&client_id) else {
+        return Err(admin_error(StatusCode::NOT_FOUND, "client not connected"));
+    };
+
+    Ok(serde_json::json!({ "client_id": client_id, "rtt": client.rtt.summary() }).to_string())
+}
+
+// Disconnect a single client
+pub async fn kick_client(
+    Path(client_id): Path<String>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> AdminResult {
+    authorize(&state, &headers)?;
+
+    let kicked = state
+        .clients
+        .get(&client_id)
+        .is_some_and(|client| client.disconnect.send(CloseReason::Kicked).is_ok());
+    if !kicked {
+        return Err(admin_error(StatusCode::NOT_FOUND, "client not connected"));
+    }
+
+    println!("🥾 Kicked client {}", client_id);
+    state.audit.record(&headers, "kick_client", Some(&client_id), serde_json::json!({}));
+
+    Ok(serde_json::json!({ "client_id": client_id, "kicked": true }).to_string())
+}
+
+#[derive(Deserialize)]
+pub struct DisconnectAllRequest {
+    code: Option<u16>,
+    reason: Option<String>,
+}
+
+// Close frames carry at most 123 bytes of reason
+const MAX_CLOSE_REASON_BYTES: usize = 123;
+
+// Disconnect every participant of a channel, e.g. to end a compromised session
+pub async fn disconnect_channel(
+    Path(channel_id): Path<String>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    request: Option<Json<DisconnectAllRequest>>,
+) -> AdminResult {
+    authorize(&state, &headers)?;
+
+    let request = request.map(|Json(request)| request);
+    let code = request.as_ref().and_then(|request| request.code).unwrap_or(1008);
+    // Codes a server may send, plus the ranges set aside for applications
+    if !matches!(code, 1000 | 1001 | 1008 | 1011 | 1013 | 3000..=4999) {
+        return Err(admin_error(StatusCode::BAD_REQUEST, "code must be 1000, 1001, 1008, 1011, 1013 or 3000-4999"));
+    }
+    let reason = request
+        .and_then(|request| request.reason)
+        .unwrap_or_else(|| "channel_closed".to_string());
+    if reason.len() > MAX_CLOSE_REASON_BYTES {
+        return Err(admin_error(StatusCode::BAD_REQUEST, "reason may be at most 123 bytes"));
+    }
+
+    let channel_id = resolve_channel(&state, &channel_id);
+    let participants: Vec<String> = state
+        .channel_presence
+        .get(&channel_id)
+        .map(|channel_map| channel_map.iter().map(|info| info.key().clone()).collect())
+        .unwrap_or_default();
+
+    // Away entries and entries restored from a snapshot have no connection to close
+    let mut disconnected = 0;
+    for client_id in &participants {
+        let closed = state.clients.get(client_id).is_some_and(|client| {
+            let reason = CloseReason::ChannelClosed { code, reason: reason.clone() };
+            migration::close(&state, &client, reason)
+        });
+        if closed {
+            disconnected += 1;
+        }
+    }
+
+    println!(
+        "🚪 Disconnected {} connections from channel {} ({} {})",
+        disconnected, channel_id, code, reason
+    );
+    state.audit.record(
+        &headers,
+        "disconnect_channel",
+        Some(&channel_id),
+        serde_json::json!({ "code": code, "reason": reason, "disconnected": disconnected }),
+    );
+
+    Ok(serde_json::json!({
+        "channel": channel_id,
+        "participants": participants.len(),
+        "disconnected": disconnected,
+        "not_connected": participants.len() - disconnected,
+        "code": code,
+        "reason": reason,
+    })
+    .to_string())
+}
+
+#[derive(Deserialize)]
+pub struct AliasRequest {
+    target: String,
+}
+
+// List all channel aliases
+pub async fn list_aliases(State(state): State<AppState>, headers: HeaderMap) -> AdminResult {
+    authorize(&state, &headers)?;
+
+    let aliases = state
+        .channel_aliases
+        .iter()
+        .map(|entry| (entry.key().clone(), serde_json::json!(entry.value())))
+        .collect::<serde_json::Map<_, _>>();
+
+    Ok(serde_json::json!({ "aliases": aliases }).to_string())
+}
+
+// Route an old channel name to a canonical channel
+pub async fn set_alias(
+    Path(alias): Path<String>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<AliasRequest>,
+) -> AdminResult {
+    authorize(&state, &headers)?;
+
+    let target = resolve_channel(&state, &request.target);
+    if target == alias {
+        return Err(admin_error(StatusCode::BAD_REQUEST, "alias would point to itself"));
+    }
+
+    // Keep the table one hop deep: anything that pointed at the alias now points at the target
+    for mut entry in state.channel_aliases.iter_mut() {
+        if *entry.value() == alias {
+            *entry.value_mut() = target.clone();
+        }
+    }
+    state.channel_aliases.insert(alias.clone(), target.clone());
+
+    broadcast_event(
+        &state,
+        &alias,
+        "channel_renamed",
+        serde_json::json!({ "from": alias, "to": target }),
+    );
+
+    println!("🔀 Channel {} is now an alias of {}", alias, target);
+    state.audit.record(&headers, "set_alias", Some(&alias), serde_json::json!({ "target": target }));
+
+    Ok(serde_json::json!({ "alias": alias, "target": target }).to_string())
+}
+
+// Stop routing an alias
+pub async fn remove_alias(
+    Path(alias): Path<String>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> AdminResult {
+    authorize(&state, &headers)?;
+
+    match state.channel_aliases.remove(&alias) {
+        Some((alias, target)) => {
+            println!("🔀 Removed alias {} -> {}", alias, target);
+            state.audit.record(&headers, "remove_alias", Some(&alias), serde_json::json!({ "target": target }));
+            Ok(serde_json::json!({ "alias": alias, "target": target, "removed": true }).to_string())
+        }
+        None => Err(admin_error(StatusCode::NOT_FOUND, "alias not found")),
+    }
+}
+
+// Canonical name for a channel, following its alias if it has one
+pub fn resolve_channel(state: &AppState, channel: &str) -> String {
+    state
+        .channel_aliases
+        .get(channel)
+        .map(|target| target.clone())
+        .unwrap_or_else(|| channel.to_string())
+}
@@ -0,0 +1,288 @@
+// Attendance timelines built from presence.
+
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use crate::{
+    admin::{self, admin_error},
+    clock,
+    config::Config,
+    degradation::{self, Degradations},
+    retention::{FileSink, Queue, RetentionSink},
+    AppState, ClientInfo,
+};
+
+// How often a channel's log is rewritten without its expired records
+const PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Event {
+    Joined,
+    Left,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Record {
+    event: Event,
+    participant: String,
+    client_id: String,
+    role: String,
+    // Unix ms
+    at: i64,
+}
+
+#[derive(Serialize)]
+struct Interval {
+    joined_at: i64,
+    // None while still present
+    left_at: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct Participant {
+    participant: String,
+    role: String,
+    intervals: Vec<Interval>,
+    total_ms: i64,
+    present: bool,
+    // Connections currently open
+    #[serde(skip)]
+    open: usize,
+}
+
+// Attendance recording for the channels that opted in, if a directory is configured
+pub struct Attendance {
+    sink: Arc<FileSink>,
+    queue: Arc<Queue>,
+}
+
+impl Drop for Attendance {
+    // Let the writer finish what's queued and stop
+    fn drop(&mut self) {
+        self.queue.close();
+    }
+}
+
+impl Attendance {
+    pub fn new(config: &Config, degradations: &Arc<Degradations>) -> Option<Self> {
+        let dir = PathBuf::from(config.attendance_dir.as_deref()?);
+        if let Err(e) = fs::create_dir_all(&dir) {
+            eprintln!("❌ Failed to create attendance directory {}: {}", dir.display(), e);
+            degradations.fail(degradation::ATTENDANCE, format!("cannot create {}: {}", dir.display(), e));
+            return None;
+        }
+
+        println!("📝 Recording attendance in {}", dir.display());
+        let sink = Arc::new(FileSink::new(dir));
+        // Joins and leaves never wait for the disk: a full queue drops the oldest records
+        let queue = Arc::new(Queue::new(config.attendance_queue_size));
+        spawn_writer(sink.clone(), queue.clone(), config.attendance_retention_days, degradations.clone());
+        Some(Attendance { sink, queue })
+    }
+
+    // Records waiting to be written
+    pub fn depth(&self) -> usize {
+        self.queue.depth()
+    }
+
+    // Records discarded because the queue was full
+    pub fn dropped(&self) -> u64 {
+        self.queue.dropped()
+    }
+}
+
+fn spawn_writer(sink: Arc<FileSink>, queue: Arc<Queue>, retention_days: u64, degradations: Arc<Degradations>) {
+    tokio::task::spawn_blocking(move || {
+        let mut pruned: HashMap<String, Instant> = HashMap::new();
+        while let Some((channel, line)) = queue.pop() {
+            let due = pruned.get(&channel).is_none_or(|at| at.elapsed() >= PRUNE_INTERVAL);
+            if retention_days > 0 && due {
+                pruned.insert(channel.clone(), Instant::now());
+                if let Err(e) = prune(&sink, &channel, cutoff(retention_days)) {
+                    eprintln!("❌ Failed to prune attendance on channel {}: {}", channel, e);
+                    degradations.fail(degradation::ATTENDANCE, e);
+                }
+            }
+
+            match sink.append(&channel, &line) {
+                Ok(()) => degradations.recover(degradation::ATTENDANCE),
+                Err(e) => {
+                    eprintln!("❌ Failed to record attendance on channel {}: {}", channel, e);
+                    degradations.fail(degradation::ATTENDANCE, e);
+                }
+            }
+        }
+    });
+}
+
+// Oldest record kept, in unix ms
+fn cutoff(retention_days: u64) -> i64 {
+    clock::now().timestamp_millis() - (retention_days * 86_400_000) as i64
+}
+
+fn expired(line: &str, cutoff: i64) -> bool {
+    serde_json::from_str::<Record>(line).is_ok_and(|record| record.at < cutoff)
+}
+
+// Rewrite a channel's log without records older than the cutoff
+fn prune(sink: &FileSink, channel: &str, cutoff: i64) -> io::Result<()> {
+    let Some(bytes) = sink.export(channel)? else {
+        return Ok(());
+    };
+    let log = String::from_utf8_lossy(&bytes);
+    if !log.lines().any(|line| expired(line, cutoff)) {
+        return Ok(());
+    }
+
+    let kept: String = log
+        .lines()
+        .filter(|line| !expired(line, cutoff))
+        .map(|line| format!("{}\n", line))
+        .collect();
+    // Replaced in one step, so a report never reads a half-written log
+    let path = sink.path(channel);
+    let tmp = path.with_extension("jsonl.tmp");
+    fs::write(&tmp, kept)?;
+    fs::rename(tmp, path)
+}
+
+// Whether a channel records attendance
+pub fn is_recorded(state: &AppState, channel: &str) -> bool {
+    state.attendance.is_some()
+        && state
+            .config
+            .attendance_channel_prefixes
+            .iter()
+            .any(|prefix| channel.starts_with(prefix.as_str()))
+}
+
+// Queue a join or leave ("user_joined" or "user_left") for the channel's log
+pub fn record(state: &AppState, channel: &str, event_type: &str, info: &ClientInfo) {
+    let Some(attendance) = &state.attendance else {
+        return;
+    };
+    let event = match event_type {
+        "user_joined" => Event::Joined,
+        "user_left" => Event::Left,
+        _ => return,
+    };
+    if !is_recorded(state, channel) {
+        return;
+    }
+
+    let record = Record {
+        event,
+        participant: info.identity.clone().unwrap_or_else(|| info.id.clone()),
+        client_id: info.id.clone(),
+        role: info.role.clone(),
+        at: clock::now().timestamp_millis(),
+    };
+    match serde_json::to_string(&record) {
+        Ok(line) => attendance.queue.push(channel.to_string(), line),
+        Err(e) => eprintln!("❌ Failed to serialize attendance on channel {}: {}", channel, e),
+    }
+}
+
+// Fold a log into participants, in order of first arrival
+fn report(log: &str, cutoff: Option<i64>, now: i64) -> Vec<Participant> {
+    let mut participants: Vec<Participant> = Vec::new();
+    let mut index: HashMap<String, usize> = HashMap::new();
+
+    let records = log
+        .lines()
+        .filter_map(|line| serde_json::from_str::<Record>(line).ok())
+        .filter(|record| cutoff.is_none_or(|cutoff| record.at >= cutoff));
+    for record in records {
+        let position = *index.entry(record.participant.clone()).or_insert_with(|| {
+            participants.push(Participant {
+                participant: record.participant.clone(),
+                role: record.role.clone(),
+                intervals: Vec::new(),
+                total_ms: 0,
+                present: false,
+                open: 0,
+            });
+            participants.len() - 1
+        });
+        let participant = &mut participants[position];
+
+        match record.event {
+            Event::Joined => {
+                if participant.open == 0 {
+                    participant.intervals.push(Interval {
+                        joined_at: record.at,
+                        left_at: None,
+                    });
+                }
+                participant.open += 1;
+                participant.role = record.role;
+            }
+            // A leave whose join has expired says nothing about the time that's left
+            Event::Left if participant.open == 0 => {}
+            Event::Left => {
+                participant.open -= 1;
+                let closed = participant.open == 0;
+                if let Some(interval) = participant.intervals.last_mut().filter(|_| closed) {
+                    interval.left_at = Some(record.at);
+                }
+            }
+        }
+    }
+
+    for participant in &mut participants {
+        participant.present = participant.open > 0;
+        participant.total_ms = participant
+            .intervals
+            .iter()
+            .map(|interval| interval.left_at.unwrap_or(now) - interval.joined_at)
+            .sum();
+    }
+    participants.retain(|participant| !participant.intervals.is_empty());
+    participants
+}
+
+// Per-participant attendance intervals for a channel that records attendance
+pub async fn get_attendance(
+    Path(channel_id): Path<String>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<String, (StatusCode, String)> {
+    admin::authorize(&state, &headers)?;
+
+    let channel_id = admin::resolve_channel(&state, &channel_id);
+    let Some(attendance) = state.attendance.as_ref().filter(|_| is_recorded(&state, &channel_id)) else {
+        return Err(admin_error(StatusCode::NOT_FOUND, "channel does not record attendance"));
+    };
+
+    let sink = attendance.sink.clone();
+    let channel = channel_id.clone();
+    let log = tokio::task::spawn_blocking(move || sink.export(&channel))
+        .await
+        .unwrap_or_else(|e| Err(io::Error::other(e)))
+        .map_err(|e| {
+            eprintln!("❌ Failed to read attendance: {}", e);
+            admin_error(StatusCode::INTERNAL_SERVER_ERROR, "attendance could not be read")
+        })?
+        .unwrap_or_default();
+
+    let retention_days = state.config.attendance_retention_days;
+    let cutoff = (retention_days > 0).then(|| cutoff(retention_days));
+    let participants = report(&String::from_utf8_lossy(&log), cutoff, clock::now().timestamp_millis());
+
+    state.audit.record(&headers, "get_attendance", Some(&channel_id), serde_json::json!({}));
+    Ok(serde_json::json!({
+        "channel": channel_id,
+        "participants": participants,
+    })
+    .to_string())
+}
@@ -0,0 +1,159 @@
+// Append-only audit log of administrative actions.
+
+use axum::{
+    extract::{Query, State},
+    http::HeaderMap,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::{io::AsyncWriteExt, sync::mpsc};
+
+use crate::{
+    admin, clock,
+    config::Config,
+    degradation::{self, Degradations},
+    AppState,
+};
+
+// Header naming the person or system behind an admin call
+const ACTOR_HEADER: &str = "x-admin-actor";
+const MAX_ACTOR_CHARS: usize = 128;
+
+// Entries returned per page unless the request asks for fewer
+const MAX_PAGE: usize = 1000;
+
+#[derive(Clone, Serialize)]
+pub struct AuditEntry {
+    // Increases by one per entry, so a reader can resume after the last one it saw
+    seq: u64,
+    at: i64,
+    actor: String,
+    action: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target: Option<String>,
+    details: serde_json::Value,
+}
+
+pub struct AuditLog {
+    entries: Mutex<VecDeque<AuditEntry>>,
+    capacity: usize,
+    next_seq: AtomicU64,
+    file: Option<mpsc::UnboundedSender<String>>,
+}
+
+impl AuditLog {
+    pub fn new(config: &Config, degradations: &Arc<Degradations>) -> Self {
+        AuditLog {
+            entries: Mutex::new(VecDeque::new()),
+            capacity: config.audit_log_size,
+            next_seq: AtomicU64::new(1),
+            file: config
+                .audit_log_file
+                .clone()
+                .map(|path| spawn_file_writer(path, degradations.clone())),
+        }
+    }
+
+    pub fn record(
+        &self,
+        headers: &HeaderMap,
+        action: &'static str,
+        target: Option<&str>,
+        details: serde_json::Value,
+    ) {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        // Numbered under the lock so the ring and the file stay in seq order
+        let entry = AuditEntry {
+            seq: self.next_seq.fetch_add(1, Ordering::Relaxed),
+            at: clock::now().timestamp(),
+            actor: actor(headers),
+            action,
+            target: target.map(str::to_string),
+            details,
+        };
+
+        if let Some(file) = &self.file {
+            let _ = file.send(serde_json::to_string(&entry).unwrap_or_default());
+        }
+        if self.capacity > 0 {
+            if entries.len() == self.capacity {
+                entries.pop_front();
+            }
+            entries.push_back(entry);
+        }
+    }
+
+    // Kept entries after `after`, oldest first
+    fn page(&self, after: u64, limit: usize) -> (Vec<AuditEntry>, bool) {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let mut newer = entries.iter().filter(|entry| entry.seq > after);
+        let page: Vec<AuditEntry> = newer.by_ref().take(limit).cloned().collect();
+        (page, newer.next().is_some())
+    }
+}
+
+fn actor(headers: &HeaderMap) -> String {
+    headers
+        .get(ACTOR_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|actor| !actor.is_empty())
+        .map(|actor| actor.chars().take(MAX_ACTOR_CHARS).collect())
+        .unwrap_or_else(|| "admin".to_string())
+}
+
+fn spawn_file_writer(path: String, degradations: Arc<Degradations>) -> mpsc::UnboundedSender<String> {
+    let (tx, mut rx) = mpsc::unbounded_channel::<String>();
+
+    tokio::spawn(async move {
+        let mut file = match tokio::fs::OpenOptions::new().create(true).append(true).open(&path).await {
+            Ok(file) => file,
+            Err(e) => {
+                eprintln!("❌ Failed to open audit log {}: {}", path, e);
+                degradations.fail(degradation::AUDIT_LOG, format!("cannot open {}: {}", path, e));
+                return;
+            }
+        };
+
+        println!("📝 Audit log writing to {}", path);
+
+        while let Some(line) = rx.recv().await {
+            match file.write_all(format!("{}\n", line).as_bytes()).await {
+                Ok(()) => degradations.recover(degradation::AUDIT_LOG),
+                Err(e) => {
+                    eprintln!("❌ Failed to write audit entry: {}", e);
+                    degradations.fail(degradation::AUDIT_LOG, e);
+                }
+            }
+        }
+    });
+
+    tx
+}
+
+#[derive(Deserialize)]
+pub struct AuditQuery {
+    // Only entries with a higher seq, for paging forward
+    after: Option<u64>,
+    limit: Option<usize>,
+}
+
+pub async fn list(
+    Query(query): Query<AuditQuery>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<String, (axum::http::StatusCode, String)> {
+    admin::authorize(&state, &headers)?;
+
+    let limit = query.limit.unwrap_or(100).clamp(1, MAX_PAGE);
+    let (entries, more) = state.audit.page(query.after.unwrap_or(0), limit);
+    let next = more.then(|| entries.last().map(|entry| entry.seq)).flatten();
+
+    Ok(serde_json::json!({ "entries": entries, "next": next }).to_string())
+}
@@ -0,0 +1,486 @@
+// Pluggable connection authentication.
+
+use axum::http::HeaderMap;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
+
+use crate::{clock, config::Config};
+
+// What a provider gets to look at for one connection attempt
+pub struct AuthContext {
+    pub headers: HeaderMap,
+    // Token from the connect URL, for browsers that can't set headers on a WebSocket
+    pub token: Option<String>,
+    // Self-declared identity from the connect URL, trusted only by the allow-all provider
+    pub identity: Option<String>,
+}
+
+impl AuthContext {
+    // Bearer token from the Authorization header, falling back to the URL
+    pub fn bearer_token(&self) -> Option<&str> {
+        self.headers
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .or(self.token.as_deref())
+            .map(str::trim)
+            .filter(|token| !token.is_empty())
+    }
+}
+
+// Who a connection belongs to
+#[derive(Clone, Debug, Default)]
+pub struct Identity {
+    // Stable id across reconnects; None for anonymous connections
+    pub id: Option<String>,
+    // Highest role the connection may hold
+    pub role: Option<String>,
+    pub tenant: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    MissingCredentials,
+    InvalidCredentials(&'static str),
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::MissingCredentials => write!(f, "missing credentials"),
+            AuthError::InvalidCredentials(reason) => write!(f, "invalid credentials: {}", reason),
+        }
+    }
+}
+
+pub trait Authenticator: Send + Sync {
+    fn authenticate<'a>(&'a self, ctx: &'a AuthContext) -> BoxFuture<'a, Result<Identity, AuthError>>;
+}
+
+// Which built-in provider to use
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthProvider {
+    None,
+    Jwt,
+}
+
+impl FromStr for AuthProvider {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "none" => Ok(AuthProvider::None),
+            "jwt" => Ok(AuthProvider::Jwt),
+            _ => Err(()),
+        }
+    }
+}
+
+// The configured provider; JWT without a secret falls back to rejecting everyone
+pub fn from_config(config: &Config) -> Box<dyn Authenticator> {
+    match config.auth_provider {
+        AuthProvider::None => {
+            println!("🔓 Authentication disabled; every connection is accepted");
+            Box::new(AllowAll)
+        }
+        AuthProvider::Jwt => {
+            let Some(secret) = config.jwt_secret.clone() else {
+                eprintln!("❌ RABLY_AUTH=jwt needs RABLY_JWT_SECRET; rejecting all connections");
+                return Box::new(DenyAll);
+            };
+            println!("🔐 Authenticating connections with HS256 JWTs");
+            Box::new(JwtAuthenticator {
+                secret: secret.into_bytes(),
+                issuer: config.jwt_issuer.clone(),
+            })
+        }
+    }
+}
+
+// Development default: anyone may connect, under whatever identity they declare
+pub struct AllowAll;
+
+impl Authenticator for AllowAll {
+    fn authenticate<'a>(&'a self, ctx: &'a AuthContext) -> BoxFuture<'a, Result<Identity, AuthError>> {
+        Box::pin(async move {
+            Ok(Identity {
+                id: ctx.identity.clone(),
+                ..Identity::default()
+            })
+        })
+    }
+}
+
+struct DenyAll;
+
+impl Authenticator for DenyAll {
+    fn authenticate<'a>(&'a self, _ctx: &'a AuthContext) -> BoxFuture<'a, Result<Identity, AuthError>> {
+        Box::pin(async { Err(AuthError::InvalidCredentials("authentication is misconfigured")) })
+    }
+}
+
+// HS256-signed JWTs: `sub` becomes the identity, with optional `role` and `tenant` claims
+pub struct JwtAuthenticator {
+    secret: Vec<u8>,
+    issuer: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct JwtHeader {
+    alg: String,
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+    role: Option<String>,
+    tenant: Option<String>,
+    iss: Option<String>,
+    exp: Option<i64>,
+    nbf: Option<i64>,
+}
+
+impl JwtAuthenticator {
+    fn verify(&self, token: &str) -> Result<Identity, AuthError> {
+        let mut parts = token.split('.');
+        let (Some(header), Some(payload), Some(signature), None) = (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(AuthError::InvalidCredentials("malformed token"));
+        };
+
+        let header: JwtHeader = decode_json(header)?;
+        // Only the algorithm we configured; never "none" or an attacker's choice
+        if header.alg != "HS256" {
+            return Err(AuthError::InvalidCredentials("unsupported algorithm"));
+        }
+
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| AuthError::InvalidCredentials("malformed token"))?;
+        let signing_input = &token[..header_and_payload_len(token)];
+        if !constant_time_eq(&hmac_sha256(&self.secret, signing_input.as_bytes()), &signature) {
+            return Err(AuthError::InvalidCredentials("bad signature"));
+        }
+
+        let claims: Claims = decode_json(payload)?;
+        let now = clock::now().timestamp();
+        if claims.exp.is_some_and(|exp| now >= exp) {
+            return Err(AuthError::InvalidCredentials("token expired"));
+        }
+        if claims.nbf.is_some_and(|nbf| now < nbf) {
+            return Err(AuthError::InvalidCredentials("token not yet valid"));
+        }
+        if self.issuer.is_some() && claims.iss != self.issuer {
+            return Err(AuthError::InvalidCredentials("wrong issuer"));
+        }
+
+        Ok(Identity {
+            id: Some(claims.sub),
+            role: claims.role,
+            tenant: claims.tenant,
+        })
+    }
+}
+
+impl Authenticator for JwtAuthenticator {
+    fn authenticate<'a>(&'a self, ctx: &'a AuthContext) -> BoxFuture<'a, Result<Identity, AuthError>> {
+        Box::pin(async move {
+            let token = ctx.bearer_token().ok_or(AuthError::MissingCredentials)?;
+            self.verify(token)
+        })
+    }
+}
+
+fn decode_json<T: serde::de::DeserializeOwned>(segment: &str) -> Result<T, AuthError> {
+    let bytes = URL_SAFE_NO_PAD
+        .decode(segment)
+        .map_err(|_| AuthError::InvalidCredentials("malformed token"))?;
+    serde_json::from_slice(&bytes).map_err(|_| AuthError::InvalidCredentials("malformed token"))
+}
+
+// Length of "header.payload", the part of a token the signature covers
+fn header_and_payload_len(token: &str) -> usize {
+    token.rfind('.').unwrap_or(token.len())
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+// HMAC (RFC 2104) over SHA-256
+pub fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    const BLOCK: usize = 64;
+    let mut block_key = [0u8; BLOCK];
+    if key.len() > BLOCK {
+        block_key[..32].copy_from_slice(&sha256(key));
+    } else {
+        block_key[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner = block_key.map(|byte| byte ^ 0x36).to_vec();
+    inner.extend_from_slice(message);
+    let mut outer = block_key.map(|byte| byte ^ 0x5c).to_vec();
+    outer.extend_from_slice(&sha256(&inner));
+    sha256(&outer)
+}
+
+// SHA-256 (FIPS 180-4)
+fn sha256(data: &[u8]) -> [u8; 32] {
+    const K: [u32; 64] = [
+        0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+        0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+        0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+        0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+        0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+        0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+        0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+        0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+    ];
+    let mut h: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+    ];
+
+    let mut padded = data.to_vec();
+    padded.push(0x80);
+    while padded.len() % 64 != 56 {
+        padded.push(0);
+    }
+    padded.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for chunk in padded.chunks_exact(64) {
+        let mut w = [0u32; 64];
+        for (i, word) in chunk.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut hh] = h;
+        for (k, wi) in K.iter().zip(w) {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = hh.wrapping_add(s1).wrapping_add(ch).wrapping_add(*k).wrapping_add(wi);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            hh = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+
+        for (state, value) in h.iter_mut().zip([a, b, c, d, e, f, g, hh]) {
+            *state = state.wrapping_add(value);
+        }
+    }
+
+    let mut digest = [0u8; 32];
+    for (bytes, word) in digest.chunks_exact_mut(4).zip(h) {
+        bytes.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::mock::MockClock;
+    use chrono::DateTime;
+
+    const SECRET: &[u8] = b"classroom-secret";
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    fn authenticator(issuer: Option<&str>) -> JwtAuthenticator {
+        JwtAuthenticator {
+            secret: SECRET.to_vec(),
+            issuer: issuer.map(str::to_string),
+        }
+    }
+
+    // A token with the given header and claims, signed under `secret`
+    fn token(secret: &[u8], header: serde_json::Value, claims: serde_json::Value) -> String {
+        let signing_input = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(header.to_string()),
+            URL_SAFE_NO_PAD.encode(claims.to_string())
+        );
+        format!("{}.{}", signing_input, URL_SAFE_NO_PAD.encode(hmac_sha256(secret, signing_input.as_bytes())))
+    }
+
+    // Correctly signed, whatever the header and payload segments hold
+    fn signed(signing_input: &str) -> String {
+        let signature = URL_SAFE_NO_PAD.encode(hmac_sha256(SECRET, signing_input.as_bytes()));
+        format!("{}.{}", signing_input, signature)
+    }
+
+    fn hs256(claims: serde_json::Value) -> String {
+        token(SECRET, serde_json::json!({ "alg": "HS256", "typ": "JWT" }), claims)
+    }
+
+    fn rejection(result: Result<Identity, AuthError>) -> &'static str {
+        match result {
+            Err(AuthError::InvalidCredentials(reason)) => reason,
+            other => panic!("expected invalid credentials, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn sha256_known_answers() {
+        assert_eq!(hex(&sha256(b"")), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(hex(&sha256(b"abc")), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        // Two blocks once padded
+        assert_eq!(
+            hex(&sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+        assert_eq!(hex(&sha256(&[b'a'; 1000])), "41edece42d63e8d9bf515a9ba6932e1c20cbc9f5a5d134645adb5db1b9737ea3");
+    }
+
+    #[test]
+    fn hmac_sha256_rfc4231() {
+        // Test cases 1, 2, 3, 6 and 7
+        assert_eq!(
+            hex(&hmac_sha256(&[0x0b; 20], b"Hi There")),
+            "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7"
+        );
+        assert_eq!(
+            hex(&hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(
+            hex(&hmac_sha256(&[0xaa; 20], &[0xdd; 50])),
+            "773ea91e36800e46854db8ebd09181a72959098b3ef8c122d9635514ced565fe"
+        );
+        assert_eq!(
+            hex(&hmac_sha256(&[0xaa; 131], b"Test Using Larger Than Block-Size Key - Hash Key First")),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+        assert_eq!(
+            hex(&hmac_sha256(
+                &[0xaa; 131],
+                b"This is a test using a larger than block-size key and a larger than block-size data. \
+                  The key needs to be hashed before being used by the HMAC algorithm."
+            )),
+            "9b09ffa71b942fcb27635fbcd5b0e944bfdc63644f0713938a7f51535c3a35e2"
+        );
+    }
+
+    #[test]
+    fn jwt_accepts_a_valid_token() {
+        let token = hs256(serde_json::json!({ "sub": "teacher-1", "role": "teacher", "tenant": "school-a" }));
+        let identity = authenticator(None).verify(&token).expect("valid token");
+        assert_eq!(identity.id.as_deref(), Some("teacher-1"));
+        assert_eq!(identity.role.as_deref(), Some("teacher"));
+        assert_eq!(identity.tenant.as_deref(), Some("school-a"));
+    }
+
+    #[test]
+    fn jwt_rejects_a_bad_signature() {
+        let forged = token(b"another-secret", serde_json::json!({ "alg": "HS256" }), serde_json::json!({ "sub": "x" }));
+        assert_eq!(rejection(authenticator(None).verify(&forged)), "bad signature");
+
+        // A valid signature over different claims
+        let genuine = hs256(serde_json::json!({ "sub": "student" }));
+        let (_, signature) = genuine.rsplit_once('.').unwrap();
+        let claims = URL_SAFE_NO_PAD.encode(r#"{"sub":"teacher"}"#);
+        let header = genuine.split('.').next().unwrap();
+        let tampered = format!("{}.{}.{}", header, claims, signature);
+        assert_eq!(rejection(authenticator(None).verify(&tampered)), "bad signature");
+    }
+
+    #[test]
+    fn jwt_rejects_other_algorithms() {
+        let claims = serde_json::json!({ "sub": "x" });
+        let unsigned = format!(
+            "{}.{}.",
+            URL_SAFE_NO_PAD.encode(r#"{"alg":"none"}"#),
+            URL_SAFE_NO_PAD.encode(claims.to_string())
+        );
+        assert_eq!(rejection(authenticator(None).verify(&unsigned)), "unsupported algorithm");
+
+        for alg in ["HS512", "RS256", "hs256"] {
+            let token = token(SECRET, serde_json::json!({ "alg": alg }), claims.clone());
+            assert_eq!(rejection(authenticator(None).verify(&token)), "unsupported algorithm", "{}", alg);
+        }
+    }
+
+    #[test]
+    fn jwt_checks_exp_and_nbf_against_the_clock() {
+        let clock = MockClock::install(DateTime::from_timestamp(1_700_000_000, 0).unwrap());
+        let auth = authenticator(None);
+
+        let expiring = hs256(serde_json::json!({ "sub": "x", "exp": 1_700_000_060 }));
+        assert!(auth.verify(&expiring).is_ok());
+        clock.advance(std::time::Duration::from_secs(60));
+        assert_eq!(rejection(auth.verify(&expiring)), "token expired");
+
+        let early = hs256(serde_json::json!({ "sub": "x", "nbf": 1_700_000_120 }));
+        assert_eq!(rejection(auth.verify(&early)), "token not yet valid");
+        clock.advance(std::time::Duration::from_secs(60));
+        assert!(auth.verify(&early).is_ok());
+    }
+
+    #[test]
+    fn jwt_checks_the_issuer() {
+        let auth = authenticator(Some("https://idp.example"));
+        let right = hs256(serde_json::json!({ "sub": "x", "iss": "https://idp.example" }));
+        let wrong = hs256(serde_json::json!({ "sub": "x", "iss": "https://evil.example" }));
+        let missing = hs256(serde_json::json!({ "sub": "x" }));
+
+        assert!(auth.verify(&right).is_ok());
+        assert_eq!(rejection(auth.verify(&wrong)), "wrong issuer");
+        assert_eq!(rejection(auth.verify(&missing)), "wrong issuer");
+    }
+
+    #[test]
+    fn jwt_rejects_malformed_tokens() {
+        let valid = hs256(serde_json::json!({ "sub": "x" }));
+        let header = valid.split('.').next().unwrap();
+        let cases = [
+            String::new(),
+            "not-a-token".to_string(),
+            format!("{}.payload", header),
+            format!("{}.extra", valid),
+            format!("{}.{}.sig", URL_SAFE_NO_PAD.encode("not json"), URL_SAFE_NO_PAD.encode("{}")),
+            format!("{}.%%%", &valid[..valid.rfind('.').unwrap()]),
+            signed(&format!("{}.!!!", header)),
+            signed(&format!("{}.{}", header, URL_SAFE_NO_PAD.encode("not json"))),
+        ];
+        for case in &cases {
+            assert_eq!(rejection(authenticator(None).verify(case)), "malformed token", "{:?}", case);
+        }
+
+        // Signed, but without the `sub` claim
+        let anonymous = hs256(serde_json::json!({ "role": "teacher" }));
+        assert_eq!(rejection(authenticator(None).verify(&anonymous)), "malformed token");
+    }
+
+    #[tokio::test]
+    async fn jwt_authenticator_reads_the_bearer_token() {
+        let auth = authenticator(None);
+        let mut ctx = AuthContext {
+            headers: HeaderMap::new(),
+            token: None,
+            identity: None,
+        };
+        assert!(matches!(auth.authenticate(&ctx).await, Err(AuthError::MissingCredentials)));
+
+        let token = hs256(serde_json::json!({ "sub": "from-header" }));
+        ctx.headers.insert("authorization", format!("Bearer {}", token).parse().unwrap());
+        ctx.token = Some(hs256(serde_json::json!({ "sub": "from-url" })));
+        assert_eq!(auth.authenticate(&ctx).await.unwrap().id.as_deref(), Some("from-header"));
+    }
+}
@@ -0,0 +1,247 @@
+// CBOR (RFC 8949) encoding for constrained clients.
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use serde_json::{Map, Number, Value};
+
+// WebSocket subprotocol a client offers to switch its connection to CBOR
+pub const SUBPROTOCOL: &str = "rably.cbor";
+
+// Deepest nesting accepted from a client, so a hostile frame can't exhaust the stack
+const MAX_DEPTH: usize = 64;
+
+const BREAK: u8 = 0xff;
+
+pub fn encode(value: &Value) -> Vec<u8> {
+    let mut out = Vec::new();
+    write_value(&mut out, value);
+    out
+}
+
+// Initial byte and argument of a data item, in the shortest form
+fn write_head(out: &mut Vec<u8>, major: u8, argument: u64) {
+    let major = major << 5;
+    match argument {
+        0..=23 => out.push(major | argument as u8),
+        24..=0xff => out.extend_from_slice(&[major | 24, argument as u8]),
+        0x100..=0xffff => {
+            out.push(major | 25);
+            out.extend_from_slice(&(argument as u16).to_be_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            out.push(major | 26);
+            out.extend_from_slice(&(argument as u32).to_be_bytes());
+        }
+        _ => {
+            out.push(major | 27);
+            out.extend_from_slice(&argument.to_be_bytes());
+        }
+    }
+}
+
+fn write_value(out: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Null => out.push(0xf6),
+        Value::Bool(false) => out.push(0xf4),
+        Value::Bool(true) => out.push(0xf5),
+        Value::Number(number) => {
+            if let Some(unsigned) = number.as_u64() {
+                write_head(out, 0, unsigned);
+            } else if let Some(negative) = number.as_i64() {
+                // Major type 1 holds -1 - n
+                write_head(out, 1, !negative as u64);
+            } else {
+                out.push(0xfb);
+                out.extend_from_slice(&number.as_f64().unwrap_or_default().to_be_bytes());
+            }
+        }
+        Value::String(text) => {
+            write_head(out, 3, text.len() as u64);
+            out.extend_from_slice(text.as_bytes());
+        }
+        Value::Array(items) => {
+            write_head(out, 4, items.len() as u64);
+            for item in items {
+                write_value(out, item);
+            }
+        }
+        Value::Object(fields) => {
+            write_head(out, 5, fields.len() as u64);
+            for (key, field) in fields {
+                write_head(out, 3, key.len() as u64);
+                out.extend_from_slice(key.as_bytes());
+                write_value(out, field);
+            }
+        }
+    }
+}
+
+// One complete data item, as the JSON value it stands for
+pub fn decode(bytes: &[u8]) -> Result<Value, &'static str> {
+    let mut reader = Reader { bytes, at: 0 };
+    let value = reader.value(0)?;
+    if reader.at != bytes.len() {
+        return Err("trailing bytes after the data item");
+    }
+    Ok(value)
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8], &'static str> {
+        let end = self.at.checked_add(count).filter(|end| *end <= self.bytes.len()).ok_or("truncated data item")?;
+        let taken = &self.bytes[self.at..end];
+        self.at = end;
+        Ok(taken)
+    }
+
+    fn byte(&mut self) -> Result<u8, &'static str> {
+        Ok(self.take(1)?[0])
+    }
+
+    // Consume a break if one is next, ending an indefinite-length item
+    fn at_break(&mut self) -> bool {
+        let found = self.bytes.get(self.at) == Some(&BREAK);
+        if found {
+            self.at += 1;
+        }
+        found
+    }
+
+    // The argument following an initial byte; None for indefinite length
+    fn argument(&mut self, info: u8) -> Result<Option<u64>, &'static str> {
+        let argument = match info {
+            0..=23 => info as u64,
+            24 => self.byte()? as u64,
+            25 => u16::from_be_bytes(self.take(2)?.try_into().unwrap_or_default()) as u64,
+            26 => u32::from_be_bytes(self.take(4)?.try_into().unwrap_or_default()) as u64,
+            27 => u64::from_be_bytes(self.take(8)?.try_into().unwrap_or_default()),
+            31 => return Ok(None),
+            _ => return Err("malformed data item"),
+        };
+        Ok(Some(argument))
+    }
+
+    fn definite(&mut self, info: u8) -> Result<u64, &'static str> {
+        self.argument(info)?.ok_or("indefinite length not allowed here")
+    }
+
+    // Contents of a byte or text string of the given major type, joining indefinite chunks
+    fn string(&mut self, major: u8, info: u8) -> Result<Vec<u8>, &'static str> {
+        match self.argument(info)? {
+            Some(length) => Ok(self.take(usize::try_from(length).map_err(|_| "truncated data item")?)?.to_vec()),
+            None => {
+                let mut joined = Vec::new();
+                while !self.at_break() {
+                    let initial = self.byte()?;
+                    if initial >> 5 != major {
+                        return Err("malformed string chunk");
+                    }
+                    let length = self.definite(initial & 0x1f)?;
+                    joined.extend_from_slice(self.take(usize::try_from(length).map_err(|_| "truncated data item")?)?);
+                }
+                Ok(joined)
+            }
+        }
+    }
+
+    fn text(&mut self, info: u8) -> Result<String, &'static str> {
+        String::from_utf8(self.string(3, info)?).map_err(|_| "text string is not valid UTF-8")
+    }
+
+    // Items left to read bound how much a claimed length may preallocate
+    fn capacity(&self, claimed: u64) -> usize {
+        (claimed as usize).min(self.bytes.len() - self.at)
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value, &'static str> {
+        if depth > MAX_DEPTH {
+            return Err("nested too deeply");
+        }
+        let initial = self.byte()?;
+        let (major, info) = (initial >> 5, initial & 0x1f);
+
+        match major {
+            0 => Ok(Value::from(self.definite(info)?)),
+            1 => {
+                let n = i64::try_from(self.definite(info)?).map_err(|_| "integer out of range")?;
+                Ok(Value::from(-1 - n))
+            }
+            2 => Ok(Value::String(BASE64_STANDARD.encode(self.string(2, info)?))),
+            3 => Ok(Value::String(self.text(info)?)),
+            4 => {
+                let length = self.argument(info)?;
+                let mut items = Vec::with_capacity(length.map_or(0, |length| self.capacity(length)));
+                match length {
+                    Some(length) => {
+                        for _ in 0..length {
+                            items.push(self.value(depth + 1)?);
+                        }
+                    }
+                    None => {
+                        while !self.at_break() {
+                            items.push(self.value(depth + 1)?);
+                        }
+                    }
+                }
+                Ok(Value::Array(items))
+            }
+            5 => {
+                let length = self.argument(info)?;
+                let mut fields = Map::new();
+                let mut remaining = length;
+                loop {
+                    if let Some(left) = remaining.as_mut() {
+                        if *left == 0 {
+                            break;
+                        }
+                        *left -= 1;
+                    } else if self.at_break() {
+                        break;
+                    }
+                    let key_initial = self.byte()?;
+                    if key_initial >> 5 != 3 {
+                        return Err("map keys must be text strings");
+                    }
+                    let key = self.text(key_initial & 0x1f)?;
+                    fields.insert(key, self.value(depth + 1)?);
+                }
+                Ok(Value::Object(fields))
+            }
+            // Tags add meaning the envelope doesn't use; keep the tagged item
+            6 => {
+                self.definite(info)?;
+                self.value(depth + 1)
+            }
+            _ => match info {
+                20 => Ok(Value::Bool(false)),
+                21 => Ok(Value::Bool(true)),
+                22 | 23 => Ok(Value::Null),
+                25 => float(half(u16::from_be_bytes(self.take(2)?.try_into().unwrap_or_default()))),
+                26 => float(f32::from_be_bytes(self.take(4)?.try_into().unwrap_or_default()) as f64),
+                27 => float(f64::from_be_bytes(self.take(8)?.try_into().unwrap_or_default())),
+                _ => Err("unsupported simple value"),
+            },
+        }
+    }
+}
+
+fn float(value: f64) -> Result<Value, &'static str> {
+    Number::from_f64(value).map(Value::Number).ok_or("NaN and infinity have no JSON form")
+}
+
+// IEEE 754 half precision, which CBOR encoders use for short floats
+fn half(bits: u16) -> f64 {
+    let exponent = (bits >> 10) & 0x1f;
+    let mantissa = (bits & 0x3ff) as f64;
+    let magnitude = match exponent {
+        0 => mantissa * 2f64.powi(-24),
+        31 if mantissa == 0.0 => f64::INFINITY,
+        31 => f64::NAN,
+        _ => (mantissa + 1024.0) * 2f64.powi(exponent as i32 - 25),
+    };
+    if bits & 0x8000 != 0 { -magnitude } else { magnitude }
+}
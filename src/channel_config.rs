@@ -0,0 +1,158 @@
+// Per-channel overrides of global settings.
+
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::{ordering::{self, OrderingMode}, presence, publish_slots, roles, AppState};
+
+// Largest history an override may ask for, so one channel can't take all the memory
+const MAX_HISTORY_SIZE: usize = 100_000;
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChannelOverrides {
+    // Participants allowed in the channel at once; otherwise unlimited
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_subscribers: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub slide_change_max_per_sec: Option<f64>,
+    // Slides may only move forward, by slide_index
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub monotonic_slides: Option<bool>,
+    // Publishes processed at once, 0 meaning unlimited
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_concurrent_publishes: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub history_size: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub history_max_bytes: Option<usize>,
+    // Takes precedence over ordering rules and a moderator's choice
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ordering: Option<OrderingMode>,
+    // Seconds from creation until the channel expires, 0 meaning never
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_lifetime_secs: Option<u64>,
+    // Publishes wait for slow subscribers rather than letting them skip messages
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reliable: Option<bool>,
+}
+
+impl ChannelOverrides {
+    pub fn is_empty(&self) -> bool {
+        self.max_subscribers.is_none()
+            && self.slide_change_max_per_sec.is_none()
+            && self.monotonic_slides.is_none()
+            && self.max_concurrent_publishes.is_none()
+            && self.history_size.is_none()
+            && self.history_max_bytes.is_none()
+            && self.ordering.is_none()
+            && self.max_lifetime_secs.is_none()
+            && self.reliable.is_none()
+    }
+
+    // Catch values that deserialize fine but make no sense
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.max_subscribers == Some(0) {
+            return Err("max_subscribers must be at least 1");
+        }
+        if self
+            .slide_change_max_per_sec
+            .is_some_and(|rate| !rate.is_finite() || rate < 0.0)
+        {
+            return Err("slide_change_max_per_sec must be zero or a positive number");
+        }
+        if self.history_size.is_some_and(|size| size > MAX_HISTORY_SIZE) {
+            return Err("history_size may be at most 100000");
+        }
+        Ok(())
+    }
+}
+
+// The settings a channel runs with once overrides are applied
+#[derive(Serialize)]
+pub struct ChannelSettings {
+    pub max_subscribers: Option<usize>,
+    pub slide_change_max_per_sec: f64,
+    pub monotonic_slides: bool,
+    pub max_concurrent_publishes: usize,
+    pub history_size: usize,
+    pub history_max_bytes: usize,
+    pub ordering: OrderingMode,
+    pub max_lifetime_secs: u64,
+    pub reliable: bool,
+}
+
+pub fn overrides(state: &AppState, channel: &str) -> ChannelOverrides {
+    state.channel_overrides.get(channel).map(|overrides| *overrides).unwrap_or_default()
+}
+
+pub fn effective(state: &AppState, channel: &str) -> ChannelSettings {
+    let overrides = overrides(state, channel);
+    let live = state.live();
+    ChannelSettings {
+        max_subscribers: overrides.max_subscribers,
+        slide_change_max_per_sec: overrides.slide_change_max_per_sec.unwrap_or(live.slide_change_max_per_sec),
+        monotonic_slides: overrides.monotonic_slides.unwrap_or(state.config.monotonic_slides),
+        max_concurrent_publishes: publish_slots::limit(state, channel),
+        history_size: overrides.history_size.unwrap_or(live.history_size),
+        history_max_bytes: overrides.history_max_bytes.unwrap_or(live.history_max_bytes),
+        ordering: ordering::mode(state, channel),
+        max_lifetime_secs: max_lifetime_secs(state, channel),
+        reliable: reliable(state, channel),
+    }
+}
+
+// Message count and byte budget for the channel's history buffer
+pub fn history_limits(state: &AppState, channel: &str) -> (usize, usize) {
+    let overrides = overrides(state, channel);
+    let live = state.live();
+    (
+        overrides.history_size.unwrap_or(live.history_size),
+        overrides.history_max_bytes.unwrap_or(live.history_max_bytes),
+    )
+}
+
+// Seconds from creation until the channel expires, 0 if it never does
+pub fn max_lifetime_secs(state: &AppState, channel: &str) -> u64 {
+    overrides(state, channel)
+        .max_lifetime_secs
+        .unwrap_or(state.config.channel_max_lifetime_secs)
+}
+
+// Whether publishes on the channel wait for its slowest subscriber
+pub fn reliable(state: &AppState, channel: &str) -> bool {
+    overrides(state, channel).reliable.unwrap_or_else(|| {
+        state
+            .config
+            .reliable_channels
+            .iter()
+            .any(|pattern| roles::matches_pattern(pattern, channel))
+    })
+}
+
+// Minimum spacing between one client's slide changes on the channel, if capped
+pub fn slide_interval(state: &AppState, channel: &str) -> Option<Duration> {
+    Some(
+        overrides(state, channel)
+            .slide_change_max_per_sec
+            .unwrap_or(state.live().slide_change_max_per_sec),
+    )
+    .filter(|rate| *rate > 0.0)
+    .map(|rate| Duration::from_secs_f64(1.0 / rate))
+}
+
+// Whether the channel refuses slide changes that go back to an earlier slide
+pub fn monotonic_slides(state: &AppState, channel: &str) -> bool {
+    overrides(state, channel).monotonic_slides.unwrap_or(state.config.monotonic_slides)
+}
+
+// Whether the channel has room for one more participant
+pub fn has_room(state: &AppState, channel: &str) -> bool {
+    let Some(max_subscribers) = overrides(state, channel).max_subscribers else {
+        return true;
+    };
+    // Away entries don't count, so participants in their grace window can't lock others out
+    state.channel_presence.get(channel).is_none_or(|channel_map| {
+        channel_map.iter().filter(|info| info.status == presence::STATUS_ONLINE).count() < max_subscribers
+    })
+}
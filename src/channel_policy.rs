@@ -0,0 +1,116 @@
+// Pluggable channel lifecycle policies: ChannelPolicy hooks, registered by channel name prefix
+// in `from_config`, for creation, first subscriber, emptying and each publish.
+
+use std::sync::Arc;
+
+use crate::{broadcast_event, config::Config, headers::Headers, sticky, AppState};
+
+// What a hook may look at and do on its channel
+pub struct ChannelContext<'a> {
+    state: &'a AppState,
+    channel: &'a str,
+}
+
+// A policy's toolkit, which the no-op default leaves unused
+#[allow(dead_code)]
+impl ChannelContext<'_> {
+    pub fn channel(&self) -> &str {
+        self.channel
+    }
+
+    pub fn subscribers(&self) -> usize {
+        self.state.channels.get(self.channel).map(|tx| tx.receiver_count()).unwrap_or(0)
+    }
+
+    // Send a server event to everyone on the channel; it is numbered and kept in history
+    pub fn broadcast(&self, event_type: &str, data: serde_json::Value) {
+        broadcast_event(self.state, self.channel, event_type, data);
+    }
+
+    // Pin a message for clients that subscribe from now on
+    pub fn set_sticky(&self, data: serde_json::Value) {
+        sticky::set(self.state, self.channel, data, "server");
+    }
+}
+
+// A client publish as a policy sees it
+#[allow(dead_code)]
+pub struct PublishAttempt<'a> {
+    // The publishing action, e.g. "publish" or "slide_change"
+    pub action: &'a str,
+    pub client_id: &'a str,
+    // Withheld from end-to-end encrypted publishes
+    pub data: Option<&'a serde_json::Value>,
+    pub encrypted: bool,
+    // Readable even when the data isn't
+    pub headers: Option<&'a Headers>,
+}
+
+pub trait ChannelPolicy: Send + Sync {
+    fn on_create(&self, _ctx: &ChannelContext) {}
+
+    fn on_first_subscriber(&self, _ctx: &ChannelContext) {}
+
+    fn on_empty(&self, _ctx: &ChannelContext) {}
+
+    // The reason to refuse the publish, if it should be
+    fn on_message(&self, _ctx: &ChannelContext, _attempt: &PublishAttempt) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+// Does nothing; the behavior of a deployment without custom policies
+pub struct NoopPolicy;
+
+impl ChannelPolicy for NoopPolicy {}
+
+// Policies by the channel name prefix they apply to
+#[derive(Default)]
+pub struct ChannelPolicies {
+    policies: Vec<(String, Arc<dyn ChannelPolicy>)>,
+}
+
+impl ChannelPolicies {
+    // Apply a policy to channels whose name starts with the prefix ("" for every channel)
+    pub fn register(&mut self, prefix: &str, policy: Arc<dyn ChannelPolicy>) {
+        self.policies.push((prefix.to_string(), policy));
+    }
+
+    fn matching<'a>(&'a self, channel: &'a str) -> impl Iterator<Item = &'a Arc<dyn ChannelPolicy>> {
+        self.policies
+            .iter()
+            .filter(move |(prefix, _)| channel.starts_with(prefix.as_str()))
+            .map(|(_, policy)| policy)
+    }
+}
+
+// The deployment's policies; register custom ones here
+pub fn from_config(_config: &Config) -> ChannelPolicies {
+    let mut policies = ChannelPolicies::default();
+    policies.register("", Arc::new(NoopPolicy));
+    policies
+}
+
+pub fn created(state: &AppState, channel: &str) {
+    let ctx = ChannelContext { state, channel };
+    state.channel_policies.matching(channel).for_each(|policy| policy.on_create(&ctx));
+}
+
+pub fn first_subscriber(state: &AppState, channel: &str) {
+    let ctx = ChannelContext { state, channel };
+    state.channel_policies.matching(channel).for_each(|policy| policy.on_first_subscriber(&ctx));
+}
+
+pub fn emptied(state: &AppState, channel: &str) {
+    let ctx = ChannelContext { state, channel };
+    state.channel_policies.matching(channel).for_each(|policy| policy.on_empty(&ctx));
+}
+
+// Ask every matching policy about a publish; the first refusal wins
+pub fn check_publish(state: &AppState, channel: &str, attempt: &PublishAttempt) -> Result<(), String> {
+    let ctx = ChannelContext { state, channel };
+    state
+        .channel_policies
+        .matching(channel)
+        .try_for_each(|policy| policy.on_message(&ctx, attempt))
+}
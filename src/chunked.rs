@@ -0,0 +1,177 @@
+// Chunked publishes.
+
+use std::{collections::HashMap, fmt, time::Duration};
+use tokio::time::Instant;
+
+use crate::{send_direct, AppState, Outgoing};
+
+// Transfers one connection may have in progress at once
+const MAX_TRANSFERS: usize = 4;
+
+// A payload being reassembled
+pub struct Transfer {
+    channel: String,
+    total_size: usize,
+    buffer: String,
+    // When the transfer is abandoned unless another chunk arrives
+    deadline: Instant,
+}
+
+// A connection's transfers in progress, by transfer id
+pub type Transfers = HashMap<String, Transfer>;
+
+#[derive(Debug)]
+pub enum TransferError {
+    Disabled,
+    MissingId,
+    TooLarge { max: usize },
+    TooMany,
+    AlreadyStarted,
+    Unknown,
+    WrongChannel,
+    NotAString,
+    Overrun { total_size: usize },
+    Incomplete { received: usize, total_size: usize },
+    InvalidJson(String),
+}
+
+impl TransferError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            TransferError::Disabled | TransferError::MissingId | TransferError::NotAString => "invalid_request",
+            TransferError::TooLarge { .. } => "transfer_too_large",
+            TransferError::TooMany => "too_many_transfers",
+            TransferError::AlreadyStarted => "duplicate_transfer",
+            TransferError::Unknown | TransferError::WrongChannel => "unknown_transfer",
+            TransferError::Overrun { .. } | TransferError::Incomplete { .. } => "transfer_size_mismatch",
+            TransferError::InvalidJson(_) => "invalid_transfer",
+        }
+    }
+}
+
+impl fmt::Display for TransferError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransferError::Disabled => write!(f, "Chunked transfers are disabled"),
+            TransferError::MissingId => write!(f, "Chunked transfers need a transfer_id"),
+            TransferError::TooLarge { max } => write!(f, "A transfer may be at most {} bytes; give its total_size", max),
+            TransferError::TooMany => write!(f, "At most {} transfers may be in progress at once", MAX_TRANSFERS),
+            TransferError::AlreadyStarted => write!(f, "A transfer with this transfer_id is already in progress"),
+            TransferError::Unknown => write!(f, "No transfer with this transfer_id is in progress"),
+            TransferError::WrongChannel => write!(f, "The transfer was started on another channel"),
+            TransferError::NotAString => write!(f, "A chunk's data must be a string"),
+            TransferError::Overrun { total_size } => write!(f, "Chunks add up to more than the total_size of {} bytes", total_size),
+            TransferError::Incomplete { received, total_size } => {
+                write!(f, "Only {} of the total_size of {} bytes arrived", received, total_size)
+            }
+            TransferError::InvalidJson(e) => write!(f, "Reassembled data is not valid JSON: {}", e),
+        }
+    }
+}
+
+fn timeout(state: &AppState) -> Duration {
+    Duration::from_secs(state.config.transfer_timeout_secs.max(1))
+}
+
+// Begin reassembling a transfer announced by chunk_start
+pub fn start(
+    state: &AppState,
+    transfers: &mut Transfers,
+    transfer_id: Option<&str>,
+    channel: &str,
+    total_size: Option<usize>,
+) -> Result<(), TransferError> {
+    let max = state.config.max_transfer_size;
+    if max == 0 {
+        return Err(TransferError::Disabled);
+    }
+    let transfer_id = transfer_id.ok_or(TransferError::MissingId)?;
+    let total_size = total_size.filter(|size| *size <= max).ok_or(TransferError::TooLarge { max })?;
+    if transfers.contains_key(transfer_id) {
+        return Err(TransferError::AlreadyStarted);
+    }
+    if transfers.len() >= MAX_TRANSFERS {
+        return Err(TransferError::TooMany);
+    }
+
+    transfers.insert(
+        transfer_id.to_string(),
+        Transfer {
+            channel: channel.to_string(),
+            total_size,
+            buffer: String::new(),
+            deadline: Instant::now() + timeout(state),
+        },
+    );
+    Ok(())
+}
+
+// Take the transfer a chunk or chunk_end refers to, if it's on the same channel
+fn take(transfers: &mut Transfers, transfer_id: Option<&str>, channel: &str) -> Result<(String, Transfer), TransferError> {
+    let transfer_id = transfer_id.ok_or(TransferError::MissingId)?;
+    let transfer = transfers.remove(transfer_id).ok_or(TransferError::Unknown)?;
+    if transfer.channel != channel {
+        transfers.insert(transfer_id.to_string(), transfer);
+        return Err(TransferError::WrongChannel);
+    }
+    Ok((transfer_id.to_string(), transfer))
+}
+
+// Add the next piece of a transfer. A transfer that goes wrong is abandoned.
+pub fn append(
+    state: &AppState,
+    transfers: &mut Transfers,
+    transfer_id: Option<&str>,
+    channel: &str,
+    data: Option<serde_json::Value>,
+) -> Result<(), TransferError> {
+    let (transfer_id, mut transfer) = take(transfers, transfer_id, channel)?;
+    let Some(serde_json::Value::String(piece)) = data else {
+        return Err(TransferError::NotAString);
+    };
+    if transfer.buffer.len() + piece.len() > transfer.total_size {
+        return Err(TransferError::Overrun { total_size: transfer.total_size });
+    }
+
+    transfer.buffer.push_str(&piece);
+    transfer.deadline = Instant::now() + timeout(state);
+    transfers.insert(transfer_id, transfer);
+    Ok(())
+}
+
+// The data a completed transfer carries, parsed from its reassembled text
+pub fn finish(transfers: &mut Transfers, transfer_id: Option<&str>, channel: &str) -> Result<serde_json::Value, TransferError> {
+    let (_, transfer) = take(transfers, transfer_id, channel)?;
+    if transfer.buffer.len() != transfer.total_size {
+        return Err(TransferError::Incomplete { received: transfer.buffer.len(), total_size: transfer.total_size });
+    }
+    serde_json::from_str(&transfer.buffer).map_err(|e| TransferError::InvalidJson(e.to_string()))
+}
+
+// When the next transfer in progress stalls, if any is
+pub fn next_deadline(transfers: &Transfers) -> Option<Instant> {
+    transfers.values().map(|transfer| transfer.deadline).min()
+}
+
+// Abandon the transfers that stalled, telling the client which
+pub fn abandon_stalled(transfers: &mut Transfers, outgoing_tx: &Outgoing) {
+    let now = Instant::now();
+    transfers.retain(|transfer_id, transfer| {
+        if transfer.deadline > now {
+            return true;
+        }
+        let error = serde_json::json!({
+            "code": "transfer_stalled",
+            "message": "No chunk arrived in time; the transfer was abandoned",
+            "transfer_id": transfer_id,
+        });
+        send_direct(outgoing_tx, None, &transfer.channel, "error", error);
+        false
+    });
+}
+
+// Report a failed transfer message, naming the transfer
+pub fn send_error(outgoing_tx: &Outgoing, request_id: Option<&str>, channel: &str, transfer_id: Option<&str>, e: TransferError) {
+    let error = serde_json::json!({ "code": e.code(), "message": e.to_string(), "transfer_id": transfer_id });
+    send_direct(outgoing_tx, request_id, channel, "error", error);
+}
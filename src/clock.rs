@@ -0,0 +1,126 @@
+// Wall-clock time behind message timestamps, join times, expiry and the sweepers.
+
+use chrono::{DateTime, Utc};
+
+pub trait Clock {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    #[inline]
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+#[cfg(not(test))]
+#[inline]
+pub fn now() -> DateTime<Utc> {
+    SystemClock.now()
+}
+
+// The thread's mock clock if one is installed, otherwise the system clock
+#[cfg(test)]
+pub fn now() -> DateTime<Utc> {
+    mock::CURRENT
+        .with(|current| current.borrow().as_ref().map(|clock| clock.now()))
+        .unwrap_or_else(|| SystemClock.now())
+}
+
+// Support for tests of time-based features
+#[cfg(test)]
+pub mod mock {
+    use chrono::{DateTime, Utc};
+    use std::{
+        cell::RefCell,
+        sync::{
+            atomic::{AtomicI64, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use super::Clock;
+
+    thread_local! {
+        pub(super) static CURRENT: RefCell<Option<Arc<MockClock>>> = const { RefCell::new(None) };
+    }
+
+    // A clock that only moves when told to
+    pub struct MockClock {
+        now_ms: AtomicI64,
+    }
+
+    impl MockClock {
+        // Make the thread's clock read `start` until advanced; the system clock is back once
+        // the returned guard is dropped
+        pub fn install(start: DateTime<Utc>) -> MockGuard {
+            let clock = Arc::new(MockClock {
+                now_ms: AtomicI64::new(start.timestamp_millis()),
+            });
+            CURRENT.with(|current| *current.borrow_mut() = Some(clock.clone()));
+            MockGuard { clock }
+        }
+
+        pub fn advance(&self, by: Duration) {
+            self.now_ms.fetch_add(by.as_millis() as i64, Ordering::Relaxed);
+        }
+
+        pub fn set(&self, to: DateTime<Utc>) {
+            self.now_ms.store(to.timestamp_millis(), Ordering::Relaxed);
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> DateTime<Utc> {
+            DateTime::from_timestamp_millis(self.now_ms.load(Ordering::Relaxed)).unwrap_or_default()
+        }
+    }
+
+    pub struct MockGuard {
+        clock: Arc<MockClock>,
+    }
+
+    impl std::ops::Deref for MockGuard {
+        type Target = MockClock;
+
+        fn deref(&self) -> &MockClock {
+            &self.clock
+        }
+    }
+
+    impl Drop for MockGuard {
+        fn drop(&mut self) {
+            CURRENT.with(|current| *current.borrow_mut() = None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{mock::MockClock, *};
+    use std::time::Duration;
+
+    #[test]
+    fn the_mock_moves_only_when_told_and_goes_away_with_its_guard() {
+        let start = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        {
+            let clock = MockClock::install(start);
+            assert_eq!(now(), start);
+            std::thread::sleep(Duration::from_millis(5));
+            assert_eq!(now(), start);
+
+            clock.advance(Duration::from_millis(1500));
+            assert_eq!(now().timestamp_millis(), start.timestamp_millis() + 1500);
+            clock.set(start);
+            assert_eq!(now(), start);
+
+            // Other threads keep the system clock
+            let elsewhere = std::thread::spawn(now).join().unwrap();
+            assert!(elsewhere > start + chrono::Duration::days(365));
+        }
+        assert!(now() > start + chrono::Duration::days(365));
+    }
+}
@@ -0,0 +1,147 @@
+use axum::extract::ws::{CloseFrame, Message};
+
+// Why the server closed a connection, sent to the client in the close frame
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CloseReason {
+    // No frames received within the idle timeout
+    Idle,
+    // Disconnected by an administrator
+    Kicked,
+    // Outgoing queue stayed full past the slow-consumer grace period
+    TooSlow,
+    // A send to the socket stalled past the send timeout
+    SendTimeout,
+    // The server is shutting down; reconnect to another instance
+    ServerShutdown,
+    // Closed to relieve memory pressure; lower connection tiers go first
+    Overloaded,
+    // An administrator ended the whole channel, with a code and reason of their choosing
+    ChannelClosed { code: u16, reason: String },
+    // The connection's channel reached its maximum lifetime
+    ChannelExpired,
+}
+
+impl CloseReason {
+    pub fn as_str(&self) -> &str {
+        match self {
+            CloseReason::Idle => "idle",
+            CloseReason::Kicked => "kicked",
+            CloseReason::TooSlow => "too_slow",
+            CloseReason::SendTimeout => "send_timeout",
+            CloseReason::ServerShutdown => "server_shutdown",
+            CloseReason::Overloaded => "overloaded",
+            CloseReason::ChannelClosed { reason, .. } => reason,
+            CloseReason::ChannelExpired => "channel_expired",
+        }
+    }
+
+    // Fixed name of the reason, for metric labels; admins choose channel_closed reasons freely
+    pub fn kind(&self) -> &'static str {
+        match self {
+            CloseReason::ChannelClosed { .. } => "channel_closed",
+            CloseReason::ChannelExpired => "channel_expired",
+            CloseReason::Idle => "idle",
+            CloseReason::Kicked => "kicked",
+            CloseReason::TooSlow => "too_slow",
+            CloseReason::SendTimeout => "send_timeout",
+            CloseReason::ServerShutdown => "server_shutdown",
+            CloseReason::Overloaded => "overloaded",
+        }
+    }
+
+    // RFC 6455 status code, so clients can decide whether to reconnect
+    pub fn code(&self) -> u16 {
+        match self {
+            CloseReason::Idle | CloseReason::ChannelExpired => 1000,
+            CloseReason::ServerShutdown => 1001,
+            CloseReason::Kicked => 1008,
+            CloseReason::TooSlow | CloseReason::SendTimeout | CloseReason::Overloaded => 1013,
+            CloseReason::ChannelClosed { code, .. } => *code,
+        }
+    }
+
+    pub fn frame(&self) -> Message {
+        Message::Close(Some(CloseFrame {
+            code: self.code(),
+            reason: self.as_str().into(),
+        }))
+    }
+}
+
+// How a connection ended, for logging and cleanup
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DisconnectReason {
+    // The client sent a close frame
+    ClientClosed,
+    // The stream ended without a close frame, e.g. the TCP connection dropped
+    StreamEnded,
+    // Reading from the socket failed
+    ReadError,
+    // The server ended the connection
+    Server(CloseReason),
+}
+
+impl DisconnectReason {
+    pub fn as_str(&self) -> &str {
+        match self {
+            DisconnectReason::ClientClosed => "client_closed",
+            DisconnectReason::StreamEnded => "stream_ended",
+            DisconnectReason::ReadError => "read_error",
+            DisconnectReason::Server(reason) => reason.as_str(),
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            DisconnectReason::Server(reason) => reason.kind(),
+            DisconnectReason::ClientClosed => "client_closed",
+            DisconnectReason::StreamEnded => "stream_ended",
+            DisconnectReason::ReadError => "read_error",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close_frame(reason: &CloseReason) -> (u16, String) {
+        match reason.frame() {
+            Message::Close(Some(frame)) => (frame.code, frame.reason.to_string()),
+            other => panic!("expected a close frame, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn each_reason_closes_with_its_code() {
+        let cases = [
+            (CloseReason::Idle, 1000, "idle"),
+            (CloseReason::ChannelExpired, 1000, "channel_expired"),
+            (CloseReason::ServerShutdown, 1001, "server_shutdown"),
+            (CloseReason::Kicked, 1008, "kicked"),
+            (CloseReason::TooSlow, 1013, "too_slow"),
+            (CloseReason::SendTimeout, 1013, "send_timeout"),
+            (CloseReason::Overloaded, 1013, "overloaded"),
+        ];
+        for (reason, code, text) in cases {
+            assert_eq!(close_frame(&reason), (code, text.to_string()), "{:?}", reason);
+            assert_eq!(reason.kind(), text);
+        }
+    }
+
+    #[test]
+    fn channel_closes_use_the_admins_code_and_reason() {
+        let reason = CloseReason::ChannelClosed { code: 4000, reason: "lesson_over".to_string() };
+        assert_eq!(close_frame(&reason), (4000, "lesson_over".to_string()));
+        assert_eq!(reason.kind(), "channel_closed");
+        assert_eq!(DisconnectReason::Server(reason).kind(), "channel_closed");
+    }
+
+    #[test]
+    fn client_side_endings_have_their_own_kinds() {
+        assert_eq!(DisconnectReason::ClientClosed.kind(), "client_closed");
+        assert_eq!(DisconnectReason::StreamEnded.kind(), "stream_ended");
+        assert_eq!(DisconnectReason::ReadError.kind(), "read_error");
+        assert_eq!(DisconnectReason::Server(CloseReason::Kicked).as_str(), "kicked");
+    }
+}
@@ -0,0 +1,51 @@
+// Per-connection coalescing of slide changes.
+
+use axum::extract::ws::Message;
+use std::sync::{Arc, Mutex};
+
+type Slot = Arc<Mutex<Option<Message>>>;
+
+// An entry in a connection's high-priority queue
+pub enum Queued {
+    Frame(Message),
+    // The channel's latest slide change, taken when it's sent
+    Slide(Slot),
+}
+
+impl Queued {
+    // The frame to send now, if the slot wasn't emptied already
+    pub fn take(self) -> Option<Message> {
+        match self {
+            Queued::Frame(frame) => Some(frame),
+            Queued::Slide(slot) => slot.lock().unwrap_or_else(|e| e.into_inner()).take(),
+        }
+    }
+}
+
+// One subscription's slide slot still waiting in the queue, if any
+#[derive(Default)]
+pub struct SlideCoalescer {
+    waiting: Option<Slot>,
+}
+
+impl SlideCoalescer {
+    // Queue entry for a new slide change, or None if it replaced one not yet sent
+    pub fn offer(&mut self, frame: Message) -> Option<Queued> {
+        if let Some(slot) = &self.waiting {
+            let mut pending = slot.lock().unwrap_or_else(|e| e.into_inner());
+            if pending.is_some() {
+                *pending = Some(frame);
+                return None;
+            }
+        }
+
+        let slot = Arc::new(Mutex::new(Some(frame)));
+        self.waiting = Some(slot.clone());
+        Some(Queued::Slide(slot))
+    }
+
+    // Keep the slot's place in line: later slides queue after what follows it
+    pub fn seal(&mut self) {
+        self.waiting = None;
+    }
+}
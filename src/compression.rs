@@ -0,0 +1,247 @@
+// Preset-dictionary compression for slide broadcasts.
+
+use serde::Serialize;
+
+use crate::{config::Config, roles};
+
+// DEFLATE's window: matches can reach at most this far back, into the dictionary included
+const WINDOW: usize = 32 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+// Candidates tried per position, trading ratio for speed
+const MAX_CHAIN: usize = 64;
+const HASH_BITS: usize = 15;
+
+#[derive(Serialize)]
+pub struct Dictionary {
+    // Adler-32 of the dictionary in hex, as stored in each frame's header
+    pub id: String,
+    // Channel name pattern the dictionary is used for, "*" matching any run of characters
+    pub channels: String,
+    #[serde(skip)]
+    checksum: u32,
+    #[serde(skip)]
+    pub bytes: Vec<u8>,
+}
+
+// Read the configured dictionaries, skipping any that can't be loaded
+pub fn load(config: &Config) -> Vec<Dictionary> {
+    config
+        .compression_dictionaries
+        .iter()
+        .filter_map(|(channels, path)| match std::fs::read(path) {
+            Ok(bytes) if bytes.is_empty() => {
+                eprintln!("❌ Compression dictionary {} is empty; ignoring it", path);
+                None
+            }
+            Ok(bytes) => {
+                // Only the last window's worth can ever be referenced
+                let bytes = bytes[bytes.len().saturating_sub(WINDOW)..].to_vec();
+                let checksum = adler32(&bytes);
+                println!("🗜️ Loaded {} byte compression dictionary {:08x} for channels {}", bytes.len(), checksum, channels);
+                Some(Dictionary {
+                    id: format!("{:08x}", checksum),
+                    channels: channels.clone(),
+                    checksum,
+                    bytes,
+                })
+            }
+            Err(e) => {
+                eprintln!("❌ Failed to load compression dictionary {}: {}", path, e);
+                None
+            }
+        })
+        .collect()
+}
+
+// The dictionary for a channel: the first whose pattern matches
+pub fn for_channel<'a>(dictionaries: &'a [Dictionary], channel: &str) -> Option<&'a Dictionary> {
+    dictionaries
+        .iter()
+        .find(|dictionary| roles::matches_pattern(&dictionary.channels, channel))
+}
+
+// A zlib stream of `data` compressed against the dictionary
+pub fn compress(dictionary: &Dictionary, data: &[u8]) -> Vec<u8> {
+    // CMF: deflate with a 32K window. FLG: preset dictionary, check bits making the pair a multiple of 31.
+    let cmf: u8 = 0x78;
+    let mut flg: u8 = 0x20;
+    flg += (31 - ((cmf as u16) << 8 | flg as u16) % 31) as u8 % 31;
+
+    let mut out = vec![cmf, flg];
+    out.extend_from_slice(&dictionary.checksum.to_be_bytes());
+    deflate(&dictionary.bytes, data, &mut out);
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    const MOD: u32 = 65521;
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= MOD;
+        b %= MOD;
+    }
+    b << 16 | a
+}
+
+// DEFLATE bit stream, least significant bit first
+struct BitWriter<'a> {
+    out: &'a mut Vec<u8>,
+    bits: u64,
+    count: u32,
+}
+
+impl BitWriter<'_> {
+    fn write(&mut self, value: u32, count: u32) {
+        self.bits |= (value as u64) << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    // Huffman codes are defined most significant bit first
+    fn write_code(&mut self, code: u32, count: u32) {
+        self.write(code.reverse_bits() >> (32 - count), count);
+    }
+
+    fn finish(mut self) {
+        if self.count > 0 {
+            self.out.push(self.bits as u8);
+        }
+        self.bits = 0;
+        self.count = 0;
+    }
+}
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145,
+    8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+
+// Literal/length symbol in the fixed Huffman code (RFC 1951 section 3.2.6)
+fn write_symbol(writer: &mut BitWriter, symbol: u32) {
+    match symbol {
+        0..=143 => writer.write_code(0x30 + symbol, 8),
+        144..=255 => writer.write_code(0x190 + symbol - 144, 9),
+        256..=279 => writer.write_code(symbol - 256, 7),
+        _ => writer.write_code(0xc0 + symbol - 280, 8),
+    }
+}
+
+// Index of the last table entry not above the value
+fn bucket(bases: &[u16], value: usize) -> usize {
+    bases.iter().rposition(|&base| base as usize <= value).unwrap_or(0)
+}
+
+fn write_match(writer: &mut BitWriter, length: usize, distance: usize) {
+    let index = bucket(&LENGTH_BASE, length);
+    write_symbol(writer, 257 + index as u32);
+    writer.write((length - LENGTH_BASE[index] as usize) as u32, LENGTH_EXTRA[index] as u32);
+
+    let index = bucket(&DISTANCE_BASE, distance);
+    writer.write_code(index as u32, 5);
+    writer.write((distance - DISTANCE_BASE[index] as usize) as u32, DISTANCE_EXTRA[index] as u32);
+}
+
+// Hash chains over every position in the window, most recent first
+struct Chains {
+    head: Vec<usize>,
+    prev: Vec<usize>,
+}
+
+const NONE: usize = usize::MAX;
+
+fn hash(window: &[u8], at: usize) -> usize {
+    let bytes = &window[at..at + MIN_MATCH];
+    ((bytes[0] as usize) << 10 ^ (bytes[1] as usize) << 5 ^ bytes[2] as usize) & ((1 << HASH_BITS) - 1)
+}
+
+impl Chains {
+    fn insert(&mut self, window: &[u8], at: usize) {
+        if at + MIN_MATCH <= window.len() {
+            let h = hash(window, at);
+            self.prev[at] = self.head[h];
+            self.head[h] = at;
+        }
+    }
+
+    // Longest earlier match for the position, as (length, distance)
+    fn longest_match(&self, window: &[u8], at: usize) -> (usize, usize) {
+        let max_length = MAX_MATCH.min(window.len() - at);
+        if max_length < MIN_MATCH {
+            return (0, 0);
+        }
+
+        let (mut best_length, mut best_distance) = (0, 0);
+        let mut candidate = self.head[hash(window, at)];
+        let mut tried = 0;
+        while candidate != NONE && tried < MAX_CHAIN && at - candidate <= WINDOW {
+            let length = (0..max_length)
+                .take_while(|&offset| window[candidate + offset] == window[at + offset])
+                .count();
+            if length > best_length {
+                best_length = length;
+                best_distance = at - candidate;
+                if length == max_length {
+                    break;
+                }
+            }
+            candidate = self.prev[candidate];
+            tried += 1;
+        }
+        (best_length, best_distance)
+    }
+}
+
+// One final fixed-Huffman block, with greedy LZ77 matching against the dictionary and
+// the data seen so far
+fn deflate(dictionary: &[u8], data: &[u8], out: &mut Vec<u8>) {
+    let mut window = Vec::with_capacity(dictionary.len() + data.len());
+    window.extend_from_slice(dictionary);
+    window.extend_from_slice(data);
+
+    let mut chains = Chains {
+        head: vec![NONE; 1 << HASH_BITS],
+        prev: vec![NONE; window.len()],
+    };
+    for at in 0..dictionary.len() {
+        chains.insert(&window, at);
+    }
+
+    let mut writer = BitWriter { out, bits: 0, count: 0 };
+    // BFINAL, then BTYPE 01: fixed Huffman codes
+    writer.write(1, 1);
+    writer.write(1, 2);
+
+    let mut at = dictionary.len();
+    while at < window.len() {
+        let (length, distance) = chains.longest_match(&window, at);
+        if length >= MIN_MATCH {
+            write_match(&mut writer, length, distance);
+            for offset in 0..length {
+                chains.insert(&window, at + offset);
+            }
+            at += length;
+        } else {
+            write_symbol(&mut writer, window[at] as u32);
+            chains.insert(&window, at);
+            at += 1;
+        }
+    }
+
+    write_symbol(&mut writer, 256);
+    writer.finish();
+}
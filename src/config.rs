@@ -0,0 +1,709 @@
+use serde::{Deserialize, Serialize, Serializer};
+use std::{env, net::SocketAddr, str::FromStr};
+
+use crate::{
+    access::Cidr,
+    auth::AuthProvider,
+    metadata::{FieldKind, MetadataPolicy},
+    monitoring::EndpointAccess,
+    ordering::OrderingMode,
+    retention::QueuePolicy,
+    safe_integers::UnsafeIntegerPolicy,
+    tenancy::Tenancy,
+    timestamps::TimestampPolicy,
+};
+
+// Whether subscribing to an unknown channel creates it
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChannelCreation {
+    // Any subscribe creates the channel (default)
+    Auto,
+    // Channels must be declared via config or the admin API first
+    Declared,
+}
+
+impl ChannelCreation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChannelCreation::Auto => "auto",
+            ChannelCreation::Declared => "declared",
+        }
+    }
+}
+
+impl FromStr for ChannelCreation {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "auto" => Ok(ChannelCreation::Auto),
+            "declared" => Ok(ChannelCreation::Declared),
+            _ => Err(()),
+        }
+    }
+}
+
+// Server configuration, read once from the environment at startup. The fields mirrored
+// in LiveConfig can be changed later through the admin API; read those from there.
+#[derive(Clone, Debug, Serialize)]
+pub struct Config {
+    // Identifies this node in responses, for debugging behind a load balancer
+    pub instance_id: String,
+    // Also stamp the instance id on every server message
+    pub instance_id_in_messages: bool,
+    // Source addresses allowed to connect (empty allows all)
+    pub allow_cidrs: Vec<Cidr>,
+    // Source addresses always rejected
+    pub deny_cidrs: Vec<Cidr>,
+    // Proxies whose X-Forwarded-For header is trusted
+    pub trusted_proxies: Vec<Cidr>,
+    // Concurrent connections one authenticated identity may hold (0 is unlimited)
+    pub max_connections_per_identity: usize,
+    // Role -> connection limit for identities with that role, instead of the default
+    pub connections_per_role: Vec<(String, usize)>,
+    // Role -> connection tier; higher tiers are shed and evicted after lower ones
+    pub connection_tiers: Vec<(String, u8)>,
+    // Only accept WebSocket upgrades a trusted proxy marks as X-Forwarded-Proto: https
+    pub require_secure_upgrades: bool,
+    // Bearer token required by /admin endpoints; the admin API is disabled when unset
+    #[serde(serialize_with = "redacted")]
+    pub admin_token: Option<String>,
+    // Let the admin API publish messages that appear to come from a given client and role
+    pub admin_publish: bool,
+    // Second listener for the admin API and monitoring endpoints, e.g. 127.0.0.1:9090
+    pub internal_addr: Option<SocketAddr>,
+    // Who may read /health and /ready, /metrics and /stats
+    pub health_access: EndpointAccess,
+    pub metrics_access: EndpointAccess,
+    pub stats_access: EndpointAccess,
+    // Start in read-only maintenance mode
+    pub maintenance_mode: bool,
+    // Minimum seconds between server-wide announcements
+    pub announcement_interval_secs: u64,
+    // Largest inbound WebSocket message accepted, in bytes
+    pub max_message_size: usize,
+    // Most headers one publish may carry
+    pub max_headers: usize,
+    // Largest total length of a publish's header names and values, in bytes
+    pub max_header_bytes: usize,
+    // Largest publish data a chunked transfer may reassemble, in bytes (0 disables chunked transfers)
+    pub max_transfer_size: usize,
+    // Seconds a chunked transfer may go without a chunk before it is abandoned
+    pub transfer_timeout_secs: u64,
+    // Messages buffered per connection before publishers to it have to wait
+    pub outgoing_queue_size: usize,
+    // Disconnect a client whose outgoing queue stays full this long, in ms (0 disables)
+    pub slow_consumer_grace_ms: u64,
+    // Close a connection that sends nothing for this long, in seconds (0 disables)
+    pub idle_timeout_secs: u64,
+    // Warn a client this many seconds before it's closed as idle (0 disables; must be below the timeout)
+    pub idle_warning_secs: u64,
+    // Drop a connection whose socket doesn't accept a frame within this long, in ms (0 disables)
+    pub send_timeout_ms: u64,
+    // Ping each connection this often to measure its round-trip time, in seconds (0 disables)
+    pub rtt_ping_interval_secs: u64,
+    // How long a publish_quorum collects acks when the publisher doesn't say, in milliseconds
+    pub quorum_timeout_ms: u64,
+    // Quorum publishes one connection may have collecting acks per channel (0 is unlimited)
+    pub max_unacked_per_publisher: usize,
+    // How long an at-least-once message waits for a subscriber's ack before it's sent again
+    pub redelivery_timeout_ms: u64,
+    // Sends of an at-least-once message to one subscriber before it's given up on (0 disables)
+    pub redelivery_max_attempts: u32,
+    // Unacked at-least-once messages tracked per connection
+    pub max_outstanding_deliveries: usize,
+    // How client-supplied event times outside the accepted window are handled
+    pub client_timestamp_policy: TimestampPolicy,
+    // What to do with integers in published data that JavaScript can't represent exactly
+    pub unsafe_integer_policy: UnsafeIntegerPolicy,
+    // Oldest accepted client timestamp, in ms before server time
+    pub client_timestamp_max_past_ms: u64,
+    // Newest accepted client timestamp, in ms after server time
+    pub client_timestamp_max_future_ms: u64,
+    // Shift client timestamps and deliver_at by the client's estimated clock skew
+    pub clock_skew_correction: bool,
+    // Repeats of a publish's idempotency key within this many ms aren't broadcast again (0 disables)
+    pub idempotency_window_ms: u64,
+    // Message ids remembered per participant so a reconnect isn't sent them again (0 disables)
+    pub dedup_window: usize,
+    // Per-client, per-channel cap on slide_change broadcasts; extra changes are coalesced (0 disables)
+    pub slide_change_max_per_sec: f64,
+    // Refuse slide changes to a lower slide_index unless they allow going backward
+    pub monotonic_slides: bool,
+    // Deliver only the newest of the slide changes waiting for a slow connection
+    pub coalesce_slide_changes: bool,
+    // Most broadcasts a subscription forwards per wake-up (1 handles each on its own)
+    pub fanout_batch_size: usize,
+    // How long a woken subscription waits for more broadcasts to batch, in ms (0 doesn't wait)
+    pub fanout_linger_ms: u64,
+    // Publishes processed at once per channel; more wait briefly or get `busy` (0 disables)
+    pub channel_max_concurrent_publishes: usize,
+    // Subscribes set up at once across the server; more wait their turn (0 is unlimited)
+    pub subscribe_concurrency: usize,
+    // How long a publish waits for a free slot before `busy`, in ms
+    pub publish_slot_wait_ms: u64,
+    // Start shedding new connections above this fraction of total outgoing queue capacity (0 disables)
+    pub shed_queue_threshold: f64,
+    // Start shedding new connections above this much event-loop lag, in ms (0 disables)
+    pub shed_lag_ms: u64,
+    // Retry-After sent with shed connections
+    pub shed_retry_after_secs: u64,
+    // Spread reconnects over this many seconds after startup (0 disables)
+    pub warmup_secs: u64,
+    // Longest random accept delay at startup, in ms, shrinking to zero across the warm-up
+    pub warmup_max_delay_ms: u64,
+    // Refuse new connections and channels above this much memory, in MB (0 disables)
+    pub memory_limit_mb: u64,
+    // Measure memory as resident set size instead of estimating it from counts
+    pub memory_use_rss: bool,
+    // Connections closed per second while over the memory limit, lowest tier first (0 disables)
+    pub memory_evictions_per_sec: usize,
+    // On shutdown, seconds to report not ready before notifying clients, so load balancers stop routing here
+    pub shutdown_unready_secs: u64,
+    // Longest random reconnect delay suggested to clients in server_shutdown, in ms
+    pub shutdown_reconnect_spread_ms: u64,
+    // On shutdown, how long to wait for outgoing queues to drain, in ms
+    pub shutdown_flush_timeout_ms: u64,
+    // On shutdown, how long connections get to close before the process exits, in ms
+    pub shutdown_close_timeout_ms: u64,
+    // How long a client told to prepare_migration has to reply ready_to_migrate (0 closes at once)
+    pub migration_timeout_ms: u64,
+    // Seconds a disconnected client's presence is kept as "away" before removal
+    pub presence_grace_secs: u64,
+    // Longer grace window for pinned presence entries
+    pub pinned_presence_grace_secs: u64,
+    // Mark online entries away when their connection hasn't sent a heartbeat for this long (0 disables)
+    pub presence_stale_secs: u64,
+    // Most participants GET /channels/{id}/presence returns at once; larger rosters are paged
+    pub presence_response_max: usize,
+    // Most participants in the presence snapshot a new subscriber gets (0 sends none)
+    pub presence_snapshot_max: usize,
+    // File to persist presence in, so rosters survive a short restart (unset disables)
+    pub presence_store: Option<String>,
+    // Don't restore a presence snapshot older than this, in seconds
+    pub presence_store_max_age_secs: u64,
+    // Keys clients may set in their presence metadata, and how each is checked
+    pub presence_metadata_fields: Vec<(String, FieldKind)>,
+    // Longest presence metadata value, in characters
+    pub presence_metadata_max_len: usize,
+    // Whether metadata breaking the schema is cleaned up or refused
+    pub presence_metadata_policy: MetadataPolicy,
+    // Scheduled messages a channel may hold at once
+    pub max_scheduled_per_channel: usize,
+    // Scheduled messages the server may hold at once, across all channels
+    pub max_scheduled_total: usize,
+    // File to persist scheduled messages in, so they survive a restart (unset disables)
+    pub schedule_store: Option<String>,
+    // Roles whose presence is pinned to the top of the roster
+    pub pinned_roles: Vec<String>,
+    // Roles from highest to lowest; each inherits the permissions of those below it
+    pub role_hierarchy: Vec<String>,
+    // Cap on distinct roles present in one channel at a time (0 is unlimited)
+    pub max_roles_per_channel: usize,
+    // Allow one client per channel in the top role, who alone may change slides
+    pub single_presenter: bool,
+    // Only the client holding a channel's presenter lock may change its slides
+    pub presenter_lock: bool,
+    // Default role by channel name pattern, first match wins, for subscribes without a requested role
+    pub channel_role_rules: Vec<(String, String)>,
+    // Channel name patterns whose first subscriber is made the presenter, unless auth grants a role
+    pub first_subscriber_presents: Vec<String>,
+    // Channel name patterns whose publishes wait for slow subscribers instead of letting them skip
+    pub reliable_channels: Vec<String>,
+    // Longest a publish to a reliable channel waits for its slowest subscriber, in ms
+    pub backpressure_timeout_ms: u64,
+    // Minimum role allowed to publish
+    pub publish_role: String,
+    // Minimum role allowed to change slides
+    pub slide_change_role: String,
+    // Minimum role allowed to query another client's presence
+    pub query_presence_role: String,
+    // Minimum role allowed to change other clients' roles
+    pub manage_roles_role: String,
+    // Minimum role allowed to set a channel's sticky message for late joiners
+    pub sticky_message_role: String,
+    // Minimum role allowed to start and close polls
+    pub poll_role: String,
+    // Minimum role allowed to publish with a min_role, hiding the message from lower roles
+    pub min_role_publish_role: String,
+    // Highest role a client may claim for itself on subscribe (unset allows any)
+    pub max_self_assigned_role: Option<String>,
+    // How new connections are authenticated
+    pub auth_provider: AuthProvider,
+    // Shared secret for HS256 JWTs
+    #[serde(serialize_with = "redacted")]
+    pub jwt_secret: Option<String>,
+    // Required `iss` claim, if set
+    pub jwt_issuer: Option<String>,
+    // Shared key for HMAC signatures on server messages (unset disables signing)
+    #[serde(serialize_with = "redacted")]
+    pub message_signing_key: Option<String>,
+    // How channels map to tenants
+    pub tenancy: Tenancy,
+    // Separates the tenant from the rest of a channel name under prefix tenancy
+    pub tenant_separator: String,
+    // Messages kept per channel for replay to new subscribers (0 disables history)
+    pub history_size: usize,
+    // Serialized bytes of history kept per channel, on top of the message count (0 is unlimited)
+    pub history_max_bytes: usize,
+    // Keep publishes in history even when nobody is subscribed yet
+    pub store_without_subscribers: bool,
+    // A publish to a channel nobody has subscribed to creates it and is kept for the first subscriber
+    pub publish_creates_channel: bool,
+    // Whether subscribers receive their own publishes unless they say otherwise on subscribe
+    pub publish_echo: bool,
+    // Refuse publishes from connections not subscribed to the channel
+    pub require_subscription_to_publish: bool,
+    // Subscribe connections to the channel (and role) named in the connect URL
+    pub subscribe_on_connect: bool,
+    // Issue resume tokens, with which a new connection takes over an ended one's subscriptions
+    pub resume_tokens: bool,
+    // Channels starting with any of these prefixes are delivered in strict order
+    pub ordered_channel_prefixes: Vec<String>,
+    // Channel pattern -> ordering mode, first match wins; fixed modes can't be changed by clients
+    pub channel_ordering_rules: Vec<(String, OrderingMode)>,
+    // Channel pattern -> message types the channel accepts, first match wins; unmatched channels accept all
+    pub channel_allowed_types: Vec<(String, Vec<String>)>,
+    // Channel pattern -> origins a connection must come from to use it, first match wins; unmatched channels allow any
+    pub channel_allowed_origins: Vec<(String, Vec<String>)>,
+    // Directory for full channel transcripts (unset disables retention)
+    pub retention_dir: Option<String>,
+    // Channels starting with any of these prefixes keep a full transcript
+    pub retention_channel_prefixes: Vec<String>,
+    // Transcript lines that may wait for the writer before the queue policy applies
+    pub retention_queue_size: usize,
+    // What gives when the transcript writer falls behind a full queue
+    pub retention_queue_policy: QueuePolicy,
+    // http:// endpoint that broadcasts of the webhook event types are POSTed to (unset disables webhooks)
+    pub webhook_url: Option<String>,
+    // Broadcast types sent to the webhook
+    pub webhook_events: Vec<String>,
+    // How long one attempt may take before it counts as failed
+    pub webhook_timeout_ms: u64,
+    // Deliveries that may wait for a first attempt or a retry; more go to the dead-letter log
+    pub webhook_queue_size: usize,
+    // Retries after the first failed attempt before a delivery is dead-lettered
+    pub webhook_max_retries: u32,
+    // Wait before the first retry, doubled for each one after, up to the maximum
+    pub webhook_retry_base_ms: u64,
+    pub webhook_retry_max_ms: u64,
+    // Directory for attendance logs (unset disables attendance recording)
+    pub attendance_dir: Option<String>,
+    // Channels starting with any of these prefixes record joins and leaves
+    pub attendance_channel_prefixes: Vec<String>,
+    // Days attendance records are kept (0 keeps them indefinitely)
+    pub attendance_retention_days: u64,
+    // Attendance records that may wait for the writer before the oldest are dropped
+    pub attendance_queue_size: usize,
+    // Channel creation policy
+    pub channel_creation: ChannelCreation,
+    // Channels declared at startup when creation policy is "declared"
+    pub declared_channels: Vec<String>,
+    // Seconds a channel with no subscribers may stay idle before it is reaped (0 disables)
+    pub channel_idle_secs: u64,
+    // Seconds after its creation a channel is closed and torn down, however active (0 disables)
+    pub channel_max_lifetime_secs: u64,
+    // How often per-channel state left behind by torn-down channels is cleaned up, in seconds (0 disables)
+    pub compaction_interval_secs: u64,
+    // Archive idle channels (read-only, history kept) instead of tearing them down
+    pub archive_idle_channels: bool,
+    // Interval for batched presence diffs, in ms (0 sends one event per change)
+    pub presence_diff_interval_ms: u64,
+    // Channels with at least this many participants use batched presence diffs
+    pub presence_diff_min_participants: usize,
+    // How often watch_presence_counts reports changed participant counts, in ms
+    pub presence_count_interval_ms: u64,
+    // Patterns one connection may watch at once
+    pub max_pattern_watches: usize,
+    // Patterns watched across all connections (0 is unlimited)
+    pub max_pattern_watches_total: usize,
+    // Minimum role allowed to watch patterns starting with `*` (unset refuses them)
+    pub broad_pattern_role: Option<String>,
+    // Joins arriving within this many ms of the previous one are sent as one presence_batch_joined (0 disables)
+    pub presence_join_batch_ms: u64,
+    // Dead-letter sink: unset = disabled, "log" = stdout, anything else = file path
+    pub dead_letter_sink: Option<String>,
+    // Maximum dead-letter entries recorded per minute before suppressing the rest
+    pub dead_letter_max_per_minute: u32,
+    // Admin actions kept in memory for GET /admin/audit
+    pub audit_log_size: usize,
+    // File every admin action is also appended to, as JSON lines
+    pub audit_log_file: Option<String>,
+    // Encode each broadcast once per wire format and share the frame across subscribers
+    pub serialization_cache: bool,
+    // Percentage of connections that receive broadcasts in the next envelope format
+    pub next_format_percent: u32,
+    // Channel pattern -> file holding a preset dictionary for compressing its slide broadcasts
+    pub compression_dictionaries: Vec<(String, String)>,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        let config = Config {
+            instance_id: env_string("RABLY_INSTANCE_ID")
+                .unwrap_or_else(|| uuid::Uuid::new_v4().simple().to_string()[..8].to_string()),
+            instance_id_in_messages: env_parse("RABLY_INSTANCE_ID_IN_MESSAGES", false),
+            allow_cidrs: env_cidrs("RABLY_ALLOW_CIDRS"),
+            deny_cidrs: env_cidrs("RABLY_DENY_CIDRS"),
+            trusted_proxies: env_cidrs("RABLY_TRUSTED_PROXIES"),
+            max_connections_per_identity: env_parse("RABLY_MAX_CONNECTIONS_PER_IDENTITY", 0),
+            connections_per_role: env_pairs("RABLY_CONNECTIONS_PER_ROLE")
+                .into_iter()
+                .filter_map(|(role, limit)| match limit.parse() {
+                    Ok(limit) => Some((role, limit)),
+                    Err(_) => {
+                        eprintln!("⚠️ Ignoring connection limit {}={}: not a number", role, limit);
+                        None
+                    }
+                })
+                .collect(),
+            connection_tiers: env_pairs("RABLY_CONNECTION_TIERS")
+                .into_iter()
+                .filter_map(|(role, tier)| match tier.parse() {
+                    Ok(tier) => Some((role, tier)),
+                    Err(_) => {
+                        eprintln!("⚠️ Ignoring connection tier {}={}: not a number from 0 to 255", role, tier);
+                        None
+                    }
+                })
+                .collect(),
+            require_secure_upgrades: env_parse("RABLY_REQUIRE_SECURE_UPGRADES", false),
+            admin_token: env_string("RABLY_ADMIN_TOKEN"),
+            admin_publish: env_parse("RABLY_ADMIN_PUBLISH", false),
+            internal_addr: env_string("RABLY_INTERNAL_ADDR").and_then(|addr| match addr.parse() {
+                Ok(addr) => Some(addr),
+                Err(_) => {
+                    eprintln!("⚠️ Ignoring invalid value for RABLY_INTERNAL_ADDR: {}", addr);
+                    None
+                }
+            }),
+            health_access: env_parse("RABLY_HEALTH_ACCESS", EndpointAccess::Public),
+            metrics_access: env_parse("RABLY_METRICS_ACCESS", EndpointAccess::Public),
+            stats_access: env_parse("RABLY_STATS_ACCESS", EndpointAccess::Public),
+            maintenance_mode: env_parse("RABLY_MAINTENANCE_MODE", false),
+            announcement_interval_secs: env_parse("RABLY_ANNOUNCEMENT_INTERVAL_SECS", 10),
+            max_message_size: env_parse("RABLY_MAX_MESSAGE_SIZE", 64 << 20),
+            max_headers: env_parse("RABLY_MAX_HEADERS", 16),
+            max_header_bytes: env_parse("RABLY_MAX_HEADER_BYTES", 4096),
+            max_transfer_size: env_parse("RABLY_MAX_TRANSFER_SIZE", 16 << 20),
+            transfer_timeout_secs: env_parse("RABLY_TRANSFER_TIMEOUT_SECS", 30),
+            outgoing_queue_size: env_parse("RABLY_OUTGOING_QUEUE_SIZE", 1024).max(1),
+            slow_consumer_grace_ms: env_parse("RABLY_SLOW_CONSUMER_GRACE_MS", 0),
+            idle_timeout_secs: env_parse("RABLY_IDLE_TIMEOUT_SECS", 0),
+            idle_warning_secs: env_parse("RABLY_IDLE_WARNING_SECS", 0),
+            rtt_ping_interval_secs: env_parse("RABLY_RTT_PING_INTERVAL_SECS", 0),
+            send_timeout_ms: env_parse("RABLY_SEND_TIMEOUT_MS", 10000),
+            quorum_timeout_ms: env_parse("RABLY_QUORUM_TIMEOUT_MS", 10000),
+            max_unacked_per_publisher: env_parse("RABLY_MAX_UNACKED_PER_PUBLISHER", 0),
+            redelivery_timeout_ms: env_parse("RABLY_REDELIVERY_TIMEOUT_MS", 5_000),
+            redelivery_max_attempts: env_parse("RABLY_REDELIVERY_MAX_ATTEMPTS", 0),
+            max_outstanding_deliveries: env_parse("RABLY_MAX_OUTSTANDING_DELIVERIES", 100),
+            client_timestamp_policy: env_parse("RABLY_CLIENT_TIMESTAMP_POLICY", TimestampPolicy::Clamp),
+            unsafe_integer_policy: env_parse("RABLY_UNSAFE_INTEGER_POLICY", UnsafeIntegerPolicy::Off),
+            client_timestamp_max_past_ms: env_parse("RABLY_CLIENT_TIMESTAMP_MAX_PAST_MS", 300_000),
+            client_timestamp_max_future_ms: env_parse("RABLY_CLIENT_TIMESTAMP_MAX_FUTURE_MS", 5_000),
+            clock_skew_correction: env_parse("RABLY_CLOCK_SKEW_CORRECTION", false),
+            idempotency_window_ms: env_parse("RABLY_IDEMPOTENCY_WINDOW_MS", 60000),
+            dedup_window: env_parse("RABLY_DEDUP_WINDOW", 0),
+            slide_change_max_per_sec: env_parse("RABLY_SLIDE_CHANGE_MAX_PER_SEC", 0.0),
+            monotonic_slides: env_parse("RABLY_MONOTONIC_SLIDES", false),
+            coalesce_slide_changes: env_parse("RABLY_COALESCE_SLIDE_CHANGES", false),
+            fanout_batch_size: env_parse("RABLY_FANOUT_BATCH_SIZE", 1),
+            fanout_linger_ms: env_parse("RABLY_FANOUT_LINGER_MS", 0),
+            channel_max_concurrent_publishes: env_parse("RABLY_CHANNEL_MAX_CONCURRENT_PUBLISHES", 0),
+            subscribe_concurrency: env_parse("RABLY_SUBSCRIBE_CONCURRENCY", 0),
+            publish_slot_wait_ms: env_parse("RABLY_PUBLISH_SLOT_WAIT_MS", 50),
+            shed_queue_threshold: env_parse("RABLY_SHED_QUEUE_THRESHOLD", 0.0),
+            shed_lag_ms: env_parse("RABLY_SHED_LAG_MS", 0),
+            shed_retry_after_secs: env_parse("RABLY_SHED_RETRY_AFTER_SECS", 5),
+            warmup_secs: env_parse("RABLY_WARMUP_SECS", 0),
+            warmup_max_delay_ms: env_parse("RABLY_WARMUP_MAX_DELAY_MS", 2000),
+            memory_limit_mb: env_parse("RABLY_MEMORY_LIMIT_MB", 0),
+            memory_use_rss: env_parse("RABLY_MEMORY_USE_RSS", false),
+            memory_evictions_per_sec: env_parse("RABLY_MEMORY_EVICTIONS_PER_SEC", 0),
+            shutdown_unready_secs: env_parse("RABLY_SHUTDOWN_UNREADY_SECS", 5),
+            shutdown_reconnect_spread_ms: env_parse("RABLY_SHUTDOWN_RECONNECT_SPREAD_MS", 5000),
+            shutdown_flush_timeout_ms: env_parse("RABLY_SHUTDOWN_FLUSH_TIMEOUT_MS", 5000),
+            shutdown_close_timeout_ms: env_parse("RABLY_SHUTDOWN_CLOSE_TIMEOUT_MS", 2000),
+            migration_timeout_ms: env_parse("RABLY_MIGRATION_TIMEOUT_MS", 0),
+            presence_grace_secs: env_parse("RABLY_PRESENCE_GRACE_SECS", 10),
+            pinned_presence_grace_secs: env_parse("RABLY_PINNED_PRESENCE_GRACE_SECS", 60),
+            presence_stale_secs: env_parse("RABLY_PRESENCE_STALE_SECS", 0),
+            presence_response_max: env_parse("RABLY_PRESENCE_RESPONSE_MAX", 1000).max(1),
+            presence_snapshot_max: env_parse("RABLY_PRESENCE_SNAPSHOT_MAX", 100),
+            presence_store: env_string("RABLY_PRESENCE_STORE"),
+            presence_store_max_age_secs: env_parse("RABLY_PRESENCE_STORE_MAX_AGE_SECS", 60),
+            presence_metadata_fields: presence_metadata_fields(),
+            presence_metadata_max_len: env_parse("RABLY_PRESENCE_METADATA_MAX_LEN", 256),
+            presence_metadata_policy: env_parse("RABLY_PRESENCE_METADATA_POLICY", MetadataPolicy::Sanitize),
+            max_scheduled_per_channel: env_parse("RABLY_MAX_SCHEDULED_PER_CHANNEL", 50),
+            max_scheduled_total: env_parse("RABLY_MAX_SCHEDULED_TOTAL", 10_000),
+            schedule_store: env_string("RABLY_SCHEDULE_STORE"),
+            pinned_roles: env_list("RABLY_PINNED_ROLES", &["teacher"]),
+            role_hierarchy: env_list("RABLY_ROLE_HIERARCHY", &["teacher", "observer", "student"]),
+            max_roles_per_channel: env_parse("RABLY_MAX_ROLES_PER_CHANNEL", 0),
+            single_presenter: env_parse("RABLY_SINGLE_PRESENTER", false),
+            presenter_lock: env_parse("RABLY_PRESENTER_LOCK", false),
+            channel_role_rules: env_pairs("RABLY_CHANNEL_ROLE_RULES"),
+            first_subscriber_presents: env_list("RABLY_FIRST_SUBSCRIBER_PRESENTS", &[]),
+            reliable_channels: env_list("RABLY_RELIABLE_CHANNELS", &[]),
+            backpressure_timeout_ms: env_parse("RABLY_BACKPRESSURE_TIMEOUT_MS", 2000),
+            publish_role: env_string("RABLY_PUBLISH_ROLE").unwrap_or_else(|| "student".to_string()),
+            slide_change_role: env_string("RABLY_SLIDE_CHANGE_ROLE").unwrap_or_else(|| "student".to_string()),
+            query_presence_role: env_string("RABLY_QUERY_PRESENCE_ROLE").unwrap_or_else(|| "observer".to_string()),
+            manage_roles_role: env_string("RABLY_MANAGE_ROLES_ROLE").unwrap_or_else(|| "teacher".to_string()),
+            sticky_message_role: env_string("RABLY_STICKY_MESSAGE_ROLE").unwrap_or_else(|| "teacher".to_string()),
+            poll_role: env_string("RABLY_POLL_ROLE").unwrap_or_else(|| "teacher".to_string()),
+            min_role_publish_role: env_string("RABLY_MIN_ROLE_PUBLISH_ROLE").unwrap_or_else(|| "teacher".to_string()),
+            max_self_assigned_role: env_string("RABLY_MAX_SELF_ASSIGNED_ROLE"),
+            auth_provider: env_parse("RABLY_AUTH", AuthProvider::None),
+            jwt_secret: env_string("RABLY_JWT_SECRET"),
+            message_signing_key: env_string("RABLY_MESSAGE_SIGNING_KEY"),
+            jwt_issuer: env_string("RABLY_JWT_ISSUER"),
+            tenancy: env_parse("RABLY_TENANCY", Tenancy::None),
+            tenant_separator: env_string("RABLY_TENANT_SEPARATOR").unwrap_or_else(|| ":".to_string()),
+            history_size: env_parse("RABLY_HISTORY_SIZE", 0),
+            history_max_bytes: env_parse("RABLY_HISTORY_MAX_BYTES", 0),
+            store_without_subscribers: env_parse("RABLY_STORE_WITHOUT_SUBSCRIBERS", false),
+            publish_creates_channel: env_parse("RABLY_PUBLISH_CREATES_CHANNEL", false),
+            publish_echo: env_parse("RABLY_PUBLISH_ECHO", true),
+            require_subscription_to_publish: env_parse("RABLY_REQUIRE_SUBSCRIPTION_TO_PUBLISH", false),
+            subscribe_on_connect: env_parse("RABLY_SUBSCRIBE_ON_CONNECT", false),
+            resume_tokens: env_parse("RABLY_RESUME_TOKENS", false),
+            ordered_channel_prefixes: env_list("RABLY_ORDERED_CHANNEL_PREFIXES", &[]),
+            channel_ordering_rules: env_pairs("RABLY_CHANNEL_ORDERING_RULES")
+                .into_iter()
+                .filter_map(|(pattern, mode)| match mode.parse() {
+                    Ok(mode) => Some((pattern, mode)),
+                    Err(_) => {
+                        eprintln!("⚠️ Ignoring ordering rule {}={}: mode must be ordered or fast", pattern, mode);
+                        None
+                    }
+                })
+                .collect(),
+            channel_allowed_types: env_pairs("RABLY_CHANNEL_ALLOWED_TYPES")
+                .into_iter()
+                .map(|(pattern, types)| (pattern, types.split('|').map(|kind| kind.trim().to_string()).filter(|kind| !kind.is_empty()).collect()))
+                .collect(),
+            channel_allowed_origins: env_pairs("RABLY_CHANNEL_ALLOWED_ORIGINS")
+                .into_iter()
+                .map(|(pattern, origins)| {
+                    let origins = origins.split('|').map(|origin| origin.trim().to_string()).filter(|origin| !origin.is_empty());
+                    (pattern, origins.collect())
+                })
+                .collect(),
+            retention_dir: env_string("RABLY_RETENTION_DIR"),
+            retention_channel_prefixes: env_list("RABLY_RETENTION_CHANNEL_PREFIXES", &[]),
+            retention_queue_size: env_parse("RABLY_RETENTION_QUEUE_SIZE", 10000),
+            retention_queue_policy: env_parse("RABLY_RETENTION_QUEUE_POLICY", QueuePolicy::DropOldest),
+            webhook_url: env_string("RABLY_WEBHOOK_URL"),
+            webhook_events: env_list("RABLY_WEBHOOK_EVENTS", &["user_joined", "user_left"]),
+            webhook_timeout_ms: env_parse("RABLY_WEBHOOK_TIMEOUT_MS", 5000),
+            webhook_queue_size: env_parse("RABLY_WEBHOOK_QUEUE_SIZE", 1000),
+            webhook_max_retries: env_parse("RABLY_WEBHOOK_MAX_RETRIES", 5),
+            webhook_retry_base_ms: env_parse("RABLY_WEBHOOK_RETRY_BASE_MS", 1000),
+            webhook_retry_max_ms: env_parse("RABLY_WEBHOOK_RETRY_MAX_MS", 60_000),
+            attendance_dir: env_string("RABLY_ATTENDANCE_DIR"),
+            attendance_channel_prefixes: env_list("RABLY_ATTENDANCE_CHANNEL_PREFIXES", &[]),
+            attendance_retention_days: env_parse("RABLY_ATTENDANCE_RETENTION_DAYS", 30),
+            attendance_queue_size: env_parse("RABLY_ATTENDANCE_QUEUE_SIZE", 10000),
+            channel_creation: env_parse("RABLY_CHANNEL_CREATION", ChannelCreation::Auto),
+            declared_channels: env_list("RABLY_DECLARED_CHANNELS", &[]),
+            channel_idle_secs: env_parse("RABLY_CHANNEL_IDLE_SECS", 3600),
+            channel_max_lifetime_secs: env_parse("RABLY_CHANNEL_MAX_LIFETIME_SECS", 0),
+            compaction_interval_secs: env_parse("RABLY_COMPACTION_INTERVAL_SECS", 300),
+            archive_idle_channels: env_parse("RABLY_ARCHIVE_IDLE_CHANNELS", false),
+            presence_diff_interval_ms: env_parse("RABLY_PRESENCE_DIFF_INTERVAL_MS", 0),
+            presence_diff_min_participants: env_parse("RABLY_PRESENCE_DIFF_MIN_PARTICIPANTS", 50),
+            presence_count_interval_ms: env_parse("RABLY_PRESENCE_COUNT_INTERVAL_MS", 1000),
+            max_pattern_watches: env_parse("RABLY_MAX_PATTERN_WATCHES", 8),
+            max_pattern_watches_total: env_parse("RABLY_MAX_PATTERN_WATCHES_TOTAL", 1000),
+            broad_pattern_role: env_string("RABLY_BROAD_PATTERN_ROLE"),
+            presence_join_batch_ms: env_parse("RABLY_PRESENCE_JOIN_BATCH_MS", 0),
+            dead_letter_sink: env_string("RABLY_DEAD_LETTER"),
+            dead_letter_max_per_minute: env_parse("RABLY_DEAD_LETTER_MAX_PER_MINUTE", 100),
+            audit_log_size: env_parse("RABLY_AUDIT_LOG_SIZE", 1000),
+            audit_log_file: env_string("RABLY_AUDIT_LOG"),
+            serialization_cache: env_parse("RABLY_SERIALIZATION_CACHE", true),
+            next_format_percent: env_parse("RABLY_NEXT_FORMAT_PERCENT", 0).min(100),
+            compression_dictionaries: env_pairs("RABLY_COMPRESSION_DICTIONARIES"),
+        };
+
+        let minimum_roles = [
+            &config.publish_role,
+            &config.slide_change_role,
+            &config.query_presence_role,
+            &config.manage_roles_role,
+            &config.sticky_message_role,
+            &config.poll_role,
+            &config.min_role_publish_role,
+        ];
+        for role in minimum_roles {
+            if !config.role_hierarchy.contains(role) {
+                eprintln!("⚠️ Minimum role {} is not in RABLY_ROLE_HIERARCHY; nobody will be allowed", role);
+            }
+        }
+
+        if let Some(role) = config.broad_pattern_role.as_ref().filter(|role| !config.role_hierarchy.contains(role)) {
+            eprintln!("⚠️ RABLY_BROAD_PATTERN_ROLE {} is not in RABLY_ROLE_HIERARCHY; nobody will be allowed broad patterns", role);
+        }
+
+        for (pattern, role) in &config.channel_role_rules {
+            if !config.role_hierarchy.contains(role) {
+                eprintln!("⚠️ Channel role rule {}={} names a role outside RABLY_ROLE_HIERARCHY; ignoring it", pattern, role);
+            }
+        }
+
+        if config.idle_timeout_secs > 0 && config.idle_warning_secs >= config.idle_timeout_secs {
+            eprintln!("⚠️ RABLY_IDLE_WARNING_SECS is not below RABLY_IDLE_TIMEOUT_SECS; no idle warnings will be sent");
+        }
+
+        let monitoring = [
+            ("/health", config.health_access),
+            ("/metrics", config.metrics_access),
+            ("/stats", config.stats_access),
+        ];
+        for (path, access) in monitoring {
+            if access == EndpointAccess::Admin && config.admin_token.is_none() {
+                eprintln!("⚠️ {} requires the admin token but RABLY_ADMIN_TOKEN is unset; it will refuse every request", path);
+            }
+        }
+
+        config
+    }
+}
+
+// Settings that can be changed while the server runs, via PATCH /admin/config
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LiveConfig {
+    // Read on every use, so changes apply immediately
+    pub announcement_interval_secs: u64,
+    pub quorum_timeout_ms: u64,
+    pub presence_grace_secs: u64,
+    pub pinned_presence_grace_secs: u64,
+    pub history_size: usize,
+    pub history_max_bytes: usize,
+    pub shed_retry_after_secs: u64,
+    // Read when a connection opens, so changes apply to new connections
+    pub send_timeout_ms: u64,
+    pub slow_consumer_grace_ms: u64,
+    pub idle_timeout_secs: u64,
+    pub idle_warning_secs: u64,
+    pub slide_change_max_per_sec: f64,
+}
+
+impl LiveConfig {
+    pub fn from_config(config: &Config) -> Self {
+        LiveConfig {
+            announcement_interval_secs: config.announcement_interval_secs,
+            quorum_timeout_ms: config.quorum_timeout_ms,
+            presence_grace_secs: config.presence_grace_secs,
+            pinned_presence_grace_secs: config.pinned_presence_grace_secs,
+            history_size: config.history_size,
+            history_max_bytes: config.history_max_bytes,
+            shed_retry_after_secs: config.shed_retry_after_secs,
+            send_timeout_ms: config.send_timeout_ms,
+            slow_consumer_grace_ms: config.slow_consumer_grace_ms,
+            idle_timeout_secs: config.idle_timeout_secs,
+            idle_warning_secs: config.idle_warning_secs,
+            slide_change_max_per_sec: config.slide_change_max_per_sec,
+        }
+    }
+
+    // Catch values that deserialize fine but make no sense
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if !self.slide_change_max_per_sec.is_finite() || self.slide_change_max_per_sec < 0.0 {
+            return Err("slide_change_max_per_sec must be zero or a positive number");
+        }
+        if self.idle_timeout_secs > 0 && self.idle_warning_secs >= self.idle_timeout_secs {
+            return Err("idle_warning_secs must be below idle_timeout_secs");
+        }
+        Ok(())
+    }
+}
+
+// Show whether a secret is set without revealing it
+fn redacted<S: Serializer>(value: &Option<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(_) => serializer.serialize_str("<redacted>"),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn env_string(key: &str) -> Option<String> {
+    env::var(key).ok().map(|v| v.trim().to_string()).filter(|v| !v.is_empty())
+}
+
+fn env_list(key: &str, default: &[&str]) -> Vec<String> {
+    match env_string(key) {
+        Some(value) => value
+            .split(',')
+            .map(|item| item.trim().to_string())
+            .filter(|item| !item.is_empty())
+            .collect(),
+        None => default.iter().map(|item| item.to_string()).collect(),
+    }
+}
+
+// Comma-separated CIDR ranges
+fn env_cidrs(key: &str) -> Vec<Cidr> {
+    env_list(key, &[])
+        .into_iter()
+        .filter_map(|item| match item.parse() {
+            Ok(range) => Some(range),
+            Err(_) => {
+                eprintln!("⚠️ Ignoring invalid entry for {}: {}", key, item);
+                None
+            }
+        })
+        .collect()
+}
+
+// Presence metadata schema from `key=kind` pairs, by default a display name and an avatar
+fn presence_metadata_fields() -> Vec<(String, FieldKind)> {
+    let pairs = env_pairs("RABLY_PRESENCE_METADATA_FIELDS");
+    if pairs.is_empty() {
+        return vec![
+            ("display_name".to_string(), FieldKind::Text),
+            ("avatar_url".to_string(), FieldKind::Url),
+        ];
+    }
+    pairs
+        .into_iter()
+        .filter_map(|(key, kind)| match kind.parse() {
+            Ok(kind) => Some((key, kind)),
+            Err(_) => {
+                eprintln!("⚠️ Ignoring presence metadata field {}={}: kind must be text or url", key, kind);
+                None
+            }
+        })
+        .collect()
+}
+
+// Comma-separated "key=value" entries
+fn env_pairs(key: &str) -> Vec<(String, String)> {
+    env_list(key, &[])
+        .into_iter()
+        .filter_map(|item| match item.split_once('=') {
+            Some((name, value)) if !name.trim().is_empty() && !value.trim().is_empty() => {
+                Some((name.trim().to_string(), value.trim().to_string()))
+            }
+            _ => {
+                eprintln!("⚠️ Ignoring invalid entry for {}: {}", key, item);
+                None
+            }
+        })
+        .collect()
+}
+
+fn env_parse<T: FromStr>(key: &str, default: T) -> T {
+    match env_string(key) {
+        Some(value) => value.parse().unwrap_or_else(|_| {
+            eprintln!("⚠️ Ignoring invalid value for {}: {}", key, value);
+            default
+        }),
+        None => default,
+    }
+}
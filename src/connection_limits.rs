@@ -0,0 +1,61 @@
+// Concurrent connection limits per authenticated identity.
+
+use std::sync::Arc;
+
+use dashmap::DashMap;
+
+use crate::{auth::Identity, AppState};
+
+// One counted connection; the count drops again when this is dropped, whether the
+// connection ran and ended or the upgrade never completed
+pub struct ConnectionSlot {
+    counts: Arc<DashMap<String, usize>>,
+    identity: String,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.counts.remove_if_mut(&self.identity, |_, count| {
+            *count -= 1;
+            *count == 0
+        });
+    }
+}
+
+// The identity's concurrent connection limit, 0 meaning unlimited
+fn limit(state: &AppState, identity: &Identity) -> usize {
+    identity
+        .role
+        .as_deref()
+        .and_then(|role| {
+            state
+                .config
+                .connections_per_role
+                .iter()
+                .find(|(limited, _)| limited == role)
+                .map(|(_, limit)| *limit)
+        })
+        .unwrap_or(state.config.max_connections_per_identity)
+}
+
+// Count a new connection for the identity, failing with the limit when it already has as
+// many as allowed. Anonymous and unlimited connections aren't counted and get no slot.
+pub fn claim(state: &AppState, identity: &Identity) -> Result<Option<ConnectionSlot>, usize> {
+    let Some(id) = identity.id.clone() else {
+        return Ok(None);
+    };
+    let limit = limit(state, identity);
+    if limit == 0 {
+        return Ok(None);
+    }
+
+    let mut count = state.identity_connections.entry(id.clone()).or_insert(0);
+    if *count >= limit {
+        return Err(limit);
+    }
+    *count += 1;
+    Ok(Some(ConnectionSlot {
+        counts: state.identity_connections.clone(),
+        identity: id,
+    }))
+}
@@ -0,0 +1,156 @@
+use std::{
+    borrow::Cow,
+    sync::{
+        atomic::{AtomicI64, AtomicU32, Ordering},
+        Arc,
+    },
+};
+use tokio::{io::AsyncWriteExt, sync::mpsc};
+
+use crate::{
+    clock,
+    config::Config,
+    degradation::{self, Degradations},
+};
+
+// Longest payload excerpt kept per dead-letter entry
+const MAX_PAYLOAD_CHARS: usize = 1024;
+
+// Why a message could not be delivered
+#[derive(Clone, Copy, Debug)]
+pub enum DeadLetterReason {
+    NoSubscribers,
+    SendFailed,
+    Lagged,
+    Expired,
+    Undelivered,
+    WebhookFailed,
+}
+
+impl DeadLetterReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeadLetterReason::NoSubscribers => "no_subscribers",
+            DeadLetterReason::SendFailed => "send_failed",
+            DeadLetterReason::Lagged => "lagged",
+            DeadLetterReason::Expired => "expired",
+            DeadLetterReason::Undelivered => "undelivered",
+            DeadLetterReason::WebhookFailed => "webhook_failed",
+        }
+    }
+}
+
+enum DeadLetterSink {
+    Stdout,
+    File(mpsc::Sender<String>),
+}
+
+// Rate-bounded diagnostic record of undeliverable messages
+pub struct DeadLetterLog {
+    sink: Option<DeadLetterSink>,
+    max_per_minute: u32,
+    window_minute: AtomicI64,
+    window_count: AtomicU32,
+    suppressed: AtomicU32,
+}
+
+impl DeadLetterLog {
+    pub fn new(config: &Config, degradations: &Arc<Degradations>) -> Self {
+        let sink = config.dead_letter_sink.as_deref().map(|target| match target {
+            "log" | "stdout" => DeadLetterSink::Stdout,
+            path => DeadLetterSink::File(spawn_file_writer(path.to_string(), degradations.clone())),
+        });
+
+        DeadLetterLog {
+            sink,
+            max_per_minute: config.dead_letter_max_per_minute,
+            window_minute: AtomicI64::new(0),
+            window_count: AtomicU32::new(0),
+            suppressed: AtomicU32::new(0),
+        }
+    }
+
+    pub fn record(&self, reason: DeadLetterReason, channel: Option<&str>, client_id: &str, payload: &str) {
+        let Some(sink) = &self.sink else {
+            return;
+        };
+
+        let now = clock::now();
+        let minute = now.timestamp() / 60;
+        if self.window_minute.swap(minute, Ordering::Relaxed) != minute {
+            self.window_count.store(0, Ordering::Relaxed);
+            let suppressed = self.suppressed.swap(0, Ordering::Relaxed);
+            if suppressed > 0 {
+                println!("🪦 Dead-letter log suppressed {} entries in the previous window", suppressed);
+            }
+        }
+
+        if self.window_count.fetch_add(1, Ordering::Relaxed) >= self.max_per_minute {
+            self.suppressed.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        let entry = serde_json::json!({
+            "timestamp": now.timestamp(),
+            "reason": reason.as_str(),
+            "channel": channel,
+            "client_id": client_id,
+            "payload": without_ciphertext(payload).chars().take(MAX_PAYLOAD_CHARS).collect::<String>(),
+        })
+        .to_string();
+
+        match sink {
+            DeadLetterSink::Stdout => println!("🪦 Dead letter: {}", entry),
+            DeadLetterSink::File(tx) => {
+                // Never block delivery on the file writer; drop the entry if it's behind
+                if tx.try_send(entry).is_err() {
+                    self.suppressed.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+}
+
+// An end-to-end encrypted message's envelope without its data, which isn't the server's to log
+fn without_ciphertext(payload: &str) -> Cow<'_, str> {
+    if !payload.contains("\"encrypted\":true") {
+        return Cow::Borrowed(payload);
+    }
+    match serde_json::from_str::<serde_json::Value>(payload) {
+        Ok(serde_json::Value::Object(mut envelope)) if envelope.get("encrypted") == Some(&serde_json::Value::Bool(true)) => {
+            envelope.remove("data");
+            Cow::Owned(serde_json::Value::Object(envelope).to_string())
+        }
+        _ => Cow::Borrowed(payload),
+    }
+}
+
+// Append dead-letter entries to a file from a dedicated task
+fn spawn_file_writer(path: String, degradations: Arc<Degradations>) -> mpsc::Sender<String> {
+    let (tx, mut rx) = mpsc::channel::<String>(1000);
+
+    tokio::spawn(async move {
+        let mut file = match tokio::fs::OpenOptions::new().create(true).append(true).open(&path).await {
+            Ok(file) => file,
+            Err(e) => {
+                eprintln!("❌ Failed to open dead-letter file {}: {}", path, e);
+                degradations.fail(degradation::DEAD_LETTER, format!("cannot open {}: {}", path, e));
+                return;
+            }
+        };
+
+        println!("🪦 Dead-letter log writing to {}", path);
+
+        while let Some(entry) = rx.recv().await {
+            match file.write_all(format!("{}\n", entry).as_bytes()).await {
+                Ok(()) => degradations.recover(degradation::DEAD_LETTER),
+                Err(e) => {
+                    eprintln!("❌ Failed to write dead-letter entry: {}", e);
+                    degradations.fail(degradation::DEAD_LETTER, e);
+                }
+            }
+        }
+    });
+
+    tx
+}
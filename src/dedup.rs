@@ -0,0 +1,114 @@
+// Remembering the message ids recently written to each participant, so replays and reconnects
+// don't send them again.
+
+use axum::extract::ws::Message;
+use serde::Deserialize;
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::{auth::Identity, cbor, metrics::Metrics, AppState};
+
+// Ids of the messages most recently written to a participant, oldest first
+pub struct Delivered {
+    ids: VecDeque<String>,
+    seen: HashSet<String>,
+    capacity: usize,
+}
+
+pub type DeliveredIds = Arc<Mutex<Delivered>>;
+
+impl Delivered {
+    fn record(&mut self, id: &str) {
+        if self.seen.contains(id) {
+            return;
+        }
+        self.seen.insert(id.to_string());
+        self.ids.push_back(id.to_string());
+        while self.ids.len() > self.capacity {
+            if let Some(oldest) = self.ids.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+    }
+}
+
+// A new connection's record of delivered messages, taken over from the identity's last
+// connection if it ended within the grace window. None if deduplication is off.
+pub fn attach(state: &AppState, identity: &Identity) -> Option<DeliveredIds> {
+    let capacity = state.config.dedup_window;
+    if capacity == 0 {
+        return None;
+    }
+    let parked = identity
+        .id
+        .as_ref()
+        .and_then(|id| state.delivered.remove(id))
+        .map(|(_, delivered)| delivered);
+    Some(parked.unwrap_or_else(|| {
+        Arc::new(Mutex::new(Delivered {
+            ids: VecDeque::new(),
+            seen: HashSet::new(),
+            capacity,
+        }))
+    }))
+}
+
+// Keep an ended connection's record for the identity's next connection, until the grace
+// window is over. Without an identity nobody can take it over.
+pub fn detach(state: &AppState, identity: &Identity, delivered: Option<DeliveredIds>) {
+    let (Some(id), Some(delivered)) = (identity.id.clone(), delivered) else {
+        return;
+    };
+    state.delivered.insert(id.clone(), delivered.clone());
+
+    let live = state.live();
+    let grace = live.presence_grace_secs.max(live.pinned_presence_grace_secs);
+    let state = state.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_secs(grace)).await;
+        state.delivered.remove_if(&id, |_, parked| Arc::ptr_eq(parked, &delivered));
+    });
+}
+
+// Just the id of an envelope; the rest is skipped over
+#[derive(Deserialize)]
+struct Envelope {
+    message_id: Option<String>,
+}
+
+// The message_id of a frame as the writer sends it, JSON or CBOR. A binary frame that
+// isn't CBOR is a compressed slide.
+fn frame_id(msg: &Message) -> Option<String> {
+    match msg {
+        Message::Text(text) => serde_json::from_str::<Envelope>(text).ok()?.message_id,
+        Message::Binary(bytes) => match cbor::decode(bytes).ok()?.get("message_id")? {
+            serde_json::Value::String(id) => Some(id.clone()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// Remember a frame the writer has just sent
+pub fn record(delivered: Option<&DeliveredIds>, msg: &Message) {
+    let Some(delivered) = delivered else {
+        return;
+    };
+    if let Some(id) = frame_id(msg) {
+        delivered.lock().unwrap_or_else(|e| e.into_inner()).record(&id);
+    }
+}
+
+// Whether a message was already written to the participant, counting it as suppressed if so
+pub fn already_delivered(state: &AppState, delivered: Option<&DeliveredIds>, message_id: &str) -> bool {
+    let seen = delivered.is_some_and(|delivered| {
+        delivered.lock().unwrap_or_else(|e| e.into_inner()).seen.contains(message_id)
+    });
+    if seen {
+        Metrics::inc(&state.metrics.duplicates_suppressed);
+    }
+    seen
+}
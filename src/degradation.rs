@@ -0,0 +1,66 @@
+// Registry of optional subsystems that are currently failing.
+
+use dashmap::DashMap;
+use std::fmt;
+
+use crate::clock;
+
+pub const RETENTION: &str = "retention";
+pub const PRESENCE_STORE: &str = "presence_store";
+pub const DEAD_LETTER: &str = "dead_letter";
+pub const AUDIT_LOG: &str = "audit_log";
+pub const SCHEDULE_STORE: &str = "schedule_store";
+pub const ATTENDANCE: &str = "attendance";
+pub const WEBHOOK: &str = "webhook";
+
+struct Degraded {
+    reason: String,
+    since: i64,
+}
+
+#[derive(Default)]
+pub struct Degradations {
+    subsystems: DashMap<&'static str, Degraded>,
+}
+
+impl Degradations {
+    // Mark a subsystem degraded, keeping the original start time if it already was
+    pub fn fail(&self, subsystem: &'static str, reason: impl fmt::Display) {
+        let reason = reason.to_string();
+        let mut entry = self.subsystems.entry(subsystem).or_insert_with(|| {
+            eprintln!("⚠️ Subsystem {} degraded: {}", subsystem, reason);
+            Degraded {
+                reason: String::new(),
+                since: clock::now().timestamp(),
+            }
+        });
+        entry.reason = reason;
+    }
+
+    pub fn recover(&self, subsystem: &'static str) {
+        if self.subsystems.remove(subsystem).is_some() {
+            println!("✅ Subsystem {} recovered", subsystem);
+        }
+    }
+
+    pub fn any(&self) -> bool {
+        !self.subsystems.is_empty()
+    }
+
+    // Degraded subsystems by name, for the status endpoints
+    pub fn report(&self) -> Vec<serde_json::Value> {
+        let mut report: Vec<_> = self
+            .subsystems
+            .iter()
+            .map(|entry| {
+                serde_json::json!({
+                    "subsystem": entry.key(),
+                    "reason": entry.reason,
+                    "since": entry.since,
+                })
+            })
+            .collect();
+        report.sort_by(|a, b| a["subsystem"].as_str().cmp(&b["subsystem"].as_str()));
+        report
+    }
+}
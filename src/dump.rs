@@ -0,0 +1,97 @@
+// Server state snapshot for incident response.
+
+use axum::{
+    extract::{Query, State},
+    http::HeaderMap,
+};
+use serde::Deserialize;
+use std::collections::BTreeSet;
+
+use crate::{admin, channel_config, clock, epoch, history, presence, slides, throughput, AppState};
+
+// Channels in one dump unless the request asks for fewer
+const MAX_CHANNELS: usize = 200;
+// Roster entries listed per channel; larger rosters are counted and truncated
+const MAX_ROSTER: usize = 100;
+
+#[derive(Deserialize)]
+pub struct DumpQuery {
+    // Only channels whose name starts with this
+    prefix: Option<String>,
+    limit: Option<usize>,
+}
+
+pub async fn dump(
+    Query(query): Query<DumpQuery>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<String, (axum::http::StatusCode, String)> {
+    admin::authorize(&state, &headers)?;
+
+    let prefix = query.prefix.unwrap_or_default();
+    let limit = query.limit.unwrap_or(MAX_CHANNELS).clamp(1, MAX_CHANNELS);
+
+    // A channel may exist in any of these without the others, e.g. history kept after its
+    // subscribers left
+    let mut names = BTreeSet::new();
+    let mut collect = |keys: Vec<String>| names.extend(keys.into_iter().filter(|name| name.starts_with(&prefix)));
+    collect(state.channels.iter().map(|entry| entry.key().clone()).collect());
+    collect(state.channel_presence.iter().map(|entry| entry.key().clone()).collect());
+    collect(state.channel_history.iter().map(|entry| entry.key().clone()).collect());
+    collect(state.archived_channels.iter().map(|entry| entry.key().clone()).collect());
+    collect(state.channel_overrides.iter().map(|entry| entry.key().clone()).collect());
+
+    let matched = names.len();
+    let channels: Vec<serde_json::Value> = names
+        .into_iter()
+        .take(limit)
+        .map(|channel| channel_snapshot(&state, &channel))
+        .collect();
+    println!("🔍 State dump of {} of {} matching channels", channels.len(), matched);
+    state.audit.record(
+        &headers,
+        "dump",
+        None,
+        serde_json::json!({ "prefix": prefix, "channels": channels.len() }),
+    );
+
+    Ok(serde_json::json!({
+        "instance": state.config.instance_id,
+        "generated_at": clock::now().timestamp_millis(),
+        "connections": state.clients.len(),
+        "prefix": prefix,
+        "matched_channels": matched,
+        "truncated": matched > channels.len(),
+        "channels": channels,
+    })
+    .to_string())
+}
+
+fn channel_snapshot(state: &AppState, channel: &str) -> serde_json::Value {
+    let roster = presence::page(state, channel, None, MAX_ROSTER);
+    serde_json::json!({
+        "channel": channel,
+        "subscribers": state.channels.get(channel).map(|tx| tx.receiver_count()).unwrap_or(0),
+        "archived": state.archived_channels.contains_key(channel),
+        "epoch": epoch::current(state, channel),
+        "seq": state.channel_seq.get(channel).map(|seq| *seq).unwrap_or(0),
+        "last_activity": state.channel_activity.get(channel).map(|at| *at),
+        "created_at": state.channel_created.get(channel).map(|at| *at),
+        "messages_per_sec": throughput::per_sec(state, channel),
+        "presence": {
+            "count": roster.total,
+            "truncated": roster.next.is_some(),
+            "participants": roster.participants,
+        },
+        "history": {
+            "messages": state.channel_history.get(channel).map(|buffer| buffer.len()).unwrap_or(0),
+            "bytes": history::bytes(state, channel),
+            "evicted": state.history_evicted.get(channel).map(|evicted| *evicted).unwrap_or(0),
+        },
+        "sticky": state.sticky_messages.contains_key(channel),
+        "scheduled": state.scheduled.count(channel),
+        "slide_version": slides::version(state, channel),
+        "overrides": channel_config::overrides(state, channel),
+        "effective": channel_config::effective(state, channel),
+    })
+}
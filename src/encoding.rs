@@ -0,0 +1,213 @@
+use axum::extract::ws::Message;
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::OnceLock,
+};
+
+use crate::{cbor, compression, metrics::Metrics, projection::Projection, signing, AppState, ChannelEvent, PROTOCOL_VERSION};
+
+// Protocol version of the envelope being rolled out to the "next" cohort
+pub const NEXT_PROTOCOL_VERSION: u32 = PROTOCOL_VERSION + 1;
+
+// Wire formats a channel broadcast can be delivered in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WireFormat {
+    Json,
+    // Candidate envelope for the A/B cohort; change `encode` as the schema evolves
+    JsonNext,
+    // The current envelope as binary CBOR, for connections that negotiated it
+    Cbor,
+}
+
+impl WireFormat {
+    pub fn cohort(&self) -> &'static str {
+        match self {
+            WireFormat::Json => "current",
+            WireFormat::JsonNext => "next",
+            WireFormat::Cbor => "current",
+        }
+    }
+}
+
+// Encoded frames for one broadcast, built on first use and shared by every subscriber
+#[derive(Default)]
+pub struct FrameCache {
+    json: OnceLock<Message>,
+    json_next: OnceLock<Message>,
+    cbor: OnceLock<Message>,
+    // Binary frame compressed with the channel's dictionary, for connections that opted in
+    compressed: OnceLock<Message>,
+}
+
+impl FrameCache {
+    fn slot(&self, format: WireFormat) -> &OnceLock<Message> {
+        match format {
+            WireFormat::Json => &self.json,
+            WireFormat::JsonNext => &self.json_next,
+            WireFormat::Cbor => &self.cbor,
+        }
+    }
+}
+
+// Format for a new connection: a stable hash of its id puts the configured
+// percentage of connections into the "next" cohort
+pub fn cohort_format(state: &AppState, client_id: &str) -> WireFormat {
+    let mut hasher = DefaultHasher::new();
+    client_id.hash(&mut hasher);
+
+    if hasher.finish() % 100 < state.config.next_format_percent as u64 {
+        WireFormat::JsonNext
+    } else {
+        WireFormat::Json
+    }
+}
+
+// The frame a subscriber receives for a broadcast in its format
+pub fn frame(state: &AppState, event: &ChannelEvent, format: WireFormat) -> Message {
+    if !state.config.serialization_cache {
+        Metrics::inc(&state.metrics.frames_encoded);
+        return encode(event, format);
+    }
+
+    let mut encoded = false;
+    let frame = event
+        .frames
+        .slot(format)
+        .get_or_init(|| {
+            encoded = true;
+            encode(event, format)
+        })
+        .clone();
+
+    if encoded {
+        Metrics::inc(&state.metrics.frames_encoded);
+    } else {
+        Metrics::inc(&state.metrics.frame_cache_hits);
+    }
+    frame
+}
+
+// The frame for one subscriber, given its preferences. A projection wins over compression:
+// trimmed frames are specific to the subscriber, so they're encoded every time rather than
+// cached. Compressed frames are only built for the current format, and never for payloads
+// the publisher marked incompressible.
+pub fn subscriber_frame(
+    state: &AppState,
+    event: &ChannelEvent,
+    format: WireFormat,
+    projection: Option<&Projection>,
+    compress: bool,
+) -> Message {
+    if let Some(projection) = projection.filter(|projection| projection.applies_to(event)) {
+        return projected_frame(state, event, format, projection);
+    }
+    (compress && format == WireFormat::Json && !event.msg.incompressible)
+        .then(|| compressed_frame(state, event))
+        .flatten()
+        .unwrap_or_else(|| frame(state, event, format))
+}
+
+fn projected_frame(state: &AppState, event: &ChannelEvent, format: WireFormat, projection: &Projection) -> Message {
+    Metrics::inc(&state.metrics.frames_encoded);
+    let mut envelope = serde_json::to_value(&event.msg).unwrap_or_default();
+    if let Some(fields) = envelope.as_object_mut() {
+        fields.insert("data".to_string(), projection.apply(&event.msg.data));
+        if format == WireFormat::JsonNext {
+            fields.insert("v".to_string(), serde_json::json!(NEXT_PROTOCOL_VERSION));
+        }
+    }
+    if format == WireFormat::Cbor {
+        return Message::Binary(cbor::encode(&envelope).into());
+    }
+    Message::Text(signing::sign(envelope.to_string()).into())
+}
+
+// Slide broadcasts on channels with a dictionary, compressed once and shared
+fn compressed_frame(state: &AppState, event: &ChannelEvent) -> Option<Message> {
+    if !matches!(event.msg.r#type.as_str(), "slide_change" | "slide_diff") {
+        return None;
+    }
+    let dictionary = compression::for_channel(&state.dictionaries, &event.msg.channel)?;
+
+    let frame = event
+        .frames
+        .compressed
+        .get_or_init(|| {
+            Metrics::inc(&state.metrics.frames_encoded);
+            Message::Binary(compression::compress(dictionary, event.json.as_bytes()).into())
+        })
+        .clone();
+    Some(frame)
+}
+
+fn encode(event: &ChannelEvent, format: WireFormat) -> Message {
+    match format {
+        // Already serialized once when the broadcast was created
+        WireFormat::Json => Message::Text(event.json.clone().into()),
+        WireFormat::JsonNext => {
+            // Same envelope under the next version number
+            let mut envelope = serde_json::to_value(&event.msg).unwrap_or_default();
+            if let Some(fields) = envelope.as_object_mut() {
+                fields.insert("v".to_string(), serde_json::json!(NEXT_PROTOCOL_VERSION));
+            }
+            Message::Text(signing::sign(envelope.to_string()).into())
+        }
+        WireFormat::Cbor => Message::Binary(cbor::encode(&serde_json::to_value(&event.msg).unwrap_or_default()).into()),
+    }
+}
+
+// A frame as a CBOR connection receives it: JSON text frames, such as replies and
+// notices, are converted; binary and control frames pass through. Signatures cover JSON
+// text, so they are left out.
+pub fn to_cbor(frame: Message) -> Message {
+    match frame {
+        Message::Text(text) => match serde_json::from_str::<serde_json::Value>(&text) {
+            Ok(mut value) => {
+                if let Some(fields) = value.as_object_mut() {
+                    fields.remove("sig");
+                }
+                Message::Binary(cbor::encode(&value).into())
+            }
+            Err(_) => Message::Text(text),
+        },
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing, ServerMessage};
+
+    fn broadcast(data: serde_json::Value) -> ChannelEvent {
+        let msg = ServerMessage::new("message", "lesson", data);
+        let json = msg.to_json().expect("serializes");
+        ChannelEvent { msg, origin: "test".to_string(), json, frames: FrameCache::default() }
+    }
+
+    fn version(frame: &Message) -> serde_json::Value {
+        let envelope: serde_json::Value = match frame {
+            Message::Text(text) => serde_json::from_str(text).unwrap(),
+            Message::Binary(bytes) => cbor::decode(bytes).unwrap(),
+            other => panic!("unexpected frame {:?}", other),
+        };
+        envelope["v"].clone()
+    }
+
+    #[test]
+    fn each_format_carries_its_envelope_version() {
+        let state = testing::state(|_| {});
+        let event = broadcast(serde_json::json!({ "slide": { "index": 3, "notes": "..." } }));
+
+        assert_eq!(version(&frame(&state, &event, WireFormat::Json)), PROTOCOL_VERSION);
+        assert_eq!(version(&frame(&state, &event, WireFormat::Cbor)), PROTOCOL_VERSION);
+        assert_eq!(version(&frame(&state, &event, WireFormat::JsonNext)), NEXT_PROTOCOL_VERSION);
+
+        // Trimmed frames are built separately and keep the version too
+        let projection = Projection::parse(&["slide.index".to_string()]).expect("valid fields");
+        for (format, expected) in [(WireFormat::Json, PROTOCOL_VERSION), (WireFormat::JsonNext, NEXT_PROTOCOL_VERSION)] {
+            let trimmed = subscriber_frame(&state, &event, format, Some(&projection), false);
+            assert_eq!(version(&trimmed), expected, "{:?}", format);
+        }
+    }
+}
@@ -0,0 +1,32 @@
+// Channel epochs: generations of a channel's sequence numbers.
+
+use crate::{broadcast_event, AppState};
+
+pub fn current(state: &AppState, channel: &str) -> u64 {
+    state.channel_epochs.get(channel).map(|epoch| *epoch).unwrap_or(0)
+}
+
+// Start the channel's next epoch, returning it
+pub fn advance(state: &AppState, channel: &str) -> u64 {
+    let mut epoch = state.channel_epochs.entry(channel.to_string()).or_insert(0);
+    *epoch += 1;
+    *epoch
+}
+
+// Restart a live channel's numbering in a new epoch. History from the old epoch is dropped,
+// so a replay never mixes the two; subscribers learn of the reset from `epoch_reset`, the
+// first message of the new epoch.
+pub fn reset(state: &AppState, channel: &str) -> u64 {
+    let epoch = {
+        // Held across the switch so no broadcast is numbered with the old epoch and new seq
+        let mut seq = state.channel_seq.entry(channel.to_string()).or_insert(0);
+        *seq = 0;
+        state.channel_history.remove(channel);
+        state.history_bytes.remove(channel);
+        state.history_evicted.remove(channel);
+        advance(state, channel)
+    };
+
+    broadcast_event(state, channel, "epoch_reset", serde_json::json!({ "epoch": epoch }));
+    epoch
+}
@@ -0,0 +1,118 @@
+// Per-message headers.
+
+use std::{collections::BTreeMap, fmt};
+
+use crate::AppState;
+
+pub type Headers = BTreeMap<String, String>;
+
+#[derive(Debug)]
+pub enum HeaderError {
+    EmptyName,
+    TooMany { max: usize },
+    TooLarge { max: usize },
+}
+
+impl fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HeaderError::EmptyName => write!(f, "Header names cannot be empty"),
+            HeaderError::TooMany { max } => write!(f, "A message carries at most {} headers", max),
+            HeaderError::TooLarge { max } => write!(f, "Header names and values add up to more than {} bytes", max),
+        }
+    }
+}
+
+// Check a message's headers against the limits
+pub fn check(state: &AppState, headers: Option<&Headers>) -> Result<(), HeaderError> {
+    let Some(headers) = headers else {
+        return Ok(());
+    };
+    if headers.keys().any(String::is_empty) {
+        return Err(HeaderError::EmptyName);
+    }
+
+    let max = state.config.max_headers;
+    if headers.len() > max {
+        return Err(HeaderError::TooMany { max });
+    }
+    let max = state.config.max_header_bytes;
+    let bytes: usize = headers.iter().map(|(name, value)| name.len() + value.len()).sum();
+    if bytes > max {
+        return Err(HeaderError::TooLarge { max });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TestServer};
+
+    fn headers(pairs: &[(&str, &str)]) -> Headers {
+        pairs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn limits_count_headers_and_their_bytes() {
+        let state = testing::state(|config| {
+            config.max_headers = 2;
+            config.max_header_bytes = 16;
+        });
+        assert!(check(&state, None).is_ok());
+        assert!(check(&state, Some(&headers(&[("trace", "abc"), ("kind", "quiz")]))).is_ok());
+        // Exactly at the byte limit
+        assert!(check(&state, Some(&headers(&[("content-type", "json")]))).is_ok());
+
+        let too_many = headers(&[("a", "1"), ("b", "2"), ("c", "3")]);
+        assert!(matches!(check(&state, Some(&too_many)), Err(HeaderError::TooMany { max: 2 })));
+        let too_large = headers(&[("content-type", "json5")]);
+        assert!(matches!(check(&state, Some(&too_large)), Err(HeaderError::TooLarge { max: 16 })));
+        assert!(matches!(check(&state, Some(&headers(&[("", "x")]))), Err(HeaderError::EmptyName)));
+    }
+
+    #[tokio::test]
+    async fn headers_reach_subscribers_unchanged() {
+        let server = TestServer::start(|config| config.history_size = 10).await;
+        let mut teacher = server.connect("").await;
+        teacher.subscribe("lesson", serde_json::json!({})).await;
+        let mut student = server.connect("").await;
+        student.subscribe("lesson", serde_json::json!({})).await;
+
+        let sent = serde_json::json!({ "content-type": "application/vnd.quiz+json", "trace-id": "4bf92f35", "x-empty": "" });
+        teacher
+            .send(serde_json::json!({ "action": "publish", "channel": "lesson", "data": { "question": 1 }, "headers": sent }))
+            .await;
+        let msg = student.expect("message").await;
+        assert_eq!(msg["headers"], sent);
+        // Kept out of the payload
+        assert_eq!(msg["data"], serde_json::json!({ "question": 1 }));
+
+        // And replayed with them to whoever subscribes later
+        let mut latecomer = server.connect("").await;
+        latecomer.send(serde_json::json!({ "action": "subscribe", "channel": "lesson" })).await;
+        assert_eq!(latecomer.expect("message").await["headers"], sent);
+
+        // A message without headers has no headers field
+        teacher.send(serde_json::json!({ "action": "publish", "channel": "lesson", "data": "plain" })).await;
+        let msg = student.expect("message").await;
+        assert_eq!(msg["data"], "plain");
+        assert!(msg.get("headers").is_none());
+    }
+
+    #[tokio::test]
+    async fn a_publish_over_the_limits_is_refused_and_not_delivered() {
+        let server = TestServer::start(|config| config.max_headers = 1).await;
+        let mut teacher = server.connect("").await;
+        teacher.subscribe("lesson", serde_json::json!({})).await;
+        let mut student = server.connect("").await;
+        student.subscribe("lesson", serde_json::json!({})).await;
+
+        let headers = serde_json::json!({ "a": "1", "b": "2" });
+        teacher.send(serde_json::json!({ "action": "publish", "channel": "lesson", "data": "over", "headers": headers })).await;
+        assert_eq!(teacher.expect("error").await["data"]["code"], "invalid_headers");
+
+        teacher.send(serde_json::json!({ "action": "publish", "channel": "lesson", "data": "within", "headers": { "a": "1" } })).await;
+        assert_eq!(student.expect("message").await["data"], "within");
+    }
+}
@@ -0,0 +1,75 @@
+use std::{collections::VecDeque, sync::Arc};
+
+use crate::{channel_config, AppState, ChannelEvent};
+
+// Only application messages are replayed; presence is delivered as a live snapshot instead
+fn is_recordable(event: &ChannelEvent) -> bool {
+    matches!(event.msg.r#type.as_str(), "message" | "slide_change" | "slide_diff")
+}
+
+// Append a broadcast to its channel's history buffer, evicting the oldest entries past
+// the message count or byte budget. A message bigger than the whole budget isn't kept.
+// Returns whether the message was kept.
+pub fn record(state: &AppState, event: &Arc<ChannelEvent>) -> bool {
+    if !is_recordable(event) {
+        return false;
+    }
+    let channel = &event.msg.channel;
+    let (limit, max_bytes) = channel_config::history_limits(state, channel);
+    if limit == 0 {
+        return false;
+    }
+
+    // Byte totals are updated under the buffer's entry lock so they stay in step with it
+    let mut buffer = state.channel_history.entry(channel.clone()).or_default();
+    let mut bytes = state.history_bytes.get(channel).map(|bytes| *bytes).unwrap_or(0);
+
+    buffer.push_back(event.clone());
+    bytes += event.json.len();
+    while buffer.len() > limit || (max_bytes > 0 && bytes > max_bytes) {
+        let Some(evicted) = buffer.pop_front() else {
+            break;
+        };
+        bytes -= evicted.json.len();
+        if let Some(seq) = evicted.msg.seq {
+            state.history_evicted.insert(channel.clone(), seq);
+        }
+    }
+    state.history_bytes.insert(channel.clone(), bytes);
+    buffer.back().is_some_and(|last| Arc::ptr_eq(last, event))
+}
+
+// Serialized size of everything in a channel's history buffer
+pub fn bytes(state: &AppState, channel: &str) -> usize {
+    state.history_bytes.get(channel).map(|bytes| *bytes).unwrap_or(0)
+}
+
+// Recent unexpired messages for a channel, oldest first
+pub fn recent(state: &AppState, channel: &str) -> VecDeque<Arc<ChannelEvent>> {
+    state
+        .channel_history
+        .get(channel)
+        .map(|buffer| buffer.iter().filter(|event| !event.msg.is_expired()).cloned().collect())
+        .unwrap_or_default()
+}
+
+// Unexpired messages after a client's cursor, and whether some it missed are no longer
+// buffered. A cursor ahead of the channel means it predates a reset, so everything is replayed.
+pub fn since(state: &AppState, channel: &str, since_seq: u64) -> (VecDeque<Arc<ChannelEvent>>, bool) {
+    let latest = state.channel_seq.get(channel).map(|seq| *seq).unwrap_or(0);
+    if since_seq > latest {
+        return (recent(state, channel), true);
+    }
+
+    let truncated = if channel_config::history_limits(state, channel).0 == 0 {
+        since_seq < latest
+    } else {
+        state.history_evicted.get(channel).is_some_and(|evicted| *evicted > since_seq)
+    };
+
+    let events = recent(state, channel)
+        .into_iter()
+        .filter(|event| event.msg.seq.is_some_and(|seq| seq > since_seq))
+        .collect();
+    (events, truncated)
+}
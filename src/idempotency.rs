@@ -0,0 +1,56 @@
+// Publish de-duplication by idempotency key.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use crate::AppState;
+
+// Keys remembered per channel, oldest dropped first, so a flood of keys stays bounded
+const MAX_KEYS_PER_CHANNEL: usize = 10_000;
+
+#[derive(Default)]
+pub struct RecentKeys {
+    order: VecDeque<(Instant, String)>,
+    message_ids: HashMap<String, String>,
+}
+
+impl RecentKeys {
+    fn expire(&mut self, window: Duration) {
+        while let Some((seen_at, key)) = self.order.front() {
+            if seen_at.elapsed() < window && self.order.len() <= MAX_KEYS_PER_CHANNEL {
+                break;
+            }
+            self.message_ids.remove(key);
+            self.order.pop_front();
+        }
+    }
+}
+
+// Record a key for a message about to be broadcast. If the key was already used on this
+// channel within the window, returns the message id it was first published as.
+pub fn claim(state: &AppState, channel: &str, key: &str, message_id: &str) -> Option<String> {
+    let window = Duration::from_millis(state.config.idempotency_window_ms);
+    if window.is_zero() {
+        return None;
+    }
+
+    let mut recent = state.idempotency_keys.entry(channel.to_string()).or_default();
+    recent.expire(window);
+    if let Some(original) = recent.message_ids.get(key) {
+        return Some(original.clone());
+    }
+
+    recent.order.push_back((Instant::now(), key.to_string()));
+    recent.message_ids.insert(key.to_string(), message_id.to_string());
+    None
+}
+
+// Forget a key whose publish didn't go out, so a retry can succeed
+pub fn release(state: &AppState, channel: &str, key: &str) {
+    if let Some(mut recent) = state.idempotency_keys.get_mut(channel) {
+        recent.message_ids.remove(key);
+        recent.order.retain(|(_, recorded)| recorded != key);
+    }
+}
@@ -0,0 +1,109 @@
+// Admin-injected messages.
+
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    admin::{self, admin_error},
+    headers::{self, Headers},
+    scheduler::Priority,
+    send_to_channel, AppState, ServerMessage,
+};
+
+// Who an injected message appears to come from
+#[derive(Clone, Debug, Serialize)]
+pub struct Sender {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    role: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct InjectRequest {
+    #[serde(default)]
+    data: serde_json::Value,
+    // Client id the message appears to come from
+    as_client_id: Option<String>,
+    // Role it appears to come from; must be in the hierarchy
+    as_role: Option<String>,
+    headers: Option<Headers>,
+    #[serde(default)]
+    priority: Priority,
+    correlation_id: Option<String>,
+    // Mark the message as injected; turning it off makes it indistinguishable on the wire
+    #[serde(default = "marked_by_default")]
+    marked: bool,
+}
+
+fn marked_by_default() -> bool {
+    true
+}
+
+pub async fn inject_message(
+    Path(channel_id): Path<String>,
+    State(state): State<AppState>,
+    request_headers: HeaderMap,
+    Json(request): Json<InjectRequest>,
+) -> Result<String, (StatusCode, String)> {
+    admin::authorize(&state, &request_headers)?;
+    if !state.config.admin_publish {
+        return Err(admin_error(StatusCode::FORBIDDEN, "admin publishing is disabled"));
+    }
+
+    let channel = admin::resolve_channel(&state, &channel_id);
+    if state.archived_channels.contains_key(&channel) {
+        return Err(admin_error(StatusCode::CONFLICT, "channel is archived and read-only"));
+    }
+    if request.as_role.as_ref().is_some_and(|role| !state.config.role_hierarchy.contains(role)) {
+        return Err(admin_error(StatusCode::BAD_REQUEST, "as_role is not in the role hierarchy"));
+    }
+    if let Err(e) = headers::check(&state, request.headers.as_ref()) {
+        return Err(admin_error(StatusCode::BAD_REQUEST, &e.to_string()));
+    }
+
+    let client_id = request.as_client_id.filter(|client_id| !client_id.is_empty());
+    let correlation_id = request.correlation_id.unwrap_or_else(|| Uuid::new_v4().to_string());
+    let msg = ServerMessage {
+        correlation_id: Some(correlation_id.clone()),
+        priority: request.priority,
+        headers: request.headers,
+        sender: (client_id.is_some() || request.as_role.is_some())
+            .then(|| Sender { client_id: client_id.clone(), role: request.as_role.clone() }),
+        injected: request.marked,
+        ..ServerMessage::new("message", &channel, request.data)
+    };
+    let message_id = msg.message_id.clone();
+
+    // Messages with no client behind them come from the server, like announcements
+    let origin = client_id.clone().unwrap_or_else(|| "server".to_string());
+    let delivered = send_to_channel(&state, msg, &origin);
+
+    println!(
+        "🎭 Admin injected message {} on channel {} as {} ({})",
+        message_id,
+        channel,
+        client_id.as_deref().unwrap_or("the server"),
+        request.as_role.as_deref().unwrap_or("no role")
+    );
+    state.audit.record(
+        &request_headers,
+        "inject_message",
+        Some(&channel),
+        serde_json::json!({
+            "message_id": message_id,
+            "correlation_id": correlation_id,
+            "as_client_id": client_id,
+            "as_role": request.as_role,
+            "marked": request.marked,
+            "delivered": delivered,
+        }),
+    );
+
+    Ok(serde_json::json!({ "message_id": message_id, "correlation_id": correlation_id, "delivered": delivered }).to_string())
+}
@@ -0,0 +1,484 @@
+use dashmap::DashMap;
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::sync::broadcast;
+
+use crate::{
+    broadcast_event, channel_config, channel_policy, clock, close::CloseReason, epoch, migration, scheduled, AppState,
+    ChannelCreation, ChannelEvent,
+};
+
+// Broadcasts buffered per channel before slow receivers start lagging
+pub const CHANNEL_CAPACITY: usize = 1000;
+
+// Whether the creation policy lets a client subscribe to this channel
+pub fn may_subscribe(state: &AppState, channel: &str) -> bool {
+    match state.config.channel_creation {
+        ChannelCreation::Auto => true,
+        ChannelCreation::Declared => state.declared_channels.contains_key(channel),
+    }
+}
+
+// Record that something happened on a channel
+pub fn touch(state: &AppState, channel: &str) {
+    state
+        .channel_activity
+        .insert(channel.to_string(), clock::now().timestamp());
+}
+
+// Start a new channel's lifetime clock. A sender recreated for a channel that kept its
+// history is the same channel, so the clock only starts when there's none running.
+fn mark_created(state: &AppState, channel: &str) {
+    state
+        .channel_created
+        .entry(channel.to_string())
+        .or_insert_with(|| clock::now().timestamp());
+}
+
+// Unix time at which the channel reaches its maximum lifetime, if it has one
+pub fn expires_at(state: &AppState, channel: &str) -> Option<i64> {
+    let lifetime = channel_config::max_lifetime_secs(state, channel);
+    if lifetime == 0 {
+        return None;
+    }
+    state.channel_created.get(channel).map(|created| *created + lifetime as i64)
+}
+
+// Whether the channel is past its maximum lifetime and waiting to be torn down.
+// Archived channels are kept, as by the idle reaper, until they're revived.
+pub fn is_expired(state: &AppState, channel: &str) -> bool {
+    !state.archived_channels.contains_key(channel)
+        && expires_at(state, channel).is_some_and(|expires_at| clock::now().timestamp() >= expires_at)
+}
+
+// Subscribe to a channel, creating its broadcast sender on first use. The entry stays
+// locked from lookup to subscribe: concurrent first subscribes serialize on it, so the
+// sender is only ever created once and every caller receives from the one that's stored,
+// and release_sender can't drop it in between. Channel policies hear about it once the
+// entry is unlocked again.
+pub fn subscribe(state: &AppState, channel: &str) -> broadcast::Receiver<Arc<ChannelEvent>> {
+    let mut created = false;
+    let (rx, first) = {
+        let tx = state.channels.entry(channel.to_string()).or_insert_with(|| {
+            created = true;
+            broadcast::channel(CHANNEL_CAPACITY).0
+        });
+        let first = tx.receiver_count() == 0;
+        (tx.subscribe(), first)
+    };
+    touch(state, channel);
+
+    if created {
+        mark_created(state, channel);
+        channel_policy::created(state, channel);
+    }
+    if first {
+        channel_policy::first_subscriber(state, channel);
+    }
+    rx
+}
+
+// Create a channel for a publish that arrives before any subscriber, if the creation
+// policy and memory allow. Returns whether the channel exists.
+pub fn open_for_publish(state: &AppState, channel: &str) -> bool {
+    if state.channels.contains_key(channel) {
+        return true;
+    }
+    if !may_subscribe(state, channel) || state.memory.over_limit() {
+        return false;
+    }
+    let mut created = false;
+    state.channels.entry(channel.to_string()).or_insert_with(|| {
+        created = true;
+        broadcast::channel(CHANNEL_CAPACITY).0
+    });
+    touch(state, channel);
+
+    if created {
+        mark_created(state, channel);
+        channel_policy::created(state, channel);
+    }
+    true
+}
+
+// Whether a publish that nobody receives is still recorded in the channel's history
+pub fn keeps_unreceived(state: &AppState, channel: &str) -> bool {
+    state.config.store_without_subscribers || (state.config.publish_creates_channel && state.channels.contains_key(channel))
+}
+
+// Drop a channel's broadcast sender once nobody is subscribed, keeping its history and seq.
+// Returns whether the sender was removed.
+pub fn release_sender(state: &AppState, channel: &str) -> bool {
+    // Removing under the map's entry lock means a concurrent subscribe either lands
+    // before this check (and keeps the channel) or creates a fresh sender after it
+    let released = state
+        .channels
+        .remove_if(channel, |_, tx| tx.receiver_count() == 0)
+        .is_some();
+    if released {
+        channel_policy::emptied(state, channel);
+    }
+    released
+}
+
+// Remove a channel and all of its per-channel state, unless someone is still subscribed.
+// Returns whether the channel was torn down.
+pub fn teardown(state: &AppState, channel: &str) -> bool {
+    if !release_sender(state, channel) && state.channels.contains_key(channel) {
+        return false;
+    }
+
+    state.channel_presence.remove(channel);
+    state.presence_diffs.remove(channel);
+    state.join_batches.remove(channel);
+    state.presence_sets.remove(channel);
+    state.slide_state.remove(channel);
+    state.presenter_locks.remove(channel);
+    state.first_subscribers.remove(channel);
+    state.sticky_messages.remove(channel);
+    state.polls.remove(channel);
+    state.idempotency_keys.remove(channel);
+    state.channel_history.remove(channel);
+    state.history_evicted.remove(channel);
+    state.history_bytes.remove(channel);
+    // A channel that comes back numbers its messages in a new epoch
+    if state.channel_seq.remove(channel).is_some() {
+        epoch::advance(state, channel);
+    }
+    state.ordered_writers.remove(channel);
+    state.publish_slots.remove(channel);
+    state.channel_ordering.remove(channel);
+    state.channel_activity.remove(channel);
+    state.channel_created.remove(channel);
+    state.channel_throughput.remove(channel);
+    state.archived_channels.remove(channel);
+    true
+}
+
+// Make a channel read-only, keeping its history. Returns false if it was already archived.
+pub fn archive(state: &AppState, channel: &str) -> bool {
+    let newly_archived = state
+        .archived_channels
+        .insert(channel.to_string(), clock::now().timestamp())
+        .is_none();
+
+    if newly_archived {
+        broadcast_event(state, channel, "channel_archived", serde_json::json!({ "archived": true }));
+    }
+    newly_archived
+}
+
+// Accept publishes on an archived channel again. Returns false if it wasn't archived.
+pub fn revive(state: &AppState, channel: &str) -> bool {
+    if state.archived_channels.remove(channel).is_none() {
+        return false;
+    }
+
+    // Restart the idle clock so the reaper doesn't archive it again straight away
+    touch(state, channel);
+    broadcast_event(state, channel, "channel_revived", serde_json::json!({ "archived": false }));
+    true
+}
+
+// Periodically tear down channels that have been idle with no subscribers
+pub fn spawn_idle_reaper(state: AppState) {
+    let idle_secs = state.config.channel_idle_secs;
+    if idle_secs == 0 {
+        return;
+    }
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(idle_secs.clamp(1, 60)));
+        loop {
+            interval.tick().await;
+
+            let cutoff = clock::now().timestamp() - idle_secs as i64;
+            let idle: Vec<String> = state
+                .channel_activity
+                .iter()
+                .filter(|entry| *entry.value() < cutoff)
+                .map(|entry| entry.key().clone())
+                .collect();
+
+            for channel in idle {
+                // Archived channels stay until they're revived
+                if state.archived_channels.contains_key(&channel) {
+                    continue;
+                }
+
+                if state.config.archive_idle_channels {
+                    if archive(&state, &channel) {
+                        println!("🗃️ Archived channel {} after {}s idle", channel, idle_secs);
+                    }
+                } else if teardown(&state, &channel) {
+                    println!("🧹 Reaped channel {} after {}s idle", channel, idle_secs);
+                }
+            }
+        }
+    });
+}
+
+// Every second, close the channels past their maximum lifetime: announce it with a
+// `channel_expired` broadcast and drop the messages still scheduled on them, then a tick
+// later, once the broadcast had time to go out, disconnect their participants, and tear
+// them down when the last connection is gone. Until then they refuse subscribes and
+// publishes.
+pub fn spawn_expirer(state: AppState) {
+    tokio::spawn(async move {
+        // Expired channels already announced, and whether they were disconnected too
+        let mut closing: HashMap<String, bool> = HashMap::new();
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+
+            let expired: Vec<String> = state
+                .channel_created
+                .iter()
+                .map(|entry| entry.key().clone())
+                .filter(|channel| is_expired(&state, channel))
+                .collect();
+            for channel in expired {
+                match closing.get_mut(&channel) {
+                    None => {
+                        announce_expiry(&state, &channel);
+                        closing.insert(channel, false);
+                        continue;
+                    }
+                    Some(disconnected) if !*disconnected => {
+                        *disconnected = true;
+                        disconnect_expired(&state, &channel);
+                    }
+                    Some(_) => {}
+                }
+                if teardown(&state, &channel) {
+                    closing.remove(&channel);
+                    println!("⌛ Tore down expired channel {}", channel);
+                }
+            }
+            // Torn down or revived some other way meanwhile
+            closing.retain(|channel, _| is_expired(&state, channel));
+        }
+    });
+}
+
+fn announce_expiry(state: &AppState, channel: &str) {
+    let lifetime = channel_config::max_lifetime_secs(state, channel);
+    let created_at = state.channel_created.get(channel).map(|created| *created);
+    broadcast_event(
+        state,
+        channel,
+        "channel_expired",
+        serde_json::json!({ "created_at": created_at, "max_lifetime_secs": lifetime }),
+    );
+    let unscheduled = scheduled::cancel_channel(state, channel);
+    println!(
+        "⌛ Channel {} expired after {}s; dropped {} scheduled messages",
+        channel, lifetime, unscheduled
+    );
+}
+
+fn disconnect_expired(state: &AppState, channel: &str) {
+    // Away entries and entries restored from a snapshot have no connection to close
+    let participants: Vec<String> = state
+        .channel_presence
+        .get(channel)
+        .map(|channel_map| channel_map.iter().map(|info| info.key().clone()).collect())
+        .unwrap_or_default();
+    let disconnected = participants
+        .iter()
+        .filter(|client_id| {
+            state
+                .clients
+                .get(*client_id)
+                .is_some_and(|client| migration::close(state, &client, CloseReason::ChannelExpired))
+        })
+        .count();
+    println!("⌛ Disconnected {} connections from expired channel {}", disconnected, channel);
+}
+
+// Whether nothing is left of a channel but stray state: no sender, no activity the idle
+// reaper is waiting on, not archived and nobody on its roster
+fn is_orphaned(state: &AppState, channel: &str) -> bool {
+    !state.channels.contains_key(channel)
+        && !state.channel_activity.contains_key(channel)
+        && !state.archived_channels.contains_key(channel)
+        && state.channel_presence.get(channel).is_none_or(|channel_map| channel_map.is_empty())
+}
+
+// Count the map's orphaned channels towards the entries each would free
+fn orphans_in<V>(state: &AppState, map: &DashMap<String, V>, orphans: &mut HashMap<String, usize>) {
+    let channels: Vec<String> = map.iter().map(|entry| entry.key().clone()).collect();
+    for channel in channels.into_iter().filter(|channel| is_orphaned(state, channel)) {
+        *orphans.entry(channel).or_default() += 1;
+    }
+}
+
+// Periodically remove per-channel state left behind by channels that are otherwise gone,
+// such as a presence event or late publish that landed after its channel was torn down.
+// Channel settings that are meant to outlive a channel (epochs, declarations, aliases and
+// overrides) are kept.
+pub fn spawn_compactor(state: AppState) {
+    let interval_secs = state.config.compaction_interval_secs;
+    if interval_secs == 0 {
+        return;
+    }
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(interval_secs));
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+
+            let mut orphans = HashMap::new();
+            orphans_in(&state, &state.channel_presence, &mut orphans);
+            orphans_in(&state, &state.presence_diffs, &mut orphans);
+            orphans_in(&state, &state.join_batches, &mut orphans);
+            orphans_in(&state, &state.presence_sets, &mut orphans);
+            orphans_in(&state, &state.slide_state, &mut orphans);
+            orphans_in(&state, &state.presenter_locks, &mut orphans);
+            orphans_in(&state, &state.first_subscribers, &mut orphans);
+            orphans_in(&state, &state.sticky_messages, &mut orphans);
+            orphans_in(&state, &state.polls, &mut orphans);
+            orphans_in(&state, &state.idempotency_keys, &mut orphans);
+            orphans_in(&state, &state.channel_history, &mut orphans);
+            orphans_in(&state, &state.history_evicted, &mut orphans);
+            orphans_in(&state, &state.history_bytes, &mut orphans);
+            orphans_in(&state, &state.channel_seq, &mut orphans);
+            orphans_in(&state, &state.ordered_writers, &mut orphans);
+            orphans_in(&state, &state.publish_slots, &mut orphans);
+            orphans_in(&state, &state.channel_ordering, &mut orphans);
+            orphans_in(&state, &state.channel_created, &mut orphans);
+            orphans_in(&state, &state.channel_throughput, &mut orphans);
+
+            let mut channels = 0;
+            let mut entries = 0;
+            for (channel, count) in orphans {
+                // Checked again, as a subscribe may have brought it back since the scan
+                if is_orphaned(&state, &channel) && teardown(&state, &channel) {
+                    channels += 1;
+                    entries += count;
+                }
+            }
+            if channels > 0 {
+                println!("🧽 Compacted {} orphaned entries of {} torn-down channels", entries, channels);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{send_to_channel, testing, ServerMessage};
+
+    const ROUNDS: u64 = 1000;
+
+    // Publish a numbered message and wait for it to come back on `rx`
+    async fn round_trip(state: &AppState, rx: &mut broadcast::Receiver<Arc<ChannelEvent>>, worker: u64, round: u64) {
+        let data = serde_json::json!({ "worker": worker, "round": round });
+        assert!(send_to_channel(state, ServerMessage::new("message", "lesson", data.clone()), "test"));
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                match rx.recv().await {
+                    Ok(event) if event.msg.data == data => return,
+                    Ok(_) => continue,
+                    Err(e) => panic!("subscriber missed round {} of worker {}: {}", round, worker, e),
+                }
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("round {} of worker {} never arrived", round, worker));
+    }
+
+    #[tokio::test]
+    async fn resubscribing_after_the_channel_is_released_loses_nothing() {
+        let state = testing::state(|_| {});
+        for round in 0..ROUNDS {
+            let mut rx = subscribe(&state, "lesson");
+            round_trip(&state, &mut rx, 0, round).await;
+            drop(rx);
+            assert!(release_sender(&state, "lesson"));
+            assert!(!state.channels.contains_key("lesson"));
+        }
+    }
+
+    #[test]
+    fn concurrent_unsubscribe_and_resubscribe_never_orphan_a_subscriber() {
+        let state = testing::state(|_| {});
+        // Each thread keeps leaving the channel (possibly releasing it) and joining again while
+        // the others do the same. A subscriber is always on the channel's current sender, so
+        // publishes keep reaching it; one left on a released sender would never hear another.
+        let threads = (0..4)
+            .map(|_| {
+                let state = state.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..ROUNDS * 100 {
+                        let rx = subscribe(&state, "lesson");
+                        let attached = state.channels.get("lesson").is_some_and(|tx| tx.receiver_count() > 0);
+                        assert!(attached, "subscriber left on a released sender");
+                        drop(rx);
+                        release_sender(&state, "lesson");
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().expect("subscriber thread panicked");
+        }
+        assert!(!state.channels.contains_key("lesson"));
+    }
+
+    #[test]
+    fn concurrent_first_subscribers_all_share_one_sender() {
+        const SUBSCRIBERS: usize = 32;
+        let state = testing::state(|_| {});
+        for round in 0..50 {
+            let channel = format!("new-channel-{}", round);
+            // Everyone subscribes to the channel at the same moment, racing to create it
+            let start = Arc::new(std::sync::Barrier::new(SUBSCRIBERS));
+            let threads = (0..SUBSCRIBERS)
+                .map(|_| {
+                    let (state, channel, start) = (state.clone(), channel.clone(), start.clone());
+                    std::thread::spawn(move || {
+                        start.wait();
+                        subscribe(&state, &channel)
+                    })
+                })
+                .collect::<Vec<_>>();
+            let mut receivers = threads
+                .into_iter()
+                .map(|thread| thread.join().expect("subscriber thread panicked"))
+                .collect::<Vec<_>>();
+
+            assert_eq!(state.channels.get(&channel).map(|tx| tx.receiver_count()), Some(SUBSCRIBERS));
+            let data = serde_json::json!({ "round": round });
+            assert!(send_to_channel(&state, ServerMessage::new("message", &channel, data.clone()), "test"));
+            for rx in &mut receivers {
+                assert_eq!(rx.try_recv().expect("subscriber missed the publish").msg.data, data);
+            }
+        }
+    }
+
+    #[test]
+    fn a_channel_expires_once_its_lifetime_is_up() {
+        let start = chrono::DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let clock = crate::clock::mock::MockClock::install(start);
+        let state = testing::state(|config| config.channel_max_lifetime_secs = 3600);
+
+        mark_created(&state, "lesson");
+        assert_eq!(expires_at(&state, "lesson"), Some(start.timestamp() + 3600));
+        clock.advance(Duration::from_secs(3599));
+        assert!(!is_expired(&state, "lesson"));
+        // Recreating the sender doesn't restart the clock
+        mark_created(&state, "lesson");
+        clock.advance(Duration::from_secs(1));
+        assert!(is_expired(&state, "lesson"));
+
+        // Unless it's archived
+        state.archived_channels.insert("lesson".to_string(), start.timestamp());
+        assert!(!is_expired(&state, "lesson"));
+    }
+}
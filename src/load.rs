@@ -0,0 +1,115 @@
+// Adaptive load shedding.
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use crate::AppState;
+
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(500);
+
+// Latest load measurements, stored as f64 bits so they can be read lock-free
+#[derive(Default)]
+pub struct LoadMonitor {
+    queue_utilization: AtomicU64,
+    lag_ms: AtomicU64,
+    pressure: AtomicU64,
+    shed_rate: AtomicU64,
+}
+
+impl LoadMonitor {
+    pub fn queue_utilization(&self) -> f64 {
+        f64::from_bits(self.queue_utilization.load(Ordering::Relaxed))
+    }
+
+    pub fn lag_ms(&self) -> u64 {
+        self.lag_ms.load(Ordering::Relaxed)
+    }
+
+    pub fn shed_rate(&self) -> f64 {
+        f64::from_bits(self.shed_rate.load(Ordering::Relaxed))
+    }
+
+    // Roll the dice for a new connection of the tier under the current pressure
+    pub fn should_shed(&self, tier: u8) -> bool {
+        let pressure = f64::from_bits(self.pressure.load(Ordering::Relaxed));
+        let rate = (pressure - 1.0 - tier as f64).clamp(0.0, 1.0);
+        rate > 0.0 && rand::random::<f64>() < rate
+    }
+}
+
+// Random accept delay for a new connection during the startup warm-up, so a herd of
+// clients reconnecting after a restart is spread out. The ceiling decays linearly to zero.
+pub fn warmup_delay(state: &AppState) -> Option<Duration> {
+    let window = Duration::from_secs(state.config.warmup_secs);
+    let uptime = state.stats.uptime();
+    if uptime >= window || state.config.warmup_max_delay_ms == 0 {
+        return None;
+    }
+
+    let remaining = 1.0 - uptime.as_secs_f64() / window.as_secs_f64();
+    let max_delay_ms = state.config.warmup_max_delay_ms as f64 * remaining;
+    Some(Duration::from_millis((rand::random::<f64>() * max_delay_ms) as u64))
+}
+
+// Log the start and end of the warm-up window
+pub fn spawn_warmup_notice(state: AppState) {
+    let window = state.config.warmup_secs;
+    if window == 0 || state.config.warmup_max_delay_ms == 0 {
+        return;
+    }
+
+    println!(
+        "🌅 Connection warm-up active for {}s (accept delays up to {}ms)",
+        window, state.config.warmup_max_delay_ms
+    );
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_secs(window)).await;
+        println!("✅ Connection warm-up finished");
+    });
+}
+
+pub fn spawn_sampler(state: AppState) {
+    let queue_threshold = state.config.shed_queue_threshold;
+    let lag_threshold_ms = state.config.shed_lag_ms;
+    if queue_threshold <= 0.0 && lag_threshold_ms == 0 {
+        return;
+    }
+
+    tokio::spawn(async move {
+        loop {
+            let started = Instant::now();
+            tokio::time::sleep(SAMPLE_INTERVAL).await;
+            let lag_ms = started.elapsed().saturating_sub(SAMPLE_INTERVAL).as_millis() as u64;
+
+            let (queued, capacity) = state.clients.iter().fold((0usize, 0usize), |(queued, capacity), client| {
+                let outgoing = &client.outgoing;
+                (queued + outgoing.max_capacity() - outgoing.capacity(), capacity + outgoing.max_capacity())
+            });
+            let utilization = if capacity == 0 { 0.0 } else { queued as f64 / capacity as f64 };
+
+            let mut pressure: f64 = 0.0;
+            if queue_threshold > 0.0 {
+                pressure = pressure.max(utilization / queue_threshold);
+            }
+            if lag_threshold_ms > 0 {
+                pressure = pressure.max(lag_ms as f64 / lag_threshold_ms as f64);
+            }
+            let shed_rate = (pressure - 1.0).clamp(0.0, 1.0);
+
+            let load = &state.load;
+            let was_shedding = load.shed_rate() > 0.0;
+            load.queue_utilization.store(utilization.to_bits(), Ordering::Relaxed);
+            load.lag_ms.store(lag_ms, Ordering::Relaxed);
+            load.pressure.store(pressure.to_bits(), Ordering::Relaxed);
+            load.shed_rate.store(shed_rate.to_bits(), Ordering::Relaxed);
+
+            if shed_rate > 0.0 && !was_shedding {
+                println!("⚠️ Load shedding started (pressure {:.2})", pressure);
+            } else if shed_rate == 0.0 && was_shedding {
+                println!("✅ Load shedding stopped");
+            }
+        }
+    });
+}
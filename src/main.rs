@@ -1,51 +1,615 @@
 use axum::{
     extract::ws::{Message, WebSocket, WebSocketUpgrade},
     extract::State,
-    response::IntoResponse,
-    routing::get,
+    http::{header, HeaderMap, StatusCode},
+    middleware,
+    response::{IntoResponse, Response},
+    routing::{get, patch, post, put},
     Router,
 };
+use base64::{engine::general_purpose::STANDARD as BASE64_STANDARD, Engine};
 use dashmap::DashMap;
 use futures::{sink::SinkExt, stream::StreamExt};
 use serde::{Deserialize, Serialize};
-use std::{net::SocketAddr, sync::Arc};
-use tokio::sync::broadcast;
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicI64, AtomicU64, AtomicUsize, Ordering},
+        Arc, OnceLock, RwLock,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::{broadcast, mpsc};
 use tower_http::cors::CorsLayer;
 use uuid::Uuid;
 
+mod access;
+mod admin;
+mod attendance;
+mod audit;
+mod auth;
+mod cbor;
+mod channel_config;
+mod channel_policy;
+mod chunked;
+mod clock;
+mod close;
+mod coalesce;
+mod compression;
+mod config;
+mod connection_limits;
+mod dead_letter;
+mod dedup;
+mod degradation;
+mod dump;
+mod encoding;
+mod epoch;
+mod headers;
+mod history;
+mod idempotency;
+mod injection;
+mod lifecycle;
+mod load;
+mod malformed;
+mod memory;
+mod message_types;
+mod metadata;
+mod metrics;
+mod migration;
+mod monitoring;
+mod ordering;
+mod origins;
+mod polls;
+mod presence;
+mod presence_counts;
+mod presence_store;
+mod presenter;
+mod projection;
+mod publish_slots;
+mod quorum;
+mod redelivery;
+mod reliable;
+mod resume;
+mod retention;
+mod roles;
+mod rtt;
+mod safe_integers;
+mod scheduled;
+mod scheduler;
+mod seed;
+mod shutdown;
+mod signing;
+mod skew;
+mod slides;
+mod stats;
+mod sticky;
+mod stream;
+mod subscribe_queue;
+mod tenancy;
+#[cfg(test)]
+mod testing;
+mod throughput;
+mod tiers;
+mod timestamps;
+mod webhook;
+
+use close::{CloseReason, DisconnectReason};
+use roles::{FirstSubscriber, Permission, RoleChangeError};
+use safe_integers::UnsafeIntegerPolicy;
+use scheduler::Priority;
+use config::{ChannelCreation, Config, LiveConfig};
+use dead_letter::{DeadLetterLog, DeadLetterReason};
+use load::LoadMonitor;
+use metrics::Metrics;
+
+// Wire protocol version spoken by this server
+const PROTOCOL_VERSION: u32 = 1;
+
+// Instance id stamped on every server message, when enabled; global so envelopes can be
+// built without AppState
+static INSTANCE_TAG: OnceLock<String> = OnceLock::new();
+
+// Actions accepted from WebSocket clients
+const SUPPORTED_ACTIONS: &[&str] = &[
+    "subscribe",
+    "publish",
+    "publish_quorum",
+    "chunk_start",
+    "chunk",
+    "chunk_end",
+    "ack",
+    "receipt",
+    "slide_change",
+    "slide_diff",
+    "query_presence",
+    "watch_presence_counts",
+    "unwatch_presence_counts",
+    "list_subscriptions",
+    "time_sync",
+    "diagnostic",
+    "heartbeat",
+    "set_role",
+    "transfer_role",
+    "acquire_presenter",
+    "release_presenter",
+    "transfer_presenter",
+    "set_group",
+    "presence_set",
+    "presence_update",
+    "set_sticky_message",
+    "schedule",
+    "cancel_schedule",
+    "skip_backlog",
+    "ready_to_migrate",
+    "start_poll",
+    "poll_vote",
+    "close_poll",
+];
+
+// How often each connection checks whether its outgoing queue is stuck full
+const SLOW_CONSUMER_CHECK_MS: u64 = 250;
+
+// Shortest interval between looks for unacked at-least-once messages to send again
+const REDELIVERY_CHECK_MIN_MS: u64 = 50;
+
+// Bounded queue of serialized messages waiting to be written to one connection
+type Outgoing = mpsc::Sender<Message>;
+
+// Builds the `backlog_skipped` marker once the writer knows how many messages it dropped
+type BacklogMarker = Box<dyn FnOnce(usize) -> Option<Message> + Send>;
+
+// Slide changes held back by the per-channel rate cap; only the latest is kept
+struct SlideThrottle {
+    interval: Duration,
+    last_sent: tokio::time::Instant,
+    // The held slide and whether it may go backward
+    pending: Option<(ServerMessage, bool)>,
+}
+
+// Everything a connection keeps while it's open, owned by its receive loop and lent to
+// the handler of each message it sends
+struct ConnectionContext {
+    client_id: String,
+    identity: auth::Identity,
+    // Origin header of the upgrade, for channels restricted to certain embedders
+    origin: Option<String>,
+    // Negotiated dictionary compression, and the encoding broadcasts are sent in
+    compress: bool,
+    format: encoding::WireFormat,
+    outgoing_tx: Outgoing,
+    control_tx: mpsc::UnboundedSender<Message>,
+    priority_tx: mpsc::Sender<coalesce::Queued>,
+    skip_tx: mpsc::UnboundedSender<BacklogMarker>,
+    // Per channel, the (epoch, seq) through which the client skipped its backlog; forwarders
+    // drop anything older that was still waiting on the broadcast
+    skipped_through: Arc<DashMap<String, (u64, u64)>>,
+    client_rtt: Arc<rtt::RttWindow>,
+    migration: Arc<migration::PendingMigration>,
+    // Messages the writer has taken off the normal queue, so the slow-consumer check can tell
+    // a replay backlog being worked through from a client that stopped reading
+    dequeued: Arc<AtomicU64>,
+    // Message ids written to this participant, carried over from its last connection
+    delivered: Option<dedup::DeliveredIds>,
+    // Forwarding tasks for the channels the connection is subscribed to, each feeding
+    // its own lane of the connection's fair scheduler
+    subscriptions: HashMap<String, tokio::task::JoinHandle<()>>,
+    // Tasks reporting presence counts, by the channel pattern they watch
+    presence_watches: HashMap<String, presence_counts::Watch>,
+    lanes: scheduler::Lanes,
+    // When the outgoing queue was first seen full, for the slow-consumer policy
+    full_since: Option<Instant>,
+    // Count of dequeued messages by which the latest history replay has left the queue; until
+    // then a full queue is replay backlog, not a slow client
+    replay_drained_at: u64,
+    // Slide-change rate cap, tracked per channel this client changes slides on
+    slide_throttles: HashMap<String, SlideThrottle>,
+    // Chunked publishes being reassembled
+    transfers: chunked::Transfers,
+    // The connection's resume token and its subscriptions, with RABLY_RESUME_TOKENS
+    resume: Option<resume::Session>,
+    // How far the client's clock is off, from its time_syncs
+    clock_skew: skew::SkewEstimate,
+    // At-least-once messages sent to the connection and not yet acked
+    deliveries: Arc<redelivery::Deliveries>,
+}
+
 // Application state shared across connections
 #[derive(Clone)]
 struct AppState {
+    // Settings loaded at startup
+    config: Arc<Config>,
+    // The subset of settings that can be changed at runtime
+    live_config: Arc<RwLock<LiveConfig>>,
     // Map channel_id -> broadcast sender for that channel
-    channels: Arc<DashMap<String, broadcast::Sender<String>>>,
+    channels: Arc<DashMap<String, broadcast::Sender<Arc<ChannelEvent>>>>,
     // Track active connections per channel for presence
     channel_presence: Arc<DashMap<String, DashMap<String, ClientInfo>>>,
+    // Named presence sets per channel (e.g. raised hands): set name -> members in join order
+    presence_sets: Arc<DashMap<String, HashMap<String, Vec<String>>>>,
+    // Presence changes batched for large channels
+    presence_diffs: Arc<DashMap<String, presence::PresenceDiff>>,
+    // Joins held back during a join burst, per channel
+    join_batches: Arc<DashMap<String, presence::JoinBatch>>,
+    // Recent broadcasts per channel, replayed to new subscribers
+    channel_history: Arc<DashMap<String, VecDeque<Arc<ChannelEvent>>>>,
+    // Serialized bytes held in each channel's history buffer
+    history_bytes: Arc<DashMap<String, usize>>,
+    // Highest sequence number dropped from each channel's history buffer
+    history_evicted: Arc<DashMap<String, u64>>,
+    // Last sequence number assigned per channel
+    channel_seq: Arc<DashMap<String, u64>>,
+    // Generation of each channel's seq numbering, bumped whenever it restarts
+    channel_epochs: Arc<DashMap<String, u64>>,
+    // Channels that may be subscribed to under the "declared" creation policy
+    declared_channels: Arc<DashMap<String, i64>>,
+    // Read-only channels kept for history and export, with the time they were archived
+    archived_channels: Arc<DashMap<String, i64>>,
+    // Old channel name -> canonical channel it now routes to
+    channel_aliases: Arc<DashMap<String, String>>,
+    // Unix timestamp of the last publish or subscribe per channel
+    channel_activity: Arc<DashMap<String, i64>>,
+    // Unix timestamp at which each channel came into use, for its maximum lifetime
+    channel_created: Arc<DashMap<String, i64>>,
+    // Recent messages per second per channel
+    channel_throughput: Arc<DashMap<String, throughput::Rate>>,
+    // Single-writer queues for channels that require strict ordering
+    ordered_writers: Arc<DashMap<String, mpsc::UnboundedSender<ordering::OrderedPublish>>>,
+    // Semaphores capping concurrent publishes per channel
+    publish_slots: Arc<DashMap<String, publish_slots::PublishSlots>>,
+    // Paces subscribe setup during join stampedes
+    subscribe_queue: Arc<subscribe_queue::SubscribeQueue>,
+    // Per-channel overrides of global settings, set via the admin API
+    channel_overrides: Arc<DashMap<String, channel_config::ChannelOverrides>>,
+    // Ordering modes chosen by moderators, for channels config leaves open
+    channel_ordering: Arc<DashMap<String, ordering::OrderingMode>>,
+    // Read-only maintenance mode: publishes are rejected while subscribe and presence keep working
+    maintenance: Arc<AtomicBool>,
+    // When the last server-wide announcement went out, for rate limiting
+    last_announcement: Arc<AtomicI64>,
+    // Current slide, its version and the diffs applied since, per channel
+    slide_state: Arc<DashMap<String, slides::SlideState>>,
+    // Message for late joiners per channel, delivered on subscribe only
+    sticky_messages: Arc<DashMap<String, sticky::StickyMessage>>,
+    // Polls by channel, then by poll id
+    polls: Arc<DashMap<String, HashMap<String, polls::Poll>>>,
+    // Publishes waiting for their delivery time
+    scheduled: Arc<scheduled::Schedules>,
+    // Client holding each channel's presenter lock
+    presenter_locks: Arc<DashMap<String, String>>,
+    // Presence count watches running across all connections
+    pattern_watches: Arc<AtomicUsize>,
+    // Participant who subscribed first, on channels whose first subscriber presents
+    first_subscribers: Arc<DashMap<String, String>>,
+    // Recent publish idempotency keys per channel
+    idempotency_keys: Arc<DashMap<String, idempotency::RecentKeys>>,
+    // Delivered message ids of ended connections, by identity, for their next connection
+    delivered: Arc<DashMap<String, dedup::DeliveredIds>>,
+    // Subscriptions of ended connections, by resume token, for the connection that resumes them
+    resumable: Arc<DashMap<String, resume::Parked>>,
+    // Quorum publishes awaiting acks, by message id
+    pending_quorums: Arc<DashMap<String, quorum::PendingQuorum>>,
+    // Quorums still collecting acks, by publisher connection and channel
+    unacked_quorums: Arc<DashMap<(String, String), usize>>,
+    // Live connections by client id
+    clients: Arc<DashMap<String, ClientHandle>>,
+    // Open connections per authenticated identity, for the per-identity limit
+    identity_connections: Arc<DashMap<String, usize>>,
+    // Diagnostic record of messages that couldn't be delivered
+    dead_letters: Arc<DeadLetterLog>,
+    // Accountable record of admin actions
+    audit: Arc<audit::AuditLog>,
+    // Full transcripts for channels that opted in, if configured
+    retention: Option<Arc<retention::Retention>>,
+    webhooks: Option<Arc<webhook::Webhooks>>,
+    // Join and leave logs for channels that record attendance, if configured
+    attendance: Option<Arc<attendance::Attendance>>,
+    // Counters exposed on /metrics
+    metrics: Arc<Metrics>,
+    // Aggregates behind /stats
+    stats: Arc<stats::Stats>,
+    // Latest load measurements used for shedding
+    load: Arc<LoadMonitor>,
+    // Memory watermark status
+    memory: Arc<memory::MemoryGuard>,
+    // Optional subsystems currently failing
+    degradations: Arc<degradation::Degradations>,
+    // Whether a graceful shutdown is under way
+    shutdown: Arc<shutdown::Shutdown>,
+    // Decides who may connect, and as whom
+    authenticator: Arc<dyn auth::Authenticator>,
+    // Maps channels to tenants and keeps connections to their own
+    tenant_resolver: Arc<dyn tenancy::TenantResolver>,
+    // Custom lifecycle and publish hooks, by channel prefix
+    channel_policies: Arc<channel_policy::ChannelPolicies>,
+    // On-disk presence snapshot for restarts, if configured
+    presence_store: Option<Arc<presence_store::PresenceStore>>,
+    // Preset compression dictionaries for slide broadcasts, loaded at startup
+    dictionaries: Arc<Vec<compression::Dictionary>>,
+}
+
+impl AppState {
+    // Fresh state for the given settings, before any background task is started
+    fn new(config: Arc<Config>) -> Self {
+        let degradations = Arc::new(degradation::Degradations::default());
+        let dead_letters = Arc::new(DeadLetterLog::new(&config, &degradations));
+        AppState {
+            config: config.clone(),
+            live_config: Arc::new(RwLock::new(LiveConfig::from_config(&config))),
+            channels: Arc::new(DashMap::new()),
+            channel_presence: Arc::new(DashMap::new()),
+            presence_sets: Arc::new(DashMap::new()),
+            presence_diffs: Arc::new(DashMap::new()),
+            join_batches: Arc::new(DashMap::new()),
+            channel_history: Arc::new(DashMap::new()),
+            history_bytes: Arc::new(DashMap::new()),
+            history_evicted: Arc::new(DashMap::new()),
+            channel_seq: Arc::new(DashMap::new()),
+            channel_epochs: Arc::new(DashMap::new()),
+            ordered_writers: Arc::new(DashMap::new()),
+            channel_ordering: Arc::new(DashMap::new()),
+            channel_overrides: Arc::new(DashMap::new()),
+            publish_slots: Arc::new(DashMap::new()),
+            subscribe_queue: Arc::new(subscribe_queue::SubscribeQueue::new(&config)),
+            channel_activity: Arc::new(DashMap::new()),
+            channel_created: Arc::new(DashMap::new()),
+            channel_throughput: Arc::new(DashMap::new()),
+            declared_channels: Arc::new(
+                config
+                    .declared_channels
+                    .iter()
+                    .map(|channel| (channel.clone(), clock::now().timestamp()))
+                    .collect(),
+            ),
+            archived_channels: Arc::new(DashMap::new()),
+            channel_aliases: Arc::new(DashMap::new()),
+            maintenance: Arc::new(AtomicBool::new(config.maintenance_mode)),
+            last_announcement: Arc::new(AtomicI64::new(0)),
+            slide_state: Arc::new(DashMap::new()),
+            sticky_messages: Arc::new(DashMap::new()),
+            polls: Arc::new(DashMap::new()),
+            scheduled: Arc::new(scheduled::Schedules::new(&config)),
+            presenter_locks: Arc::new(DashMap::new()),
+            pattern_watches: Arc::new(AtomicUsize::new(0)),
+            first_subscribers: Arc::new(DashMap::new()),
+            idempotency_keys: Arc::new(DashMap::new()),
+            delivered: Arc::new(DashMap::new()),
+            resumable: Arc::new(DashMap::new()),
+            pending_quorums: Arc::new(DashMap::new()),
+            unacked_quorums: Arc::new(DashMap::new()),
+            clients: Arc::new(DashMap::new()),
+            identity_connections: Arc::new(DashMap::new()),
+            webhooks: webhook::Webhooks::new(&config, &dead_letters, &degradations).map(Arc::new),
+            dead_letters,
+            audit: Arc::new(audit::AuditLog::new(&config, &degradations)),
+            retention: retention::Retention::new(&config, &degradations).map(Arc::new),
+            attendance: attendance::Attendance::new(&config, &degradations).map(Arc::new),
+            metrics: Arc::new(Metrics::default()),
+            stats: Arc::new(stats::Stats::default()),
+            load: Arc::new(LoadMonitor::default()),
+            memory: Arc::new(memory::MemoryGuard::default()),
+            degradations,
+            shutdown: Arc::new(shutdown::Shutdown::default()),
+            authenticator: auth::from_config(&config).into(),
+            tenant_resolver: tenancy::from_config(&config).into(),
+            channel_policies: Arc::new(channel_policy::from_config(&config)),
+            presence_store: config
+                .presence_store
+                .as_deref()
+                .map(|path| Arc::new(presence_store::PresenceStore::new(path))),
+            dictionaries: Arc::new(compression::load(&config)),
+        }
+    }
+
+    // Current values of the runtime-adjustable settings
+    fn live(&self) -> LiveConfig {
+        *self.live_config.read().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+// Handle to a live connection, for server-wide operations
+struct ClientHandle {
+    outgoing: Outgoing,
+    // Ask the connection to close itself
+    disconnect: mpsc::UnboundedSender<CloseReason>,
+    // Recent round-trip times to the client
+    rtt: Arc<rtt::RttWindow>,
+    // Close waiting for the client's ready_to_migrate
+    migration: Arc<migration::PendingMigration>,
+    // Priority under load shedding; lower tiers are evicted first
+    tier: u8,
 }
 
 // Client connection info for presence tracking
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 struct ClientInfo {
     id: String,
-    role: String, // "teacher" or "student"
+    role: String, // a role from the configured hierarchy, e.g. "teacher" or "student"
     joined_at: i64,
+    status: String, // "online", or "away" while within the reconnection grace window
+    pinned: bool,   // sorted first and kept longer after disconnect (e.g. teachers)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    group: Option<String>, // breakout group within the channel
+    #[serde(skip_serializing_if = "Option::is_none")]
+    identity: Option<String>, // stable across reconnects, unlike the per-connection id
+    #[serde(default)]
+    last_activity: i64, // unix ms of the join or the connection's latest heartbeat
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<serde_json::Map<String, serde_json::Value>>, // e.g. display_name, checked against the metadata schema
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    metadata_expires_at: BTreeMap<String, i64>, // unix ms at which each metadata key given a TTL is cleared
 }
 
 // Incoming messages from WebSocket clients
 #[derive(Deserialize, Debug)]
 struct ClientMessage {
     action: String,
-    channel: String,
+    #[serde(default)]
+    channel: String, // empty for connection-level actions
     data: Option<serde_json::Value>,
-    role: Option<String>, // "teacher" or "student"
+    role: Option<String>, // e.g. "teacher" or "student"; defaults to the lowest role
+    target_client_id: Option<String>,
+    correlation_id: Option<String>, // trace id echoed on the resulting broadcast
+    request_id: Option<String>,     // echoed on the replies to this message, for RPC-style correlation
+    expires_in_ms: Option<u64>,     // drop the publish if not delivered within this window
+    priority: Option<Priority>,     // "high" jumps ahead of queued normal messages
+    incompressible: Option<bool>,   // payload is already compressed; deliver uncompressed even to dictionary connections
+    encrypted: Option<bool>,        // publish data is end-to-end encrypted; the server never looks inside it
+    group: Option<String>,          // breakout group to join on subscribe or set_group
+    set: Option<String>,            // named presence set for presence_set, e.g. "hand_raised"
+    member: Option<bool>,           // join (default) or leave the presence set
+    expected_acks: Option<usize>,   // acks a publish_quorum waits for
+    timeout_ms: Option<u64>,        // how long a publish_quorum collects acks
+    at_least_once: Option<bool>,    // on a publish: send to each subscriber again until it acks
+    message_id: Option<String>,     // the message an ack confirms
+    idempotency_key: Option<String>, // publishes repeating a recent key aren't broadcast again
+    since_seq: Option<u64>,         // resume a subscribe after this seq instead of replaying all history
+    since_epoch: Option<u64>,       // epoch since_seq was seen in; a stale one replays the current epoch from the start
+    fields: Option<Vec<String>>,    // data paths a subscriber wants, e.g. ["slide.index"]; the rest is trimmed
+    echo: Option<bool>,             // receive your own publishes: on a subscribe, defaulting to RABLY_PUBLISH_ECHO; on a publish, overriding that
+    presence_only: Option<bool>,    // subscribe for roster changes alone, e.g. for a roster display
+    metadata: Option<serde_json::Value>, // how the client appears on the roster, e.g. {"display_name": "..."}
+    metadata_ttl_secs: Option<HashMap<String, u64>>, // metadata keys cleared unless set again within this many seconds
+    base_version: Option<u64>,      // slide version a slide_diff was computed against
+    allow_backward: Option<bool>,   // let a slide_change go to a lower slide_index on a monotonic channel
+    ordering: Option<ordering::OrderingMode>, // mode a moderator picks for the channel on subscribe
+    deliver_at: Option<i64>,        // unix ms at which a scheduled message is broadcast
+    schedule_id: Option<String>,    // the scheduled message cancel_schedule withdraws
+    poll_id: Option<String>,        // the poll a poll_vote or close_poll is for
+    option: Option<usize>,          // index of the option a poll_vote picks
+    min_role: Option<String>,       // lowest role that receives a publish, e.g. "teacher" for staff-only notes
+    headers: Option<headers::Headers>, // metadata delivered alongside a publish's data, e.g. {"content-type": "..."}
+    transfer_id: Option<String>,    // the chunked transfer a chunk_start, chunk or chunk_end belongs to
+    total_size: Option<usize>,      // bytes of data text a chunk_start announces
 }
 
 // Outgoing messages to WebSocket clients
 #[derive(Serialize, Debug)]
 struct ServerMessage {
+    // Envelope version, so clients can branch on its structure as the schema evolves
+    v: u32,
+    // Globally unique id, stable across replays, for client-side dedupe
+    message_id: String,
     r#type: String,
     channel: String,
     data: serde_json::Value,
     timestamp: i64,
+    // Generation of the channel's numbering; (epoch, seq) orders broadcasts across resets
+    #[serde(skip_serializing_if = "Option::is_none")]
+    epoch: Option<u64>,
+    // Per-channel sequence number, set on channel broadcasts only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seq: Option<u64>,
+    // Trace id propagated from the publisher, or generated by the server
+    #[serde(skip_serializing_if = "Option::is_none")]
+    correlation_id: Option<String>,
+    // Echo of the client's request_id on replies sent only to that client
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+    // Unix time in milliseconds after which the message is no longer delivered
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<i64>,
+    // Delivery tier on the way to each subscriber; not part of the envelope
+    #[serde(skip)]
+    priority: Priority,
+    // Publisher marked the payload as not worth compressing; not part of the envelope
+    #[serde(skip)]
+    incompressible: bool,
+    // Whether the publisher gets this back on its own subscription, if it said; not part of the envelope
+    #[serde(skip)]
+    echo: Option<bool>,
+    // Data is ciphertext the server passes through without reading
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    encrypted: bool,
+    // When the event happened according to the publisher, in unix ms, after validation
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_timestamp: Option<i64>,
+    // Set on quorum and at-least-once publishes: subscribers should reply with an ack
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    ack_requested: bool,
+    // Sent to each subscriber again until it acks
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    at_least_once: bool,
+    // Slide version after applying this slide_change or slide_diff
+    #[serde(skip_serializing_if = "Option::is_none")]
+    slide_version: Option<u64>,
+    // Lowest role the message is delivered to; subscribers below it never see it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    min_role: Option<String>,
+    // Publisher's headers, passed through unchanged
+    #[serde(skip_serializing_if = "Option::is_none")]
+    headers: Option<headers::Headers>,
+    // Client and role an admin-injected message appears to come from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sender: Option<injection::Sender>,
+    // Published through the admin API rather than by a client
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    injected: bool,
+    // Node that produced the message, if RABLY_INSTANCE_ID_IN_MESSAGES is on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    instance: Option<&'static str>,
+}
+
+impl ServerMessage {
+    fn new(event_type: &str, channel: &str, data: serde_json::Value) -> Self {
+        ServerMessage {
+            v: PROTOCOL_VERSION,
+            message_id: Uuid::new_v4().to_string(),
+            r#type: event_type.to_string(),
+            channel: channel.to_string(),
+            data,
+            timestamp: clock::now().timestamp(),
+            epoch: None,
+            seq: None,
+            correlation_id: None,
+            request_id: None,
+            expires_at: None,
+            priority: Priority::for_event_type(event_type),
+            incompressible: false,
+            echo: None,
+            encrypted: false,
+            client_timestamp: None,
+            ack_requested: false,
+            at_least_once: false,
+            slide_version: None,
+            min_role: None,
+            headers: None,
+            sender: None,
+            injected: false,
+            instance: INSTANCE_TAG.get().map(String::as_str),
+        }
+    }
+
+    // Serialize for the wire, logging and counting failures instead of dropping silently
+    fn to_json(&self) -> Option<String> {
+        serialize(self, &self.r#type, &self.channel).map(signing::sign)
+    }
+
+    fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| clock::now().timestamp_millis() >= expires_at)
+    }
+}
+
+// Serialize a message, or log and count why it couldn't be
+fn serialize<T: Serialize>(value: &T, event_type: &str, channel: &str) -> Option<String> {
+    match serde_json::to_string(value) {
+        Ok(json) => Some(json),
+        Err(e) => {
+            Metrics::inc(&metrics::SERIALIZATION_FAILURES);
+            eprintln!("❌ Failed to serialize {} message on channel {}: {}", event_type, channel, e);
+            None
+        }
+    }
+}
+
+// A channel broadcast, serialized once and shared by every subscriber
+struct ChannelEvent {
+    msg: ServerMessage,
+    // Client id of the publisher, or "server"
+    origin: String,
+    json: String,
+    frames: encoding::FrameCache,
 }
 
 #[tokio::main]
@@ -55,20 +619,35 @@ async fn main() {
 
     println!("🔧 Initializing Rably WebSocket server...");
 
-    let state = AppState {
-        channels: Arc::new(DashMap::new()),
-        channel_presence: Arc::new(DashMap::new()),
-    };
+    let config = Arc::new(Config::from_env());
+    println!("🏷️ Instance id {}", config.instance_id);
+    if config.instance_id_in_messages {
+        let _ = INSTANCE_TAG.set(config.instance_id.clone());
+    }
+    signing::init(&config);
+    if config.require_secure_upgrades && config.trusted_proxies.is_empty() {
+        eprintln!("⚠️ RABLY_REQUIRE_SECURE_UPGRADES without RABLY_TRUSTED_PROXIES refuses every WebSocket upgrade");
+    }
+    let state = AppState::new(config.clone());
+
+    presence_store::restore(&state);
+    scheduled::restore(&state);
+
+    lifecycle::spawn_idle_reaper(state.clone());
+    lifecycle::spawn_compactor(state.clone());
+    lifecycle::spawn_expirer(state.clone());
+    load::spawn_sampler(state.clone());
+    load::spawn_warmup_notice(state.clone());
+    memory::spawn_sampler(state.clone());
+    presence::spawn_diff_flusher(state.clone());
+    presence::spawn_metadata_sweeper(state.clone());
+    presence::spawn_stale_sweeper(state.clone());
+    presence_store::spawn_flusher(state.clone());
+    scheduled::spawn_flusher(state.clone());
 
     println!("🔧 Building router...");
 
-    // Build the router with CORS support
-    let app = Router::new()
-        .route("/ws", get(ws_handler))
-        .route("/health", get(health_check))
-        .route("/channels/{channel_id}/presence", get(get_channel_presence))
-        .layer(CorsLayer::permissive())
-        .with_state(state);
+    let (app, internal_app) = routers(&state);
 
     let port = std::env::var("PORT").unwrap_or_else(|_| "8080".to_string());
     let addr = SocketAddr::from(([0, 0, 0, 0], port.parse().unwrap()));
@@ -89,8 +668,31 @@ async fn main() {
         }
     };
 
+    // Not drained on shutdown, so probes and metrics keep answering until the process exits
+    if let Some(internal_addr) = state.config.internal_addr {
+        let internal_listener = match tokio::net::TcpListener::bind(internal_addr).await {
+            Ok(listener) => {
+                println!("🔒 Internal listener for admin and monitoring bound to {}", internal_addr);
+                listener
+            }
+            Err(e) => {
+                eprintln!("❌ Failed to bind internal listener to {}: {}", internal_addr, e);
+                std::process::exit(1);
+            }
+        };
+        tokio::spawn(async move {
+            let service = internal_app.into_make_service_with_connect_info::<SocketAddr>();
+            if let Err(e) = axum::serve(internal_listener, service).await {
+                eprintln!("❌ Internal listener error: {}", e);
+            }
+        });
+    }
+
     println!("🔧 Starting axum server...");
-    match axum::serve(listener, app).await {
+    match axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(shutdown::drain(state))
+        .await
+    {
         Ok(_) => {
             println!("✅ Server shut down gracefully");
         }
@@ -101,164 +703,2765 @@ async fn main() {
     }
 }
 
+// The public router, and the internal one carrying whatever admin and monitoring routes are
+// served on their own listener
+fn routers(state: &AppState) -> (Router, Router) {
+    // Admin API, served on the internal listener when there is one
+    let admin_routes = Router::new()
+        .route("/admin/config", get(admin::get_config).patch(admin::update_config))
+        .route("/admin/maintenance", get(admin::get_maintenance).put(admin::set_maintenance))
+        .route("/admin/broadcast", post(admin::broadcast_announcement))
+        .route("/admin/channels", get(admin::list_declared_channels))
+        .route(
+            "/admin/channels/{channel_id}",
+            put(admin::declare_channel).delete(admin::undeclare_channel),
+        )
+        .route(
+            "/admin/channels/{channel_id}/config",
+            patch(admin::update_channel_config).delete(admin::clear_channel_config),
+        )
+        .route(
+            "/admin/channels/{channel_id}/archive",
+            put(admin::archive_channel).delete(admin::revive_channel),
+        )
+        .route("/admin/channels/{channel_id}/reset-seq", post(admin::reset_channel_epoch))
+        .route("/admin/channels/{channel_id}/presence/probe", post(admin::probe_presence))
+        .route("/admin/channels/{channel_id}/disconnect-all", post(admin::disconnect_channel))
+        .route("/admin/channels/{channel_id}/seed", post(seed::seed_channel))
+        .route("/admin/channels/{channel_id}/publish", post(injection::inject_message))
+        .route("/admin/dump", get(dump::dump))
+        .route("/admin/audit", get(audit::list))
+        .route("/admin/clients/{client_id}", get(admin::get_client).delete(admin::kick_client))
+        .route("/admin/aliases", get(admin::list_aliases))
+        .route("/admin/aliases/{alias}", put(admin::set_alias).delete(admin::remove_alias))
+        .route("/channels/{channel_id}/export", get(admin::export_channel))
+        .route("/channels/{channel_id}/attendance", get(attendance::get_attendance))
+        .route("/channels/{channel_id}/stream", get(stream::stream_channel));
+
+    let routers = monitoring::Routers::new()
+        .monitoring(state, "/health", state.config.health_access, get(health_check))
+        .monitoring(state, "/ready", state.config.health_access, get(readiness_check))
+        .monitoring(state, "/metrics", state.config.metrics_access, get(get_metrics))
+        .monitoring(state, "/stats", state.config.stats_access, get(get_stats))
+        .admin(state, admin_routes);
+
+    // Build the router with CORS support
+    let app = routers
+        .public
+        .route("/ws", get(ws_handler))
+        .route("/capabilities", get(get_capabilities))
+        .route("/dictionaries/{dictionary_id}", get(get_dictionary))
+        .route("/channels", get(get_channels))
+        .route("/channels/{channel_id}", get(get_channel))
+        .route("/channels/{channel_id}/presence", get(get_channel_presence))
+        .route("/channels/{channel_id}/presence/{set}", get(get_presence_set))
+        .route("/channels/{channel_id}/roles", get(get_channel_roles))
+        .route("/channels/{channel_id}/history", get(get_channel_history))
+        .route("/channels/{channel_id}/config", get(get_channel_config))
+        .layer(middleware::from_fn_with_state(state.clone(), access::enforce))
+        .layer(CorsLayer::permissive())
+        .with_state(state.clone());
+    let internal_app = routers
+        .internal
+        .layer(middleware::from_fn_with_state(state.clone(), access::enforce))
+        .with_state(state.clone());
+    (app, internal_app)
+}
+
 // Health check endpoint
-async fn health_check() -> impl IntoResponse {
+async fn health_check(State(state): State<AppState>) -> impl IntoResponse {
     serde_json::json!({
-        "status": "healthy",
+        "status": if state.degradations.any() { "degraded" } else { "healthy" },
         "service": "rably",
-        "timestamp": chrono::Utc::now().timestamp()
+        "instance": state.config.instance_id,
+        "timestamp": clock::now().timestamp(),
+        "degraded": state.degradations.report(),
     }).to_string()
 }
 
-// Get presence info for a channel
-async fn get_channel_presence(
+// Whether this instance should receive new traffic
+async fn readiness_check(State(state): State<AppState>) -> impl IntoResponse {
+    if state.shutdown.draining() {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            serde_json::json!({ "status": "shutting_down" }).to_string(),
+        );
+    }
+    if state.memory.over_limit() {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            serde_json::json!({ "status": "degraded", "reasons": ["memory"] }).to_string(),
+        );
+    }
+
+    // Failing optional subsystems are reported, but core delivery still works
+    (
+        StatusCode::OK,
+        serde_json::json!({ "status": "ready", "degraded": state.degradations.report() }).to_string(),
+    )
+}
+
+// Prometheus metrics
+async fn get_metrics(State(state): State<AppState>) -> impl IntoResponse {
+    metrics::render(&state)
+}
+
+// Aggregate server overview for status pages
+async fn get_stats(State(state): State<AppState>) -> impl IntoResponse {
+    stats::render(&state)
+}
+
+// Describe what this server supports so SDKs can adapt without probing
+async fn get_capabilities(State(state): State<AppState>) -> impl IntoResponse {
+    let history_size = state.live().history_size;
+    serde_json::json!({
+        "service": "rably",
+        "protocol_versions": [PROTOCOL_VERSION],
+        "actions": SUPPORTED_ACTIONS,
+        "max_message_size": state.config.max_message_size,
+        "max_transfer_size": state.config.max_transfer_size,
+        "features": {
+            "presence": true,
+            "history": history_size > 0,
+            "history_size": history_size,
+            "compression": !state.dictionaries.is_empty(),
+            "auth": state.config.auth_provider,
+            "ordered_channels": true,
+            "wire_formats": ["json", cbor::SUBPROTOCOL],
+            "signed_messages": signing::enabled(),
+            "dead_letter": state.config.dead_letter_sink.is_some()
+        }
+    }).to_string()
+}
+
+// Active channels; archived ones are left out but stay queryable by name
+async fn get_channels(State(state): State<AppState>) -> impl IntoResponse {
+    let mut channels: Vec<(String, usize)> = state
+        .channels
+        .iter()
+        .filter(|entry| !state.archived_channels.contains_key(entry.key()))
+        .map(|entry| (entry.key().clone(), entry.value().receiver_count()))
+        .collect();
+    channels.sort();
+
+    let channels = channels
+        .into_iter()
+        .map(|(channel, subscribers)| serde_json::json!({ "channel": channel, "subscribers": subscribers }))
+        .collect::<Vec<_>>();
+
+    serde_json::json!({ "channels": channels }).to_string()
+}
+
+// Raw bytes of a compression dictionary, by the id advertised on connect
+async fn get_dictionary(
+    axum::extract::Path(dictionary_id): axum::extract::Path<String>,
+    State(state): State<AppState>,
+) -> Response {
+    match state.dictionaries.iter().find(|dictionary| dictionary.id == dictionary_id) {
+        Some(dictionary) => (
+            [
+                (header::CONTENT_TYPE, "application/octet-stream"),
+                // The id is a checksum of the contents, so they never change under it
+                (header::CACHE_CONTROL, "public, max-age=31536000, immutable"),
+            ],
+            dictionary.bytes.clone(),
+        )
+            .into_response(),
+        None => (StatusCode::NOT_FOUND, serde_json::json!({ "error": "dictionary not found" }).to_string()).into_response(),
+    }
+}
+
+// Overview of one channel, including archived channels
+async fn get_channel(
+    axum::extract::Path(channel_id): axum::extract::Path<String>,
+    State(state): State<AppState>,
+) -> Result<String, (StatusCode, String)> {
+    let channel_id = admin::resolve_channel(&state, &channel_id);
+    let subscribers = state.channels.get(&channel_id).map(|tx| tx.receiver_count());
+    let history_messages = state.channel_history.get(&channel_id).map(|buffer| buffer.len());
+    let archived = state.archived_channels.contains_key(&channel_id);
+
+    if subscribers.is_none() && history_messages.is_none() && !archived {
+        return Err(admin::admin_error(StatusCode::NOT_FOUND, "channel not found"));
+    }
+
+    Ok(serde_json::json!({
+        "channel": channel_id,
+        "subscribers": subscribers.unwrap_or(0),
+        "archived": archived,
+        "epoch": epoch::current(&state, &channel_id),
+        "seq": state.channel_seq.get(&channel_id).map(|seq| *seq).unwrap_or(0),
+        "history": {
+            "messages": history_messages.unwrap_or(0),
+            "bytes": history::bytes(&state, &channel_id),
+        },
+        "slide": slides::current(&state, &channel_id),
+        "presenter": presenter::holder(&state, &channel_id),
+        "ordering": ordering::mode(&state, &channel_id),
+        "created_at": state.channel_created.get(&channel_id).map(|created| *created),
+        "expires_at": lifecycle::expires_at(&state, &channel_id),
+        "messages_per_sec": throughput::per_sec(&state, &channel_id),
+        "polls": polls::list(&state, &channel_id),
+    }).to_string())
+}
+
+// A channel's overrides and the settings it runs with after applying them
+async fn get_channel_config(
     axum::extract::Path(channel_id): axum::extract::Path<String>,
     State(state): State<AppState>,
 ) -> impl IntoResponse {
-    let presence = state
-        .channel_presence
-        .get(&channel_id)
-        .map(|channel_map| {
-            channel_map.iter().map(|entry| entry.value().clone()).collect::<Vec<_>>()
-        })
-        .unwrap_or_default();
+    let channel_id = admin::resolve_channel(&state, &channel_id);
+    serde_json::json!({
+        "channel": channel_id,
+        "overrides": channel_config::overrides(&state, &channel_id),
+        "effective": channel_config::effective(&state, &channel_id),
+    })
+    .to_string()
+}
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    token: Option<String>,
+}
+
+// Recent history for a channel, including archived channels. The caller is authenticated
+// like a connection and kept to its tenant's channels; staff-only messages are left out,
+// as a request holds no role on the channel.
+async fn get_channel_history(
+    axum::extract::Path(channel_id): axum::extract::Path<String>,
+    axum::extract::Query(query): axum::extract::Query<HistoryQuery>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<String, (StatusCode, String)> {
+    let ctx = auth::AuthContext { headers, token: query.token, identity: None };
+    let identity = state
+        .authenticator
+        .authenticate(&ctx)
+        .await
+        .map_err(|e| admin::admin_error(StatusCode::UNAUTHORIZED, &e.to_string()))?;
+    let channel_id = admin::resolve_channel(&state, &channel_id);
+    state
+        .tenant_resolver
+        .resolve(&channel_id, &identity)
+        .await
+        .map_err(|e| admin::admin_error(StatusCode::FORBIDDEN, &e.to_string()))?;
+
+    let messages = history::recent(&state, &channel_id)
+        .iter()
+        .filter(|event| event.msg.min_role.is_none())
+        .filter_map(|event| serde_json::to_value(&event.msg).ok())
+        .collect::<Vec<_>>();
+
+    Ok(serde_json::json!({
+        "channel": channel_id,
+        "archived": state.archived_channels.contains_key(&channel_id),
+        "messages": messages
+    }).to_string())
+}
+
+// Get presence info for a channel. Rosters over RABLY_PRESENCE_RESPONSE_MAX come back
+// truncated; `limit` and `after` page through them, and `format=ndjson` streams them whole.
+#[derive(Deserialize)]
+struct PresenceQuery {
+    group_by: Option<String>,
+    limit: Option<usize>,
+    after: Option<String>, // the `next` cursor from the previous page
+    format: Option<String>,
+}
+
+async fn get_channel_presence(
+    axum::extract::Path(channel_id): axum::extract::Path<String>,
+    axum::extract::Query(query): axum::extract::Query<PresenceQuery>,
+    State(state): State<AppState>,
+) -> Result<Response, (StatusCode, String)> {
+    let channel_id = admin::resolve_channel(&state, &channel_id);
+    let max = state.config.presence_response_max;
+    let paged = query.limit.is_some() || query.after.is_some();
+
+    match query.format.as_deref() {
+        None | Some("json") => {}
+        Some("ndjson") if query.group_by.is_none() && !paged => {
+            return Ok((
+                [(header::CONTENT_TYPE, "application/x-ndjson")],
+                axum::body::Body::from_stream(presence::stream(state.clone(), channel_id)),
+            )
+                .into_response());
+        }
+        Some("ndjson") => {
+            return Err(admin::admin_error(StatusCode::BAD_REQUEST, "format=ndjson can't be combined with paging or group_by"));
+        }
+        Some(_) => return Err(admin::admin_error(StatusCode::BAD_REQUEST, "format must be \"json\" or \"ndjson\"")),
+    }
+
+    let after = match query.after.as_deref() {
+        Some(cursor) => Some(
+            presence::RosterKey::from_cursor(cursor)
+                .ok_or_else(|| admin::admin_error(StatusCode::BAD_REQUEST, "after must be a cursor from a previous page"))?,
+        ),
+        None => None,
+    };
+    let page = presence::page(&state, &channel_id, after.as_ref(), query.limit.unwrap_or(max).clamp(1, max));
+
+    if paged {
+        if query.group_by.is_some() {
+            return Err(admin::admin_error(StatusCode::BAD_REQUEST, "group_by can't be combined with paging"));
+        }
+        return Ok(serde_json::json!({
+            "channel": channel_id,
+            "participants": page.participants,
+            "total": page.total,
+            "next": page.next
+        }).to_string().into_response());
+    }
+
+    // Unpaged: as much of the roster as fits, pointing at the next page if it didn't all fit
+    let truncated = page.next.is_some();
+    let mut response = match query.group_by.as_deref() {
+        None => serde_json::json!({
+            "channel": channel_id,
+            "participants": page.participants
+        }),
+        Some("group") => {
+            let (groups, ungrouped) = presence::by_group(page.participants);
+            serde_json::json!({
+                "channel": channel_id,
+                "groups": groups,
+                "ungrouped": ungrouped
+            })
+        }
+        Some(_) => return Err(admin::admin_error(StatusCode::BAD_REQUEST, "group_by must be \"group\"")),
+    };
+    if truncated {
+        response["truncated"] = serde_json::json!(true);
+        response["total"] = serde_json::json!(page.total);
+        response["next"] = serde_json::json!(page.next);
+    }
+    Ok(response.to_string().into_response())
+}
+
+// Current members of a named presence set, in the order they joined it
+async fn get_presence_set(
+    axum::extract::Path((channel_id, set)): axum::extract::Path<(String, String)>,
+    State(state): State<AppState>,
+) -> String {
+    let channel_id = admin::resolve_channel(&state, &channel_id);
+    serde_json::json!({
+        "channel": channel_id,
+        "set": set,
+        "members": presence::set_snapshot(&state, &channel_id, &set)
+    }).to_string()
+}
 
+// How many participants hold each role, without the roster itself
+async fn get_channel_roles(
+    axum::extract::Path(channel_id): axum::extract::Path<String>,
+    State(state): State<AppState>,
+) -> String {
+    let channel_id = admin::resolve_channel(&state, &channel_id);
+    let (roles, online) = presence::role_counts(&state, &channel_id);
     serde_json::json!({
         "channel": channel_id,
-        "participants": presence
+        "total": roles.values().sum::<usize>(),
+        "roles": roles,
+        "online": online
     }).to_string()
 }
 
+// Broadcast a server-generated event to everyone subscribed to a channel
+fn broadcast_event(state: &AppState, channel: &str, event_type: &str, data: serde_json::Value) {
+    send_to_channel(state, ServerMessage::new(event_type, channel, data), "server");
+}
+
+// Broadcast a server-generated event on every channel
+fn broadcast_all(state: &AppState, event_type: &str, data: serde_json::Value) -> usize {
+    let channels: Vec<String> = state.channels.iter().map(|entry| entry.key().clone()).collect();
+    for channel in &channels {
+        broadcast_event(state, channel, event_type, data.clone());
+    }
+    channels.len()
+}
+
+// Broadcast a message on its channel, through the single writer if the channel is ordered.
+// Returns false if the channel has no subscribers to receive it.
+fn send_to_channel(state: &AppState, msg: ServerMessage, origin: &str) -> bool {
+    let has_subscribers = state
+        .channels
+        .get(&msg.channel)
+        .is_some_and(|tx| tx.receiver_count() > 0);
+
+    if has_subscribers && ordering::is_ordered(state, &msg.channel) {
+        ordering::enqueue(state, msg, origin);
+        true
+    } else {
+        deliver(state, msg, origin)
+    }
+}
+
+// The channel's current epoch and next sequence number in it
+fn next_seq(state: &AppState, channel: &str) -> (u64, u64) {
+    let mut last_seq = state.channel_seq.entry(channel.to_string()).or_insert(0);
+    *last_seq += 1;
+    (epoch::current(state, channel), *last_seq)
+}
+
+// Assign the channel's next sequence number, record it in history and hand it to subscribers
+fn deliver(state: &AppState, mut msg: ServerMessage, origin: &str) -> bool {
+    let tx = state
+        .channels
+        .get(&msg.channel)
+        .map(|tx| tx.clone())
+        .filter(|tx| tx.receiver_count() > 0);
+
+    if tx.is_none() && !lifecycle::keeps_unreceived(state, &msg.channel) {
+        if let Some(msg_str) = msg.to_json() {
+            webhook::notify(state, origin, &msg.channel, &msg.r#type, &msg_str);
+            state.dead_letters.record(DeadLetterReason::NoSubscribers, Some(&msg.channel), origin, &msg_str);
+        }
+        return false;
+    }
+
+    let (epoch, seq) = next_seq(state, &msg.channel);
+    msg.epoch = Some(epoch);
+    msg.seq = Some(seq);
+
+    let Some(json) = msg.to_json() else {
+        return false;
+    };
+    let event = Arc::new(ChannelEvent {
+        msg,
+        origin: origin.to_string(),
+        json,
+        frames: encoding::FrameCache::default(),
+    });
+
+    lifecycle::touch(state, &event.msg.channel);
+    throughput::record(state, &event.msg.channel);
+
+    let stored = history::record(state, &event);
+    retention::record(state, &event);
+    webhook::notify(state, origin, &event.msg.channel, &event.msg.r#type, &event.json);
+
+    match tx.map(|tx| tx.send(event.clone())) {
+        Some(Ok(_)) => {
+            state.stats.record_message();
+            true
+        }
+        _ => {
+            // Kept in history, it reaches whoever subscribes next, so it isn't lost
+            if !stored {
+                state.dead_letters.record(DeadLetterReason::NoSubscribers, Some(&event.msg.channel), origin, &event.json);
+            }
+            false
+        }
+    }
+}
+
+// Broadcast a teacher's slide change to the channel
+fn broadcast_slide_change(
+    state: &AppState,
+    outgoing_tx: &Outgoing,
+    request_id: Option<&str>,
+    client_id: &str,
+    slide_msg: ServerMessage,
+    allow_backward: bool,
+) {
+    let channel = slide_msg.channel.clone();
+    let correlation_id = slide_msg.correlation_id.clone().unwrap_or_default();
+
+    match slides::change(state, slide_msg, client_id, allow_backward) {
+        Ok(true) => println!(
+            "🎯 Slide change broadcast to channel {} by client {} (correlation_id {})",
+            channel, client_id, correlation_id
+        ),
+        Ok(false) => notify_no_subscribers(state, outgoing_tx, request_id, &channel),
+        Err(slides::ChangeError::Backward { current_index }) => send_direct(
+            outgoing_tx,
+            request_id,
+            &channel,
+            "error",
+            serde_json::json!({
+                "code": "out_of_order_slide",
+                "message": "Slides on this channel only move forward; set allow_backward to go back",
+                "current_index": current_index,
+            }),
+        ),
+    }
+}
+
+// Let a publisher know nobody received its message, and whether it was kept for replay
+fn notify_no_subscribers(state: &AppState, outgoing_tx: &Outgoing, request_id: Option<&str>, channel: &str) {
+    let stored = lifecycle::keeps_unreceived(state, channel) && channel_config::history_limits(state, channel).0 > 0;
+    send_direct(
+        outgoing_tx,
+        request_id,
+        channel,
+        "info",
+        serde_json::json!({ "code": "no_subscribers", "stored": stored }),
+    );
+}
+
+// Send a server-generated message to a single connection, echoing the request it answers
+fn send_direct(outgoing_tx: &Outgoing, request_id: Option<&str>, channel: &str, event_type: &str, data: serde_json::Value) {
+    if let Some(frame) = direct_frame(request_id, channel, event_type, data) {
+        let _ = outgoing_tx.try_send(frame);
+    }
+}
+
+fn direct_frame(request_id: Option<&str>, channel: &str, event_type: &str, data: serde_json::Value) -> Option<Message> {
+    let msg = ServerMessage {
+        request_id: request_id.map(str::to_string),
+        ..ServerMessage::new(event_type, channel, data)
+    };
+    msg.to_json().map(|msg_str| Message::Text(msg_str.into()))
+}
+
+// The marker that follows a dropped backlog: how much was dropped and where each subscribed
+// channel's numbering stood, so the client knows what it skipped. Older broadcasts not yet
+// forwarded are skipped from here on as well.
+fn backlog_skipped(
+    state: &AppState,
+    client_id: &str,
+    channels: &[String],
+    skipped_through: &DashMap<String, (u64, u64)>,
+    request_id: Option<String>,
+    dropped: usize,
+) -> Option<Message> {
+    println!("⏩ Client {} skipped its backlog of {} queued messages", client_id, dropped);
+    let positions: serde_json::Map<String, serde_json::Value> = channels
+        .iter()
+        .map(|channel| {
+            let epoch = epoch::current(state, channel);
+            let seq = state.channel_seq.get(channel).map(|seq| *seq).unwrap_or(0);
+            skipped_through.insert(channel.clone(), (epoch, seq));
+            (channel.clone(), serde_json::json!({ "epoch": epoch, "seq": seq }))
+        })
+        .collect();
+
+    let msg = ServerMessage {
+        request_id,
+        ..ServerMessage::new("backlog_skipped", "", serde_json::json!({ "dropped": dropped, "channels": positions }))
+    };
+    msg.to_json().map(|json| Message::Text(json.into()))
+}
+
+// Tell a single connection its request was rejected
+fn send_error(outgoing_tx: &Outgoing, request_id: Option<&str>, channel: &str, code: &str, message: &str) {
+    send_direct(outgoing_tx, request_id, channel, "error", serde_json::json!({ "code": code, "message": message }));
+}
+
 // WebSocket upgrade handler
-async fn ws_handler(ws: WebSocketUpgrade, State(state): State<AppState>) -> impl IntoResponse {
-    ws.on_upgrade(move |socket| handle_socket(socket, state))
+#[derive(Deserialize)]
+struct ConnectQuery {
+    // Stable client identity, trusted only when authentication is disabled
+    identity: Option<String>,
+    // Credentials for clients that can't send an Authorization header
+    token: Option<String>,
+    // "dictionary" to receive slide broadcasts compressed with the channel's dictionary
+    compress: Option<String>,
+    // Channel the connection is for, so an origin it may not use is refused at the upgrade,
+    // and with RABLY_SUBSCRIBE_ON_CONNECT the channel it's subscribed to straight away
+    channel: Option<String>,
+    // Role for that subscribe
+    role: Option<String>,
+    // Resume token of an ended connection, whose subscriptions this one takes over
+    resume: Option<String>,
+}
+
+async fn ws_handler(
+    ws: WebSocketUpgrade,
+    axum::extract::Query(query): axum::extract::Query<ConnectQuery>,
+    axum::extract::ConnectInfo(peer): axum::extract::ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> Response {
+    if !access::is_secure_upgrade(&state, peer.ip(), &headers) {
+        println!("🚫 Rejected plaintext upgrade from {}", peer);
+        return (StatusCode::BAD_REQUEST, serde_json::json!({ "error": "secure transport required" }).to_string()).into_response();
+    }
+
+    let connect_subscribe = match connect_subscribe(&state, &query) {
+        Ok(subscribe) => subscribe,
+        Err(reason) => {
+            return (StatusCode::BAD_REQUEST, serde_json::json!({ "error": reason }).to_string()).into_response();
+        }
+    };
+
+    if query.resume.is_some() && !state.config.resume_tokens {
+        return (StatusCode::BAD_REQUEST, serde_json::json!({ "error": "resume needs RABLY_RESUME_TOKENS" }).to_string()).into_response();
+    }
+
+    let origin = headers.get(header::ORIGIN).and_then(|origin| origin.to_str().ok()).map(str::to_string);
+    if let Some(channel) = query.channel.as_deref().filter(|channel| !origins::permits(&state, channel, origin.as_deref())) {
+        println!("🚫 Rejected upgrade for channel {} from origin {}", channel, origin.as_deref().unwrap_or("(none)"));
+        return (StatusCode::FORBIDDEN, serde_json::json!({ "error": "origin not allowed" }).to_string()).into_response();
+    }
+
+    let ctx = auth::AuthContext {
+        headers,
+        token: query.token,
+        identity: query.identity.filter(|identity| !identity.is_empty()),
+    };
+    let identity = match state.authenticator.authenticate(&ctx).await {
+        Ok(identity) => identity,
+        Err(e) => {
+            println!("🚫 Rejected connection: {}", e);
+            return (StatusCode::UNAUTHORIZED, serde_json::json!({ "error": e.to_string() }).to_string()).into_response();
+        }
+    };
+
+    let slot = match connection_limits::claim(&state, &identity) {
+        Ok(slot) => slot,
+        Err(limit) => {
+            println!("🚫 Rejected connection: {} already has {} open", identity.id.as_deref().unwrap_or_default(), limit);
+            return (
+                StatusCode::TOO_MANY_REQUESTS,
+                serde_json::json!({ "error": "too many connections for this identity", "limit": limit }).to_string(),
+            )
+                .into_response();
+        }
+    };
+
+    if state.shutdown.draining() {
+        return (StatusCode::SERVICE_UNAVAILABLE, serde_json::json!({ "error": "shutting_down" }).to_string()).into_response();
+    }
+
+    // Turn away some new connections while overloaded so existing ones stay healthy
+    if tiers::should_shed(&state, tiers::of(&state, &identity)) {
+        Metrics::inc(&state.metrics.connections_shed);
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(header::RETRY_AFTER, state.live().shed_retry_after_secs.to_string())],
+            serde_json::json!({ "error": "overloaded" }).to_string(),
+        )
+            .into_response();
+    }
+
+    // Spread out the reconnect storm that follows a restart
+    if let Some(delay) = load::warmup_delay(&state) {
+        tokio::time::sleep(delay).await;
+    }
+
+    let compress = query.compress.as_deref() == Some("dictionary");
+    let ws = ws.protocols([cbor::SUBPROTOCOL]);
+    let use_cbor = ws.selected_protocol().is_some();
+    ws.max_message_size(state.config.max_message_size)
+        .on_upgrade(move |socket| async move {
+            // Held for as long as the connection is open
+            let _slot = slot;
+            let on_connect = OnConnect { resume: query.resume, subscribe: connect_subscribe };
+            handle_socket(socket, state, identity, origin, compress, use_cbor, on_connect).await
+        })
+        .into_response()
+}
+
+// The subscribe asked for in the connect URL, if RABLY_SUBSCRIBE_ON_CONNECT is on. It's
+// handled like one the client sent, so only what can be told from the URL alone is checked here.
+fn connect_subscribe(state: &AppState, query: &ConnectQuery) -> Result<Option<ClientMessage>, &'static str> {
+    let channel = query.channel.as_deref().filter(|channel| !channel.is_empty());
+    if query.role.is_some() && !state.config.subscribe_on_connect {
+        return Err("role in the connect URL needs RABLY_SUBSCRIBE_ON_CONNECT");
+    }
+    if query.role.is_some() && channel.is_none() {
+        return Err("role in the connect URL needs a channel");
+    }
+    if query.role.as_ref().is_some_and(|role| !state.config.role_hierarchy.contains(role)) {
+        return Err("invalid role");
+    }
+    let Some(channel) = channel.filter(|_| state.config.subscribe_on_connect) else {
+        return Ok(None);
+    };
+
+    let msg = serde_json::json!({ "action": "subscribe", "channel": channel, "role": query.role });
+    Ok(serde_json::from_value(msg).ok())
+}
+
+// What the connect URL asks to be done before the client's first message is read
+struct OnConnect {
+    // Resume token of an ended connection, whose subscriptions to take over
+    resume: Option<String>,
+    // The subscribe named in the URL
+    subscribe: Option<ClientMessage>,
 }
 
 // Handle individual WebSocket connection
-async fn handle_socket(socket: WebSocket, state: AppState) {
+async fn handle_socket(
+    socket: WebSocket,
+    state: AppState,
+    identity: auth::Identity,
+    origin: Option<String>,
+    compress: bool,
+    use_cbor: bool,
+    on_connect: OnConnect,
+) {
     let client_id = Uuid::new_v4().to_string();
     let (sender, mut receiver) = socket.split();
 
-    println!("🔌 Client {} connected", client_id);
+    match (&identity.id, &identity.tenant) {
+        (Some(id), Some(tenant)) => println!("🔌 Client {} connected as {} (tenant {})", client_id, id, tenant),
+        (Some(id), None) => println!("🔌 Client {} connected as {}", client_id, id),
+        _ => println!("🔌 Client {} connected", client_id),
+    }
+
+    // Bounded queue for outgoing messages, plus an unbounded control queue that is
+    // always written first so disconnect notices can get past a full queue
+    let (outgoing_tx, mut outgoing_rx) = mpsc::channel::<Message>(state.config.outgoing_queue_size);
+    // A/B cohort for broadcast encoding, fixed for the life of the connection; CBOR
+    // connections stay on the current envelope
+    let format = if use_cbor {
+        println!("📦 Client {} negotiated CBOR", client_id);
+        encoding::WireFormat::Cbor
+    } else {
+        encoding::cohort_format(&state, &client_id)
+    };
+    if format == encoding::WireFormat::JsonNext {
+        println!("🧪 Client {} is in the {} protocol cohort", client_id, format.cohort());
+    }
+    let (control_tx, mut control_rx) = mpsc::unbounded_channel::<Message>();
+    // High-priority broadcasts, drained after control messages but before the normal queue
+    let (priority_tx, mut priority_rx) = mpsc::channel::<coalesce::Queued>(state.config.outgoing_queue_size);
+    // Requests to drop the normal queue, which only the writer can drain
+    let (skip_tx, mut skip_rx) = mpsc::unbounded_channel::<BacklogMarker>();
+    let skipped_through: Arc<DashMap<String, (u64, u64)>> = Arc::new(DashMap::new());
+
+    let (disconnect_tx, mut disconnect_rx) = mpsc::unbounded_channel::<CloseReason>();
+    let client_rtt = Arc::new(rtt::RttWindow::default());
+    let migration = Arc::new(migration::PendingMigration::default());
+
+    state.clients.insert(
+        client_id.clone(),
+        ClientHandle {
+            outgoing: outgoing_tx.clone(),
+            disconnect: disconnect_tx.clone(),
+            rtt: client_rtt.clone(),
+            migration: migration.clone(),
+            tier: tiers::of(&state, &identity),
+        },
+    );
+
+    let resume = resume::start(&state);
+    let cursors = resume.as_ref().map(|session| session.cursors.clone());
+
+    // On the control queue, so it's ahead of the broadcasts of a subscribe from the connect URL
+    let mut connected = ServerMessage::new(
+        "connected",
+        "",
+        serde_json::json!({
+            "client_id": client_id,
+            "instance": state.config.instance_id,
+            "protocol_version": PROTOCOL_VERSION,
+            "dictionaries": *state.dictionaries,
+        }),
+    );
+    if let Some(session) = &resume {
+        connected.data["resume_token"] = serde_json::json!(session.token);
+    }
+    if let Some(connected) = connected.to_json() {
+        let _ = control_tx.send(Message::Text(connected.into()));
+    }
 
-    // Create a channel for outgoing messages
-    let (outgoing_tx, mut outgoing_rx) = tokio::sync::mpsc::unbounded_channel::<String>();
+    let dequeued = Arc::new(AtomicU64::new(0));
+    let delivered = dedup::attach(&state, &identity);
 
     // Spawn task to handle outgoing messages
-    let sender_handle = {
+    let mut sender_handle = {
         let mut sender = sender;
+        let dequeued = dequeued.clone();
+        let delivered = delivered.clone();
+        let cursors = cursors.clone();
+        let dead_letters = state.dead_letters.clone();
+        let client_id = client_id.clone();
+        let send_timeout = Duration::from_millis(state.live().send_timeout_ms);
         tokio::spawn(async move {
-            while let Some(msg) = outgoing_rx.recv().await {
-                if sender.send(Message::Text(msg.into())).await.is_err() {
+            loop {
+                // Which queue the message came from: control, or the normal one
+                let (msg, is_control, is_queued) = tokio::select! {
+                    biased;
+                    control = control_rx.recv() => {
+                        let Some(msg) = control else {
+                            break;
+                        };
+                        (msg, true, false)
+                    }
+                    Some(marker) = skip_rx.recv() => {
+                        let mut dropped = 0;
+                        while outgoing_rx.try_recv().is_ok() {
+                            dropped += 1;
+                        }
+                        dequeued.fetch_add(dropped as u64, Ordering::Relaxed);
+                        let Some(msg) = marker(dropped) else {
+                            continue;
+                        };
+                        (msg, false, false)
+                    }
+                    Some(queued) = priority_rx.recv() => {
+                        // A slide slot emptied by an earlier turn has nothing left to send
+                        let Some(msg) = queued.take() else {
+                            continue;
+                        };
+                        (msg, false, false)
+                    }
+                    Some(msg) = outgoing_rx.recv() => {
+                        dequeued.fetch_add(1, Ordering::Relaxed);
+                        (msg, false, true)
+                    }
+                };
+                // Nothing may follow a close frame
+                let closing = matches!(msg, Message::Close(_));
+                let msg = if use_cbor { encoding::to_cbor(msg) } else { msg };
+
+                // A client that stops reading eventually fills the TCP window and stalls
+                // the send; past the timeout it's treated as gone
+                let sent = if send_timeout.is_zero() {
+                    Some(sender.send(msg.clone()).await)
+                } else {
+                    tokio::time::timeout(send_timeout, sender.send(msg.clone())).await.ok()
+                };
+
+                match sent {
+                    Some(Ok(())) => {
+                        if !is_control {
+                            dedup::record(delivered.as_ref(), &msg);
+                        }
+                        if is_queued {
+                            resume::record(cursors.as_ref(), &msg);
+                        }
+                    }
+                    Some(Err(_)) => {
+                        if !is_control {
+                            let payload = msg.to_text().unwrap_or("<binary>");
+                            dead_letters.record(DeadLetterReason::SendFailed, None, &client_id, payload);
+                        }
+                        break;
+                    }
+                    None => {
+                        println!("⏳ Client {} stopped reading; send timed out", client_id);
+                        if !is_control {
+                            let payload = msg.to_text().unwrap_or("<binary>");
+                            dead_letters.record(DeadLetterReason::SendFailed, None, &client_id, payload);
+                        }
+                        let _ = disconnect_tx.send(CloseReason::SendTimeout);
+                        break;
+                    }
+                }
+                if closing {
                     break;
                 }
             }
         })
     };
 
-    // Handle incoming messages
-    while let Some(Ok(msg)) = receiver.next().await {
-        if let Message::Text(text) = msg {
-            if let Ok(client_msg) = serde_json::from_str::<ClientMessage>(&text) {
-                match client_msg.action.as_str() {
-                    "subscribe" => {
-                        let channel = client_msg.channel.clone();
-
-                        // Get or create broadcast sender for this channel
-                        let tx = state.channels
-                            .entry(channel.clone())
-                            .or_insert_with(|| broadcast::channel(1000).0)
-                            .clone();
-
-                        // Subscribe to the channel and forward messages
-                        let mut rx = tx.subscribe();
-                        let outgoing_tx_clone = outgoing_tx.clone();
-
-                        tokio::spawn(async move {
-                            while let Ok(msg) = rx.recv().await {
-                                if outgoing_tx_clone.send(msg).is_err() {
-                                    break;
-                                }
-                            }
-                        });
+    let (lanes, scheduler_handle) = scheduler::spawn(outgoing_tx.clone());
 
-                        // Add to presence tracking
-                        let client_info = ClientInfo {
-                            id: client_id.clone(),
-                            role: client_msg.role.unwrap_or_else(|| "student".to_string()),
-                            joined_at: chrono::Utc::now().timestamp(),
-                        };
+    // Slow-consumer policy: disconnect if the outgoing queue stays full for too long
+    let slow_grace = Some(state.live().slow_consumer_grace_ms)
+        .filter(|ms| *ms > 0)
+        .map(Duration::from_millis);
+    let mut slow_check = tokio::time::interval(Duration::from_millis(SLOW_CONSUMER_CHECK_MS));
 
-                        state.channel_presence
-                            .entry(channel.clone())
-                            .or_insert_with(DashMap::new)
-                            .insert(client_id.clone(), client_info.clone());
-
-                        // Notify channel of new participant
-                        let presence_msg = ServerMessage {
-                            r#type: "user_joined".to_string(),
-                            channel: channel.clone(),
-                            data: serde_json::to_value(&client_info).unwrap(),
-                            timestamp: chrono::Utc::now().timestamp(),
-                        };
+    // Redelivery of unacked at-least-once messages, checked a few times per timeout
+    let redelivers = redelivery::enabled(&state);
+    let mut redelivery_check =
+        tokio::time::interval(Duration::from_millis((state.config.redelivery_timeout_ms / 4).max(REDELIVERY_CHECK_MIN_MS)));
 
-                        if let Ok(msg_str) = serde_json::to_string(&presence_msg) {
-                            let _ = tx.send(msg_str);
-                        }
+    // Idle policy: close the connection if the client goes quiet
+    let idle_timeout = Some(state.live().idle_timeout_secs)
+        .filter(|secs| *secs > 0)
+        .map(Duration::from_secs);
+    let mut idle_deadline = idle_timeout.map(|timeout| tokio::time::Instant::now() + timeout);
+    // Warn that far ahead of the deadline, so the client can show it's still there
+    let idle_warning = Some(Duration::from_secs(state.live().idle_warning_secs))
+        .filter(|lead| !lead.is_zero() && idle_timeout.is_some_and(|timeout| *lead < timeout));
+    let mut idle_warn_at = idle_deadline.zip(idle_warning).map(|(deadline, lead)| deadline - lead);
 
-                        println!("📋 Client {} subscribed to channel {}", client_id, channel);
-                    }
+    // RTT pings go on the control queue, so time spent behind queued broadcasts isn't counted
+    let rtt_interval = Some(state.config.rtt_ping_interval_secs)
+        .filter(|secs| *secs > 0)
+        .map(Duration::from_secs);
+    let mut rtt_ping = tokio::time::interval(rtt_interval.unwrap_or(Duration::from_secs(3600)));
+    rtt_ping.reset();
 
-                    "publish" => {
-                        let channel = client_msg.channel.clone();
+    let mut ctx = ConnectionContext {
+        client_id,
+        identity,
+        origin,
+        compress,
+        format,
+        outgoing_tx,
+        control_tx,
+        priority_tx,
+        skip_tx,
+        skipped_through,
+        client_rtt,
+        migration,
+        dequeued,
+        delivered,
+        subscriptions: HashMap::new(),
+        presence_watches: HashMap::new(),
+        lanes,
+        full_since: None,
+        replay_drained_at: 0,
+        slide_throttles: HashMap::new(),
+        transfers: chunked::Transfers::new(),
+        resume,
+        clock_skew: skew::SkewEstimate::default(),
+        deliveries: Arc::default(),
+    };
 
-                        if let Some(tx) = state.channels.get(&channel) {
-                            let server_msg = ServerMessage {
-                                r#type: "message".to_string(),
-                                channel: channel.clone(),
-                                data: client_msg.data.unwrap_or(serde_json::json!({})),
-                                timestamp: chrono::Utc::now().timestamp(),
-                            };
+    // How the connection ended; a server-side reason is sent as the close frame
+    let mut disconnect = DisconnectReason::StreamEnded;
 
-                            if let Ok(msg_str) = serde_json::to_string(&server_msg) {
-                                let _ = tx.send(msg_str);
-                                println!("📡 Message published to channel {} by client {}", channel, client_id);
-                            }
-                        }
-                    }
+    // Resumed subscriptions, and a subscribe from the connect URL, come before anything the
+    // client sends. A subscribe never closes the connection; only ready_to_migrate does.
+    if let Some(token) = on_connect.resume {
+        resume::restore(&state, &mut ctx, &token).await;
+    }
+    if let Some(subscribe) = on_connect.subscribe {
+        let _ = dispatch(&state, &mut ctx, subscribe, Instant::now()).await;
+    }
 
-                    "slide_change" => {
-                        // Special handling for slide changes (core feature)
-                        let channel = client_msg.channel.clone();
-
-                        if let Some(tx) = state.channels.get(&channel) {
-                            let slide_msg = ServerMessage {
-                                r#type: "slide_change".to_string(),
-                                channel: channel.clone(),
-                                data: client_msg.data.unwrap_or(serde_json::json!({})),
-                                timestamp: chrono::Utc::now().timestamp(),
-                            };
-
-                            if let Ok(msg_str) = serde_json::to_string(&slide_msg) {
-                                let _ = tx.send(msg_str);
-                                println!("🎯 Slide change broadcast to channel {} by client {}", channel, client_id);
+    // Handle incoming messages
+    loop {
+        let next_slide_flush = ctx.slide_throttles
+            .values()
+            .filter(|throttle| throttle.pending.is_some())
+            .map(|throttle| throttle.last_sent + throttle.interval)
+            .min();
+        let next_transfer_stall = chunked::next_deadline(&ctx.transfers);
+
+        let msg = tokio::select! {
+            msg = receiver.next() => match msg {
+                Some(Ok(msg)) => {
+                    // Pongs answer the server's own pings, so they don't show the client is active
+                    if !matches!(msg, Message::Pong(_)) {
+                        idle_deadline = idle_timeout.map(|timeout| tokio::time::Instant::now() + timeout);
+                        idle_warn_at = idle_deadline.zip(idle_warning).map(|(deadline, lead)| deadline - lead);
+                    }
+                    msg
+                }
+                Some(Err(e)) => {
+                    eprintln!("⚠️ Error reading from client {}: {}", ctx.client_id, e);
+                    disconnect = DisconnectReason::ReadError;
+                    break;
+                }
+                None => break,
+            },
+            Some(reason) = disconnect_rx.recv() => {
+                disconnect = DisconnectReason::Server(reason);
+                break;
+            }
+            _ = tokio::time::sleep_until(idle_deadline.unwrap_or_else(tokio::time::Instant::now)), if idle_deadline.is_some() => {
+                println!("💤 Client {} disconnected after being idle", ctx.client_id);
+                disconnect = DisconnectReason::Server(CloseReason::Idle);
+                break;
+            }
+            _ = tokio::time::sleep_until(idle_warn_at.unwrap_or_else(tokio::time::Instant::now)), if idle_warn_at.is_some() => {
+                // Once per quiet spell; activity rearms it
+                idle_warn_at = None;
+                let seconds_remaining = idle_warning.map(|lead| lead.as_secs()).unwrap_or(0);
+                let warning = ServerMessage::new("idle_warning", "", serde_json::json!({ "seconds_remaining": seconds_remaining }));
+                if let Some(warning) = warning.to_json() {
+                    let _ = ctx.control_tx.send(Message::Text(warning.into()));
+                }
+                continue;
+            }
+            _ = tokio::time::sleep_until(next_slide_flush.unwrap_or_else(tokio::time::Instant::now)), if next_slide_flush.is_some() => {
+                // Send the latest coalesced slide for every channel whose interval has elapsed
+                let now = tokio::time::Instant::now();
+                let due = ctx.slide_throttles
+                    .values_mut()
+                    .filter(|throttle| throttle.last_sent + throttle.interval <= now);
+                for throttle in due {
+                    if let Some((slide_msg, allow_backward)) = throttle.pending.take() {
+                        throttle.last_sent = now;
+                        broadcast_slide_change(&state, &ctx.outgoing_tx, None, &ctx.client_id, slide_msg, allow_backward);
+                    }
+                }
+                continue;
+            }
+            _ = tokio::time::sleep_until(next_transfer_stall.unwrap_or_else(tokio::time::Instant::now)), if next_transfer_stall.is_some() => {
+                chunked::abandon_stalled(&mut ctx.transfers, &ctx.outgoing_tx);
+                continue;
+            }
+            _ = rtt_ping.tick(), if rtt_interval.is_some() => {
+                let _ = ctx.control_tx.send(Message::Ping(ctx.client_rtt.ping().into()));
+                continue;
+            }
+            _ = redelivery_check.tick(), if redelivers => {
+                ctx.deliveries.redeliver_due(&state, &ctx.client_id, &ctx.outgoing_tx);
+                continue;
+            }
+            _ = slow_check.tick(), if slow_grace.is_some() => {
+                if ctx.outgoing_tx.capacity() > 0 || ctx.dequeued.load(Ordering::Relaxed) < ctx.replay_drained_at {
+                    ctx.full_since = None;
+                    continue;
+                }
+
+                let since = *ctx.full_since.get_or_insert_with(Instant::now);
+                if slow_grace.is_some_and(|grace| since.elapsed() >= grace) {
+                    let notice = ServerMessage::new(
+                        "error",
+                        "",
+                        serde_json::json!({ "code": "too_slow", "message": "Outgoing queue stayed full; disconnecting" }),
+                    );
+                    if let Some(notice) = notice.to_json() {
+                        let _ = ctx.control_tx.send(Message::Text(notice.into()));
+                    }
+                    println!("🐢 Client {} disconnected as too slow", ctx.client_id);
+                    disconnect = DisconnectReason::Server(CloseReason::TooSlow);
+                    break;
+                }
+                continue;
+            }
+        };
+
+        let text = match msg {
+            Message::Text(text) => text,
+            Message::Close(_) => {
+                println!("🔌 Client {} requested close", ctx.client_id);
+                disconnect = DisconnectReason::ClientClosed;
+                break;
+            }
+            Message::Binary(bytes) if use_cbor => match cbor::decode(&bytes) {
+                Ok(value) => value.to_string().into(),
+                Err(e) => {
+                    send_error(&ctx.outgoing_tx, None, "", "invalid_cbor", e);
+                    continue;
+                }
+            },
+            Message::Binary(_) => {
+                send_error(&ctx.outgoing_tx, None, "", "unsupported_frame", "Binary frames are not supported; send JSON text");
+                continue;
+            }
+            Message::Pong(payload) => {
+                if let Some(rtt) = ctx.client_rtt.pong(&payload) {
+                    state.metrics.client_rtt.observe(rtt.as_secs_f64());
+                }
+                continue;
+            }
+            // Pings are answered by the WebSocket layer itself
+            Message::Ping(_) => continue,
+        };
+
+        let received_at = Instant::now();
+        let client_msg = match serde_json::from_str::<ClientMessage>(&text) {
+            Ok(client_msg) => client_msg,
+            Err(e) => {
+                malformed::report(&ctx.outgoing_tx, &text, e);
+                continue;
+            }
+        };
+        if let Some(reason) = dispatch(&state, &mut ctx, client_msg, received_at).await {
+            disconnect = reason;
+            break;
+        }
+    }
+
+    // Cleanup
+    state.clients.remove(&ctx.client_id);
+    resume::park(&state, &mut ctx, &disconnect);
+
+    // Students should still converge on the last slide this client sent
+    for (slide_msg, allow_backward) in ctx.slide_throttles.into_values().filter_map(|throttle| throttle.pending) {
+        broadcast_slide_change(&state, &ctx.outgoing_tx, None, &ctx.client_id, slide_msg, allow_backward);
+    }
+
+    for (channel, forward_handle) in ctx.subscriptions {
+        // Wait for the forwarder to actually drop its receiver before counting subscribers
+        forward_handle.abort();
+        let _ = forward_handle.await;
+        presenter::leave(&state, &channel, &ctx.client_id);
+        presence::begin_grace(&state, &channel, &ctx.client_id);
+        lifecycle::release_sender(&state, &channel);
+    }
+    ctx.presence_watches.clear();
+    ctx.deliveries.abandon(&state, &ctx.client_id);
+    drop(ctx.lanes);
+    scheduler_handle.abort();
+
+    if let DisconnectReason::Server(reason) = &disconnect {
+        let _ = ctx.control_tx.send(reason.frame());
+    }
+
+    // Let the sender flush any pending control messages before closing
+    drop(ctx.control_tx);
+    if tokio::time::timeout(Duration::from_secs(1), &mut sender_handle).await.is_err() {
+        sender_handle.abort();
+    }
+    dedup::detach(&state, &ctx.identity, ctx.delivered);
+
+    Metrics::inc(&state.metrics.disconnects.entry(disconnect.kind()).or_default());
+    println!("🔌 Client {} disconnected ({})", ctx.client_id, disconnect.as_str());
+}
+
+// Handle one message from a client. Returns why the connection has to close, if it does.
+async fn dispatch(
+    state: &AppState,
+    ctx: &mut ConnectionContext,
+    mut client_msg: ClientMessage,
+    received_at: Instant,
+) -> Option<DisconnectReason> {
+    let _timer = metrics::ActionTimer::new(&state.metrics, &client_msg.action, received_at);
+    let request_id = client_msg.request_id.take();
+    let request_id = request_id.as_deref();
+    client_msg.channel = admin::resolve_channel(state, &client_msg.channel);
+
+    // A completed chunked transfer carries on as the publish of its whole data
+    if client_msg.action == "chunk_end" {
+        match chunked::finish(&mut ctx.transfers, client_msg.transfer_id.as_deref(), &client_msg.channel) {
+            Ok(data) => {
+                client_msg.action = "publish".to_string();
+                client_msg.data = Some(data);
+            }
+            Err(e) => {
+                chunked::send_error(&ctx.outgoing_tx, request_id, &client_msg.channel, client_msg.transfer_id.as_deref(), e);
+                return None;
+            }
+        }
+    }
+
+    let is_publish = matches!(
+        client_msg.action.as_str(),
+        "publish"
+            | "publish_quorum"
+            | "slide_change"
+            | "slide_diff"
+            | "set_sticky_message"
+            | "schedule"
+            | "start_poll"
+            | "poll_vote"
+            | "close_poll"
+    );
+    if is_publish && state.maintenance.load(Ordering::Relaxed) {
+        send_error(&ctx.outgoing_tx, request_id, &client_msg.channel, "maintenance", "Server is in maintenance mode; publishing is paused");
+        return None;
+    }
+    if is_publish && state.archived_channels.contains_key(&client_msg.channel) {
+        send_error(&ctx.outgoing_tx, request_id, &client_msg.channel, "channel_archived", "Channel is archived and read-only");
+        return None;
+    }
+    // End-to-end encrypted publishes skip everything that would have to read their data
+    let encrypted = client_msg.encrypted.unwrap_or(false) && matches!(client_msg.action.as_str(), "publish" | "publish_quorum");
+    let readable_data = client_msg.data.as_ref().filter(|_| !encrypted);
+    if is_publish && !message_types::permits(state, &client_msg.channel, &client_msg.action, readable_data) {
+        send_error(&ctx.outgoing_tx, request_id, &client_msg.channel, "type_not_allowed", "Channel does not accept this message type");
+        return None;
+    }
+
+    // Tenant isolation applies to everything that reads from or writes to a channel
+    let tenant = if is_publish || client_msg.action == "subscribe" {
+        match state.tenant_resolver.resolve(&client_msg.channel, &ctx.identity).await {
+            Ok(tenant) => tenant,
+            Err(e) => {
+                println!("🏢 Client {} refused channel {}: {}", ctx.client_id, client_msg.channel, e);
+                send_error(&ctx.outgoing_tx, request_id, &client_msg.channel, "forbidden", &e.to_string());
+                return None;
+            }
+        }
+    } else {
+        None
+    };
+
+    if (is_publish || client_msg.action == "subscribe") && !origins::permits(state, &client_msg.channel, ctx.origin.as_deref()) {
+        send_error(&ctx.outgoing_tx, request_id, &client_msg.channel, "origin_not_allowed", "Channel cannot be used from this origin");
+        return None;
+    }
+
+    if is_publish && state.config.require_subscription_to_publish && !ctx.subscriptions.contains_key(&client_msg.channel) {
+        send_error(&ctx.outgoing_tx, request_id, &client_msg.channel, "not_subscribed", "Subscribe to the channel before publishing to it");
+        return None;
+    }
+
+    if (is_publish || client_msg.action == "subscribe") && lifecycle::is_expired(state, &client_msg.channel) {
+        send_error(&ctx.outgoing_tx, request_id, &client_msg.channel, "channel_expired", "Channel has reached its maximum lifetime");
+        return None;
+    }
+
+    // Checked before policies get to read them
+    if let Err(e) = headers::check(state, client_msg.headers.as_ref()) {
+        send_error(&ctx.outgoing_tx, request_id, &client_msg.channel, "invalid_headers", &e.to_string());
+        return None;
+    }
+
+    if is_publish {
+        let attempt = channel_policy::PublishAttempt {
+            action: &client_msg.action,
+            client_id: &ctx.client_id,
+            data: readable_data,
+            encrypted,
+            headers: client_msg.headers.as_ref(),
+        };
+        if let Err(reason) = channel_policy::check_publish(state, &client_msg.channel, &attempt) {
+            send_error(&ctx.outgoing_tx, request_id, &client_msg.channel, "rejected_by_policy", &reason);
+            return None;
+        }
+    }
+
+    // Client-supplied event times are checked before they can reach history
+    let broadcasts_data = matches!(
+        client_msg.action.as_str(),
+        "publish" | "publish_quorum" | "slide_change" | "slide_diff"
+    );
+    let client_timestamp = if broadcasts_data && !encrypted {
+        match timestamps::take(state, &ctx.clock_skew, &mut client_msg.data) {
+            Ok(client_timestamp) => client_timestamp,
+            Err(e) => {
+                send_error(&ctx.outgoing_tx, request_id, &client_msg.channel, "invalid_timestamp", &e.to_string());
+                return None;
+            }
+        }
+    } else {
+        None
+    };
+    if broadcasts_data && !encrypted {
+        let unsafe_integers = safe_integers::check(state, &mut client_msg.data);
+        if !unsafe_integers.is_empty() {
+            if state.config.unsafe_integer_policy == UnsafeIntegerPolicy::Reject {
+                send_error(
+                    &ctx.outgoing_tx,
+                    request_id,
+                    &client_msg.channel,
+                    "unsafe_integer",
+                    &format!("Integers beyond ±(2^53 - 1) lose precision in browsers: {}", unsafe_integers.join(", ")),
+                );
+                return None;
+            }
+            if state.config.unsafe_integer_policy == UnsafeIntegerPolicy::Warn {
+                send_direct(
+                    &ctx.outgoing_tx,
+                    request_id,
+                    &client_msg.channel,
+                    "info",
+                    serde_json::json!({ "code": "unsafe_integers", "paths": unsafe_integers }),
+                );
+            }
+        }
+    }
+
+    // A transcript writer that fell behind slows publishes to retained channels down to its pace
+    if broadcasts_data {
+        retention::wait_for_room(state, &client_msg.channel).await;
+    }
+
+    // On a reliable channel, the slowest subscriber sets the pace
+    if broadcasts_data && !reliable::wait_for_subscribers(state, &client_msg.channel).await {
+        Metrics::inc(&state.metrics.backpressure_timeouts);
+        send_error(
+            &ctx.outgoing_tx,
+            request_id,
+            &client_msg.channel,
+            "backpressure_timeout",
+            "A subscriber is too far behind; retry shortly",
+        );
+        return None;
+    }
+
+    // Held until this message is handled, so bursts on one channel are processed a few at a time
+    let _publish_slot = if broadcasts_data {
+        match publish_slots::acquire(state, &client_msg.channel).await {
+            Ok(slot) => slot,
+            Err(()) => {
+                Metrics::inc(&state.metrics.publishes_busy);
+                send_error(&ctx.outgoing_tx, request_id, &client_msg.channel, "busy", "Channel is busy; retry shortly");
+                return None;
+            }
+        }
+    } else {
+        None
+    };
+
+    // Held while a subscribe is set up, so a join stampede is worked through a few at a time
+    let _subscribe_turn = if client_msg.action == "subscribe" {
+        state.subscribe_queue.enter().await
+    } else {
+        None
+    };
+
+    // Publishing ahead of the first subscribe can create the channel, so the publish is kept for replay
+    if broadcasts_data && state.config.publish_creates_channel {
+        lifecycle::open_for_publish(state, &client_msg.channel);
+    }
+
+    match client_msg.action.as_str() {
+        "subscribe" => {
+            let channel = client_msg.channel.clone();
+
+            // A second forwarder would deliver every broadcast twice
+            if ctx.subscriptions.contains_key(&channel) {
+                send_error(&ctx.outgoing_tx, request_id, &channel, "already_subscribed", "Already subscribed to this channel");
+                return None;
+            }
+
+            if !lifecycle::may_subscribe(state, &channel) {
+                send_error(&ctx.outgoing_tx, request_id, &channel, "channel_not_found", "Channel has not been declared");
+                return None;
+            }
+
+            if !channel_config::has_room(state, &channel) {
+                send_error(&ctx.outgoing_tx, request_id, &channel, "channel_full", "Channel is at its participant limit");
+                return None;
+            }
+
+            if state.memory.over_limit() && !state.channels.contains_key(&channel) {
+                send_error(&ctx.outgoing_tx, request_id, &channel, "server_busy", "Server is low on memory; no new channels");
+                return None;
+            }
+
+            // An authenticated role is both the default and the ceiling for this connection
+            let first_subscriber = roles::first_subscriber(state, &channel, &ctx.identity, &ctx.client_id);
+            let requested = match first_subscriber {
+                FirstSubscriber::First => roles::presenter_role(state).cloned(),
+                FirstSubscriber::Later => Some(client_msg.role.unwrap_or_else(|| roles::default_role(state))),
+                FirstSubscriber::Unaffected => client_msg.role.or_else(|| ctx.identity.role.clone()),
+            };
+            let Some(role) = roles::subscribe_role(state, &channel, requested) else {
+                send_error(&ctx.outgoing_tx, request_id, &channel, "invalid_role", "Unknown role");
+                return None;
+            };
+            if !roles::admits_role(state, &channel, &ctx.client_id, &role) {
+                send_error(&ctx.outgoing_tx, request_id, &channel, "invalid_role", "Channel already has the maximum number of distinct roles");
+                return None;
+            }
+            if roles::presenter_taken(state, &channel, &ctx.client_id, ctx.identity.id.as_deref(), &role) {
+                send_error(&ctx.outgoing_tx, request_id, &channel, "presenter_taken", "Channel already has a presenter");
+                return None;
+            }
+            let permitted = match &ctx.identity.role {
+                Some(granted) => roles::at_most(state, &role, granted),
+                None => {
+                    first_subscriber == FirstSubscriber::First
+                        || roles::may_self_assign(state, &role)
+                        || resume::held_before(ctx.resume.as_ref(), &role)
+                }
+            };
+            if !permitted {
+                send_error(&ctx.outgoing_tx, request_id, &channel, "forbidden", "This role must be granted by a moderator");
+                return None;
+            }
+
+            if client_msg.ordering.is_some() && !roles::allows(state, &role, Permission::ManageRoles) {
+                send_error(&ctx.outgoing_tx, request_id, &channel, "forbidden", "Only a moderator can choose the channel's ordering");
+                return None;
+            }
+
+            let metadata = match client_msg.metadata.as_ref().map(|metadata| metadata::validate(state, metadata)).transpose() {
+                Ok(metadata) => metadata.filter(|metadata| !metadata.is_empty()),
+                Err(e) => {
+                    send_error(&ctx.outgoing_tx, request_id, &channel, "invalid_metadata", &e.to_string());
+                    return None;
+                }
+            };
+
+            let echo = client_msg.echo.unwrap_or(state.config.publish_echo);
+            let presence_only = client_msg.presence_only.unwrap_or(false);
+            let fields = client_msg.fields.clone();
+            let projection = match client_msg.fields.as_deref().map(projection::Projection::parse).transpose() {
+                Ok(projection) => projection,
+                Err(message) => {
+                    send_error(&ctx.outgoing_tx, request_id, &channel, "invalid_projection", &message);
+                    return None;
+                }
+            };
+
+            let mut rx = lifecycle::subscribe(state, &channel);
+            // Broadcasts numbered after this arrive live
+            let subscribed_at = state.channel_seq.get(&channel).map(|seq| *seq).unwrap_or(0);
+
+            if let Some(requested) = client_msg.ordering {
+                let (mode, chosen) = ordering::choose(state, &channel, requested);
+                if chosen {
+                    println!("🔀 Channel {} set to {} ordering by client {}", channel, mode.as_str(), ctx.client_id);
+                }
+                send_direct(
+                    &ctx.outgoing_tx,
+                    request_id,
+                    &channel,
+                    "info",
+                    serde_json::json!({ "code": "ordering_mode", "ordering": mode, "chosen": chosen }),
+                );
+            }
+
+            // Replay recent history (or just what followed the client's cursor),
+            // then forward live messages
+            // A cursor from an earlier epoch says nothing about the current numbering
+            let current_epoch = epoch::current(state, &channel);
+            let stale_epoch = client_msg.since_epoch.is_some_and(|since_epoch| since_epoch != current_epoch);
+            let replay = match client_msg.since_seq {
+                Some(since_seq) => {
+                    let (events, truncated) = if stale_epoch {
+                        (history::recent(state, &channel), true)
+                    } else {
+                        history::since(state, &channel, since_seq)
+                    };
+                    if truncated {
+                        send_direct(
+                            &ctx.outgoing_tx,
+                            request_id,
+                            &channel,
+                            "history_truncated",
+                            serde_json::json!({
+                                "since_seq": since_seq,
+                                "oldest_seq": events.front().and_then(|event| event.msg.seq),
+                                "epoch": current_epoch,
+                            }),
+                        );
+                    }
+                    events
+                }
+                None => history::recent(state, &channel),
+            };
+
+            // (epoch, seq) of the last replayed broadcast
+            let mut replayed_through = (0, 0);
+            for event in replay {
+                if let Some(seq) = event.msg.seq {
+                    replayed_through = (event.msg.epoch.unwrap_or(0), seq);
+                }
+                if !event.msg.echo.unwrap_or(echo) && event.origin == ctx.client_id {
+                    continue;
+                }
+                if presence_only && !presence::is_presence_event(&event.msg.r#type) {
+                    continue;
+                }
+                if !roles::may_see(state, &channel, &ctx.client_id, event.msg.min_role.as_deref()) {
+                    continue;
+                }
+                if dedup::already_delivered(state, ctx.delivered.as_ref(), &event.msg.message_id) {
+                    continue;
+                }
+                let frame = encoding::subscriber_frame(state, &event, ctx.format, projection.as_ref(), ctx.compress);
+                if event.msg.at_least_once {
+                    ctx.deliveries.track(state, &ctx.client_id, &channel, &event.msg.message_id, frame.clone());
+                }
+                let _ = ctx.outgoing_tx.send(frame).await;
+            }
+            // The replay kept the queue full on purpose, and the slow-consumer check
+            // couldn't run meanwhile; its time only counts once the backlog is sent
+            if replayed_through != (0, 0) {
+                ctx.full_since = None;
+                let queued = ctx.outgoing_tx.max_capacity() - ctx.outgoing_tx.capacity();
+                ctx.replay_drained_at = ctx.dequeued.load(Ordering::Relaxed) + queued as u64;
+            }
+            if let Some(since_seq) = client_msg.since_seq {
+                let cursor = match (replayed_through.1, stale_epoch) {
+                    (0, true) => 0,
+                    (0, false) => since_seq,
+                    (seq, _) => seq,
+                };
+                let caught_up = serde_json::json!({ "epoch": current_epoch, "seq": cursor });
+                if let Some(frame) = direct_frame(request_id, &channel, "caught_up", caught_up) {
+                    let _ = ctx.outgoing_tx.send(frame).await;
+                }
+            }
+            // Where the replay ended and live delivery begins, for the client to check the two meet.
+            // Queued behind the replay, which can have left the queue full.
+            let subscribed = serde_json::json!({
+                "epoch": current_epoch,
+                "seq": subscribed_at,
+                "replayed_through": Some(replayed_through.1).filter(|seq| *seq > 0),
+            });
+            if let Some(frame) = direct_frame(request_id, &channel, "subscribed", subscribed) {
+                let _ = ctx.outgoing_tx.send(frame).await;
+            }
+            if !presence_only {
+                sticky::deliver(state, &ctx.outgoing_tx, request_id, &channel);
+            }
+
+            let lane_tx = ctx.lanes.open();
+            let priority_tx = ctx.priority_tx.clone();
+            let (format, compress) = (ctx.format, ctx.compress);
+            let forward_state = state.clone();
+            let forward_channel = channel.clone();
+            let forward_client_id = ctx.client_id.clone();
+            let forward_skipped_through = ctx.skipped_through.clone();
+            let forward_delivered = ctx.delivered.clone();
+            let forward_deliveries = ctx.deliveries.clone();
+            let mut slides = state.config.coalesce_slide_changes.then(coalesce::SlideCoalescer::default);
+
+            let batch_size = state.config.fanout_batch_size.max(1);
+            let linger = Some(Duration::from_millis(state.config.fanout_linger_ms)).filter(|linger| !linger.is_zero());
+
+            let forward_handle = tokio::spawn(async move {
+                let lagged = |skipped: u64| {
+                    forward_state.dead_letters.record(
+                        DeadLetterReason::Lagged,
+                        Some(&forward_channel),
+                        &forward_client_id,
+                        &format!("{} messages skipped", skipped),
+                    );
+                };
+                let mut batch = Vec::with_capacity(batch_size);
+                'forward: loop {
+                    match rx.recv().await {
+                        Ok(event) => batch.push(event),
+                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                            lagged(skipped);
+                            continue;
+                        }
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    }
+                    // Take what else is waiting in the same wake-up, optionally giving the
+                    // channel a moment to fill the batch first
+                    if batch_size > 1 {
+                        if let Some(linger) = linger {
+                            tokio::time::sleep(linger).await;
+                        }
+                        while batch.len() < batch_size {
+                            match rx.try_recv() {
+                                Ok(event) => batch.push(event),
+                                Err(broadcast::error::TryRecvError::Lagged(skipped)) => lagged(skipped),
+                                Err(_) => break,
                             }
                         }
                     }
 
+                    for event in batch.drain(..) {
+                        // Already delivered as part of the history replay
+                        if event.msg.seq.is_some_and(|seq| (event.msg.epoch.unwrap_or(0), seq) <= replayed_through) {
+                            continue;
+                        }
+
+                        // Backlog the client asked to skip
+                        let position = event.msg.seq.map(|seq| (event.msg.epoch.unwrap_or(0), seq));
+                        if position.is_some_and(|position| {
+                            forward_skipped_through.get(&forward_channel).is_some_and(|through| position <= *through)
+                        }) {
+                            continue;
+                        }
+
+                        // The subscriber's own publish, which it asked not to get back
+                        if !event.msg.echo.unwrap_or(echo) && event.origin == forward_client_id {
+                            continue;
+                        }
+
+                        // Meant for roles above the subscriber's
+                        if !roles::may_see(&forward_state, &forward_channel, &forward_client_id, event.msg.min_role.as_deref()) {
+                            continue;
+                        }
+
+                        // Received before a reconnect, or in the replay
+                        if dedup::already_delivered(&forward_state, forward_delivered.as_ref(), &event.msg.message_id) {
+                            continue;
+                        }
+
+                        // Messages, slides and notices the roster display didn't ask for
+                        if presence_only && !presence::is_presence_event(&event.msg.r#type) {
+                            continue;
+                        }
+
+                        // Time-sensitive message that sat in the queue past its window
+                        if event.msg.is_expired() {
+                            forward_state.dead_letters.record(
+                                DeadLetterReason::Expired,
+                                Some(&forward_channel),
+                                &forward_client_id,
+                                &event.json,
+                            );
+                            continue;
+                        }
+
+                        let frame = encoding::subscriber_frame(&forward_state, &event, format, projection.as_ref(), compress);
+                        if event.msg.at_least_once {
+                            forward_deliveries.track(&forward_state, &forward_client_id, &forward_channel, &event.msg.message_id, frame.clone());
+                        }
+                        let sent = match (event.msg.priority, slides.as_mut()) {
+                            (Priority::Normal, _) => lane_tx.send(frame).await.is_ok(),
+                            (Priority::High, Some(slides)) if event.msg.r#type == "slide_change" => match slides.offer(frame) {
+                                Some(queued) => priority_tx.send(queued).await.is_ok(),
+                                None => {
+                                    Metrics::inc(&forward_state.metrics.slides_coalesced);
+                                    true
+                                }
+                            },
+                            (Priority::High, slides) => {
+                                if let Some(slides) = slides {
+                                    slides.seal();
+                                }
+                                priority_tx.send(coalesce::Queued::Frame(frame)).await.is_ok()
+                            }
+                        };
+                        if !sent {
+                            forward_state.dead_letters.record(
+                                DeadLetterReason::SendFailed,
+                                Some(&forward_channel),
+                                &forward_client_id,
+                                &event.json,
+                            );
+                            break 'forward;
+                        }
+                    }
+                }
+            });
+
+            ctx.subscriptions.insert(channel.clone(), forward_handle);
+            resume::remember(ctx.resume.as_mut(), &channel, resume::Options { presence_only, echo: client_msg.echo, fields });
+
+            // Add to presence tracking
+            let client_info = ClientInfo {
+                id: ctx.client_id.clone(),
+                pinned: presence::is_pinned_role(state, &role),
+                role,
+                joined_at: clock::now().timestamp(),
+                status: presence::STATUS_ONLINE.to_string(),
+                group: client_msg.group.filter(|group| !group.is_empty()),
+                identity: ctx.identity.id.clone(),
+                last_activity: clock::now().timestamp_millis(),
+                metadata_expires_at: metadata::expiries(metadata.as_ref(), client_msg.metadata_ttl_secs.as_ref()),
+                metadata,
+            };
+
+            // A quick reconnect takes over its old entry instead of showing up twice
+            if presence::join(state, &channel, client_info.clone()) {
+                presence::announce(state, &channel, "presence_update", &client_info);
+            } else {
+                presence::announce(state, &channel, "user_joined", &client_info);
+            }
+            // Who was already here, including this connection, as of its join
+            if let Some(snapshot) = presence::snapshot(state, &channel) {
+                send_direct(&ctx.outgoing_tx, request_id, &channel, "presence_snapshot", snapshot);
+            }
+            // Held by an earlier connection of the same participant, it stays theirs until that one leaves
+            if first_subscriber == FirstSubscriber::First && state.config.presenter_lock {
+                let _ = presenter::acquire(state, &channel, &ctx.client_id);
+            }
+
+            match tenant {
+                Some(tenant) => println!("📋 Client {} subscribed to channel {} (tenant {})", ctx.client_id, channel, tenant),
+                None => println!("📋 Client {} subscribed to channel {}", ctx.client_id, channel),
+            }
+        }
+
+        "publish" | "publish_quorum" => {
+            let channel = client_msg.channel.clone();
+
+            if !roles::allows(state, &roles::channel_role(state, &channel, &ctx.client_id), Permission::Publish) {
+                send_error(&ctx.outgoing_tx, request_id, &channel, "forbidden", "Your role cannot publish on this channel");
+                return None;
+            }
+
+            let expected_acks = if client_msg.action == "publish_quorum" {
+                match client_msg.expected_acks {
+                    Some(expected) if expected > 0 => Some(expected),
                     _ => {
-                        println!("❓ Unknown action: {} from client {}", client_msg.action, client_id);
+                        send_error(&ctx.outgoing_tx, request_id, &channel, "invalid_request", "publish_quorum needs expected_acks of at least 1");
+                        return None;
                     }
                 }
+            } else {
+                None
+            };
+
+            if let Some(min_role) = &client_msg.min_role {
+                if !state.config.role_hierarchy.contains(min_role) {
+                    send_error(&ctx.outgoing_tx, request_id, &channel, "invalid_role", "min_role is not in the role hierarchy");
+                    return None;
+                }
+                if !roles::allows(state, &roles::channel_role(state, &channel, &ctx.client_id), Permission::RestrictVisibility) {
+                    send_error(&ctx.outgoing_tx, request_id, &channel, "forbidden", "Your role cannot restrict who receives a publish");
+                    return None;
+                }
+            }
+
+            let at_least_once = client_msg.at_least_once.unwrap_or(false);
+            if at_least_once && !redelivery::enabled(state) {
+                send_error(&ctx.outgoing_tx, request_id, &channel, "invalid_request", "At-least-once delivery is disabled");
+                return None;
+            }
+
+            let correlation_id = client_msg.correlation_id.unwrap_or_else(|| Uuid::new_v4().to_string());
+            let expires_at = client_msg
+                .expires_in_ms
+                .map(|ttl| clock::now().timestamp_millis().saturating_add(ttl as i64));
+            let server_msg = ServerMessage {
+                correlation_id: Some(correlation_id.clone()),
+                client_timestamp,
+                expires_at,
+                priority: client_msg.priority.unwrap_or_default(),
+                // Ciphertext doesn't compress
+                incompressible: client_msg.incompressible.unwrap_or(false) || encrypted,
+                encrypted,
+                ack_requested: expected_acks.is_some() || at_least_once,
+                at_least_once,
+                echo: client_msg.echo,
+                min_role: client_msg.min_role,
+                headers: client_msg.headers,
+                ..ServerMessage::new("message", &channel, client_msg.data.unwrap_or(serde_json::json!({})))
+            };
+            let message_id = server_msg.message_id.clone();
+
+            // A retried publish: tell the publisher it already went out, and as what
+            let idempotency_key = client_msg.idempotency_key.filter(|key| !key.is_empty());
+            let duplicate_of = idempotency_key
+                .as_deref()
+                .and_then(|key| idempotency::claim(state, &channel, key, &message_id));
+            if let Some(original) = duplicate_of {
+                send_direct(
+                    &ctx.outgoing_tx,
+                    request_id,
+                    &channel,
+                    "info",
+                    serde_json::json!({ "code": "duplicate_publish", "idempotency_key": idempotency_key, "message_id": original }),
+                );
+                return None;
+            }
+
+            // Register before broadcasting so no ack can arrive ahead of it
+            if let Some(expected) = expected_acks {
+                let publisher = quorum::Publisher {
+                    client_id: ctx.client_id.clone(),
+                    outgoing: ctx.outgoing_tx.clone(),
+                    request_id: request_id.map(str::to_string),
+                };
+                if let Err(limit) = quorum::register(state, &message_id, &channel, publisher, expected, client_msg.timeout_ms) {
+                    if let Some(key) = &idempotency_key {
+                        idempotency::release(state, &channel, key);
+                    }
+                    send_error(
+                        &ctx.outgoing_tx,
+                        request_id,
+                        &channel,
+                        "too_many_unacked",
+                        &format!("{} quorum publishes on this channel are still waiting for acks", limit),
+                    );
+                    return None;
+                }
+            }
+
+            if send_to_channel(state, server_msg, &ctx.client_id) {
+                println!(
+                    "📡 Message published to channel {} by client {} (correlation_id {})",
+                    channel, ctx.client_id, correlation_id
+                );
+            } else {
+                quorum::cancel(state, &message_id);
+                if let Some(key) = &idempotency_key {
+                    idempotency::release(state, &channel, key);
+                }
+                notify_no_subscribers(state, &ctx.outgoing_tx, request_id, &channel);
             }
-        } else if let Message::Close(_) = msg {
-            println!("🔌 Client {} requested close", client_id);
-            break;
+        }
+
+        "chunk_start" => {
+            let channel = client_msg.channel.clone();
+
+            // Not worth buffering what couldn't be published in the end
+            if !roles::allows(state, &roles::channel_role(state, &channel, &ctx.client_id), Permission::Publish) {
+                send_error(&ctx.outgoing_tx, request_id, &channel, "forbidden", "Your role cannot publish on this channel");
+                return None;
+            }
+
+            let transfer_id = client_msg.transfer_id.as_deref();
+            match chunked::start(state, &mut ctx.transfers, transfer_id, &channel, client_msg.total_size) {
+                Ok(()) => send_direct(
+                    &ctx.outgoing_tx,
+                    request_id,
+                    &channel,
+                    "info",
+                    serde_json::json!({ "code": "transfer_started", "transfer_id": transfer_id }),
+                ),
+                Err(e) => chunked::send_error(&ctx.outgoing_tx, request_id, &channel, transfer_id, e),
+            }
+        }
+
+        "chunk" => {
+            let transfer_id = client_msg.transfer_id.as_deref();
+            if let Err(e) = chunked::append(state, &mut ctx.transfers, transfer_id, &client_msg.channel, client_msg.data) {
+                chunked::send_error(&ctx.outgoing_tx, request_id, &client_msg.channel, transfer_id, e);
+            }
+        }
+
+        "ack" | "receipt" => {
+            let channel = client_msg.channel.clone();
+            let Some(message_id) = client_msg.message_id else {
+                send_error(&ctx.outgoing_tx, request_id, &channel, "invalid_request", "ack needs a message_id");
+                return None;
+            };
+
+            // An at-least-once message can be part of a quorum as well
+            let redelivering = ctx.deliveries.ack(&message_id);
+            match quorum::ack(state, &message_id, &ctx.client_id) {
+                Ok(()) => {}
+                Err(quorum::AckError::UnknownMessage) if redelivering => {}
+                Err(quorum::AckError::UnknownMessage) => {
+                    send_error(&ctx.outgoing_tx, request_id, &channel, "unknown_message", "Nothing is waiting for an ack of this message");
+                }
+                Err(quorum::AckError::NotSubscribed) => {
+                    send_error(&ctx.outgoing_tx, request_id, &channel, "not_present", "Subscribe to the message's channel before acking it");
+                }
+            }
+        }
+
+        "slide_change" => {
+            // Special handling for slide changes (core feature)
+            let channel = client_msg.channel.clone();
+
+            if !roles::allows(state, &roles::channel_role(state, &channel, &ctx.client_id), Permission::SlideChange) {
+                send_error(&ctx.outgoing_tx, request_id, &channel, "forbidden", "Your role cannot change slides on this channel");
+                return None;
+            }
+            if !presenter::may_present(state, &channel, &ctx.client_id) {
+                send_error(&ctx.outgoing_tx, request_id, &channel, "not_presenter", "Acquire the presenter lock before changing slides");
+                return None;
+            }
+
+            let correlation_id = client_msg.correlation_id.unwrap_or_else(|| Uuid::new_v4().to_string());
+            let allow_backward = client_msg.allow_backward.unwrap_or(false);
+            let slide_msg = ServerMessage {
+                correlation_id: Some(correlation_id),
+                client_timestamp,
+                incompressible: client_msg.incompressible.unwrap_or(false),
+                ..ServerMessage::new("slide_change", &channel, client_msg.data.unwrap_or(serde_json::json!({})))
+            };
+
+            let Some(interval) = channel_config::slide_interval(state, &channel) else {
+                broadcast_slide_change(state, &ctx.outgoing_tx, request_id, &ctx.client_id, slide_msg, allow_backward);
+                return None;
+            };
+
+            // Over the rate cap: hold only the latest slide until the interval elapses
+            let now = tokio::time::Instant::now();
+            match ctx.slide_throttles.get_mut(&channel) {
+                Some(throttle) if throttle.last_sent + interval > now => {
+                    throttle.interval = interval;
+                    throttle.pending = Some((slide_msg, allow_backward));
+                }
+                _ => {
+                    ctx.slide_throttles.insert(channel.clone(), SlideThrottle { interval, last_sent: now, pending: None });
+                    broadcast_slide_change(state, &ctx.outgoing_tx, request_id, &ctx.client_id, slide_msg, allow_backward);
+                }
+            }
+        }
+
+        "slide_diff" => {
+            // Incremental update to the current slide, applied only against the version it was computed from
+            let channel = client_msg.channel.clone();
+
+            if !roles::allows(state, &roles::channel_role(state, &channel, &ctx.client_id), Permission::SlideChange) {
+                send_error(&ctx.outgoing_tx, request_id, &channel, "forbidden", "Your role cannot change slides on this channel");
+                return None;
+            }
+            if !presenter::may_present(state, &channel, &ctx.client_id) {
+                send_error(&ctx.outgoing_tx, request_id, &channel, "not_presenter", "Acquire the presenter lock before changing slides");
+                return None;
+            }
+
+            let data = client_msg.data.unwrap_or(serde_json::json!({}));
+            let diff_is_valid = data
+                .get("diff")
+                .and_then(|diff| diff.as_str())
+                .is_some_and(|diff| BASE64_STANDARD.decode(diff).is_ok());
+            let (true, Some(base_version)) = (diff_is_valid, client_msg.base_version) else {
+                send_error(&ctx.outgoing_tx, request_id, &channel, "invalid_diff", "slide_diff needs a base_version and a base64 data.diff");
+                return None;
+            };
+
+            let correlation_id = client_msg.correlation_id.unwrap_or_else(|| Uuid::new_v4().to_string());
+            let diff_msg = ServerMessage {
+                correlation_id: Some(correlation_id.clone()),
+                client_timestamp,
+                incompressible: client_msg.incompressible.unwrap_or(false),
+                ..ServerMessage::new("slide_diff", &channel, data)
+            };
+
+            match slides::apply_diff(state, diff_msg, base_version, &ctx.client_id) {
+                Ok(true) => {
+                    println!(
+                        "🧩 Slide diff on version {} broadcast to channel {} by client {} (correlation_id {})",
+                        base_version, channel, ctx.client_id, correlation_id
+                    );
+                }
+                Ok(false) => notify_no_subscribers(state, &ctx.outgoing_tx, request_id, &channel),
+                Err(slides::DiffError::Stale { current_version }) => {
+                    send_direct(
+                        &ctx.outgoing_tx,
+                        request_id,
+                        &channel,
+                        "error",
+                        serde_json::json!({
+                            "code": "stale_diff",
+                            "message": "Diff is against an old slide version; resync and diff against the current one",
+                            "current_version": current_version,
+                        }),
+                    );
+                }
+                Err(slides::DiffError::FullSlideRequired) => {
+                    send_error(&ctx.outgoing_tx, request_id, &channel, "full_slide_required", "Too many diffs since the last slide_change; send a full slide");
+                }
+            }
+        }
+
+        "query_presence" => {
+            let channel = client_msg.channel.clone();
+
+            if !roles::allows(state, &roles::channel_role(state, &channel, &ctx.client_id), Permission::QueryPresence) {
+                send_error(&ctx.outgoing_tx, request_id, &channel, "forbidden", "Your role cannot query presence on this channel");
+                return None;
+            }
+
+            let Some(target_client_id) = client_msg.target_client_id else {
+                send_error(&ctx.outgoing_tx, request_id, &channel, "invalid_request", "target_client_id is required");
+                return None;
+            };
+
+            let target = state
+                .channel_presence
+                .get(&channel)
+                .and_then(|channel_map| channel_map.get(&target_client_id).map(|info| info.clone()));
+
+            let data = match target {
+                Some(info) => serde_json::json!({
+                    "target_client_id": target_client_id,
+                    "present": true,
+                    "client": info
+                }),
+                None => serde_json::json!({
+                    "target_client_id": target_client_id,
+                    "present": false,
+                    "reason": "not_present"
+                }),
+            };
+
+            send_direct(&ctx.outgoing_tx, request_id, &channel, "presence_info", data);
+        }
+
+        "watch_presence_counts" => {
+            let pattern = client_msg.channel.clone();
+
+            // Counts span channels the client isn't on, so its own role has to allow it
+            let role = ctx.identity.role.clone().unwrap_or_else(|| roles::default_role(state));
+            if !roles::allows(state, &role, Permission::QueryPresence) {
+                send_error(&ctx.outgoing_tx, request_id, &pattern, "forbidden", "Your role cannot watch presence counts");
+                return None;
+            }
+            if pattern.is_empty() {
+                send_error(&ctx.outgoing_tx, request_id, "", "invalid_request", "channel must be a channel pattern, e.g. lesson-*");
+                return None;
+            }
+            if !presence_counts::may_watch(state, &role, &pattern) {
+                send_error(&ctx.outgoing_tx, request_id, &pattern, "forbidden", "Your role cannot watch patterns that start with *");
+                return None;
+            }
+            let max = state.config.max_pattern_watches;
+            if !ctx.presence_watches.contains_key(&pattern) && ctx.presence_watches.len() >= max {
+                send_error(
+                    &ctx.outgoing_tx,
+                    request_id,
+                    &pattern,
+                    "too_many_watches",
+                    &format!("At most {} patterns can be watched at once", max),
+                );
+                return None;
+            }
+
+            // Watching a pattern again starts over with a full snapshot, in the place the old watch frees
+            ctx.presence_watches.remove(&pattern);
+            let watch = presence_counts::watch(
+                state.clone(),
+                ctx.identity.clone(),
+                pattern.clone(),
+                request_id.map(str::to_string),
+                ctx.outgoing_tx.clone(),
+            );
+            let Some(watch) = watch else {
+                send_error(&ctx.outgoing_tx, request_id, &pattern, "too_many_watches", "The server is watching as many patterns as it allows");
+                return None;
+            };
+            ctx.presence_watches.insert(pattern, watch);
+        }
+
+        "unwatch_presence_counts" => {
+            let pattern = client_msg.channel.clone();
+            match ctx.presence_watches.remove(&pattern) {
+                Some(_) => {
+                    send_direct(
+                        &ctx.outgoing_tx,
+                        request_id,
+                        &pattern,
+                        "info",
+                        serde_json::json!({ "code": "presence_counts_stopped", "pattern": pattern }),
+                    );
+                }
+                None => send_error(&ctx.outgoing_tx, request_id, &pattern, "not_watching", "This pattern isn't being watched"),
+            }
+        }
+
+        "set_group" => {
+            let channel = client_msg.channel.clone();
+            let target_client_id = client_msg.target_client_id.unwrap_or_else(|| ctx.client_id.clone());
+
+            // Teachers can move others between groups; everyone else only themselves
+            let may_move_others =
+                roles::allows(state, &roles::channel_role(state, &channel, &ctx.client_id), Permission::ManageRoles);
+            if target_client_id != ctx.client_id && !may_move_others {
+                send_error(&ctx.outgoing_tx, request_id, &channel, "forbidden", "You cannot move other clients between groups");
+                return None;
+            }
+
+            let group = client_msg.group.filter(|group| !group.is_empty());
+            match presence::set_group(state, &channel, &target_client_id, group) {
+                Some(info) => {
+                    presence::announce(state, &channel, "presence_update", &info);
+                    println!(
+                        "👥 Client {} moved {} to group {} in channel {}",
+                        ctx.client_id,
+                        target_client_id,
+                        info.group.as_deref().unwrap_or("-"),
+                        channel
+                    );
+                }
+                None => send_error(&ctx.outgoing_tx, request_id, &channel, "not_present", "Client is not in this channel"),
+            }
+        }
+
+        "presence_set" => {
+            let channel = client_msg.channel.clone();
+            let target_client_id = client_msg.target_client_id.unwrap_or_else(|| ctx.client_id.clone());
+            let Some(set) = client_msg.set.filter(|set| !set.is_empty()) else {
+                send_error(&ctx.outgoing_tx, request_id, &channel, "invalid_request", "set is required");
+                return None;
+            };
+            let member = client_msg.member.unwrap_or(true);
+
+            // Teachers can change others' membership (e.g. lower a hand); everyone else only their own
+            let may_change_others =
+                roles::allows(state, &roles::channel_role(state, &channel, &ctx.client_id), Permission::ManageRoles);
+            if target_client_id != ctx.client_id && !may_change_others {
+                send_error(&ctx.outgoing_tx, request_id, &channel, "forbidden", "You cannot change other clients' presence sets");
+                return None;
+            }
+
+            let present = state
+                .channel_presence
+                .get(&channel)
+                .is_some_and(|channel_map| channel_map.contains_key(&target_client_id));
+            if !present {
+                send_error(&ctx.outgoing_tx, request_id, &channel, "not_present", "Client is not in this channel");
+                return None;
+            }
+
+            if let Some(members) = presence::update_set(state, &channel, &set, &target_client_id, member) {
+                presence::announce_set(state, &channel, &set, &target_client_id, member, members);
+                let change = if member { "added to" } else { "removed from" };
+                println!(
+                    "✋ Client {} {} set {} by {} in channel {}",
+                    target_client_id, change, set, ctx.client_id, channel
+                );
+            }
+        }
+
+        "presence_update" => {
+            // A participant changing how they appear on the roster; without metadata it's cleared
+            let channel = client_msg.channel.clone();
+            let metadata = match client_msg.metadata.as_ref().map(|metadata| metadata::validate(state, metadata)).transpose() {
+                Ok(metadata) => metadata.filter(|metadata| !metadata.is_empty()),
+                Err(e) => {
+                    send_error(&ctx.outgoing_tx, request_id, &channel, "invalid_metadata", &e.to_string());
+                    return None;
+                }
+            };
+
+            let expires_at = metadata::expiries(metadata.as_ref(), client_msg.metadata_ttl_secs.as_ref());
+            match presence::set_metadata(state, &channel, &ctx.client_id, metadata, expires_at) {
+                Some(info) => {
+                    presence::announce(state, &channel, "presence_update", &info);
+                    println!("🪪 Client {} updated its presence metadata in channel {}", ctx.client_id, channel);
+                }
+                None => send_error(&ctx.outgoing_tx, request_id, &channel, "not_present", "Subscribe to the channel before updating presence"),
+            }
+        }
+
+        "set_sticky_message" => {
+            let channel = client_msg.channel.clone();
+
+            if !roles::allows(state, &roles::channel_role(state, &channel, &ctx.client_id), Permission::StickyMessage) {
+                send_error(&ctx.outgoing_tx, request_id, &channel, "forbidden", "Your role cannot set this channel's sticky message");
+                return None;
+            }
+
+            // Without data the sticky message is cleared
+            let code = match client_msg.data.filter(|data| !data.is_null()) {
+                Some(data) => {
+                    sticky::set(state, &channel, data, &ctx.client_id);
+                    println!("📌 Sticky message set on channel {} by client {}", channel, ctx.client_id);
+                    "sticky_message_set"
+                }
+                None => {
+                    if sticky::clear(state, &channel) {
+                        println!("📌 Sticky message cleared on channel {} by client {}", channel, ctx.client_id);
+                    }
+                    "sticky_message_cleared"
+                }
+            };
+            send_direct(&ctx.outgoing_tx, request_id, &channel, "info", serde_json::json!({ "code": code }));
+        }
+
+        "schedule" => {
+            let channel = client_msg.channel.clone();
+
+            if !roles::allows(state, &roles::channel_role(state, &channel, &ctx.client_id), Permission::Publish) {
+                send_error(&ctx.outgoing_tx, request_id, &channel, "forbidden", "Your role cannot publish on this channel");
+                return None;
+            }
+            let Some(deliver_at) = client_msg.deliver_at else {
+                send_error(&ctx.outgoing_tx, request_id, &channel, "invalid_request", "schedule needs a deliver_at");
+                return None;
+            };
+            let deliver_at = skew::to_server_time(state, &ctx.clock_skew, deliver_at);
+
+            let correlation_id = client_msg.correlation_id.unwrap_or_else(|| Uuid::new_v4().to_string());
+            let request = scheduled::Request {
+                channel: channel.clone(),
+                data: client_msg.data.unwrap_or(serde_json::json!({})),
+                deliver_at,
+                scheduled_by: ctx.client_id.clone(),
+                identity: ctx.identity.id.clone(),
+                correlation_id: correlation_id.clone(),
+                priority: client_msg.priority.unwrap_or_default(),
+                incompressible: client_msg.incompressible.unwrap_or(false),
+            };
+            match scheduled::schedule(state, request) {
+                Ok(schedule_id) => {
+                    println!(
+                        "⏰ Message scheduled on channel {} by client {} for {} (schedule_id {})",
+                        channel, ctx.client_id, deliver_at, schedule_id
+                    );
+                    send_direct(
+                        &ctx.outgoing_tx,
+                        request_id,
+                        &channel,
+                        "info",
+                        serde_json::json!({
+                            "code": "scheduled",
+                            "schedule_id": schedule_id,
+                            "deliver_at": deliver_at,
+                            "correlation_id": correlation_id,
+                        }),
+                    );
+                }
+                Err(scheduled::ScheduleError::InvalidTime(reason)) => {
+                    send_error(&ctx.outgoing_tx, request_id, &channel, "invalid_request", reason);
+                }
+                Err(scheduled::ScheduleError::ChannelFull(limit)) => {
+                    send_error(
+                        &ctx.outgoing_tx,
+                        request_id,
+                        &channel,
+                        "schedule_limit_exceeded",
+                        &format!("Channel already has {} scheduled messages", limit),
+                    );
+                }
+                Err(scheduled::ScheduleError::ServerFull(limit)) => {
+                    send_error(
+                        &ctx.outgoing_tx,
+                        request_id,
+                        &channel,
+                        "schedule_limit_exceeded",
+                        &format!("The server already holds {} scheduled messages", limit),
+                    );
+                }
+            }
+        }
+
+        "cancel_schedule" => {
+            let channel = client_msg.channel.clone();
+            let Some(schedule_id) = client_msg.schedule_id else {
+                send_error(&ctx.outgoing_tx, request_id, &channel, "invalid_request", "cancel_schedule needs a schedule_id");
+                return None;
+            };
+
+            // Moderators may withdraw anyone's scheduled message; others only their own
+            let may_manage =
+                roles::allows(state, &roles::channel_role(state, &channel, &ctx.client_id), Permission::ManageRoles);
+            match scheduled::cancel(state, &channel, &schedule_id, &ctx.client_id, ctx.identity.id.as_deref(), may_manage) {
+                Ok(()) => {
+                    println!("⏰ Scheduled message {} on channel {} cancelled by client {}", schedule_id, channel, ctx.client_id);
+                    send_direct(
+                        &ctx.outgoing_tx,
+                        request_id,
+                        &channel,
+                        "info",
+                        serde_json::json!({ "code": "schedule_cancelled", "schedule_id": schedule_id }),
+                    );
+                }
+                Err(scheduled::CancelError::UnknownSchedule) => {
+                    send_error(&ctx.outgoing_tx, request_id, &channel, "unknown_schedule", "No scheduled message with this id on the channel");
+                }
+                Err(scheduled::CancelError::NotOwner) => {
+                    send_error(&ctx.outgoing_tx, request_id, &channel, "forbidden", "You cannot cancel another client's scheduled message");
+                }
+            }
+        }
+
+        "start_poll" => {
+            let channel = client_msg.channel.clone();
+
+            if !roles::allows(state, &roles::channel_role(state, &channel, &ctx.client_id), Permission::Poll) {
+                send_error(&ctx.outgoing_tx, request_id, &channel, "forbidden", "Your role cannot run polls on this channel");
+                return None;
+            }
+
+            let data = client_msg.data.unwrap_or(serde_json::json!({}));
+            match polls::start(state, &channel, &data, &ctx.client_id) {
+                Ok(poll_id) => {
+                    println!("🗳️ Poll {} started on channel {} by client {}", poll_id, channel, ctx.client_id);
+                    send_direct(
+                        &ctx.outgoing_tx,
+                        request_id,
+                        &channel,
+                        "info",
+                        serde_json::json!({ "code": "poll_started", "poll_id": poll_id }),
+                    );
+                }
+                Err(polls::StartError::Invalid(reason)) => {
+                    send_error(&ctx.outgoing_tx, request_id, &channel, "invalid_request", reason);
+                }
+                Err(polls::StartError::TooMany) => {
+                    send_error(&ctx.outgoing_tx, request_id, &channel, "too_many_polls", "Close one of the channel's open polls first");
+                }
+            }
+        }
+
+        "poll_vote" => {
+            let channel = client_msg.channel.clone();
+
+            if !ctx.subscriptions.contains_key(&channel) {
+                send_error(&ctx.outgoing_tx, request_id, &channel, "not_subscribed", "Subscribe to the channel first");
+                return None;
+            }
+            if !roles::allows(state, &roles::channel_role(state, &channel, &ctx.client_id), Permission::Publish) {
+                send_error(&ctx.outgoing_tx, request_id, &channel, "forbidden", "Your role cannot vote on this channel");
+                return None;
+            }
+            let (Some(poll_id), Some(option)) = (client_msg.poll_id, client_msg.option) else {
+                send_error(&ctx.outgoing_tx, request_id, &channel, "invalid_request", "poll_vote needs a poll_id and an option");
+                return None;
+            };
+
+            // One vote per person, whichever connection they vote from
+            let voter = ctx.identity.id.as_deref().unwrap_or(&ctx.client_id);
+            match polls::vote(state, &channel, &poll_id, voter, option) {
+                Ok(()) => send_direct(
+                    &ctx.outgoing_tx,
+                    request_id,
+                    &channel,
+                    "info",
+                    serde_json::json!({ "code": "vote_recorded", "poll_id": poll_id, "option": option }),
+                ),
+                Err(polls::VoteError::UnknownPoll) => {
+                    send_error(&ctx.outgoing_tx, request_id, &channel, "unknown_poll", "No poll with this id on the channel");
+                }
+                Err(polls::VoteError::Closed) => {
+                    send_error(&ctx.outgoing_tx, request_id, &channel, "poll_closed", "The poll is closed");
+                }
+                Err(polls::VoteError::AlreadyVoted) => {
+                    send_error(&ctx.outgoing_tx, request_id, &channel, "already_voted", "You already voted in this poll");
+                }
+                Err(polls::VoteError::InvalidOption) => {
+                    send_error(&ctx.outgoing_tx, request_id, &channel, "invalid_option", "The poll has no such option");
+                }
+            }
+        }
+
+        "close_poll" => {
+            let channel = client_msg.channel.clone();
+
+            if !roles::allows(state, &roles::channel_role(state, &channel, &ctx.client_id), Permission::Poll) {
+                send_error(&ctx.outgoing_tx, request_id, &channel, "forbidden", "Your role cannot run polls on this channel");
+                return None;
+            }
+            let Some(poll_id) = client_msg.poll_id else {
+                send_error(&ctx.outgoing_tx, request_id, &channel, "invalid_request", "close_poll needs a poll_id");
+                return None;
+            };
+
+            match polls::close(state, &channel, &poll_id) {
+                Ok(()) => println!("🗳️ Poll {} on channel {} closed by client {}", poll_id, channel, ctx.client_id),
+                Err(polls::CloseError::UnknownPoll) => {
+                    send_error(&ctx.outgoing_tx, request_id, &channel, "unknown_poll", "No poll with this id on the channel");
+                }
+                Err(polls::CloseError::AlreadyClosed) => {
+                    send_error(&ctx.outgoing_tx, request_id, &channel, "poll_closed", "The poll is already closed");
+                }
+            }
+        }
+
+        "set_role" => {
+            let channel = client_msg.channel.clone();
+            let target_client_id = client_msg.target_client_id.unwrap_or_else(|| ctx.client_id.clone());
+            let Some(new_role) = client_msg.role else {
+                send_error(&ctx.outgoing_tx, request_id, &channel, "invalid_request", "role is required");
+                return None;
+            };
+
+            match roles::change_role(state, &channel, &ctx.client_id, &target_client_id, &new_role) {
+                Ok(info) => {
+                    presence::announce(state, &channel, "presence_update", &info);
+                    println!(
+                        "🎓 Client {} set role of {} to {} in channel {}",
+                        ctx.client_id, target_client_id, new_role, channel
+                    );
+                }
+                Err(RoleChangeError::UnknownRole) => {
+                    send_error(&ctx.outgoing_tx, request_id, &channel, "invalid_role", "Unknown role");
+                }
+                Err(RoleChangeError::TooManyRoles) => {
+                    send_error(&ctx.outgoing_tx, request_id, &channel, "invalid_role", "Channel already has the maximum number of distinct roles");
+                }
+                Err(RoleChangeError::PresenterTaken) => {
+                    send_error(&ctx.outgoing_tx, request_id, &channel, "presenter_taken", "Channel already has a presenter; use transfer_role");
+                }
+                Err(RoleChangeError::NotPresent) => {
+                    send_error(&ctx.outgoing_tx, request_id, &channel, "not_present", "Client is not in this channel");
+                }
+                Err(RoleChangeError::Forbidden) => {
+                    send_error(&ctx.outgoing_tx, request_id, &channel, "forbidden", "You cannot grant this role");
+                }
+            }
+        }
+
+        "transfer_role" => {
+            let channel = client_msg.channel.clone();
+            let Some(target_client_id) = client_msg.target_client_id.filter(|target| *target != ctx.client_id) else {
+                send_error(&ctx.outgoing_tx, request_id, &channel, "invalid_request", "target_client_id of another client is required");
+                return None;
+            };
+
+            match roles::transfer_role(state, &channel, &ctx.client_id, &target_client_id) {
+                Ok((caller, target, devices)) => {
+                    presence::announce(state, &channel, "presence_update", &target);
+                    presence::announce(state, &channel, "presence_update", &caller);
+                    for device in &devices {
+                        presence::announce(state, &channel, "presence_update", device);
+                    }
+                    broadcast_event(
+                        state,
+                        &channel,
+                        "role_transferred",
+                        serde_json::json!({
+                            "from_client_id": ctx.client_id,
+                            "to_client_id": target_client_id,
+                            "role": target.role,
+                            "demoted_to": caller.role,
+                        }),
+                    );
+                    println!(
+                        "🎓 Client {} transferred role {} to {} in channel {}",
+                        ctx.client_id, target.role, target_client_id, channel
+                    );
+                }
+                Err(roles::TransferError::NotPresent) => {
+                    send_error(&ctx.outgoing_tx, request_id, &channel, "not_present", "Both clients must be in this channel");
+                }
+                Err(roles::TransferError::Forbidden) => {
+                    send_error(&ctx.outgoing_tx, request_id, &channel, "forbidden", "Only a higher-ranked manager can transfer their role");
+                }
+            }
+        }
+
+        "acquire_presenter" => {
+            let channel = client_msg.channel.clone();
+            if !state.config.presenter_lock {
+                send_error(&ctx.outgoing_tx, request_id, &channel, "invalid_request", "The presenter lock is not enabled");
+                return None;
+            }
+            if !ctx.subscriptions.contains_key(&channel) {
+                send_error(&ctx.outgoing_tx, request_id, &channel, "not_subscribed", "Subscribe to the channel first");
+                return None;
+            }
+            if !roles::allows(state, &roles::channel_role(state, &channel, &ctx.client_id), Permission::SlideChange) {
+                send_error(&ctx.outgoing_tx, request_id, &channel, "forbidden", "Your role cannot change slides on this channel");
+                return None;
+            }
+
+            match presenter::acquire(state, &channel, &ctx.client_id) {
+                Ok(acquired) => {
+                    if acquired {
+                        println!("🎤 Client {} is now presenting on channel {}", ctx.client_id, channel);
+                    }
+                    send_direct(&ctx.outgoing_tx, request_id, &channel, "presenter", serde_json::json!({ "client_id": ctx.client_id }));
+                }
+                Err(holder) => {
+                    send_error(&ctx.outgoing_tx, request_id, &channel, "presenter_locked", &format!("Client {} is presenting; ask them to hand over", holder));
+                }
+            }
+        }
+
+        "release_presenter" => {
+            let channel = client_msg.channel.clone();
+            if !presenter::release(state, &channel, &ctx.client_id) {
+                send_error(&ctx.outgoing_tx, request_id, &channel, "not_presenter", "You don't hold the presenter lock");
+                return None;
+            }
+            println!("🎤 Client {} stopped presenting on channel {}", ctx.client_id, channel);
+        }
+
+        "transfer_presenter" => {
+            let channel = client_msg.channel.clone();
+            let Some(target_client_id) = client_msg.target_client_id.filter(|target| *target != ctx.client_id) else {
+                send_error(&ctx.outgoing_tx, request_id, &channel, "invalid_request", "target_client_id of another client is required");
+                return None;
+            };
+
+            match presenter::transfer(state, &channel, &ctx.client_id, &target_client_id) {
+                Ok(()) => println!("🎤 Client {} handed presenting on channel {} to {}", ctx.client_id, channel, target_client_id),
+                Err(presenter::TransferError::NotHolder) => {
+                    send_error(&ctx.outgoing_tx, request_id, &channel, "not_presenter", "You don't hold the presenter lock");
+                }
+                Err(presenter::TransferError::InvalidTarget) => {
+                    send_error(&ctx.outgoing_tx, request_id, &channel, "invalid_target", "The target must be online in this channel and allowed to change slides");
+                }
+            }
+        }
+
+        "list_subscriptions" => {
+            let mut channels: Vec<&String> = ctx.subscriptions.keys().collect();
+            channels.sort();
+
+            let subscribed = channels
+                .into_iter()
+                .map(|channel| {
+                    serde_json::json!({
+                        "channel": channel,
+                        "role": roles::channel_role(state, channel, &ctx.client_id)
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            send_direct(&ctx.outgoing_tx, request_id, "", "subscriptions", serde_json::json!({ "channels": subscribed }));
+        }
+
+        "heartbeat" => {
+            // Keeps this connection's presence fresh where proxies swallow WebSocket pings
+            for channel in ctx.subscriptions.keys() {
+                if let Some(info) = presence::heartbeat(state, channel, &ctx.client_id) {
+                    presence::announce(state, channel, "presence_update", &info);
+                }
+            }
+            send_direct(
+                &ctx.outgoing_tx,
+                request_id,
+                "",
+                "heartbeat_ack",
+                serde_json::json!({ "server_time": clock::now().timestamp_millis() }),
+            );
+        }
+
+        "time_sync" => {
+            // Answered on the control queue so queued broadcasts don't skew the round trip
+            let client_time = client_msg.data.as_ref().and_then(|data| data.get("client_time")).cloned();
+            let server_time = clock::now().timestamp_millis();
+            if let Some(client_time) = client_time.as_ref().and_then(|client_time| client_time.as_i64()) {
+                ctx.clock_skew.sample(client_time, server_time);
+            }
+            let reply = ServerMessage {
+                request_id: request_id.map(str::to_string),
+                ..ServerMessage::new(
+                    "time_sync",
+                    "",
+                    serde_json::json!({
+                        "client_time": client_time,
+                        "server_time": server_time,
+                        "server_monotonic_ms": state.stats.uptime().as_secs_f64() * 1000.0,
+                        "clock_skew_ms": ctx.clock_skew.ms(),
+                    }),
+                )
+            };
+            if let Some(reply) = reply.to_json() {
+                let _ = ctx.control_tx.send(Message::Text(reply.into()));
+            }
+        }
+
+        "diagnostic" => {
+            let now = tokio::time::Instant::now();
+            let mut channels: Vec<&String> = ctx.subscriptions.keys().collect();
+            channels.sort();
+
+            let subscribed = channels
+                .into_iter()
+                .map(|channel| {
+                    let presence = state
+                        .channel_presence
+                        .get(channel.as_str())
+                        .and_then(|channel_map| channel_map.get(&ctx.client_id).map(|info| info.status.clone()));
+                    // One slide change per interval: a budget of one, back once the interval is up
+                    let rate_limit = channel_config::slide_interval(state, channel).map(|interval| {
+                        let throttle = ctx.slide_throttles.get(channel.as_str());
+                        let refill = throttle
+                            .map(|throttle| (throttle.last_sent + throttle.interval).saturating_duration_since(now))
+                            .unwrap_or_default();
+                        serde_json::json!({
+                            "action": "slide_change",
+                            "max_per_sec": 1.0 / interval.as_secs_f64(),
+                            "remaining": u32::from(refill.is_zero()),
+                            "refill_ms": refill.as_millis() as u64,
+                            "held": throttle.is_some_and(|throttle| throttle.pending.is_some()),
+                        })
+                    });
+                    serde_json::json!({
+                        "channel": channel,
+                        "role": roles::channel_role(state, channel, &ctx.client_id),
+                        "presence": presence,
+                        "epoch": epoch::current(state, channel),
+                        "seq": state.channel_seq.get(channel.as_str()).map(|seq| *seq).unwrap_or(0),
+                        "rate_limit": rate_limit,
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            let report = ServerMessage {
+                request_id: request_id.map(str::to_string),
+                ..ServerMessage::new(
+                    "diagnostic_report",
+                    "",
+                    serde_json::json!({
+                        "client_id": ctx.client_id,
+                        "instance": state.config.instance_id,
+                        "protocol_version": PROTOCOL_VERSION,
+                        "server_time": clock::now().timestamp_millis(),
+                        "identity": ctx.identity.id,
+                        "tier": tiers::of(state, &ctx.identity),
+                        "format": if ctx.format == encoding::WireFormat::Cbor { "cbor" } else { "json" },
+                        "cohort": ctx.format.cohort(),
+                        "compression": if ctx.compress { "dictionary" } else { "none" },
+                        "rtt": ctx.client_rtt.summary(),
+                        "clock_skew_ms": ctx.clock_skew.ms(),
+                        "queued": ctx.outgoing_tx.max_capacity() - ctx.outgoing_tx.capacity(),
+                        "queue_capacity": ctx.outgoing_tx.max_capacity(),
+                        "subscriptions": subscribed,
+                    }),
+                )
+            };
+            // On the control queue, so a backed-up connection still gets its report
+            if let Some(report) = report.to_json() {
+                let _ = ctx.control_tx.send(Message::Text(report.into()));
+            }
+        }
+
+        "ready_to_migrate" => {
+            let Some(reason) = ctx.migration.take() else {
+                send_error(&ctx.outgoing_tx, request_id, "", "invalid_request", "No migration is pending");
+                return None;
+            };
+            println!("🤝 Client {} is ready to migrate", ctx.client_id);
+            return Some(DisconnectReason::Server(reason));
+        }
+
+        "skip_backlog" => {
+            let channels: Vec<String> = ctx.subscriptions.keys().cloned().collect();
+            let marker_state = state.clone();
+            let marker_client_id = ctx.client_id.clone();
+            let marker_skipped_through = ctx.skipped_through.clone();
+            let request_id = request_id.map(str::to_string);
+            let _ = ctx.skip_tx.send(Box::new(move |dropped| {
+                backlog_skipped(&marker_state, &marker_client_id, &channels, &marker_skipped_through, request_id, dropped)
+            }));
+        }
+
+        _ => {
+            println!("❓ Unknown action: {} from client {}", client_msg.action, ctx.client_id);
         }
     }
 
-    // Cleanup
-    sender_handle.abort();
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{TestClient, TestServer};
+
+    #[test]
+    fn serialization_failures_are_counted_not_panicked_on() {
+        // JSON object keys must be strings
+        let unserializable = HashMap::from([(vec![1u8, 2], "value")]);
+        let before = metrics::SERIALIZATION_FAILURES.load(Ordering::Relaxed);
+
+        assert_eq!(serialize(&unserializable, "message", "lesson"), None);
+        assert!(metrics::SERIALIZATION_FAILURES.load(Ordering::Relaxed) > before);
+    }
+
+    #[test]
+    fn server_messages_lead_with_the_envelope_version() {
+        let msg = ServerMessage::new("message", "lesson", serde_json::json!({ "text": "hi" }));
+        let json = msg.to_json().expect("serializes");
+        assert!(json.starts_with(&format!("{{\"v\":{},", PROTOCOL_VERSION)), "{}", json);
+
+        let envelope: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(envelope["v"], PROTOCOL_VERSION);
+        assert_eq!(envelope["type"], "message");
+    }
+
+    #[tokio::test]
+    async fn a_client_that_stops_reading_is_disconnected_after_the_send_timeout() {
+        let server = TestServer::start(|config| config.send_timeout_ms = 300).await;
+        let mut stalled = server.connect("").await;
+        stalled.subscribe("lesson", serde_json::json!({})).await;
+        let mut teacher = server.connect("").await;
+
+        // Far more than the socket buffers hold, so the writer's send to `stalled` blocks
+        let filler = "x".repeat(256 << 10);
+        for _ in 0..128 {
+            teacher.send(serde_json::json!({ "action": "publish", "channel": "lesson", "data": filler })).await;
+        }
+
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while server.state.clients.contains_key(&stalled.client_id) {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("the stalled client was never disconnected");
+        let timeouts = server.state.metrics.disconnects.get("send_timeout").map(|count| count.load(Ordering::Relaxed));
+        assert_eq!(timeouts, Some(1));
+        assert!(server.state.clients.contains_key(&teacher.client_id));
+    }
+
+    // The number of connections that ended for a reason, once it reaches `expected`
+    async fn disconnects(state: &AppState, reason: &str, expected: u64) -> u64 {
+        let count = || state.metrics.disconnects.get(reason).map_or(0, |count| count.load(Ordering::Relaxed));
+        let _ = tokio::time::timeout(Duration::from_secs(5), async {
+            while count() < expected {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await;
+        count()
+    }
+
+    #[tokio::test]
+    async fn each_disconnect_is_counted_once_by_reason() {
+        let server = TestServer::start(|config| config.admin_token = Some("admin-secret".to_string())).await;
+
+        let mut kicked = server.connect("").await;
+        let (status, _) = server.request("DELETE", &format!("/admin/clients/{}", kicked.client_id), Some("admin-secret")).await;
+        assert_eq!(status, 200);
+        assert_eq!(kicked.close_frame().await, Some((1008, "kicked".to_string())));
+        assert_eq!(disconnects(&server.state, "kicked", 1).await, 1);
+
+        let mut leaving = server.connect("").await;
+        leaving.close().await;
+        assert_eq!(disconnects(&server.state, "client_closed", 1).await, 1);
+
+        // Nothing else was counted for either connection
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let total: u64 = server.state.metrics.disconnects.iter().map(|count| count.load(Ordering::Relaxed)).sum();
+        assert_eq!(total, 2);
 
-    println!("🔌 Client {} disconnected", client_id);
+        let (_, metrics) = server.request("GET", "/metrics", None).await;
+        assert!(metrics.contains("rably_disconnects_total{reason=\"client_closed\"} 1"), "{}", metrics);
+        assert!(metrics.contains("rably_disconnects_total{reason=\"kicked\"} 1"), "{}", metrics);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_large_replay_does_not_get_a_new_subscriber_dropped_as_too_slow() {
+        const HISTORY: usize = 3000;
+        const BURST: usize = 128;
+        let server = TestServer::start(|config| {
+            config.history_size = HISTORY;
+            config.store_without_subscribers = true;
+            config.outgoing_queue_size = 4;
+            // Longer than publishing the burst takes, so only the replay could trip it
+            config.slow_consumer_grace_ms = 3000;
+        })
+        .await;
+        let payload = "x".repeat(4 << 10);
+        for index in 0..HISTORY {
+            let msg = ServerMessage::new("message", "lesson", serde_json::json!({ "index": index, "payload": payload }));
+            send_to_channel(&server.state, msg, "teacher");
+        }
+
+        // A burst of live traffic, more than the socket buffers hold, fills the queue first, so
+        // the slow-consumer check already has it down as full when the replay starts
+        let mut student = server.connect("").await;
+        student.subscribe("announcements", serde_json::json!({})).await;
+        let burst = "x".repeat(256 << 10);
+        for _ in 0..BURST {
+            send_to_channel(&server.state, ServerMessage::new("message", "announcements", serde_json::json!(burst)), "teacher");
+        }
+        tokio::time::sleep(Duration::from_millis(SLOW_CONSUMER_CHECK_MS + 50)).await;
+
+        student.send(serde_json::json!({ "action": "subscribe", "channel": "lesson" })).await;
+        let (mut replayed, mut announced, mut subscribed) = (0, 0, false);
+        while !subscribed || replayed < HISTORY || announced < BURST {
+            let msg = student.next().await.expect("the new subscriber was disconnected during the replay");
+            match (msg["type"].as_str(), msg["channel"].as_str()) {
+                (Some("message"), Some("lesson")) => {
+                    assert_eq!(msg["data"]["index"], replayed);
+                    replayed += 1;
+                }
+                (Some("message"), Some("announcements")) => announced += 1,
+                (Some("subscribed"), Some("lesson")) => subscribed = true,
+                _ => {}
+            }
+        }
+
+        // Still connected, and live traffic still arrives
+        send_to_channel(&server.state, ServerMessage::new("message", "lesson", serde_json::json!("live")), "teacher");
+        assert_eq!(student.expect("message").await["data"], "live");
+        assert!(server.state.metrics.disconnects.get("too_slow").is_none());
+    }
+
+    #[tokio::test]
+    async fn the_http_history_leaves_out_staff_only_messages() {
+        let server = TestServer::start(|config| config.history_size = 10).await;
+        let mut teacher = server.connect("").await;
+        teacher.subscribe("lesson", serde_json::json!({ "role": "teacher" })).await;
+        teacher.send(serde_json::json!({ "action": "publish", "channel": "lesson", "data": "page 4", "min_role": "observer" })).await;
+        teacher.send(serde_json::json!({ "action": "publish", "channel": "lesson", "data": "welcome" })).await;
+        assert_eq!(teacher.expect("message").await["data"], "page 4");
+        assert_eq!(teacher.expect("message").await["data"], "welcome");
+
+        let (status, body) = server.request("GET", "/channels/lesson/history", None).await;
+        assert_eq!(status, 200);
+        let history: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let messages = history["messages"].as_array().expect("messages");
+        assert_eq!(messages.len(), 1, "{}", body);
+        assert_eq!(messages[0]["data"], "welcome");
+    }
+
+    #[tokio::test]
+    async fn the_http_history_is_kept_to_the_callers_tenant() {
+        let server = TestServer::start(|config| {
+            config.history_size = 10;
+            config.tenancy = tenancy::Tenancy::Prefix;
+        })
+        .await;
+        send_to_channel(&server.state, ServerMessage::new("message", "acme:lesson", serde_json::json!("hi")), "teacher");
+
+        // An anonymous caller belongs to no tenant
+        let (status, body) = server.request("GET", "/channels/acme:lesson/history", None).await;
+        assert_eq!(status, 403, "{}", body);
+        assert!(!body.contains("hi"), "{}", body);
+        let (status, _) = server.request("GET", "/channels/lesson/history", None).await;
+        assert_eq!(status, 200);
+    }
+
+    // The rate_limit a diagnostic_report gives for the client's only subscription
+    async fn budget(client: &mut TestClient) -> serde_json::Value {
+        client.send(serde_json::json!({ "action": "diagnostic" })).await;
+        client.expect("diagnostic_report").await["data"]["subscriptions"][0]["rate_limit"].clone()
+    }
+
+    #[tokio::test]
+    async fn the_diagnostic_report_shows_the_remaining_rate_limit_budget() {
+        let server = TestServer::start(|config| config.slide_change_max_per_sec = 1.0).await;
+        let mut teacher = server.connect("").await;
+        teacher.subscribe("lesson", serde_json::json!({ "role": "teacher" })).await;
+        let full = budget(&mut teacher).await;
+        assert_eq!((full["max_per_sec"].as_f64(), full["remaining"].as_u64(), full["refill_ms"].as_u64()), (Some(1.0), Some(1), Some(0)));
+
+        for slide in 1..=2 {
+            teacher.send(serde_json::json!({ "action": "slide_change", "channel": "lesson", "data": { "slide": slide } })).await;
+        }
+        let spent = budget(&mut teacher).await;
+        assert_eq!(spent["remaining"], 0);
+        let refill_ms = spent["refill_ms"].as_u64().expect("refill_ms");
+        assert!(refill_ms > 0 && refill_ms <= 1000, "{}", spent);
+        assert_eq!(spent["held"], true);
+
+        // Unlimited channels have no budget to report
+        let server = TestServer::start(|_| {}).await;
+        let mut teacher = server.connect("").await;
+        teacher.subscribe("lesson", serde_json::json!({ "role": "teacher" })).await;
+        assert!(budget(&mut teacher).await.is_null());
+    }
 }
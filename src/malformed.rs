@@ -0,0 +1,107 @@
+// Client messages that can't be read.
+
+use serde_json::error::Category;
+
+use crate::{send_direct, Outgoing};
+
+// Byte offset of a 1-based line and column in the text, clamped to its length
+fn offset(text: &str, line: usize, column: usize) -> usize {
+    let line_start: usize = text.split_inclusive('\n').take(line.saturating_sub(1)).map(str::len).sum();
+    (line_start + column.saturating_sub(1)).min(text.len())
+}
+
+// The request_id of a message that parsed as JSON but not as a message, to echo on the error
+fn request_id(text: &str) -> Option<String> {
+    match serde_json::from_str::<serde_json::Value>(text).ok()?.get("request_id")? {
+        serde_json::Value::String(id) => Some(id.clone()),
+        _ => None,
+    }
+}
+
+// Tell the client why its message couldn't be read
+pub fn report(outgoing_tx: &Outgoing, text: &str, e: serde_json::Error) {
+    // Text cut short is reported at its end, past the last character read
+    let offset = match e.classify() {
+        Category::Syntax => offset(text, e.line(), e.column()),
+        Category::Eof => text.len(),
+        Category::Data | Category::Io => {
+            let error = serde_json::json!({ "code": "invalid_message", "message": e.to_string() });
+            send_direct(outgoing_tx, request_id(text).as_deref(), "", "error", error);
+            return;
+        }
+    };
+    let error = serde_json::json!({
+        "code": "invalid_json",
+        "message": e.to_string(),
+        "line": e.line(),
+        "column": e.column(),
+        "offset": offset,
+    });
+    send_direct(outgoing_tx, None, "", "error", error);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestServer;
+
+    #[test]
+    fn offsets_count_bytes_from_the_start_of_the_text() {
+        let text = "{\n  \"action\": ,\n}";
+        assert_eq!(offset(text, 1, 1), 0);
+        assert_eq!(offset(text, 2, 13), 14);
+        assert_eq!(&text[offset(text, 2, 13)..][..1], ",");
+        // Past the end is clamped
+        assert_eq!(offset(text, 9, 9), text.len());
+    }
+
+    #[tokio::test]
+    async fn a_message_split_across_frames_is_reassembled() {
+        let server = TestServer::start(|_| {}).await;
+        let mut teacher = server.connect("").await;
+        teacher.subscribe("lesson", serde_json::json!({})).await;
+        let mut student = server.connect("").await;
+        student.subscribe("lesson", serde_json::json!({})).await;
+
+        // Split inside multi-byte characters as well
+        let notes = "Schrödinger’s cat ".repeat(8 << 10);
+        let publish = serde_json::json!({ "action": "publish", "channel": "lesson", "data": { "notes": notes } });
+        teacher.send_fragmented(&publish.to_string(), 4093).await;
+        assert_eq!(student.expect("message").await["data"]["notes"], notes.as_str());
+    }
+
+    #[tokio::test]
+    async fn truncated_json_is_reported_where_it_ends() {
+        let server = TestServer::start(|_| {}).await;
+        let mut client = server.connect("").await;
+
+        let text = r#"{"action": "publish", "channel": "lesson", "data": {"slide": 3"#;
+        client.send_text(text).await;
+        let error = client.expect("error").await;
+        assert_eq!(error["data"]["code"], "invalid_json");
+        assert_eq!(error["data"]["line"], 1);
+        assert_eq!(error["data"]["column"], text.len());
+        assert_eq!(error["data"]["offset"], text.len());
+        assert!(error["data"]["message"].as_str().is_some_and(|message| message.contains("EOF")), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn a_syntax_error_is_reported_at_its_location() {
+        let server = TestServer::start(|_| {}).await;
+        let mut client = server.connect("").await;
+
+        let text = "{\n  \"action\": \"publish\",\n  \"channel\": lesson\n}";
+        client.send_text(text).await;
+        let error = client.expect("error").await;
+        assert_eq!(error["data"]["code"], "invalid_json");
+        assert_eq!(error["data"]["line"], 3);
+        assert_eq!(error["data"]["column"], 14);
+        assert_eq!(&text[error["data"]["offset"].as_u64().expect("offset") as usize..][..1], "l");
+
+        // Valid JSON that isn't a message echoes its request_id instead
+        client.send_text(r#"{"request_id": "r-1", "action": 7}"#).await;
+        let error = client.expect("error").await;
+        assert_eq!(error["data"]["code"], "invalid_message");
+        assert_eq!(error["request_id"], "r-1");
+    }
+}
@@ -0,0 +1,99 @@
+// Memory watermark guard.
+
+use std::{
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::{tiers, AppState};
+
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+// Rough per-item costs behind the count-based estimate
+const CONNECTION_BYTES: u64 = 64 * 1024;
+const CHANNEL_BYTES: u64 = 16 * 1024;
+const PRESENCE_ENTRY_BYTES: u64 = 512;
+const QUEUED_MESSAGE_BYTES: u64 = 1024;
+
+#[derive(Default)]
+pub struct MemoryGuard {
+    estimate_bytes: AtomicU64,
+    over_limit: AtomicBool,
+}
+
+impl MemoryGuard {
+    pub fn estimate_bytes(&self) -> u64 {
+        self.estimate_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn over_limit(&self) -> bool {
+        self.over_limit.load(Ordering::Relaxed)
+    }
+}
+
+fn estimate(state: &AppState) -> u64 {
+    let connections = state.clients.len() as u64;
+    let channels = state.channels.len() as u64;
+    let presence: u64 = state.channel_presence.iter().map(|channel_map| channel_map.len() as u64).sum();
+    let queued: u64 = state
+        .clients
+        .iter()
+        .map(|client| (client.outgoing.max_capacity() - client.outgoing.capacity()) as u64)
+        .sum();
+    let history: u64 = state.history_bytes.iter().map(|bytes| *bytes as u64).sum();
+
+    connections * CONNECTION_BYTES
+        + channels * CHANNEL_BYTES
+        + presence * PRESENCE_ENTRY_BYTES
+        + queued * QUEUED_MESSAGE_BYTES
+        // Each history entry holds the message and its serialized form
+        + history * 2
+}
+
+// Resident set size from /proc, where available
+fn resident_bytes() -> Option<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    Some(pages * 4096)
+}
+
+pub fn spawn_sampler(state: AppState) {
+    let limit_bytes = state.config.memory_limit_mb * 1024 * 1024;
+    if limit_bytes == 0 {
+        return;
+    }
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SAMPLE_INTERVAL);
+        loop {
+            interval.tick().await;
+
+            let used = if state.config.memory_use_rss {
+                resident_bytes().unwrap_or_else(|| estimate(&state))
+            } else {
+                estimate(&state)
+            };
+            state.memory.estimate_bytes.store(used, Ordering::Relaxed);
+
+            let over = used >= limit_bytes;
+            if state.memory.over_limit.swap(over, Ordering::Relaxed) != over {
+                if over {
+                    eprintln!(
+                        "⚠️ Memory at {} MB is over the {} MB limit; refusing new connections and channels",
+                        used / 1024 / 1024,
+                        state.config.memory_limit_mb
+                    );
+                } else {
+                    println!("✅ Memory back under the {} MB limit", state.config.memory_limit_mb);
+                }
+            }
+
+            if over && state.config.memory_evictions_per_sec > 0 {
+                let evicted = tiers::evict(&state, state.config.memory_evictions_per_sec);
+                if evicted > 0 {
+                    eprintln!("⚠️ Closed {} connections to relieve memory pressure", evicted);
+                }
+            }
+        }
+    });
+}
@@ -0,0 +1,24 @@
+// Per-channel whitelists of message types.
+
+use crate::{roles, AppState};
+
+// The types the first matching rule allows, if any rule matches
+fn allowed<'a>(state: &'a AppState, channel: &str) -> Option<&'a [String]> {
+    state
+        .config
+        .channel_allowed_types
+        .iter()
+        .find(|(pattern, _)| roles::matches_pattern(pattern, channel))
+        .map(|(_, types)| types.as_slice())
+}
+
+pub fn permits(state: &AppState, channel: &str, action: &str, data: Option<&serde_json::Value>) -> bool {
+    let Some(types) = allowed(state, channel) else {
+        return true;
+    };
+    let action = if action == "schedule" { "publish" } else { action };
+    let custom = matches!(action, "publish" | "publish_quorum")
+        .then(|| data.and_then(|data| data.get("type")).and_then(|kind| kind.as_str()))
+        .flatten();
+    types.iter().any(|allowed| allowed == action || Some(allowed.as_str()) == custom)
+}
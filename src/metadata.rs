@@ -0,0 +1,200 @@
+// Client-supplied presence metadata: checked against the operator schema, sanitized or rejected
+// by policy, with optional per-key TTLs.
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    str::FromStr,
+};
+
+use crate::{clock, AppState};
+
+// What to do with metadata that breaks the schema
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MetadataPolicy {
+    Sanitize,
+    Reject,
+}
+
+impl FromStr for MetadataPolicy {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "sanitize" => Ok(MetadataPolicy::Sanitize),
+            "reject" => Ok(MetadataPolicy::Reject),
+            _ => Err(()),
+        }
+    }
+}
+
+// How a metadata field's value is checked
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldKind {
+    // Plain text such as a display name
+    Text,
+    // An http or https link such as an avatar
+    Url,
+}
+
+impl FromStr for FieldKind {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "text" => Ok(FieldKind::Text),
+            "url" => Ok(FieldKind::Url),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum MetadataError {
+    NotAnObject,
+    UnknownKey(String),
+    NotAString(String),
+    TooLong { key: String, max: usize },
+    Markup(String),
+    BadUrl(String),
+}
+
+impl fmt::Display for MetadataError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MetadataError::NotAnObject => write!(f, "metadata must be an object"),
+            MetadataError::UnknownKey(key) => write!(f, "metadata key {} is not allowed", key),
+            MetadataError::NotAString(key) => write!(f, "metadata {} must be a string", key),
+            MetadataError::TooLong { key, max } => write!(f, "metadata {} is longer than {} characters", key, max),
+            MetadataError::Markup(key) => write!(f, "metadata {} may not contain markup", key),
+            MetadataError::BadUrl(key) => write!(f, "metadata {} must be an http or https URL", key),
+        }
+    }
+}
+
+// Elements dropped together with everything inside them
+const HIDDEN_ELEMENTS: &[&str] = &["script", "style"];
+
+// The metadata to store, checked against the configured schema
+pub fn validate(state: &AppState, metadata: &Value) -> Result<Map<String, Value>, MetadataError> {
+    let reject = state.config.presence_metadata_policy == MetadataPolicy::Reject;
+    let max_len = state.config.presence_metadata_max_len;
+    let fields = metadata.as_object().ok_or(MetadataError::NotAnObject)?;
+
+    let mut clean = Map::new();
+    for (key, value) in fields {
+        let Some(kind) = state
+            .config
+            .presence_metadata_fields
+            .iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, kind)| *kind)
+        else {
+            if reject {
+                return Err(MetadataError::UnknownKey(key.clone()));
+            }
+            continue;
+        };
+
+        let text = match value {
+            Value::String(text) => text,
+            _ if reject => return Err(MetadataError::NotAString(key.clone())),
+            _ => continue,
+        };
+
+        let checked = match kind {
+            FieldKind::Text => {
+                if reject && text.chars().any(|c| c.is_control() || matches!(c, '<' | '>')) {
+                    return Err(MetadataError::Markup(key.clone()));
+                }
+                strip_markup(text)
+            }
+            FieldKind::Url => {
+                if !is_web_url(text) {
+                    if reject {
+                        return Err(MetadataError::BadUrl(key.clone()));
+                    }
+                    continue;
+                }
+                text.clone()
+            }
+        };
+
+        let checked = if checked.chars().count() <= max_len {
+            checked
+        } else if reject {
+            return Err(MetadataError::TooLong { key: key.clone(), max: max_len });
+        } else if kind == FieldKind::Url {
+            // A cut-off link points somewhere else
+            continue;
+        } else {
+            checked.chars().take(max_len).collect()
+        };
+
+        if !checked.is_empty() {
+            clean.insert(key.clone(), Value::String(checked));
+        }
+    }
+    Ok(clean)
+}
+
+// When each key that was given a TTL is to be cleared, in unix ms. TTLs for keys the
+// metadata doesn't have, or of zero, are ignored.
+pub fn expiries(metadata: Option<&Map<String, Value>>, ttl_secs: Option<&HashMap<String, u64>>) -> BTreeMap<String, i64> {
+    let (Some(metadata), Some(ttl_secs)) = (metadata, ttl_secs) else {
+        return BTreeMap::new();
+    };
+    let now = clock::now().timestamp_millis();
+    ttl_secs
+        .iter()
+        .filter(|(key, secs)| **secs > 0 && metadata.contains_key(*key))
+        .map(|(key, secs)| (key.clone(), now.saturating_add(i64::try_from(secs.saturating_mul(1000)).unwrap_or(i64::MAX))))
+        .collect()
+}
+
+// The text with tags, hidden elements and control characters removed
+fn strip_markup(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('<') {
+        out.push_str(&rest[..start]);
+        let tag = &rest[start..];
+        let Some(end) = tag.find('>') else {
+            // An unclosed tag swallows the rest, as a browser would
+            rest = "";
+            break;
+        };
+
+        let name: String = tag[1..end]
+            .chars()
+            .take_while(|c| c.is_ascii_alphanumeric())
+            .collect::<String>()
+            .to_ascii_lowercase();
+        rest = &tag[end + 1..];
+        if HIDDEN_ELEMENTS.contains(&name.as_str()) {
+            let closing = format!("</{}", name);
+            rest = match rest.to_ascii_lowercase().find(&closing) {
+                Some(close) => rest[close..].find('>').map_or("", |gt| &rest[close + gt + 1..]),
+                None => "",
+            };
+        }
+    }
+    out.push_str(rest);
+    out.retain(|c| !c.is_control() && c != '>');
+    out.trim().to_string()
+}
+
+// An absolute http(s) URL with nothing that could break out of an attribute
+fn is_web_url(text: &str) -> bool {
+    let lower = text.to_ascii_lowercase();
+    let Some(rest) = lower.strip_prefix("https://").or_else(|| lower.strip_prefix("http://")) else {
+        return false;
+    };
+    !rest.is_empty()
+        && !rest.starts_with('/')
+        && !text.chars().any(|c| c.is_whitespace() || c.is_control() || matches!(c, '"' | '\'' | '<' | '>' | '`' | '\\'))
+}
@@ -0,0 +1,337 @@
+use dashmap::DashMap;
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Instant,
+};
+
+use crate::{throughput, AppState, SUPPORTED_ACTIONS};
+
+// Upper bounds of the latency histogram buckets, in seconds
+const LATENCY_BUCKETS: [f64; 12] = [0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0];
+
+// Channels given their own throughput series; the rest would only add label cardinality
+const THROUGHPUT_CHANNELS: usize = 50;
+
+// Messages that could not be serialized; global so encoding helpers don't need AppState
+pub static SERIALIZATION_FAILURES: AtomicU64 = AtomicU64::new(0);
+
+// Process-wide counters exposed on /metrics
+#[derive(Default)]
+pub struct Metrics {
+    pub connections_shed: AtomicU64,
+    pub publishes_busy: AtomicU64,
+    pub backpressure_timeouts: AtomicU64,
+    // At-least-once messages sent again for want of an ack, and those given up on
+    pub redeliveries: AtomicU64,
+    pub undelivered: AtomicU64,
+    pub frames_encoded: AtomicU64,
+    pub frame_cache_hits: AtomicU64,
+    // Slide changes a lagging connection skipped for a newer one
+    pub slides_coalesced: AtomicU64,
+    // Messages not sent again because the participant already received them
+    pub duplicates_suppressed: AtomicU64,
+    // Processing time per client action; unrecognized actions share one series
+    pub action_latency: DashMap<&'static str, Histogram>,
+    // Ended connections by why they ended
+    pub disconnects: DashMap<&'static str, AtomicU64>,
+    // Round trips from server pings to client pongs
+    pub client_rtt: Histogram,
+    // Time spent holding a channel's presence locks while reading its roster order
+    pub presence_scan: Histogram,
+}
+
+// Cumulative Prometheus-style histogram
+#[derive(Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, seconds: f64) {
+        for (bucket, bound) in self.buckets.iter().zip(LATENCY_BUCKETS) {
+            if seconds <= bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add((seconds * 1_000_000.0) as u64, Ordering::Relaxed);
+    }
+}
+
+// Times one client action from parse to the end of its handling, recording on drop so
+// early exits are counted too
+pub struct ActionTimer<'a> {
+    metrics: &'a Metrics,
+    action: &'static str,
+    started: Instant,
+}
+
+impl<'a> ActionTimer<'a> {
+    pub fn new(metrics: &'a Metrics, action: &str, started: Instant) -> Self {
+        let action = SUPPORTED_ACTIONS
+            .iter()
+            .find(|supported| **supported == action)
+            .copied()
+            .unwrap_or("unknown");
+        ActionTimer { metrics, action, started }
+    }
+}
+
+impl Drop for ActionTimer<'_> {
+    fn drop(&mut self) {
+        let seconds = self.started.elapsed().as_secs_f64();
+        self.metrics.action_latency.entry(self.action).or_default().observe(seconds);
+    }
+}
+
+impl Metrics {
+    pub fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// Render current metrics in the Prometheus text exposition format
+pub fn render(state: &AppState) -> String {
+    let metrics = &state.metrics;
+    let load = &state.load;
+    let mut out = String::new();
+
+    gauge(&mut out, "rably_connections", "Open WebSocket connections", state.clients.len() as f64);
+    gauge(&mut out, "rably_channels", "Channels with a broadcast sender", state.channels.len() as f64);
+    gauge(
+        &mut out,
+        "rably_outgoing_queue_utilization",
+        "Fraction of total outgoing queue capacity in use",
+        load.queue_utilization(),
+    );
+    gauge(&mut out, "rably_event_loop_lag_ms", "Scheduling delay of the load sampler", load.lag_ms() as f64);
+    gauge(
+        &mut out,
+        "rably_memory_estimate_bytes",
+        "Memory in use as seen by the memory guard",
+        state.memory.estimate_bytes() as f64,
+    );
+    gauge(
+        &mut out,
+        "rably_subscribe_queue_depth",
+        "Subscribes waiting for their turn under RABLY_SUBSCRIBE_CONCURRENCY",
+        state.subscribe_queue.depth() as f64,
+    );
+    gauge(
+        &mut out,
+        "rably_scheduled_messages",
+        "Scheduled messages waiting to be delivered, across all channels",
+        state.scheduled.total() as f64,
+    );
+    gauge(
+        &mut out,
+        "rably_presence_count_watches",
+        "Channel patterns watched with watch_presence_counts, across all connections",
+        state.pattern_watches.load(Ordering::Relaxed) as f64,
+    );
+    if let Some(retention) = &state.retention {
+        gauge(
+            &mut out,
+            "rably_retention_queue_depth",
+            "Transcript lines waiting for the retention writer",
+            retention.depth() as f64,
+        );
+        counter(
+            &mut out,
+            "rably_retention_dropped_total",
+            "Transcript lines discarded because the retention queue was full",
+            retention.dropped(),
+        );
+    }
+    if let Some(attendance) = &state.attendance {
+        gauge(
+            &mut out,
+            "rably_attendance_queue_depth",
+            "Attendance records waiting for the attendance writer",
+            attendance.depth() as f64,
+        );
+        counter(
+            &mut out,
+            "rably_attendance_dropped_total",
+            "Attendance records discarded because the attendance queue was full",
+            attendance.dropped(),
+        );
+    }
+    if let Some(webhooks) = &state.webhooks {
+        gauge(
+            &mut out,
+            "rably_webhook_queue_depth",
+            "Webhook deliveries waiting for a first attempt or a retry",
+            webhooks.depth() as f64,
+        );
+        counter(&mut out, "rably_webhook_delivered_total", "Webhook deliveries the endpoint accepted", webhooks.delivered());
+        counter(
+            &mut out,
+            "rably_webhook_failures_total",
+            "Webhook attempts that failed, whether retried or not",
+            webhooks.failures(),
+        );
+        counter(
+            &mut out,
+            "rably_webhook_dead_lettered_total",
+            "Webhook deliveries given up on: out of retries, refused, or turned away by a full queue",
+            webhooks.dead_lettered(),
+        );
+    }
+    gauge(&mut out, "rably_shed_rate", "Probability a new tier 0 connection is rejected", load.shed_rate());
+    counter(
+        &mut out,
+        "rably_connections_shed_total",
+        "Connections rejected by load shedding",
+        metrics.connections_shed.load(Ordering::Relaxed),
+    );
+    counter(
+        &mut out,
+        "rably_publishes_busy_total",
+        "Publishes turned away because their channel had no free publish slot",
+        metrics.publishes_busy.load(Ordering::Relaxed),
+    );
+    counter(
+        &mut out,
+        "rably_backpressure_timeouts_total",
+        "Publishes to reliable channels refused because a subscriber stayed too far behind",
+        metrics.backpressure_timeouts.load(Ordering::Relaxed),
+    );
+    counter(
+        &mut out,
+        "rably_redeliveries_total",
+        "At-least-once messages sent to a subscriber again because it hadn't acked them",
+        metrics.redeliveries.load(Ordering::Relaxed),
+    );
+    counter(
+        &mut out,
+        "rably_undelivered_total",
+        "At-least-once messages given up on without the subscriber's ack",
+        metrics.undelivered.load(Ordering::Relaxed),
+    );
+    counter(
+        &mut out,
+        "rably_serialization_failures_total",
+        "Messages dropped because they could not be serialized",
+        SERIALIZATION_FAILURES.load(Ordering::Relaxed),
+    );
+    counter(
+        &mut out,
+        "rably_frames_encoded_total",
+        "Broadcast frames serialized for delivery",
+        metrics.frames_encoded.load(Ordering::Relaxed),
+    );
+    counter(
+        &mut out,
+        "rably_frame_cache_hits_total",
+        "Broadcast deliveries served from an already-encoded frame",
+        metrics.frame_cache_hits.load(Ordering::Relaxed),
+    );
+    counter(
+        &mut out,
+        "rably_slides_coalesced_total",
+        "Queued slide changes replaced by a newer one before a slow connection received them",
+        metrics.slides_coalesced.load(Ordering::Relaxed),
+    );
+    counter(
+        &mut out,
+        "rably_duplicates_suppressed_total",
+        "Replayed or live messages skipped because the participant had already received them",
+        metrics.duplicates_suppressed.load(Ordering::Relaxed),
+    );
+    channel_throughput(&mut out, state);
+    action_latency(&mut out, metrics);
+    disconnects(&mut out, metrics);
+    histogram(
+        &mut out,
+        "rably_client_rtt_seconds",
+        "Round-trip time from a server ping to the client's pong",
+        &metrics.client_rtt,
+    );
+    histogram(
+        &mut out,
+        "rably_presence_scan_seconds",
+        "Time presence locks were held reading a channel's roster for HTTP",
+        &metrics.presence_scan,
+    );
+
+    out
+}
+
+fn disconnects(out: &mut String, metrics: &Metrics) {
+    let name = "rably_disconnects_total";
+    let _ = writeln!(out, "# HELP {} Connections ended, by reason\n# TYPE {} counter", name, name);
+
+    let mut reasons: Vec<_> = metrics.disconnects.iter().collect();
+    reasons.sort_by_key(|entry| *entry.key());
+    for entry in reasons {
+        let _ = writeln!(out, "{}{{reason=\"{}\"}} {}", name, entry.key(), entry.value().load(Ordering::Relaxed));
+    }
+}
+
+fn channel_throughput(out: &mut String, state: &AppState) {
+    let name = "rably_channel_messages_per_second";
+    let _ = writeln!(
+        out,
+        "# HELP {} Recent broadcasts per second on the busiest channels, weighted over about a minute\n# TYPE {} gauge",
+        name, name
+    );
+
+    for (channel, rate) in throughput::busiest(state, THROUGHPUT_CHANNELS) {
+        let _ = writeln!(out, "{}{{channel=\"{}\"}} {}", name, label_value(&channel), rate);
+    }
+}
+
+// A label value with the characters the exposition format reserves escaped
+fn label_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn action_latency(out: &mut String, metrics: &Metrics) {
+    let name = "rably_action_duration_seconds";
+    let _ = writeln!(out, "# HELP {} Time to process a client action\n# TYPE {} histogram", name, name);
+
+    let mut actions: Vec<_> = metrics.action_latency.iter().collect();
+    actions.sort_by_key(|entry| *entry.key());
+    for entry in actions {
+        let (action, histogram) = (entry.key(), entry.value());
+        for (bucket, bound) in histogram.buckets.iter().zip(LATENCY_BUCKETS) {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{action=\"{}\",le=\"{}\"}} {}",
+                name,
+                action,
+                bound,
+                bucket.load(Ordering::Relaxed)
+            );
+        }
+        let count = histogram.count.load(Ordering::Relaxed);
+        let sum = histogram.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(out, "{}_bucket{{action=\"{}\",le=\"+Inf\"}} {}", name, action, count);
+        let _ = writeln!(out, "{}_sum{{action=\"{}\"}} {}", name, action, sum);
+        let _ = writeln!(out, "{}_count{{action=\"{}\"}} {}", name, action, count);
+    }
+}
+
+fn histogram(out: &mut String, name: &str, help: &str, histogram: &Histogram) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} histogram", name, help, name);
+    for (bucket, bound) in histogram.buckets.iter().zip(LATENCY_BUCKETS) {
+        let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, bucket.load(Ordering::Relaxed));
+    }
+    let count = histogram.count.load(Ordering::Relaxed);
+    let sum = histogram.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+    let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+    let _ = writeln!(out, "{}_sum {}", name, sum);
+    let _ = writeln!(out, "{}_count {}", name, count);
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: f64) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} gauge\n{} {}", name, help, name, name, value);
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter\n{} {}", name, help, name, name, value);
+}
@@ -0,0 +1,62 @@
+// Cooperative close before the server moves a client elsewhere.
+
+use std::{sync::Mutex, time::Duration};
+
+use crate::{close::CloseReason, send_direct, AppState, ClientHandle};
+
+// The close a connection has been told to prepare for, if any
+#[derive(Default)]
+pub struct PendingMigration(Mutex<Option<CloseReason>>);
+
+impl PendingMigration {
+    // Claim the pending close, so it happens only once
+    pub fn take(&self) -> Option<CloseReason> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).take()
+    }
+}
+
+pub fn timeout(state: &AppState) -> Option<Duration> {
+    Some(state.config.migration_timeout_ms)
+        .filter(|ms| *ms > 0)
+        .map(Duration::from_millis)
+}
+
+// Tell the client it's about to be closed for the reason, without closing it yet
+pub fn prepare(state: &AppState, client: &ClientHandle, reason: CloseReason) {
+    let timeout = timeout(state).unwrap_or_default();
+    let kind = reason.kind();
+    // Pending before the notice goes out, so even an instant reply finds it
+    *client.migration.0.lock().unwrap_or_else(|e| e.into_inner()) = Some(reason);
+    send_direct(
+        &client.outgoing,
+        None,
+        "",
+        "prepare_migration",
+        serde_json::json!({
+            "reason": kind,
+            "timeout_ms": timeout.as_millis() as u64,
+        }),
+    );
+}
+
+// Close the connection for the reason once the client is ready, or when the timeout runs
+// out; immediately if the handshake is disabled. Returns false if the connection is gone.
+pub fn close(state: &AppState, client: &ClientHandle, reason: CloseReason) -> bool {
+    let Some(timeout) = timeout(state) else {
+        return client.disconnect.send(reason).is_ok();
+    };
+    if client.disconnect.is_closed() {
+        return false;
+    }
+
+    prepare(state, client, reason);
+    let migration = client.migration.clone();
+    let disconnect = client.disconnect.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(timeout).await;
+        if let Some(reason) = migration.take() {
+            let _ = disconnect.send(reason);
+        }
+    });
+    true
+}
@@ -0,0 +1,130 @@
+// Who may reach the monitoring endpoints, and on which listener.
+
+use axum::{
+    extract::{Request, State},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::MethodRouter,
+    Router,
+};
+use serde::Serialize;
+use std::str::FromStr;
+
+use crate::{admin, AppState};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EndpointAccess {
+    // Anyone who can reach the public port
+    Public,
+    // Requests on the public port must carry the admin token
+    Admin,
+    // Only served on the internal listener
+    Internal,
+}
+
+impl FromStr for EndpointAccess {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "public" => Ok(EndpointAccess::Public),
+            "admin" => Ok(EndpointAccess::Admin),
+            "internal" => Ok(EndpointAccess::Internal),
+            _ => Err(()),
+        }
+    }
+}
+
+// Routes for the public and the internal listener
+pub struct Routers {
+    pub public: Router<AppState>,
+    pub internal: Router<AppState>,
+}
+
+impl Routers {
+    pub fn new() -> Self {
+        Routers {
+            public: Router::new(),
+            internal: Router::new(),
+        }
+    }
+
+    // Serve a monitoring endpoint as its access setting says
+    pub fn monitoring(mut self, state: &AppState, path: &str, access: EndpointAccess, route: MethodRouter<AppState>) -> Self {
+        let internal = state.config.internal_addr.is_some();
+        match access {
+            EndpointAccess::Public => self.public = self.public.route(path, route.clone()),
+            EndpointAccess::Admin => {
+                self.public = self.public.route(
+                    path,
+                    route.clone().route_layer(middleware::from_fn_with_state(state.clone(), require_admin)),
+                )
+            }
+            EndpointAccess::Internal if !internal => {
+                eprintln!("⚠️ {} is internal but RABLY_INTERNAL_ADDR is unset; it will not be served", path);
+            }
+            EndpointAccess::Internal => {}
+        }
+        if internal {
+            self.internal = self.internal.route(path, route);
+        }
+        self
+    }
+
+    // Serve the admin API on the internal listener if there is one, else on the public port
+    pub fn admin(mut self, state: &AppState, routes: Router<AppState>) -> Self {
+        if state.config.internal_addr.is_some() {
+            self.internal = self.internal.merge(routes);
+        } else {
+            self.public = self.public.merge(routes);
+        }
+        self
+    }
+}
+
+async fn require_admin(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    if let Err(rejection) = admin::authorize(&state, request.headers()) {
+        return rejection.into_response();
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{routers, testing};
+    use axum::{body::Body, extract::ConnectInfo, http::StatusCode};
+    use std::net::SocketAddr;
+    use tower::ServiceExt;
+
+    // Whether a router has a route for a GET, as opposed to its empty fallback 404
+    async fn routed(router: &Router, path: &str) -> bool {
+        let mut request = Request::get(path).header("authorization", "Bearer wrong").body(Body::empty()).unwrap();
+        request.extensions_mut().insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 5000))));
+        let response = router.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        status != StatusCode::NOT_FOUND || !body.is_empty()
+    }
+
+    #[tokio::test]
+    async fn the_internal_listener_takes_every_admin_route_off_the_public_port() {
+        let state = testing::state(|config| {
+            config.internal_addr = Some(SocketAddr::from(([127, 0, 0, 1], 0)));
+            config.admin_token = Some("secret".to_string());
+        });
+        let (public, internal) = routers(&state);
+        for path in [
+            "/admin/config",
+            "/admin/dump",
+            "/channels/lesson/export",
+            "/channels/lesson/attendance",
+            "/channels/lesson/stream",
+        ] {
+            assert!(!routed(&public, path).await, "{} is still on the public port", path);
+            assert!(routed(&internal, path).await, "{} is not on the internal listener", path);
+        }
+        assert!(routed(&public, "/channels/lesson/history").await);
+    }
+}
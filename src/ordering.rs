@@ -0,0 +1,120 @@
+// Per-channel ordering mode.
+
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use tokio::sync::mpsc;
+
+use crate::{channel_config, deliver, roles, AppState, ServerMessage};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OrderingMode {
+    // Single writer per channel: identical order everywhere, lower throughput
+    Ordered,
+    // Publishers broadcast concurrently: best-effort order
+    Fast,
+}
+
+impl OrderingMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OrderingMode::Ordered => "ordered",
+            OrderingMode::Fast => "fast",
+        }
+    }
+}
+
+impl FromStr for OrderingMode {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "ordered" => Ok(OrderingMode::Ordered),
+            "fast" => Ok(OrderingMode::Fast),
+            _ => Err(()),
+        }
+    }
+}
+
+// A broadcast waiting for the channel's writer task
+pub struct OrderedPublish {
+    pub msg: ServerMessage,
+    pub origin: String,
+}
+
+// The mode operators fixed for a channel, if any: an admin override, then the first
+// matching rule, then the ordered prefixes
+fn configured(state: &AppState, channel: &str) -> Option<OrderingMode> {
+    channel_config::overrides(state, channel)
+        .ordering
+        .or_else(|| {
+            state
+                .config
+                .channel_ordering_rules
+                .iter()
+                .find(|(pattern, _)| roles::matches_pattern(pattern, channel))
+                .map(|(_, mode)| *mode)
+        })
+        .or_else(|| {
+            state
+                .config
+                .ordered_channel_prefixes
+                .iter()
+                .any(|prefix| channel.starts_with(prefix.as_str()))
+                .then_some(OrderingMode::Ordered)
+        })
+}
+
+pub fn mode(state: &AppState, channel: &str) -> OrderingMode {
+    configured(state, channel)
+        .or_else(|| state.channel_ordering.get(channel).map(|mode| *mode))
+        .unwrap_or(OrderingMode::Fast)
+}
+
+// Whether a channel requires single-writer ordering
+pub fn is_ordered(state: &AppState, channel: &str) -> bool {
+    mode(state, channel) == OrderingMode::Ordered
+}
+
+// Record a moderator's choice of mode unless config or an earlier choice already set it.
+// Returns the mode in effect and whether this request chose it.
+pub fn choose(state: &AppState, channel: &str, requested: OrderingMode) -> (OrderingMode, bool) {
+    if let Some(mode) = configured(state, channel) {
+        return (mode, false);
+    }
+
+    let mut chosen = false;
+    let mode = *state.channel_ordering.entry(channel.to_string()).or_insert_with(|| {
+        chosen = true;
+        requested
+    });
+    (mode, chosen)
+}
+
+// Queue a broadcast on the channel's writer, starting the writer if needed
+pub fn enqueue(state: &AppState, msg: ServerMessage, origin: &str) {
+    let writer = state
+        .ordered_writers
+        .entry(msg.channel.clone())
+        .or_insert_with(|| spawn_writer(state.clone()))
+        .clone();
+
+    let publish = OrderedPublish { msg, origin: origin.to_string() };
+    if let Err(e) = writer.send(publish) {
+        // Writer is gone; deliver directly rather than dropping the message
+        let publish = e.0;
+        deliver(state, publish.msg, &publish.origin);
+    }
+}
+
+fn spawn_writer(state: AppState) -> mpsc::UnboundedSender<OrderedPublish> {
+    let (tx, mut rx) = mpsc::unbounded_channel::<OrderedPublish>();
+
+    tokio::spawn(async move {
+        while let Some(publish) = rx.recv().await {
+            deliver(&state, publish.msg, &publish.origin);
+        }
+    });
+
+    tx
+}
@@ -0,0 +1,21 @@
+// Per-channel allowed origins.
+
+use crate::{roles, AppState};
+
+// The origins the first matching rule allows, if any rule matches
+fn allowed<'a>(state: &'a AppState, channel: &str) -> Option<&'a [String]> {
+    state
+        .config
+        .channel_allowed_origins
+        .iter()
+        .find(|(pattern, _)| roles::matches_pattern(pattern, channel))
+        .map(|(_, origins)| origins.as_slice())
+}
+
+// Whether a connection from this origin may use the channel
+pub fn permits(state: &AppState, channel: &str, origin: Option<&str>) -> bool {
+    let Some(origins) = allowed(state, channel) else {
+        return true;
+    };
+    origin.is_some_and(|origin| origins.iter().any(|allowed| roles::matches_pattern(allowed, origin)))
+}
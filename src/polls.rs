@@ -0,0 +1,160 @@
+// Live polls and quizzes.
+
+use std::collections::HashMap;
+use uuid::Uuid;
+
+use crate::{broadcast_event, clock, AppState};
+
+// Options a poll may offer
+const MAX_OPTIONS: usize = 20;
+// Characters in a question or an option
+const MAX_TEXT_LEN: usize = 500;
+// Polls kept per channel; the oldest closed one makes room for a new poll
+const MAX_POLLS: usize = 20;
+
+pub struct Poll {
+    id: String,
+    question: String,
+    options: Vec<String>,
+    // Option chosen by each voter
+    votes: HashMap<String, usize>,
+    tallies: Vec<u64>,
+    started_by: String,
+    started_at: i64,
+    closed_at: Option<i64>,
+}
+
+impl Poll {
+    fn summary(&self) -> serde_json::Value {
+        serde_json::json!({
+            "poll_id": self.id,
+            "question": self.question,
+            "options": self.options,
+            "tallies": self.tallies,
+            "votes": self.votes.len(),
+            "open": self.closed_at.is_none(),
+            "started_by": self.started_by,
+            "started_at": self.started_at,
+            "closed_at": self.closed_at,
+        })
+    }
+}
+
+pub enum StartError {
+    Invalid(&'static str),
+    // The channel's polls are all still open
+    TooMany,
+}
+
+pub enum VoteError {
+    UnknownPoll,
+    Closed,
+    AlreadyVoted,
+    InvalidOption,
+}
+
+pub enum CloseError {
+    UnknownPoll,
+    AlreadyClosed,
+}
+
+// Open a poll and announce it, returning its id. `data` carries `question` and `options`.
+pub fn start(state: &AppState, channel: &str, data: &serde_json::Value, started_by: &str) -> Result<String, StartError> {
+    let question = data
+        .get("question")
+        .and_then(|question| question.as_str())
+        .map(str::trim)
+        .filter(|question| !question.is_empty())
+        .ok_or(StartError::Invalid("data.question must be a non-empty string"))?;
+    let options: Vec<String> = data
+        .get("options")
+        .and_then(|options| options.as_array())
+        .ok_or(StartError::Invalid("data.options must be a list of strings"))?
+        .iter()
+        .map(|option| option.as_str().map(|option| option.trim().to_string()))
+        .collect::<Option<_>>()
+        .ok_or(StartError::Invalid("data.options must be a list of strings"))?;
+
+    if options.len() < 2 || options.len() > MAX_OPTIONS {
+        return Err(StartError::Invalid("a poll needs between 2 and 20 options"));
+    }
+    if options.iter().any(|option| option.is_empty()) {
+        return Err(StartError::Invalid("options may not be empty"));
+    }
+    if question.chars().count() > MAX_TEXT_LEN || options.iter().any(|option| option.chars().count() > MAX_TEXT_LEN) {
+        return Err(StartError::Invalid("questions and options may be at most 500 characters"));
+    }
+
+    let mut polls = state.polls.entry(channel.to_string()).or_default();
+    if polls.len() >= MAX_POLLS {
+        let oldest_closed = polls
+            .values()
+            .filter(|poll| poll.closed_at.is_some())
+            .min_by_key(|poll| poll.started_at)
+            .map(|poll| poll.id.clone());
+        let Some(oldest_closed) = oldest_closed else {
+            return Err(StartError::TooMany);
+        };
+        polls.remove(&oldest_closed);
+    }
+
+    let poll = Poll {
+        id: Uuid::new_v4().to_string(),
+        question: question.to_string(),
+        tallies: vec![0; options.len()],
+        options,
+        votes: HashMap::new(),
+        started_by: started_by.to_string(),
+        started_at: clock::now().timestamp_millis(),
+        closed_at: None,
+    };
+    let id = poll.id.clone();
+    // Announced under the channel's entry, so no update can go out ahead of it
+    broadcast_event(state, channel, "poll_started", poll.summary());
+    polls.insert(id.clone(), poll);
+    Ok(id)
+}
+
+// Count a vote and broadcast the new tallies
+pub fn vote(state: &AppState, channel: &str, poll_id: &str, voter: &str, option: usize) -> Result<(), VoteError> {
+    let mut polls = state.polls.get_mut(channel).ok_or(VoteError::UnknownPoll)?;
+    let poll = polls.get_mut(poll_id).ok_or(VoteError::UnknownPoll)?;
+    if poll.closed_at.is_some() {
+        return Err(VoteError::Closed);
+    }
+    if option >= poll.options.len() {
+        return Err(VoteError::InvalidOption);
+    }
+    if poll.votes.contains_key(voter) {
+        return Err(VoteError::AlreadyVoted);
+    }
+
+    poll.votes.insert(voter.to_string(), option);
+    poll.tallies[option] += 1;
+    // Broadcast while the entry is held, so tallies reach subscribers in the order they grew
+    broadcast_event(state, channel, "poll_update", poll.summary());
+    Ok(())
+}
+
+// Stop taking votes and broadcast the results
+pub fn close(state: &AppState, channel: &str, poll_id: &str) -> Result<(), CloseError> {
+    let mut polls = state.polls.get_mut(channel).ok_or(CloseError::UnknownPoll)?;
+    let poll = polls.get_mut(poll_id).ok_or(CloseError::UnknownPoll)?;
+    if poll.closed_at.is_some() {
+        return Err(CloseError::AlreadyClosed);
+    }
+
+    poll.closed_at = Some(clock::now().timestamp_millis());
+    broadcast_event(state, channel, "poll_closed", poll.summary());
+    Ok(())
+}
+
+// The channel's polls, oldest first
+pub fn list(state: &AppState, channel: &str) -> Vec<serde_json::Value> {
+    let Some(polls) = state.polls.get(channel) else {
+        return Vec::new();
+    };
+    let mut polls: Vec<&Poll> = polls.values().collect();
+    polls.sort_by_key(|poll| poll.started_at);
+    polls.into_iter().map(Poll::summary).collect()
+}
@@ -0,0 +1,783 @@
+use futures::stream::{self, Stream, StreamExt};
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::Infallible,
+    time::{Duration, Instant},
+};
+
+use crate::{attendance, broadcast_event, clock, metrics::{self, Metrics}, presence_store, roles, AppState, ClientInfo};
+
+// How often expired presence metadata is looked for
+const METADATA_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+// Presence statuses
+pub const STATUS_ONLINE: &str = "online";
+pub const STATUS_AWAY: &str = "away";
+
+// Presence changes waiting to be flushed as one presence_diff event
+#[derive(Default)]
+pub struct PresenceDiff {
+    added: HashMap<String, ClientInfo>,
+    updated: HashMap<String, ClientInfo>,
+    removed: HashMap<String, ClientInfo>,
+}
+
+// Joins during a burst, waiting to go out together as presence_batch_joined
+#[derive(Default)]
+pub struct JoinBatch {
+    last_join: Option<Instant>,
+    pending: Vec<ClientInfo>,
+}
+
+// Large channels batch presence changes into periodic diffs instead of one event each
+fn uses_diffs(state: &AppState, channel: &str) -> bool {
+    state.config.presence_diff_interval_ms > 0
+        && state
+            .channel_presence
+            .get(channel)
+            .is_some_and(|channel_map| channel_map.len() >= state.config.presence_diff_min_participants)
+}
+
+// Broadcasts about who is in a channel, the only ones a presence-only subscription receives
+pub fn is_presence_event(event_type: &str) -> bool {
+    matches!(
+        event_type,
+        "user_joined" | "user_left" | "presence_update" | "presence_batch_joined" | "presence_diff" | "presence_set_update"
+    )
+}
+
+// Tell a channel about a presence change ("user_joined", "user_left" or "presence_update")
+pub fn announce(state: &AppState, channel: &str, event_type: &str, info: &ClientInfo) {
+    presence_store::mark_dirty(state);
+    attendance::record(state, channel, event_type, info);
+
+    if !uses_diffs(state, channel) {
+        if batch_join_event(state, channel, event_type, info) {
+            return;
+        }
+        match serde_json::to_value(info) {
+            Ok(data) => broadcast_event(state, channel, event_type, data),
+            Err(e) => {
+                Metrics::inc(&metrics::SERIALIZATION_FAILURES);
+                eprintln!("❌ Failed to serialize {} for client {} on channel {}: {}", event_type, info.id, channel, e);
+            }
+        }
+        return;
+    }
+
+    let mut diff = state.presence_diffs.entry(channel.to_string()).or_default();
+    let id = info.id.clone();
+    match event_type {
+        "user_joined" => {
+            diff.removed.remove(&id);
+            diff.added.insert(id, info.clone());
+        }
+        "user_left" => {
+            diff.updated.remove(&id);
+            // Joined and left within one window: nobody needs to hear about it
+            if diff.added.remove(&id).is_none() {
+                diff.removed.insert(id, info.clone());
+            }
+        }
+        _ => match diff.added.get_mut(&id) {
+            Some(added) => *added = info.clone(),
+            None => {
+                diff.updated.insert(id, info.clone());
+            }
+        },
+    }
+}
+
+// Fold a presence event into the channel's join burst, returning true if it was held
+// back. A join right after another one starts (or extends) a burst whose joins are flushed
+// together once the window passes; a join on a quiet channel goes out on its own.
+fn batch_join_event(state: &AppState, channel: &str, event_type: &str, info: &ClientInfo) -> bool {
+    let window = Duration::from_millis(state.config.presence_join_batch_ms);
+    if window.is_zero() {
+        return false;
+    }
+
+    if event_type != "user_joined" {
+        let Some(mut batch) = state.join_batches.get_mut(channel) else {
+            return false;
+        };
+        let Some(index) = batch.pending.iter().position(|pending| pending.id == info.id) else {
+            return false;
+        };
+        // Joined and left within one burst: nobody needs to hear about it
+        if event_type == "user_left" {
+            batch.pending.remove(index);
+        } else {
+            batch.pending[index] = info.clone();
+        }
+        return true;
+    }
+
+    let mut batch = state.join_batches.entry(channel.to_string()).or_default();
+    let now = Instant::now();
+    let in_burst = batch.last_join.is_some_and(|last| now.duration_since(last) < window);
+    batch.last_join = Some(now);
+    if !in_burst && batch.pending.is_empty() {
+        return false;
+    }
+
+    batch.pending.push(info.clone());
+    if batch.pending.len() == 1 {
+        let state = state.clone();
+        let channel = channel.to_string();
+        tokio::spawn(async move {
+            tokio::time::sleep(window).await;
+            flush_join_batch(&state, &channel);
+        });
+    }
+    true
+}
+
+fn flush_join_batch(state: &AppState, channel: &str) {
+    let joined = match state.join_batches.get_mut(channel) {
+        Some(mut batch) => std::mem::take(&mut batch.pending),
+        None => return,
+    };
+    if joined.is_empty() {
+        return;
+    }
+
+    broadcast_event(state, channel, "presence_batch_joined", serde_json::json!({ "participants": joined }));
+}
+
+// Periodically broadcast accumulated presence diffs
+pub fn spawn_diff_flusher(state: AppState) {
+    let interval_ms = state.config.presence_diff_interval_ms;
+    if interval_ms == 0 {
+        return;
+    }
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_millis(interval_ms));
+        loop {
+            interval.tick().await;
+
+            let channels: Vec<String> = state.presence_diffs.iter().map(|entry| entry.key().clone()).collect();
+            for channel in channels {
+                let Some((_, diff)) = state.presence_diffs.remove(&channel) else {
+                    continue;
+                };
+                if diff.added.is_empty() && diff.updated.is_empty() && diff.removed.is_empty() {
+                    continue;
+                }
+
+                broadcast_event(
+                    &state,
+                    &channel,
+                    "presence_diff",
+                    serde_json::json!({
+                        "added": diff.added.into_values().collect::<Vec<_>>(),
+                        "updated": diff.updated.into_values().collect::<Vec<_>>(),
+                        "removed": diff.removed.into_values().collect::<Vec<_>>(),
+                    }),
+                );
+            }
+        }
+    });
+}
+
+// Where an entry sorts in the roster: pinned first, then by join time, the id breaking ties
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct RosterKey {
+    unpinned: bool,
+    joined_at: i64,
+    id: String,
+}
+
+impl RosterKey {
+    // Opaque cursor for resuming after this entry
+    pub fn cursor(&self) -> String {
+        format!("{}:{}:{}", if self.unpinned { 1 } else { 0 }, self.joined_at, self.id)
+    }
+
+    pub fn from_cursor(cursor: &str) -> Option<RosterKey> {
+        let mut parts = cursor.splitn(3, ':');
+        let unpinned = match parts.next()? {
+            "0" => false,
+            "1" => true,
+            _ => return None,
+        };
+        let joined_at = parts.next()?.parse().ok()?;
+        let id = parts.next()?.to_string();
+        Some(RosterKey { unpinned, joined_at, id })
+    }
+}
+
+// Scans slower than this are logged, as they hold up joins and leaves on the channel
+const SLOW_SCAN: Duration = Duration::from_millis(5);
+
+// Participants sent per chunk of a streamed roster
+const STREAM_CHUNK: usize = 500;
+
+// The channel's roster order. Only the sort keys are copied while the channel's locks
+// are held, so even a webinar-sized roster is scanned quickly; the full entries are read
+// afterwards, a page or chunk at a time.
+fn roster_order(state: &AppState, channel: &str) -> Vec<RosterKey> {
+    let started = Instant::now();
+    let mut keys = state
+        .channel_presence
+        .get(channel)
+        .map(|channel_map| {
+            channel_map
+                .iter()
+                .map(|entry| RosterKey {
+                    unpinned: !entry.pinned,
+                    joined_at: entry.joined_at,
+                    id: entry.key().clone(),
+                })
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    let held = started.elapsed();
+
+    state.metrics.presence_scan.observe(held.as_secs_f64());
+    if held > SLOW_SCAN {
+        eprintln!(
+            "🐢 Reading the roster of {} ({} participants) held its presence locks for {:?}",
+            channel,
+            keys.len(),
+            held
+        );
+    }
+
+    keys.sort_unstable();
+    keys
+}
+
+// Current entries for the keys, skipping participants who have left since
+fn entries(state: &AppState, channel: &str, keys: &[RosterKey]) -> Vec<ClientInfo> {
+    let Some(channel_map) = state.channel_presence.get(channel) else {
+        return Vec::new();
+    };
+    keys.iter()
+        .filter_map(|key| channel_map.get(&key.id).map(|info| info.clone()))
+        .collect()
+}
+
+// One page of a channel's roster
+pub struct RosterPage {
+    pub participants: Vec<ClientInfo>,
+    // Participants in the whole roster
+    pub total: usize,
+    // Cursor for the next page, if there is one
+    pub next: Option<String>,
+}
+
+// Up to `limit` participants following `after` in roster order. A cursor stays valid when
+// its participant leaves, since it holds the entry's position rather than its index.
+pub fn page(state: &AppState, channel: &str, after: Option<&RosterKey>, limit: usize) -> RosterPage {
+    let keys = roster_order(state, channel);
+    let start = after.map_or(0, |after| keys.partition_point(|key| key <= after));
+    let end = keys.len().min(start + limit.max(1));
+
+    RosterPage {
+        participants: entries(state, channel, &keys[start..end]),
+        total: keys.len(),
+        next: (end < keys.len()).then(|| keys[end - 1].cursor()),
+    }
+}
+
+// The whole roster as JSON lines, read from the presence map a chunk at a time as the
+// response is written, so no single buffer or lock covers every participant
+pub fn stream(state: AppState, channel: String) -> impl Stream<Item = Result<String, Infallible>> {
+    let keys = roster_order(&state, &channel);
+    let chunks: Vec<Vec<RosterKey>> = keys.chunks(STREAM_CHUNK).map(|chunk| chunk.to_vec()).collect();
+
+    stream::iter(chunks).map(move |chunk| {
+        let mut lines = String::new();
+        for info in entries(&state, &channel, &chunk) {
+            if let Ok(line) = serde_json::to_string(&info) {
+                lines.push_str(&line);
+                lines.push('\n');
+            }
+        }
+        Ok(lines)
+    })
+}
+
+// A channel name as a URL path segment
+fn path_segment(channel: &str) -> String {
+    channel
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+// The roster a new subscriber is sent, in roster order and capped at
+// RABLY_PRESENCE_SNAPSHOT_MAX so joining a webinar doesn't mean a multi-megabyte message. A
+// capped snapshot says so and points at the HTTP roster for the rest. None if disabled.
+pub fn snapshot(state: &AppState, channel: &str) -> Option<serde_json::Value> {
+    let max = state.config.presence_snapshot_max;
+    if max == 0 {
+        return None;
+    }
+
+    let page = page(state, channel, None, max);
+    let mut snapshot = serde_json::json!({
+        "participants": page.participants,
+        "total": page.total,
+        "truncated": page.next.is_some(),
+    });
+    if let Some(next) = page.next {
+        snapshot["more"] = serde_json::json!(format!("/channels/{}/presence?after={}", path_segment(channel), next));
+        snapshot["next"] = serde_json::json!(next);
+    }
+    Some(snapshot)
+}
+
+// Add a participant to a channel's roster, replacing an entry for the same identity that
+// is still away in its grace window. Returns whether an entry was replaced.
+pub fn join(state: &AppState, channel: &str, info: ClientInfo) -> bool {
+    let channel_map = state.channel_presence.entry(channel.to_string()).or_default();
+
+    let stale = info.identity.as_ref().and_then(|identity| {
+        channel_map
+            .iter()
+            .find(|entry| {
+                entry.key() != &info.id
+                    && entry.status == STATUS_AWAY
+                    && entry.identity.as_ref() == Some(identity)
+            })
+            .map(|entry| entry.key().clone())
+    });
+
+    let replaced = stale
+        .and_then(|stale_id| channel_map.remove_if(&stale_id, |_, entry| entry.status == STATUS_AWAY))
+        .is_some();
+
+    channel_map.insert(info.id.clone(), info);
+    replaced
+}
+
+// Split a roster into breakout groups, keeping roster order within each group
+pub fn by_group(participants: Vec<ClientInfo>) -> (BTreeMap<String, Vec<ClientInfo>>, Vec<ClientInfo>) {
+    let mut groups: BTreeMap<String, Vec<ClientInfo>> = BTreeMap::new();
+    let mut ungrouped = Vec::new();
+
+    for info in participants {
+        match info.group.clone() {
+            Some(group) => groups.entry(group).or_default().push(info),
+            None => ungrouped.push(info),
+        }
+    }
+    (groups, ungrouped)
+}
+
+// Participants per role, and how many of them are online rather than away. Every role in
+// the hierarchy is listed, at zero if nobody holds it, and a role the hierarchy no longer
+// has is counted as the lowest role, the way permissions treat it.
+pub fn role_counts(state: &AppState, channel: &str) -> (BTreeMap<String, usize>, BTreeMap<String, usize>) {
+    let hierarchy = &state.config.role_hierarchy;
+    let mut present: BTreeMap<String, usize> = hierarchy.iter().map(|role| (role.clone(), 0)).collect();
+    let mut online = present.clone();
+
+    if let Some(channel_map) = state.channel_presence.get(channel) {
+        for entry in channel_map.iter() {
+            let role = if hierarchy.contains(&entry.role) { entry.role.clone() } else { roles::default_role(state) };
+            if entry.status == STATUS_ONLINE {
+                *online.entry(role.clone()).or_default() += 1;
+            }
+            *present.entry(role).or_default() += 1;
+        }
+    }
+    (present, online)
+}
+
+// Move a participant into a breakout group (or out of any, with None)
+pub fn set_group(state: &AppState, channel: &str, client_id: &str, group: Option<String>) -> Option<ClientInfo> {
+    let channel_map = state.channel_presence.get(channel)?;
+    let mut info = channel_map.get_mut(client_id)?;
+    info.group = group;
+    Some(info.clone())
+}
+
+// Replace a participant's roster metadata, returning the updated entry if they're present
+pub fn set_metadata(
+    state: &AppState,
+    channel: &str,
+    client_id: &str,
+    metadata: Option<serde_json::Map<String, serde_json::Value>>,
+    expires_at: BTreeMap<String, i64>,
+) -> Option<ClientInfo> {
+    let channel_map = state.channel_presence.get(channel)?;
+    let mut info = channel_map.get_mut(client_id)?;
+    info.metadata = metadata;
+    info.metadata_expires_at = expires_at;
+    Some(info.clone())
+}
+
+// Clear the participant's metadata keys whose TTL has run out, returning the updated entry
+// if any were
+fn expire_metadata(state: &AppState, channel: &str, client_id: &str, now: i64) -> Option<ClientInfo> {
+    let channel_map = state.channel_presence.get(channel)?;
+    let mut info = channel_map.get_mut(client_id)?;
+    let expired: Vec<String> = info
+        .metadata_expires_at
+        .iter()
+        .filter(|(_, expires_at)| **expires_at <= now)
+        .map(|(key, _)| key.clone())
+        .collect();
+    if expired.is_empty() {
+        return None;
+    }
+
+    for key in &expired {
+        info.metadata_expires_at.remove(key);
+        if let Some(metadata) = info.metadata.as_mut() {
+            metadata.remove(key);
+        }
+    }
+    if info.metadata.as_ref().is_some_and(|metadata| metadata.is_empty()) {
+        info.metadata = None;
+    }
+    Some(info.clone())
+}
+
+// Add a participant to a named presence set (such as raised hands) or take them out of it.
+// Returns the set's members in the order they joined, or None if nothing changed.
+pub fn update_set(state: &AppState, channel: &str, set: &str, client_id: &str, member: bool) -> Option<Vec<String>> {
+    let mut sets = state.presence_sets.entry(channel.to_string()).or_default();
+    let members = sets.entry(set.to_string()).or_default();
+
+    let position = members.iter().position(|id| id == client_id);
+    match (member, position) {
+        (true, None) => members.push(client_id.to_string()),
+        (false, Some(index)) => {
+            members.remove(index);
+        }
+        _ => return None,
+    }
+
+    let members = members.clone();
+    if members.is_empty() {
+        sets.remove(set);
+    }
+    Some(members)
+}
+
+// Tell a channel a presence set's membership changed
+pub fn announce_set(state: &AppState, channel: &str, set: &str, client_id: &str, member: bool, members: Vec<String>) {
+    broadcast_event(
+        state,
+        channel,
+        "presence_set_update",
+        serde_json::json!({
+            "set": set,
+            "client_id": client_id,
+            "member": member,
+            "members": members,
+        }),
+    );
+}
+
+// Roster entries for a presence set's members, in the order they joined it
+pub fn set_snapshot(state: &AppState, channel: &str, set: &str) -> Vec<ClientInfo> {
+    let members = state
+        .presence_sets
+        .get(channel)
+        .and_then(|sets| sets.get(set).cloned())
+        .unwrap_or_default();
+
+    let Some(channel_map) = state.channel_presence.get(channel) else {
+        return Vec::new();
+    };
+    members
+        .iter()
+        .filter_map(|id| channel_map.get(id).map(|info| info.clone()))
+        .collect()
+}
+
+// Take a departed participant out of every presence set in the channel
+fn leave_sets(state: &AppState, channel: &str, client_id: &str) {
+    let sets: Vec<String> = state
+        .presence_sets
+        .get(channel)
+        .map(|sets| sets.keys().cloned().collect())
+        .unwrap_or_default();
+
+    for set in sets {
+        if let Some(members) = update_set(state, channel, &set, client_id, false) {
+            announce_set(state, channel, &set, client_id, false, members);
+        }
+    }
+    state.presence_sets.remove_if(channel, |_, sets| sets.is_empty());
+}
+
+// Whether a role's presence should be pinned to the top of the roster
+pub fn is_pinned_role(state: &AppState, role: &str) -> bool {
+    state.config.pinned_roles.iter().any(|pinned| pinned == role)
+}
+
+// Keep a disconnected client's entry as "away" for its grace window, then reap it
+pub fn begin_grace(state: &AppState, channel: &str, client_id: &str) {
+    let info = state.channel_presence.get(channel).and_then(|channel_map| {
+        channel_map.get_mut(client_id).map(|mut entry| {
+            entry.status = STATUS_AWAY.to_string();
+            entry.clone()
+        })
+    });
+
+    let Some(info) = info else {
+        return;
+    };
+
+    let grace_secs = grace_secs(state, &info);
+    if grace_secs == 0 {
+        reap(state, channel, client_id);
+        return;
+    }
+
+    announce(state, channel, "presence_update", &info);
+    schedule_reap(state, channel, client_id, grace_secs);
+}
+
+// Put back an entry from a presence snapshot as away, with a fresh grace window
+pub fn restore_away(state: &AppState, channel: &str, mut info: ClientInfo) {
+    info.status = STATUS_AWAY.to_string();
+    let grace_secs = grace_secs(state, &info);
+    if grace_secs == 0 {
+        return;
+    }
+
+    let client_id = info.id.clone();
+    state
+        .channel_presence
+        .entry(channel.to_string())
+        .or_default()
+        .insert(client_id.clone(), info);
+    schedule_reap(state, channel, &client_id, grace_secs);
+}
+
+fn grace_secs(state: &AppState, info: &ClientInfo) -> u64 {
+    if info.pinned {
+        state.live().pinned_presence_grace_secs
+    } else {
+        state.live().presence_grace_secs
+    }
+}
+
+fn schedule_reap(state: &AppState, channel: &str, client_id: &str, grace_secs: u64) {
+    let state = state.clone();
+    let channel = channel.to_string();
+    let client_id = client_id.to_string();
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_secs(grace_secs)).await;
+        reap(&state, &channel, &client_id);
+    });
+}
+
+// Refresh a connection's entry on heartbeat. Returns the entry if it had been marked away
+// as stale and is now back online.
+pub fn heartbeat(state: &AppState, channel: &str, client_id: &str) -> Option<ClientInfo> {
+    let channel_map = state.channel_presence.get(channel)?;
+    let mut entry = channel_map.get_mut(client_id)?;
+    entry.last_activity = clock::now().timestamp_millis();
+    if entry.status == STATUS_ONLINE {
+        return None;
+    }
+
+    entry.status = STATUS_ONLINE.to_string();
+    Some(entry.clone())
+}
+
+// Clear presence metadata keys once their TTL runs out, telling the channel
+pub fn spawn_metadata_sweeper(state: AppState) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(METADATA_SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+
+            let now = clock::now().timestamp_millis();
+            let due: Vec<(String, String)> = state
+                .channel_presence
+                .iter()
+                .flat_map(|channel_map| {
+                    let channel = channel_map.key().clone();
+                    channel_map
+                        .iter()
+                        .filter(|info| info.metadata_expires_at.values().any(|expires_at| *expires_at <= now))
+                        .map(|info| (channel.clone(), info.id.clone()))
+                        .collect::<Vec<_>>()
+                })
+                .collect();
+
+            for (channel, client_id) in due {
+                if let Some(info) = expire_metadata(&state, &channel, &client_id, now) {
+                    announce(&state, &channel, "presence_update", &info);
+                }
+            }
+        }
+    });
+}
+
+// Mark online entries that haven't heartbeat within the stale window as away, starting
+// their grace window, so silent connections behind ping-swallowing proxies drop off rosters
+pub fn spawn_stale_sweeper(state: AppState) {
+    let stale_secs = state.config.presence_stale_secs;
+    if stale_secs == 0 {
+        return;
+    }
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs((stale_secs / 4).max(1)));
+        loop {
+            interval.tick().await;
+
+            let cutoff = clock::now().timestamp_millis() - (stale_secs * 1000) as i64;
+            let stale: Vec<(String, String)> = state
+                .channel_presence
+                .iter()
+                .flat_map(|channel_map| {
+                    let channel = channel_map.key().clone();
+                    channel_map
+                        .iter()
+                        .filter(|info| info.status == STATUS_ONLINE && info.last_activity < cutoff)
+                        .map(|info| (channel.clone(), info.id.clone()))
+                        .collect::<Vec<_>>()
+                })
+                .collect();
+
+            for (channel, client_id) in stale {
+                println!("🫥 Client {} went stale in channel {}", client_id, channel);
+                begin_grace(&state, &channel, &client_id);
+            }
+        }
+    });
+}
+
+// Reconcile a channel's roster with the live connections: online entries whose connection
+// no longer exists are removed and announced as user_left. Away entries are expected to
+// have no connection and are left to their grace window. Returns how many entries were
+// checked and the ones pruned.
+pub fn probe(state: &AppState, channel: &str) -> (usize, Vec<ClientInfo>) {
+    let (checked, orphaned): (usize, Vec<String>) = state
+        .channel_presence
+        .get(channel)
+        .map(|channel_map| {
+            let orphaned = channel_map
+                .iter()
+                .filter(|info| info.status == STATUS_ONLINE && !state.clients.contains_key(info.key()))
+                .map(|info| info.key().clone())
+                .collect();
+            (channel_map.len(), orphaned)
+        })
+        .unwrap_or_default();
+
+    let mut pruned = Vec::new();
+    for client_id in orphaned {
+        let removed = state.channel_presence.get(channel).and_then(|channel_map| {
+            channel_map.remove_if(&client_id, |id, info| {
+                info.status == STATUS_ONLINE && !state.clients.contains_key(id)
+            })
+        });
+        if let Some((_, info)) = removed {
+            leave_sets(state, channel, &client_id);
+            announce(state, channel, "user_left", &info);
+            println!("🩺 Pruned presence entry {} from channel {}: its connection is gone", client_id, channel);
+            pruned.push(info);
+        }
+    }
+    state.channel_presence.remove_if(channel, |_, channel_map| channel_map.is_empty());
+
+    (checked, pruned)
+}
+
+// Remove a presence entry that is still away and tell the channel it left
+fn reap(state: &AppState, channel: &str, client_id: &str) {
+    let removed = state
+        .channel_presence
+        .get(channel)
+        .and_then(|channel_map| channel_map.remove_if(client_id, |_, info| info.status == STATUS_AWAY));
+
+    if let Some((_, info)) = removed {
+        state.channel_presence.remove_if(channel, |_, channel_map| channel_map.is_empty());
+        leave_sets(state, channel, client_id);
+        announce(state, channel, "user_left", &info);
+        println!("👋 Client {} left channel {}", client_id, channel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock::mock::MockClock, testing::TestServer};
+    use chrono::DateTime;
+
+    fn start() -> DateTime<chrono::Utc> {
+        DateTime::from_timestamp(1_700_000_000, 0).unwrap()
+    }
+
+    // Long enough for a sweeper on a one-second interval to have run
+    async fn let_sweep() {
+        tokio::time::sleep(METADATA_SWEEP_INTERVAL + Duration::from_millis(200)).await;
+    }
+
+    #[tokio::test]
+    async fn metadata_keys_are_cleared_when_their_ttl_runs_out() {
+        let clock = MockClock::install(start());
+        let server = TestServer::start(|_| {}).await;
+        spawn_metadata_sweeper(server.state.clone());
+        let mut teacher = server.connect("").await;
+        teacher.subscribe("lesson", serde_json::json!({})).await;
+        let mut student = server.connect("").await;
+        let metadata = serde_json::json!({ "display_name": "Ada", "avatar_url": "https://example.com/ada.png" });
+        student
+            .subscribe("lesson", serde_json::json!({ "metadata": metadata, "metadata_ttl_secs": { "avatar_url": 60 } }))
+            .await;
+        let entry = || {
+            let channel = server.state.channel_presence.get("lesson")?;
+            channel.get(&student.client_id).map(|info| info.clone())
+        };
+        assert_eq!(entry().unwrap().metadata_expires_at.get("avatar_url"), Some(&(start().timestamp_millis() + 60_000)));
+
+        clock.advance(Duration::from_secs(59));
+        let_sweep().await;
+        assert!(entry().unwrap().metadata.unwrap().contains_key("avatar_url"));
+
+        clock.advance(Duration::from_secs(1));
+        let update = teacher.expect("presence_update").await;
+        assert_eq!(update["data"]["id"], student.client_id.as_str());
+        assert_eq!(update["data"]["metadata"], serde_json::json!({ "display_name": "Ada" }));
+        assert!(entry().unwrap().metadata_expires_at.is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_connection_that_stops_heartbeating_goes_away_and_a_heartbeat_brings_it_back() {
+        let clock = MockClock::install(start());
+        let server = TestServer::start(|config| config.presence_stale_secs = 4).await;
+        spawn_stale_sweeper(server.state.clone());
+        let mut teacher = server.connect("").await;
+        teacher.subscribe("lesson", serde_json::json!({})).await;
+        let mut student = server.connect("").await;
+        student.subscribe("lesson", serde_json::json!({})).await;
+        let status = || {
+            let channel = server.state.channel_presence.get("lesson")?;
+            channel.get(&student.client_id).map(|info| info.status.clone())
+        };
+
+        // The teacher keeps heartbeating, the student doesn't
+        clock.advance(Duration::from_secs(3));
+        teacher.send(serde_json::json!({ "action": "heartbeat" })).await;
+        teacher.expect("heartbeat_ack").await;
+        let_sweep().await;
+        assert_eq!(status().as_deref(), Some(STATUS_ONLINE));
+
+        clock.advance(Duration::from_secs(2));
+        let update = teacher.expect("presence_update").await;
+        assert_eq!(update["data"]["id"], student.client_id.as_str());
+        assert_eq!(update["data"]["status"], STATUS_AWAY);
+
+        student.send(serde_json::json!({ "action": "heartbeat" })).await;
+        let update = teacher.expect("presence_update").await;
+        assert_eq!(update["data"]["id"], student.client_id.as_str());
+        assert_eq!(update["data"]["status"], STATUS_ONLINE);
+    }
+}
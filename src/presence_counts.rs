@@ -0,0 +1,257 @@
+// Presence counts for dashboards.
+
+use axum::extract::ws::Message;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::task::JoinHandle;
+
+use crate::{auth::Identity, roles, AppState, Outgoing, ServerMessage};
+
+// Shortest interval between two `presence_counts` messages, in ms
+const MIN_INTERVAL_MS: u64 = 100;
+
+// Participants on each matching channel the identity's tenant may use
+async fn counts(
+    state: &AppState,
+    identity: &Identity,
+    pattern: &str,
+    allowed: &mut HashMap<String, bool>,
+) -> HashMap<String, i64> {
+    let matching: Vec<(String, i64)> = state
+        .channel_presence
+        .iter()
+        .filter(|channel_map| roles::matches_pattern(pattern, channel_map.key()))
+        .map(|channel_map| (channel_map.key().clone(), channel_map.len() as i64))
+        .collect();
+
+    let mut counts = HashMap::new();
+    for (channel, count) in matching {
+        // Tenancy is decided by the channel's name, so each channel is resolved only once
+        let permitted = match allowed.get(&channel) {
+            Some(permitted) => *permitted,
+            None => {
+                let permitted = state.tenant_resolver.resolve(&channel, identity).await.is_ok();
+                allowed.insert(channel.clone(), permitted);
+                permitted
+            }
+        };
+        if permitted {
+            counts.insert(channel, count);
+        }
+    }
+    counts
+}
+
+// Count changes from one tick to the next, a channel that went away dropping to zero
+fn deltas(previous: &HashMap<String, i64>, current: &HashMap<String, i64>) -> HashMap<String, i64> {
+    let changed = current
+        .iter()
+        .map(|(channel, count)| (channel.clone(), count - previous.get(channel).copied().unwrap_or(0)));
+    let gone = previous
+        .iter()
+        .filter(|(channel, _)| !current.contains_key(*channel))
+        .map(|(channel, count)| (channel.clone(), -count));
+    changed.chain(gone).filter(|(_, delta)| *delta != 0).collect()
+}
+
+// Queue a `presence_counts` message, returning whether it fit in the outgoing queue
+fn send(outgoing_tx: &Outgoing, request_id: Option<&str>, data: serde_json::Value) -> bool {
+    let msg = ServerMessage {
+        request_id: request_id.map(str::to_string),
+        ..ServerMessage::new("presence_counts", "", data)
+    };
+    msg.to_json()
+        .is_some_and(|json| outgoing_tx.try_send(Message::Text(json.into())).is_ok())
+}
+
+// A pattern with nothing fixed before its first `*`, such as `*` itself, matches channels
+// of every kind
+pub fn is_broad(pattern: &str) -> bool {
+    pattern.starts_with('*')
+}
+
+// Whether a connection with this role may watch the pattern. Broad patterns need
+// RABLY_BROAD_PATTERN_ROLE, and are refused to everyone while it's unset or unknown.
+pub fn may_watch(state: &AppState, role: &str, pattern: &str) -> bool {
+    let config = &state.config;
+    !is_broad(pattern)
+        || config
+            .broad_pattern_role
+            .as_ref()
+            .is_some_and(|broad| config.role_hierarchy.contains(broad) && roles::at_most(state, broad, role))
+}
+
+// A running watch. Dropping it stops the task and frees its place under the server-wide limit.
+pub struct Watch {
+    task: JoinHandle<()>,
+    total: Arc<AtomicUsize>,
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        self.task.abort();
+        self.total.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Send the counts for a pattern until the returned watch is dropped. None if the server
+// already has as many watches as RABLY_MAX_PATTERN_WATCHES_TOTAL allows.
+pub fn watch(
+    state: AppState,
+    identity: Identity,
+    pattern: String,
+    request_id: Option<String>,
+    outgoing_tx: Outgoing,
+) -> Option<Watch> {
+    let limit = state.config.max_pattern_watches_total;
+    let total = state.pattern_watches.clone();
+    total
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |watches| (limit == 0 || watches < limit).then_some(watches + 1))
+        .ok()?;
+
+    let interval = Duration::from_millis(state.config.presence_count_interval_ms.max(MIN_INTERVAL_MS));
+    let task = tokio::spawn(async move {
+        let mut allowed = HashMap::new();
+        // What the client was last told; None until the first full snapshot gets through
+        let mut known: Option<HashMap<String, i64>> = None;
+        let mut tick = tokio::time::interval(interval);
+        loop {
+            tick.tick().await;
+            let current = counts(&state, &identity, &pattern, &mut allowed).await;
+
+            let data = match &known {
+                None => serde_json::json!({ "pattern": pattern, "counts": current }),
+                Some(previous) => {
+                    let deltas = deltas(previous, &current);
+                    if deltas.is_empty() {
+                        continue;
+                    }
+                    serde_json::json!({ "pattern": pattern, "deltas": deltas })
+                }
+            };
+            // A message that didn't fit is folded into the next one
+            if send(&outgoing_tx, request_id.as_deref(), data) {
+                known = Some(current);
+            }
+        }
+    });
+    Some(Watch { task, total })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::Config,
+        testing::{TestClient, TestServer},
+    };
+
+    // Connections get the bottom role of the hierarchy, which may watch presence counts
+    fn with_default_role(config: &mut Config, role: &str) {
+        let at = config.role_hierarchy.iter().position(|candidate| candidate == role).expect("role in the hierarchy");
+        config.role_hierarchy.truncate(at + 1);
+        config.query_presence_role = role.to_string();
+    }
+
+    // The error a watch was refused with, or None if its first snapshot came
+    async fn watch_refusal(client: &mut TestClient, pattern: &str) -> Option<String> {
+        client.send(serde_json::json!({ "action": "watch_presence_counts", "channel": pattern })).await;
+        loop {
+            let msg = client.next().await.expect("connection closed");
+            match msg["type"].as_str() {
+                Some("presence_counts") if msg["data"]["pattern"] == pattern => return None,
+                Some("error") => return Some(msg["data"]["code"].as_str().unwrap_or_default().to_string()),
+                _ => continue,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn a_connection_watches_at_most_its_limit() {
+        let server = TestServer::start(|config| {
+            with_default_role(config, "observer");
+            config.max_pattern_watches = 2;
+        })
+        .await;
+        let mut dashboard = server.connect("").await;
+
+        assert_eq!(watch_refusal(&mut dashboard, "math-*").await, None);
+        assert_eq!(watch_refusal(&mut dashboard, "art-*").await, None);
+        assert_eq!(watch_refusal(&mut dashboard, "music-*").await.as_deref(), Some("too_many_watches"));
+        // Watching one again doesn't take another place
+        assert_eq!(watch_refusal(&mut dashboard, "math-*").await, None);
+        assert_eq!(server.state.pattern_watches.load(Ordering::Relaxed), 2);
+
+        dashboard.send(serde_json::json!({ "action": "unwatch_presence_counts", "channel": "art-*" })).await;
+        dashboard.expect("info").await;
+        assert_eq!(watch_refusal(&mut dashboard, "music-*").await, None);
+    }
+
+    #[tokio::test]
+    async fn the_server_watches_at_most_its_total() {
+        let server = TestServer::start(|config| {
+            with_default_role(config, "observer");
+            config.max_pattern_watches_total = 3;
+        })
+        .await;
+        let mut first = server.connect("").await;
+        let mut second = server.connect("").await;
+
+        assert_eq!(watch_refusal(&mut first, "math-*").await, None);
+        assert_eq!(watch_refusal(&mut first, "art-*").await, None);
+        assert_eq!(watch_refusal(&mut second, "math-*").await, None);
+        assert_eq!(watch_refusal(&mut second, "art-*").await.as_deref(), Some("too_many_watches"));
+
+        // A connection's watches are given back when it goes
+        first.close().await;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while server.state.pattern_watches.load(Ordering::Relaxed) > 1 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the closed connection's watches were never released");
+        assert_eq!(watch_refusal(&mut second, "art-*").await, None);
+        let (_, metrics) = server.request("GET", "/metrics", None).await;
+        assert!(metrics.contains("rably_presence_count_watches 2"), "{}", metrics);
+    }
+
+    #[tokio::test]
+    async fn broad_patterns_need_the_broad_pattern_role() {
+        let server = TestServer::start(|config| with_default_role(config, "observer")).await;
+        let mut observer = server.connect("").await;
+        for pattern in ["*", "*-math", "**"] {
+            assert_eq!(watch_refusal(&mut observer, pattern).await.as_deref(), Some("forbidden"), "{}", pattern);
+        }
+        assert_eq!(watch_refusal(&mut observer, "lesson-*").await, None);
+
+        let server = TestServer::start(|config| {
+            with_default_role(config, "observer");
+            config.broad_pattern_role = Some("teacher".to_string());
+        })
+        .await;
+        let mut observer = server.connect("").await;
+        assert_eq!(watch_refusal(&mut observer, "*").await.as_deref(), Some("forbidden"));
+
+        let server = TestServer::start(|config| {
+            with_default_role(config, "teacher");
+            config.broad_pattern_role = Some("teacher".to_string());
+        })
+        .await;
+        let mut teacher = server.connect("").await;
+        assert_eq!(watch_refusal(&mut teacher, "*").await, None);
+    }
+
+    #[test]
+    fn an_unknown_broad_pattern_role_lets_nobody_in() {
+        let state = crate::testing::state(|config| config.broad_pattern_role = Some("principal".to_string()));
+        assert!(!may_watch(&state, "teacher", "*"));
+        assert!(may_watch(&state, "teacher", "lesson-*"));
+    }
+}
@@ -0,0 +1,136 @@
+// Best-effort presence persistence across restarts.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::PathBuf,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+use crate::{clock, degradation, presence, AppState, ClientInfo};
+
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct PresenceStore {
+    path: PathBuf,
+    dirty: AtomicBool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    saved_at: i64,
+    channels: HashMap<String, Vec<ClientInfo>>,
+}
+
+impl PresenceStore {
+    pub fn new(path: &str) -> Self {
+        PresenceStore {
+            path: PathBuf::from(path),
+            dirty: AtomicBool::new(false),
+        }
+    }
+
+    fn save(&self, snapshot: &Snapshot) -> io::Result<()> {
+        let json = serde_json::to_vec(snapshot).map_err(io::Error::other)?;
+        let temp = self.path.with_extension("tmp");
+        fs::write(&temp, json)?;
+        fs::rename(&temp, &self.path)
+    }
+
+    fn load(&self) -> io::Result<Option<Snapshot>> {
+        match fs::read(&self.path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).map(Some).map_err(io::Error::other),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+// Note that a roster changed so the next flush writes it out
+pub fn mark_dirty(state: &AppState) {
+    if let Some(store) = &state.presence_store {
+        store.dirty.store(true, Ordering::Relaxed);
+    }
+}
+
+// Load the last snapshot, unless it's too old for its clients to still be around
+pub fn restore(state: &AppState) {
+    let Some(store) = &state.presence_store else {
+        return;
+    };
+
+    let snapshot = match store.load() {
+        Ok(Some(snapshot)) => snapshot,
+        Ok(None) => return,
+        Err(e) => {
+            eprintln!("❌ Failed to load presence from {}: {}", store.path.display(), e);
+            return;
+        }
+    };
+
+    let age = clock::now().timestamp() - snapshot.saved_at;
+    if age > state.config.presence_store_max_age_secs as i64 {
+        println!("🗑️ Presence snapshot is {}s old; not restoring it", age);
+        return;
+    }
+
+    let mut restored = 0;
+    for (channel, participants) in snapshot.channels {
+        for info in participants {
+            presence::restore_away(state, &channel, info);
+            restored += 1;
+        }
+    }
+    println!("♻️ Restored {} presence entries from a snapshot {}s old", restored, age);
+}
+
+// Write a snapshot whenever presence has changed since the last one, and rewrite an
+// unchanged one often enough that a quiet roster never looks stale on restart
+pub fn spawn_flusher(state: AppState) {
+    if state.presence_store.is_none() {
+        return;
+    }
+    let refresh = Duration::from_secs(state.config.presence_store_max_age_secs / 2).max(FLUSH_INTERVAL);
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+        let mut last_saved = Instant::now();
+        loop {
+            interval.tick().await;
+
+            let Some(store) = state.presence_store.clone() else {
+                return;
+            };
+            if !store.dirty.swap(false, Ordering::Relaxed) && last_saved.elapsed() < refresh {
+                continue;
+            }
+            last_saved = Instant::now();
+
+            let channels = state
+                .channel_presence
+                .iter()
+                .map(|entry| {
+                    let participants = entry.value().iter().map(|info| info.value().clone()).collect();
+                    (entry.key().clone(), participants)
+                })
+                .collect();
+            let snapshot = Snapshot {
+                saved_at: clock::now().timestamp(),
+                channels,
+            };
+
+            let result = tokio::task::spawn_blocking(move || store.save(&snapshot)).await;
+            match result {
+                Ok(Ok(())) => state.degradations.recover(degradation::PRESENCE_STORE),
+                Ok(Err(e)) => {
+                    eprintln!("❌ Failed to save presence snapshot: {}", e);
+                    state.degradations.fail(degradation::PRESENCE_STORE, e);
+                    mark_dirty(&state);
+                }
+                Err(_) => {}
+            }
+        }
+    });
+}
@@ -0,0 +1,200 @@
+// Per-channel presenter lock.
+
+use dashmap::mapref::entry::Entry;
+
+use crate::{broadcast_event, presence, roles::{self, Permission}, AppState};
+
+// Why a handover was refused
+#[derive(Debug)]
+pub enum TransferError {
+    // The caller doesn't hold the lock it tried to give away
+    NotHolder,
+    // The target isn't online in the channel or can't change slides
+    InvalidTarget,
+}
+
+pub fn holder(state: &AppState, channel: &str) -> Option<String> {
+    state.presenter_locks.get(channel).map(|holder| holder.clone())
+}
+
+// Whether two connections on the channel are the same participant: the same connection,
+// or two with the same identity
+fn same_participant(state: &AppState, channel: &str, a: &str, b: &str) -> bool {
+    if a == b {
+        return true;
+    }
+    state.channel_presence.get(channel).is_some_and(|channel_map| {
+        let identity = |client_id: &str| channel_map.get(client_id).and_then(|info| info.identity.clone());
+        identity(a).is_some_and(|identity_a| identity(b).is_some_and(|identity_b| identity_a == identity_b))
+    })
+}
+
+// Whether the client's slide changes are accepted on the channel
+pub fn may_present(state: &AppState, channel: &str, client_id: &str) -> bool {
+    !state.config.presenter_lock || holder(state, channel).is_some_and(|holder| same_participant(state, channel, &holder, client_id))
+}
+
+// Take the lock if it's free, returning whether it changed hands. Acquiring a lock the
+// client's participant already holds succeeds; one held by someone else fails with the holder.
+pub fn acquire(state: &AppState, channel: &str, client_id: &str) -> Result<bool, String> {
+    match state.presenter_locks.entry(channel.to_string()) {
+        Entry::Vacant(entry) => {
+            entry.insert(client_id.to_string());
+        }
+        Entry::Occupied(entry) if same_participant(state, channel, entry.get(), client_id) => return Ok(false),
+        Entry::Occupied(entry) => return Err(entry.get().clone()),
+    }
+    broadcast_event(state, channel, "presenter_acquired", serde_json::json!({ "client_id": client_id }));
+    Ok(true)
+}
+
+// Give up the lock if the client's participant holds it, telling the channel
+pub fn release(state: &AppState, channel: &str, client_id: &str) -> bool {
+    let released = state
+        .presenter_locks
+        .remove_if(channel, |_, holder| same_participant(state, channel, holder, client_id))
+        .map(|(_, holder)| holder);
+    if let Some(holder) = &released {
+        broadcast_event(state, channel, "presenter_released", serde_json::json!({ "client_id": holder }));
+    }
+    released.is_some()
+}
+
+// A connection left the channel. If it held the lock, the lock passes to another online
+// connection of the same identity that may change slides, or is released.
+pub fn leave(state: &AppState, channel: &str, client_id: &str) {
+    if holder(state, channel).is_none_or(|holder| holder != client_id) {
+        return;
+    }
+    let successor = state.channel_presence.get(channel).and_then(|channel_map| {
+        let identity = channel_map.get(client_id).and_then(|info| info.identity.clone())?;
+        channel_map
+            .iter()
+            .find(|entry| {
+                entry.key() != client_id
+                    && entry.status == presence::STATUS_ONLINE
+                    && entry.identity.as_ref() == Some(&identity)
+            })
+            .map(|entry| entry.key().clone())
+    });
+    let handed_over = successor.is_some_and(|successor| transfer(state, channel, client_id, &successor).is_ok());
+    if !handed_over {
+        release(state, channel, client_id);
+    }
+}
+
+pub fn transfer(state: &AppState, channel: &str, from: &str, to: &str) -> Result<(), TransferError> {
+    let eligible = state.channel_presence.get(channel).is_some_and(|channel_map| {
+        channel_map.get(to).is_some_and(|info| {
+            info.status == presence::STATUS_ONLINE && roles::allows(state, &info.role, Permission::SlideChange)
+        })
+    });
+    if !eligible {
+        return Err(TransferError::InvalidTarget);
+    }
+
+    let previous = match state.presenter_locks.get_mut(channel) {
+        Some(mut holder) if same_participant(state, channel, &holder, from) => std::mem::replace(&mut *holder, to.to_string()),
+        _ => return Err(TransferError::NotHolder),
+    };
+    broadcast_event(
+        state,
+        channel,
+        "presenter_transferred",
+        serde_json::json!({ "from_client_id": previous, "to_client_id": to }),
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{TestClient, TestServer};
+
+    async fn dual_device_server() -> TestServer {
+        TestServer::start(|config| {
+            config.single_presenter = true;
+            config.presenter_lock = true;
+        })
+        .await
+    }
+
+    async fn teacher(server: &TestServer, identity: &str) -> TestClient {
+        let mut device = server.connect(&format!("?identity={}", identity)).await;
+        device.subscribe("lesson", serde_json::json!({ "role": "teacher" })).await;
+        device
+    }
+
+    async fn change_slide(device: &mut TestClient, slide: u64) {
+        device.send(serde_json::json!({ "action": "slide_change", "channel": "lesson", "data": { "slide": slide } })).await;
+    }
+
+    #[tokio::test]
+    async fn both_devices_are_listed_under_one_identity_and_either_drives() {
+        let server = dual_device_server().await;
+        let mut laptop = teacher(&server, "teacher-1").await;
+        let mut phone = teacher(&server, "teacher-1").await;
+        let mut student = server.connect("").await;
+        student.subscribe("lesson", serde_json::json!({})).await;
+
+        let presence = server.state.channel_presence.get("lesson").expect("channel presence");
+        for device in [&laptop, &phone] {
+            assert_eq!(presence.get(&device.client_id).expect("listed").identity.as_deref(), Some("teacher-1"));
+        }
+        drop(presence);
+
+        // Someone else still can't be a second presenter
+        let mut other = server.connect("?identity=teacher-2").await;
+        other.send(serde_json::json!({ "action": "subscribe", "channel": "lesson", "role": "teacher" })).await;
+        assert_eq!(other.expect("error").await["data"]["code"], "presenter_taken");
+
+        laptop.send(serde_json::json!({ "action": "acquire_presenter", "channel": "lesson" })).await;
+        laptop.expect("presenter").await;
+        phone.send(serde_json::json!({ "action": "acquire_presenter", "channel": "lesson" })).await;
+        phone.expect("presenter").await;
+        assert_eq!(holder(&server.state, "lesson"), Some(laptop.client_id.clone()));
+
+        change_slide(&mut phone, 2).await;
+        assert_eq!(student.expect("slide_change").await["data"]["slide"], 2);
+        change_slide(&mut laptop, 3).await;
+        assert_eq!(student.expect("slide_change").await["data"]["slide"], 3);
+    }
+
+    #[tokio::test]
+    async fn the_lock_stays_with_the_identity_while_one_device_remains() {
+        let server = dual_device_server().await;
+        let mut laptop = teacher(&server, "teacher-1").await;
+        let mut phone = teacher(&server, "teacher-1").await;
+        let mut student = server.connect("").await;
+        student.subscribe("lesson", serde_json::json!({})).await;
+        laptop.send(serde_json::json!({ "action": "acquire_presenter", "channel": "lesson" })).await;
+        laptop.expect("presenter").await;
+
+        laptop.close().await;
+        let transferred = student.expect("presenter_transferred").await;
+        assert_eq!(transferred["data"]["from_client_id"], laptop.client_id.as_str());
+        assert_eq!(transferred["data"]["to_client_id"], phone.client_id.as_str());
+        assert_eq!(holder(&server.state, "lesson"), Some(phone.client_id.clone()));
+
+        change_slide(&mut phone, 4).await;
+        assert_eq!(student.expect("slide_change").await["data"]["slide"], 4);
+
+        // With the last device gone, the lock is free
+        phone.close().await;
+        assert_eq!(student.expect("presenter_released").await["data"]["client_id"], phone.client_id.as_str());
+        assert_eq!(holder(&server.state, "lesson"), None);
+    }
+
+    #[tokio::test]
+    async fn releasing_from_either_device_frees_the_lock() {
+        let server = dual_device_server().await;
+        let mut laptop = teacher(&server, "teacher-1").await;
+        let mut phone = teacher(&server, "teacher-1").await;
+        laptop.send(serde_json::json!({ "action": "acquire_presenter", "channel": "lesson" })).await;
+        laptop.expect("presenter").await;
+
+        phone.send(serde_json::json!({ "action": "release_presenter", "channel": "lesson" })).await;
+        assert_eq!(laptop.expect("presenter_released").await["data"]["client_id"], laptop.client_id.as_str());
+        assert_eq!(holder(&server.state, "lesson"), None);
+    }
+}
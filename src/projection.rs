@@ -0,0 +1,66 @@
+// Field projection for bandwidth-constrained subscribers.
+
+use crate::ChannelEvent;
+
+const MAX_PATHS: usize = 16;
+const MAX_DEPTH: usize = 8;
+
+pub struct Projection {
+    paths: Vec<Vec<String>>,
+}
+
+impl Projection {
+    pub fn parse(paths: &[String]) -> Result<Self, String> {
+        if paths.is_empty() || paths.len() > MAX_PATHS {
+            return Err(format!("fields must list between 1 and {} paths", MAX_PATHS));
+        }
+
+        let paths = paths
+            .iter()
+            .map(|path| {
+                let segments: Vec<String> = path.split('.').map(str::to_string).collect();
+                if segments.iter().any(String::is_empty) || segments.len() > MAX_DEPTH {
+                    Err(format!("invalid field path {:?}", path))
+                } else {
+                    Ok(segments)
+                }
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Projection { paths })
+    }
+
+    // Whether this broadcast's data is trimmed; server events keep their full data, and
+    // encrypted messages have no fields to pick
+    pub fn applies_to(&self, event: &ChannelEvent) -> bool {
+        matches!(event.msg.r#type.as_str(), "message" | "slide_change" | "slide_diff") && !event.msg.encrypted
+    }
+
+    // Copy of `data` holding only the projected paths that exist in it, at the same nesting
+    pub fn apply(&self, data: &serde_json::Value) -> serde_json::Value {
+        let mut projected = serde_json::Map::new();
+        for path in &self.paths {
+            if let Some(value) = path.iter().try_fold(data, |value, segment| value.get(segment)) {
+                insert(&mut projected, path, value.clone());
+            }
+        }
+        serde_json::Value::Object(projected)
+    }
+}
+
+fn insert(target: &mut serde_json::Map<String, serde_json::Value>, path: &[String], value: serde_json::Value) {
+    match path {
+        [last] => {
+            target.insert(last.clone(), value);
+        }
+        [first, rest @ ..] => {
+            let child = target
+                .entry(first.clone())
+                .or_insert_with(|| serde_json::Value::Object(serde_json::Map::new()));
+            // Not an object when a shorter path already copied this subtree whole
+            if let Some(child) = child.as_object_mut() {
+                insert(child, rest, value);
+            }
+        }
+        [] => {}
+    }
+}
@@ -0,0 +1,55 @@
+// Per-channel cap on publishes processed at once.
+
+use std::{sync::Arc, time::Duration};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::{channel_config, AppState};
+
+pub struct PublishSlots {
+    limit: usize,
+    semaphore: Arc<Semaphore>,
+}
+
+// Concurrent publishes allowed on the channel, 0 meaning unlimited
+pub fn limit(state: &AppState, channel: &str) -> usize {
+    channel_config::overrides(state, channel)
+        .max_concurrent_publishes
+        .unwrap_or(state.config.channel_max_concurrent_publishes)
+}
+
+// Take one of the channel's publish slots, waiting up to RABLY_PUBLISH_SLOT_WAIT_MS for
+// one to free up. Ok(None) means the channel is unlimited; Err means it stayed busy. The
+// slot is released when the permit is dropped.
+pub async fn acquire(state: &AppState, channel: &str) -> Result<Option<OwnedSemaphorePermit>, ()> {
+    let limit = limit(state, channel);
+    if limit == 0 {
+        return Ok(None);
+    }
+
+    let semaphore = {
+        let mut slots = state
+            .publish_slots
+            .entry(channel.to_string())
+            .or_insert_with(|| PublishSlots {
+                limit,
+                semaphore: Arc::new(Semaphore::new(limit)),
+            });
+        // The limit changed: publishes in flight finish on the old semaphore
+        if slots.limit != limit {
+            *slots = PublishSlots {
+                limit,
+                semaphore: Arc::new(Semaphore::new(limit)),
+            };
+        }
+        slots.semaphore.clone()
+    };
+
+    if let Ok(permit) = semaphore.clone().try_acquire_owned() {
+        return Ok(Some(permit));
+    }
+    let wait = Duration::from_millis(state.config.publish_slot_wait_ms);
+    match tokio::time::timeout(wait, semaphore.acquire_owned()).await {
+        Ok(Ok(permit)) => Ok(Some(permit)),
+        _ => Err(()),
+    }
+}
@@ -0,0 +1,144 @@
+// Publish-and-confirm for lesson checkpoints.
+
+use std::time::Duration;
+
+use crate::{send_direct, AppState, Outgoing};
+
+// Upper bound on a client-requested timeout, so abandoned quorums can't pile up
+const MAX_TIMEOUT: Duration = Duration::from_secs(300);
+
+// Who a quorum reports back to
+pub struct Publisher {
+    pub client_id: String,
+    pub outgoing: Outgoing,
+    // The publisher's request_id, echoed on the result
+    pub request_id: Option<String>,
+}
+
+pub struct PendingQuorum {
+    channel: String,
+    publisher: Publisher,
+    expected_acks: usize,
+    acked_by: Vec<String>,
+}
+
+// Why an ack wasn't counted
+pub enum AckError {
+    // No quorum is waiting on this message, or it already completed
+    UnknownMessage,
+    // The acking client isn't subscribed to the message's channel
+    NotSubscribed,
+}
+
+// Start collecting acks for a message about to be broadcast. Refused with the limit when
+// the publisher already has as many quorums outstanding on the channel as it may.
+pub fn register(
+    state: &AppState,
+    message_id: &str,
+    channel: &str,
+    publisher: Publisher,
+    expected_acks: usize,
+    timeout_ms: Option<u64>,
+) -> Result<(), usize> {
+    let limit = state.config.max_unacked_per_publisher;
+    {
+        let mut outstanding = state
+            .unacked_quorums
+            .entry((publisher.client_id.clone(), channel.to_string()))
+            .or_insert(0);
+        if limit > 0 && *outstanding >= limit {
+            return Err(limit);
+        }
+        *outstanding += 1;
+    }
+
+    state.pending_quorums.insert(
+        message_id.to_string(),
+        PendingQuorum {
+            channel: channel.to_string(),
+            publisher,
+            expected_acks,
+            acked_by: Vec::new(),
+        },
+    );
+
+    let timeout = Duration::from_millis(timeout_ms.unwrap_or(state.live().quorum_timeout_ms)).min(MAX_TIMEOUT);
+    let state = state.clone();
+    let message_id = message_id.to_string();
+    tokio::spawn(async move {
+        tokio::time::sleep(timeout).await;
+        finish(&state, &message_id);
+    });
+    Ok(())
+}
+
+// Drop a quorum without reporting, e.g. when the broadcast reached nobody
+pub fn cancel(state: &AppState, message_id: &str) {
+    if let Some((_, pending)) = state.pending_quorums.remove(message_id) {
+        release(state, &pending);
+    }
+}
+
+// The quorum no longer counts against its publisher's outstanding limit
+fn release(state: &AppState, pending: &PendingQuorum) {
+    let key = (pending.publisher.client_id.clone(), pending.channel.clone());
+    if let Some(mut outstanding) = state.unacked_quorums.get_mut(&key) {
+        *outstanding = outstanding.saturating_sub(1);
+    }
+    state.unacked_quorums.remove_if(&key, |_, outstanding| *outstanding == 0);
+}
+
+// Count one client's ack, reporting early once the expected number is in
+pub fn ack(state: &AppState, message_id: &str, client_id: &str) -> Result<(), AckError> {
+    let met = {
+        let mut pending = state.pending_quorums.get_mut(message_id).ok_or(AckError::UnknownMessage)?;
+
+        let subscribed = state
+            .channel_presence
+            .get(&pending.channel)
+            .is_some_and(|channel_map| channel_map.contains_key(client_id));
+        if !subscribed {
+            return Err(AckError::NotSubscribed);
+        }
+
+        // Repeated acks from the same client count once
+        if !pending.acked_by.iter().any(|id| id == client_id) {
+            pending.acked_by.push(client_id.to_string());
+        }
+        pending.acked_by.len() >= pending.expected_acks
+    };
+
+    if met {
+        finish(state, message_id);
+    }
+    Ok(())
+}
+
+// Send the publisher its summary; a no-op if the quorum was already reported
+fn finish(state: &AppState, message_id: &str) {
+    let Some((_, pending)) = state.pending_quorums.remove(message_id) else {
+        return;
+    };
+    release(state, &pending);
+
+    let acks_received = pending.acked_by.len();
+    let met = acks_received >= pending.expected_acks;
+    println!(
+        "🗳️ Quorum for message {} on channel {}: {}/{} acks",
+        message_id, pending.channel, acks_received, pending.expected_acks
+    );
+
+    send_direct(
+        &pending.publisher.outgoing,
+        pending.publisher.request_id.as_deref(),
+        &pending.channel,
+        "quorum_result",
+        serde_json::json!({
+            "message_id": message_id,
+            "expected_acks": pending.expected_acks,
+            "acks_received": acks_received,
+            "acked_by": pending.acked_by,
+            "met": met,
+        }),
+    );
+}
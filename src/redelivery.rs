@@ -0,0 +1,94 @@
+// At-least-once delivery: each subscriber's unacked messages are sent again on a timeout until
+// acked, or given up on and dead-lettered as `undelivered`.
+
+use axum::extract::ws::Message;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{dead_letter::DeadLetterReason, metrics::Metrics, AppState, Outgoing};
+
+// A message sent to the connection and not yet acked
+struct Outstanding {
+    channel: String,
+    frame: Message,
+    // Sends so far, the first included
+    attempts: u32,
+    // When to send it again
+    due: Instant,
+}
+
+// A connection's unacked at-least-once messages, by message id
+#[derive(Default)]
+pub struct Deliveries(Mutex<HashMap<String, Outstanding>>);
+
+// Whether at-least-once publishes are allowed
+pub fn enabled(state: &AppState) -> bool {
+    state.config.redelivery_max_attempts > 0
+}
+
+fn timeout(state: &AppState) -> Duration {
+    Duration::from_millis(state.config.redelivery_timeout_ms.max(1))
+}
+
+fn give_up(state: &AppState, client_id: &str, channel: &str, frame: &Message) {
+    Metrics::inc(&state.metrics.undelivered);
+    let payload = frame.to_text().unwrap_or("<binary>");
+    state.dead_letters.record(DeadLetterReason::Undelivered, Some(channel), client_id, payload);
+}
+
+impl Deliveries {
+    // Start waiting for the connection's ack of a message just sent to it
+    pub fn track(&self, state: &AppState, client_id: &str, channel: &str, message_id: &str, frame: Message) {
+        let mut outstanding = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        if outstanding.contains_key(message_id) {
+            return;
+        }
+        if outstanding.len() >= state.config.max_outstanding_deliveries {
+            give_up(state, client_id, channel, &frame);
+            return;
+        }
+        outstanding.insert(
+            message_id.to_string(),
+            Outstanding { channel: channel.to_string(), frame, attempts: 1, due: Instant::now() + timeout(state) },
+        );
+    }
+
+    // Stop redelivering an acked message. Returns whether it was outstanding.
+    pub fn ack(&self, message_id: &str) -> bool {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).remove(message_id).is_some()
+    }
+
+    // Send again what's gone unacked too long, and give up on what's been sent often enough.
+    // A full queue puts a redelivery off to the next check rather than using up an attempt.
+    pub fn redeliver_due(&self, state: &AppState, client_id: &str, outgoing_tx: &Outgoing) {
+        let now = Instant::now();
+        let mut outstanding = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        outstanding.retain(|_, message| {
+            if message.due > now {
+                return true;
+            }
+            if message.attempts >= state.config.redelivery_max_attempts {
+                println!("📭 Giving up on delivering a message on channel {} to client {}", message.channel, client_id);
+                give_up(state, client_id, &message.channel, &message.frame);
+                return false;
+            }
+            if outgoing_tx.try_send(message.frame.clone()).is_ok() {
+                Metrics::inc(&state.metrics.redeliveries);
+                message.attempts += 1;
+                message.due = now + timeout(state);
+            }
+            true
+        });
+    }
+
+    // Give up on everything still unacked when the connection ends
+    pub fn abandon(&self, state: &AppState, client_id: &str) {
+        let mut outstanding = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        for (_, message) in outstanding.drain() {
+            give_up(state, client_id, &message.channel, &message.frame);
+        }
+    }
+}
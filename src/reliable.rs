@@ -0,0 +1,34 @@
+// Reliable channels: a publish waits while the slowest subscriber is close to lagging, rather
+// than let it skip messages, and is refused with `backpressure_timeout` if it waits too long.
+
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::{channel_config, lifecycle, AppState};
+
+// How often a waiting publish looks at the slowest subscriber again
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+// Broadcasts the slowest subscriber may be behind before publishes wait, leaving headroom
+// below the point where it would start skipping
+const HIGH_WATER: usize = lifecycle::CHANNEL_CAPACITY * 3 / 4;
+
+// Wait until the channel's slowest subscriber has caught up enough to take another
+// broadcast without skipping. Returns false if it didn't within the timeout.
+pub async fn wait_for_subscribers(state: &AppState, channel: &str) -> bool {
+    if !channel_config::reliable(state, channel) {
+        return true;
+    }
+
+    let deadline = Instant::now() + Duration::from_millis(state.config.backpressure_timeout_ms);
+    loop {
+        let behind = state.channels.get(channel).map(|tx| tx.len()).unwrap_or(0);
+        if behind < HIGH_WATER {
+            return true;
+        }
+        if Instant::now() >= deadline {
+            return false;
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
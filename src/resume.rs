@@ -0,0 +1,263 @@
+// Resuming an ended connection's subscriptions, from where its broadcasts left off, on a new
+// connection that presents its resume token.
+
+use axum::extract::ws::Message;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use uuid::Uuid;
+
+use crate::{
+    cbor, clock, close::CloseReason, dispatch, roles, send_direct, send_error, AppState, ClientMessage, ConnectionContext,
+    DisconnectReason,
+};
+
+// The request_id on the replies to resubscribes
+const REQUEST_ID: &str = "resume";
+
+// Per channel, the (epoch, seq) of the last broadcast written to the client
+pub type Cursors = Arc<Mutex<HashMap<String, (u64, u64)>>>;
+
+// How a channel was subscribed to, beyond what its roster entry shows
+pub struct Options {
+    pub presence_only: bool,
+    pub echo: Option<bool>,
+    pub fields: Option<Vec<String>>,
+}
+
+// A connection's resume token, and what it takes to resume it
+pub struct Session {
+    pub token: String,
+    pub cursors: Cursors,
+    subscriptions: HashMap<String, Options>,
+    // The role a resubscribe in progress held before, which it may take again without a moderator
+    restoring_role: Option<String>,
+}
+
+// A subscription of an ended connection, as it stood
+struct Subscription {
+    channel: String,
+    role: String,
+    group: Option<String>,
+    metadata: Option<serde_json::Map<String, serde_json::Value>>,
+    // Seconds left on each metadata key that was given a TTL
+    metadata_ttl_secs: HashMap<String, u64>,
+    options: Options,
+    cursor: Option<(u64, u64)>,
+}
+
+// An ended connection's subscriptions, waiting for the connection that resumes them
+pub struct Parked {
+    identity: Option<String>,
+    subscriptions: Vec<Subscription>,
+}
+
+// A resume token for a new connection. None if resuming is off.
+pub fn start(state: &AppState) -> Option<Session> {
+    state.config.resume_tokens.then(|| Session {
+        token: Uuid::new_v4().to_string(),
+        cursors: Arc::default(),
+        subscriptions: HashMap::new(),
+        restoring_role: None,
+    })
+}
+
+// Note how a channel was subscribed to, to subscribe the same way on resuming
+pub fn remember(session: Option<&mut Session>, channel: &str, options: Options) {
+    if let Some(session) = session {
+        session.subscriptions.insert(channel.to_string(), options);
+    }
+}
+
+// Whether a resubscribe may take the role without its being granted again
+pub fn held_before(session: Option<&Session>, role: &str) -> bool {
+    session.is_some_and(|session| session.restoring_role.as_deref() == Some(role))
+}
+
+// Just the position of an envelope; the rest is skipped over
+#[derive(Deserialize)]
+struct Envelope {
+    r#type: Option<String>,
+    channel: Option<String>,
+    epoch: Option<u64>,
+    seq: Option<u64>,
+}
+
+// The position a `subscribed` names
+#[derive(Deserialize)]
+struct Subscribed {
+    data: Position,
+}
+
+#[derive(Deserialize)]
+struct Position {
+    epoch: u64,
+    seq: u64,
+}
+
+// The channel and (epoch, seq) a frame the writer sent brings the client up to. A
+// `subscribed` counts for the position it names, as the replay ahead of it is written by
+// then. Compressed slides can't be read and don't count.
+fn frame_position(msg: &Message) -> Option<(String, (u64, u64))> {
+    let (envelope, subscribed) = match msg {
+        Message::Text(text) => {
+            let envelope: Envelope = serde_json::from_str(text).ok()?;
+            // Only a `subscribed` has its data read, and it's small
+            let subscribed = (envelope.r#type.as_deref() == Some("subscribed"))
+                .then(|| serde_json::from_str::<Subscribed>(text).ok())
+                .flatten();
+            (envelope, subscribed)
+        }
+        Message::Binary(bytes) => {
+            let value = cbor::decode(bytes).ok()?;
+            (Envelope::deserialize(&value).ok()?, Subscribed::deserialize(&value).ok())
+        }
+        _ => return None,
+    };
+    let channel = envelope.channel.filter(|channel| !channel.is_empty())?;
+    match subscribed.filter(|_| envelope.r#type.as_deref() == Some("subscribed")) {
+        Some(Subscribed { data }) => Some((channel, (data.epoch, data.seq))),
+        None => Some((channel, (envelope.epoch.unwrap_or(0), envelope.seq?))),
+    }
+}
+
+// Move a channel's cursor up to a frame the writer has just sent from the normal queue.
+// High-priority frames can overtake it, so they'd move the cursor past broadcasts not yet
+// written; they're not counted, and may be replayed on resuming.
+pub fn record(cursors: Option<&Cursors>, msg: &Message) {
+    let Some(cursors) = cursors else {
+        return;
+    };
+    if let Some((channel, position)) = frame_position(msg) {
+        let mut cursors = cursors.lock().unwrap_or_else(|e| e.into_inner());
+        let cursor = cursors.entry(channel).or_default();
+        *cursor = (*cursor).max(position);
+    }
+}
+
+// Keep an ended connection's subscriptions under its token until the grace window is over. A
+// connection an administrator kicked can't be resumed.
+pub fn park(state: &AppState, ctx: &mut ConnectionContext, disconnect: &DisconnectReason) {
+    let Some(session) = ctx.resume.take() else {
+        return;
+    };
+    if matches!(disconnect, DisconnectReason::Server(CloseReason::Kicked)) || ctx.subscriptions.is_empty() {
+        return;
+    }
+
+    let now = clock::now().timestamp_millis();
+    let cursors = session.cursors.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    let mut options = session.subscriptions;
+    let subscriptions = ctx
+        .subscriptions
+        .keys()
+        .filter_map(|channel| {
+            let entry = state.channel_presence.get(channel)?.get(&ctx.client_id)?.clone();
+            Some(Subscription {
+                channel: channel.clone(),
+                role: entry.role,
+                group: entry.group,
+                metadata: entry.metadata,
+                metadata_ttl_secs: entry
+                    .metadata_expires_at
+                    .iter()
+                    .filter(|(_, expires_at)| **expires_at > now)
+                    .map(|(key, expires_at)| (key.clone(), ((*expires_at - now) as u64).div_ceil(1000)))
+                    .collect(),
+                options: options.remove(channel)?,
+                cursor: cursors.get(channel).copied(),
+            })
+        })
+        .collect();
+
+    state.resumable.insert(
+        session.token.clone(),
+        Parked { identity: ctx.identity.id.clone(), subscriptions },
+    );
+
+    let live = state.live();
+    let grace = live.presence_grace_secs.max(live.pinned_presence_grace_secs);
+    let state = state.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_secs(grace)).await;
+        state.resumable.remove(&session.token);
+    });
+}
+
+// The subscribe that picks a parked subscription up where it left off
+fn resubscribe(subscription: &Subscription) -> Option<ClientMessage> {
+    let msg = serde_json::json!({
+        "action": "subscribe",
+        "channel": subscription.channel,
+        "request_id": REQUEST_ID,
+        "role": subscription.role,
+        "group": subscription.group,
+        "metadata": subscription.metadata,
+        "metadata_ttl_secs": Some(&subscription.metadata_ttl_secs).filter(|ttls| !ttls.is_empty()),
+        "presence_only": subscription.options.presence_only,
+        "echo": subscription.options.echo,
+        "fields": subscription.options.fields,
+        "since_epoch": subscription.cursor.map(|(epoch, _)| epoch),
+        "since_seq": subscription.cursor.map(|(_, seq)| seq),
+    });
+    serde_json::from_value(msg).ok()
+}
+
+// Subscribe a new connection to everything the connection the token was issued to had
+pub async fn restore(state: &AppState, ctx: &mut ConnectionContext, token: &str) {
+    let parked = state
+        .resumable
+        .remove_if(token, |_, parked| parked.identity == ctx.identity.id)
+        .map(|(_, parked)| parked);
+    let Some(parked) = parked else {
+        send_error(
+            &ctx.outgoing_tx,
+            Some(REQUEST_ID),
+            "",
+            "unknown_resume_token",
+            "Nothing to resume: the token is unknown, used, expired or someone else's; subscribe again",
+        );
+        return;
+    };
+
+    let mut restored = Vec::new();
+    let mut failed = Vec::new();
+    for subscription in &parked.subscriptions {
+        if let Some(session) = ctx.resume.as_mut() {
+            session.restoring_role = Some(subscription.role.clone());
+        }
+        // A resubscribe never closes the connection
+        if let Some(msg) = resubscribe(subscription) {
+            let _ = dispatch(state, ctx, msg, Instant::now()).await;
+        }
+        if let Some(session) = ctx.resume.as_mut() {
+            session.restoring_role = None;
+        }
+
+        if ctx.subscriptions.contains_key(&subscription.channel) {
+            restored.push(serde_json::json!({
+                "channel": subscription.channel,
+                "role": roles::channel_role(state, &subscription.channel, &ctx.client_id),
+            }));
+        } else {
+            failed.push(subscription.channel.clone());
+        }
+    }
+
+    println!(
+        "🔁 Client {} resumed {} of {} subscriptions",
+        ctx.client_id,
+        restored.len(),
+        parked.subscriptions.len()
+    );
+    send_direct(
+        &ctx.outgoing_tx,
+        Some(REQUEST_ID),
+        "",
+        "resumed",
+        serde_json::json!({ "restored": restored, "failed": failed }),
+    );
+}
@@ -0,0 +1,292 @@
+// Full-transcript retention for compliance export.
+
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, Mutex,
+    },
+};
+use tokio::sync::Notify;
+
+use crate::{
+    config::Config,
+    degradation::{self, Degradations},
+    AppState, ChannelEvent,
+};
+
+// Durable storage for channel transcripts. Other backends (e.g. S3-compatible object
+// storage) implement this; calls are made from blocking threads.
+pub trait RetentionSink: Send + Sync {
+    // Append one serialized broadcast to a channel's transcript
+    fn append(&self, channel: &str, line: &str) -> io::Result<()>;
+    // A channel's transcript as JSON lines, oldest first, or None if nothing was retained
+    fn export(&self, channel: &str) -> io::Result<Option<Vec<u8>>>;
+}
+
+// One JSON-lines file per channel under a directory
+pub struct FileSink {
+    dir: PathBuf,
+}
+
+impl FileSink {
+    pub fn new(dir: PathBuf) -> Self {
+        FileSink { dir }
+    }
+
+    pub fn path(&self, channel: &str) -> PathBuf {
+        // Channel names are client-supplied; escape anything that could leave the directory
+        let name: String = channel
+            .bytes()
+            .map(|byte| match byte {
+                b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' => (byte as char).to_string(),
+                _ => format!("%{:02X}", byte),
+            })
+            .collect();
+        self.dir.join(format!("{}.jsonl", name))
+    }
+}
+
+impl RetentionSink for FileSink {
+    fn append(&self, channel: &str, line: &str) -> io::Result<()> {
+        let mut file = OpenOptions::new().create(true).append(true).open(self.path(channel))?;
+        writeln!(file, "{}", line)
+    }
+
+    fn export(&self, channel: &str) -> io::Result<Option<Vec<u8>>> {
+        match fs::read(self.path(channel)) {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+// What happens when the writer can't keep up with a full queue
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QueuePolicy {
+    DropOldest,
+    Backpressure,
+}
+
+impl FromStr for QueuePolicy {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "drop_oldest" => Ok(QueuePolicy::DropOldest),
+            "backpressure" => Ok(QueuePolicy::Backpressure),
+            _ => Err(()),
+        }
+    }
+}
+
+// (channel, line) pairs waiting for a writer, bounded by discarding the oldest
+pub struct Queue {
+    lines: Mutex<Lines>,
+    capacity: usize,
+    // Wakes the writer when a line is queued or retention shuts down
+    queued: Condvar,
+    // Wakes publishes waiting for room once the writer takes a line
+    room: Notify,
+    dropped: AtomicU64,
+}
+
+#[derive(Default)]
+struct Lines {
+    pending: VecDeque<(String, String)>,
+    closed: bool,
+}
+
+impl Queue {
+    pub fn new(capacity: usize) -> Self {
+        Queue {
+            lines: Mutex::default(),
+            capacity: capacity.max(1),
+            queued: Condvar::new(),
+            room: Notify::new(),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Lines> {
+        self.lines.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Queue a line, making room by discarding the oldest if the queue is full
+    pub fn push(&self, channel: String, line: String) {
+        let mut lines = self.lock();
+        while lines.pending.len() >= self.capacity {
+            lines.pending.pop_front();
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        lines.pending.push_back((channel, line));
+        self.queued.notify_one();
+    }
+
+    // Next line for the writer, waiting for one; None once the queue is closed and
+    // everything queued was written
+    pub fn pop(&self) -> Option<(String, String)> {
+        let mut lines = self.lock();
+        loop {
+            if let Some(next) = lines.pending.pop_front() {
+                drop(lines);
+                self.room.notify_waiters();
+                return Some(next);
+            }
+            if lines.closed {
+                return None;
+            }
+            lines = self.queued.wait(lines).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.lock().pending.len() >= self.capacity
+    }
+
+    // Let the writer finish what's queued and stop
+    pub fn close(&self) {
+        self.lock().closed = true;
+        self.queued.notify_one();
+    }
+
+    // Lines waiting to be written
+    pub fn depth(&self) -> usize {
+        self.lock().pending.len()
+    }
+
+    // Lines discarded because the queue was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+// Retention for the channels that opted in, if a sink is configured
+pub struct Retention {
+    sink: Arc<dyn RetentionSink>,
+    queue: Arc<Queue>,
+    policy: QueuePolicy,
+}
+
+impl Drop for Retention {
+    // Let the writer finish what's queued and stop
+    fn drop(&mut self) {
+        self.queue.close();
+    }
+}
+
+impl Retention {
+    pub fn new(config: &Config, degradations: &Arc<Degradations>) -> Option<Self> {
+        let dir = PathBuf::from(config.retention_dir.as_deref()?);
+        if let Err(e) = fs::create_dir_all(&dir) {
+            eprintln!("❌ Failed to create retention directory {}: {}", dir.display(), e);
+            degradations.fail(degradation::RETENTION, format!("cannot create {}: {}", dir.display(), e));
+            return None;
+        }
+
+        println!("🗄️ Retaining full transcripts in {}", dir.display());
+        let sink: Arc<dyn RetentionSink> = Arc::new(FileSink::new(dir));
+        let queue = Arc::new(Queue::new(config.retention_queue_size));
+        spawn_writer(sink.clone(), queue.clone(), degradations.clone());
+        Some(Retention { sink, queue, policy: config.retention_queue_policy })
+    }
+
+    pub fn depth(&self) -> usize {
+        self.queue.depth()
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.queue.dropped()
+    }
+}
+
+fn spawn_writer(sink: Arc<dyn RetentionSink>, queue: Arc<Queue>, degradations: Arc<Degradations>) {
+    tokio::task::spawn_blocking(move || {
+        while let Some((channel, line)) = queue.pop() {
+            match sink.append(&channel, &line) {
+                Ok(()) => degradations.recover(degradation::RETENTION),
+                Err(e) => {
+                    eprintln!("❌ Failed to retain message on channel {}: {}", channel, e);
+                    degradations.fail(degradation::RETENTION, e);
+                }
+            }
+        }
+    });
+}
+
+// Whether a channel keeps a full transcript
+pub fn is_retained(state: &AppState, channel: &str) -> bool {
+    state.retention.is_some()
+        && state
+            .config
+            .retention_channel_prefixes
+            .iter()
+            .any(|prefix| channel.starts_with(prefix.as_str()))
+}
+
+// Queue a broadcast for the channel's transcript
+pub fn record(state: &AppState, event: &ChannelEvent) {
+    let Some(retention) = &state.retention else {
+        return;
+    };
+    if is_retained(state, &event.msg.channel) {
+        retention.queue.push(event.msg.channel.clone(), event.json.clone());
+    }
+}
+
+// Under the backpressure policy, wait until the queue has room before a publish to a
+// retained channel goes ahead. Broadcasts the server makes itself don't wait, so a full
+// queue can still discard the oldest lines.
+pub async fn wait_for_room(state: &AppState, channel: &str) {
+    let Some(retention) = &state.retention else {
+        return;
+    };
+    if retention.policy != QueuePolicy::Backpressure || !is_retained(state, channel) {
+        return;
+    }
+    loop {
+        // Registered before checking, so a line taken in between still wakes it
+        let room = retention.queue.room.notified();
+        if !retention.queue.is_full() {
+            return;
+        }
+        room.await;
+    }
+}
+
+// Read a channel's full transcript without blocking the runtime
+pub async fn export(state: &AppState, channel: String) -> io::Result<Option<Vec<u8>>> {
+    let Some(retention) = &state.retention else {
+        return Ok(None);
+    };
+
+    let sink = retention.sink.clone();
+    tokio::task::spawn_blocking(move || sink.export(&channel))
+        .await
+        .unwrap_or_else(|e| Err(io::Error::other(e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_full_queue_drops_its_oldest_lines() {
+        let queue = Queue::new(2);
+        for line in ["one", "two", "three"] {
+            queue.push("lesson".to_string(), line.to_string());
+        }
+        assert_eq!((queue.depth(), queue.dropped()), (2, 1));
+
+        queue.close();
+        let lines: Vec<String> = std::iter::from_fn(|| queue.pop()).map(|(_, line)| line).collect();
+        assert_eq!(lines, ["two", "three"]);
+    }
+}
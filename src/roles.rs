@@ -0,0 +1,316 @@
+use dashmap::DashMap;
+
+use crate::{auth::Identity, presence, AppState, ClientInfo};
+
+// Actions gated by a minimum role
+#[derive(Clone, Copy, Debug)]
+pub enum Permission {
+    Publish,
+    SlideChange,
+    QueryPresence,
+    ManageRoles,
+    StickyMessage,
+    Poll,
+    RestrictVisibility,
+}
+
+// Rank of a role, where higher roles inherit everything below them.
+// Roles outside the hierarchy rank with the lowest role.
+fn rank(state: &AppState, role: &str) -> usize {
+    let hierarchy = &state.config.role_hierarchy;
+    hierarchy
+        .iter()
+        .position(|candidate| candidate == role)
+        .map(|index| hierarchy.len() - index)
+        .unwrap_or(1)
+}
+
+// Role assigned to clients that don't ask for one: the bottom of the hierarchy
+pub fn default_role(state: &AppState) -> String {
+    state
+        .config
+        .role_hierarchy
+        .last()
+        .cloned()
+        .unwrap_or_else(|| "student".to_string())
+}
+
+// Role for a subscribe: the requested role, otherwise the first channel naming rule
+// that matches, otherwise the default role. None if the requested role isn't in the hierarchy.
+pub fn subscribe_role(state: &AppState, channel: &str, requested: Option<String>) -> Option<String> {
+    let hierarchy = &state.config.role_hierarchy;
+    if let Some(role) = requested {
+        return hierarchy.contains(&role).then_some(role);
+    }
+
+    let role = state
+        .config
+        .channel_role_rules
+        .iter()
+        .find(|(pattern, role)| hierarchy.contains(role) && matches_pattern(pattern, channel))
+        .map(|(_, role)| role.clone())
+        .unwrap_or_else(|| default_role(state));
+    Some(role)
+}
+
+// How a subscribe's role is affected by RABLY_FIRST_SUBSCRIBER_PRESENTS
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum FirstSubscriber {
+    // The channel doesn't match, or auth grants the connection a role
+    Unaffected,
+    // The channel's first subscriber, who gets the presenter role whatever it asked for
+    First,
+    // Anyone after it, who gets the default role unless it asks for another
+    Later,
+}
+
+// Where a subscribe stands on a channel whose first subscriber presents. The first
+// subscriber is remembered until the channel is torn down, by identity when it has one,
+// so it can retry a failed subscribe or reconnect and still be first.
+pub fn first_subscriber(state: &AppState, channel: &str, identity: &Identity, client_id: &str) -> FirstSubscriber {
+    let applies = state
+        .config
+        .first_subscriber_presents
+        .iter()
+        .any(|pattern| matches_pattern(pattern, channel));
+    if !applies || identity.role.is_some() {
+        return FirstSubscriber::Unaffected;
+    }
+
+    let participant = identity.id.as_deref().unwrap_or(client_id);
+    let first = state.first_subscribers.entry(channel.to_string()).or_insert_with(|| participant.to_string());
+    if *first == participant {
+        FirstSubscriber::First
+    } else {
+        FirstSubscriber::Later
+    }
+}
+
+// The presenter role, the top of the hierarchy, e.g. "teacher"
+pub fn presenter_role(state: &AppState) -> Option<&String> {
+    state.config.role_hierarchy.first()
+}
+
+// Whether taking this role would give the channel a second presenter, when the
+// deployment allows only one. The presenter's other connections with the same identity,
+// such as a teacher's phone next to their laptop, are the same presenter.
+pub fn presenter_taken(state: &AppState, channel: &str, client_id: &str, identity: Option<&str>, role: &str) -> bool {
+    if !state.config.single_presenter || presenter_role(state).is_none_or(|presenter| presenter != role) {
+        return false;
+    }
+    state.channel_presence.get(channel).is_some_and(|channel_map| {
+        channel_map.iter().any(|entry| {
+            entry.key() != client_id && entry.role == role && (identity.is_none() || entry.identity.as_deref() != identity)
+        })
+    })
+}
+
+// Whether a client may take this role without the channel exceeding its cap on distinct roles
+pub fn admits_role(state: &AppState, channel: &str, client_id: &str, role: &str) -> bool {
+    match state.channel_presence.get(channel) {
+        Some(channel_map) => within_role_cap(state, &channel_map, client_id, role),
+        None => true,
+    }
+}
+
+fn within_role_cap(state: &AppState, channel_map: &DashMap<String, ClientInfo>, client_id: &str, role: &str) -> bool {
+    let max = state.config.max_roles_per_channel;
+    if max == 0 {
+        return true;
+    }
+
+    let mut others: Vec<String> = channel_map
+        .iter()
+        .filter(|entry| entry.key() != client_id)
+        .map(|entry| entry.role.clone())
+        .collect();
+    others.sort();
+    others.dedup();
+    others.iter().any(|other| other == role) || others.len() < max
+}
+
+// Glob match where "*" stands for any run of characters
+pub fn matches_pattern(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        // No "*" at all: exact match
+        return rest.is_empty();
+    };
+
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+// Whether a role ranks no higher than another
+pub fn at_most(state: &AppState, role: &str, max: &str) -> bool {
+    rank(state, role) <= rank(state, max)
+}
+
+// Whether a client may claim this role for itself without someone granting it
+pub fn may_self_assign(state: &AppState, role: &str) -> bool {
+    match &state.config.max_self_assigned_role {
+        Some(max) => at_most(state, role, max),
+        None => true,
+    }
+}
+
+// Why a role change was refused
+pub enum RoleChangeError {
+    UnknownRole,
+    TooManyRoles,
+    PresenterTaken,
+    NotPresent,
+    Forbidden,
+}
+
+// Change a client's role on a channel. Clients may only demote themselves; changing
+// anyone else needs the manage-roles permission and a rank above both the target's
+// current role and the role being granted. Returns the updated presence entry.
+pub fn change_role(
+    state: &AppState,
+    channel: &str,
+    caller_id: &str,
+    target_id: &str,
+    new_role: &str,
+) -> Result<ClientInfo, RoleChangeError> {
+    if !state.config.role_hierarchy.iter().any(|role| role == new_role) {
+        return Err(RoleChangeError::UnknownRole);
+    }
+
+    let target_identity = state
+        .channel_presence
+        .get(channel)
+        .and_then(|channel_map| channel_map.get(target_id).and_then(|info| info.identity.clone()));
+    if presenter_taken(state, channel, target_id, target_identity.as_deref(), new_role) {
+        return Err(RoleChangeError::PresenterTaken);
+    }
+
+    let caller_role = channel_role(state, channel, caller_id);
+    let channel_map = state.channel_presence.get(channel).ok_or(RoleChangeError::NotPresent)?;
+    // Checked before taking the target's entry, which would block iterating the roster
+    if !within_role_cap(state, &channel_map, target_id, new_role) {
+        return Err(RoleChangeError::TooManyRoles);
+    }
+    let mut target = channel_map.get_mut(target_id).ok_or(RoleChangeError::NotPresent)?;
+
+    let allowed = if caller_id == target_id {
+        rank(state, new_role) <= rank(state, &target.role)
+    } else {
+        allows(state, &caller_role, Permission::ManageRoles)
+            && rank(state, &caller_role) > rank(state, &target.role)
+            && rank(state, &caller_role) >= rank(state, new_role)
+    };
+    if !allowed {
+        return Err(RoleChangeError::Forbidden);
+    }
+
+    target.role = new_role.to_string();
+    target.pinned = presence::is_pinned_role(state, new_role);
+    Ok(target.clone())
+}
+
+// Why a role transfer was refused
+pub enum TransferError {
+    NotPresent,
+    Forbidden,
+}
+
+// Hand the caller's role to another participant, who gets the caller's old seat in
+// exchange: the caller takes the target's role, and so do the caller's other connections
+// in the role under the same identity. Only a manager can transfer, and only to someone
+// ranked below them. Returns the updated caller and target entries, then those of the
+// caller's other connections.
+pub fn transfer_role(
+    state: &AppState,
+    channel: &str,
+    caller_id: &str,
+    target_id: &str,
+) -> Result<(ClientInfo, ClientInfo, Vec<ClientInfo>), TransferError> {
+    let channel_map = state.channel_presence.get(channel).ok_or(TransferError::NotPresent)?;
+    let caller_role = channel_map.get(caller_id).map(|info| info.role.clone()).ok_or(TransferError::NotPresent)?;
+    let caller_identity = channel_map.get(caller_id).and_then(|info| info.identity.clone());
+    let target_role = channel_map.get(target_id).map(|info| info.role.clone()).ok_or(TransferError::NotPresent)?;
+
+    if !allows(state, &caller_role, Permission::ManageRoles) || rank(state, &caller_role) <= rank(state, &target_role) {
+        return Err(TransferError::Forbidden);
+    }
+
+    // A swap leaves the set of roles in the channel unchanged, so the role cap and the
+    // single presenter still hold afterwards. Entries are updated one at a time, since
+    // holding both could deadlock on a shared shard.
+    let swap = |client_id: &str, role: &str| {
+        channel_map.get_mut(client_id).map(|mut info| {
+            info.role = role.to_string();
+            info.pinned = presence::is_pinned_role(state, role);
+            info.clone()
+        })
+    };
+    let target = swap(target_id, &caller_role).ok_or(TransferError::NotPresent)?;
+    let caller = swap(caller_id, &target_role).ok_or(TransferError::NotPresent)?;
+
+    // Otherwise the caller would keep the role on another device
+    let devices: Vec<String> = channel_map
+        .iter()
+        .filter(|entry| {
+            entry.key() != caller_id
+                && entry.key() != target_id
+                && entry.role == caller_role
+                && caller_identity.is_some()
+                && entry.identity == caller_identity
+        })
+        .map(|entry| entry.key().clone())
+        .collect();
+    let devices = devices.iter().filter_map(|device| swap(device, &target_role)).collect();
+    Ok((caller, target, devices))
+}
+
+// Whether a role may perform an action
+pub fn allows(state: &AppState, role: &str, permission: Permission) -> bool {
+    let minimum = match permission {
+        // With a single presenter, slide control follows the presenter role rather than a minimum rank
+        Permission::SlideChange if state.config.single_presenter => match presenter_role(state) {
+            Some(presenter) => return role == presenter,
+            None => return false,
+        },
+        Permission::Publish => &state.config.publish_role,
+        Permission::SlideChange => &state.config.slide_change_role,
+        Permission::QueryPresence => &state.config.query_presence_role,
+        Permission::ManageRoles => &state.config.manage_roles_role,
+        Permission::StickyMessage => &state.config.sticky_message_role,
+        Permission::Poll => &state.config.poll_role,
+        Permission::RestrictVisibility => &state.config.min_role_publish_role,
+    };
+    meets(state, role, minimum)
+}
+
+// Whether a role ranks at least as high as a minimum role.
+// A minimum outside the hierarchy can't be met by anyone.
+pub fn meets(state: &AppState, role: &str, minimum: &str) -> bool {
+    state.config.role_hierarchy.iter().any(|candidate| candidate == minimum) && rank(state, role) >= rank(state, minimum)
+}
+
+// A client's role on a channel, or the default role if it hasn't subscribed
+pub fn channel_role(state: &AppState, channel: &str, client_id: &str) -> String {
+    state
+        .channel_presence
+        .get(channel)
+        .and_then(|channel_map| channel_map.get(client_id).map(|info| info.role.clone()))
+        .unwrap_or_else(|| default_role(state))
+}
+
+// Whether a subscriber may receive a message published with a min_role, going by its role
+// on the channel at the time of delivery
+pub fn may_see(state: &AppState, channel: &str, client_id: &str, min_role: Option<&str>) -> bool {
+    min_role.is_none_or(|minimum| meets(state, &channel_role(state, channel, client_id), minimum))
+}
@@ -0,0 +1,77 @@
+// Round-trip time to each client, measured with WebSocket pings.
+
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+// Samples kept per connection
+const WINDOW: usize = 16;
+
+#[derive(Default)]
+pub struct RttWindow {
+    inner: Mutex<RttState>,
+}
+
+#[derive(Default)]
+struct RttState {
+    next_seq: u64,
+    // The ping awaiting its pong; a newer ping replaces it, so a lost pong costs one sample
+    pending: Option<(u64, Instant)>,
+    samples: VecDeque<Duration>,
+}
+
+// What the admin API reports for one connection, in milliseconds
+#[derive(Serialize)]
+pub struct RttSummary {
+    pub last_ms: f64,
+    pub min_ms: f64,
+    pub avg_ms: f64,
+    pub max_ms: f64,
+    pub samples: usize,
+}
+
+impl RttWindow {
+    // Payload for the next ping, remembering when it was sent
+    pub fn ping(&self) -> Vec<u8> {
+        let mut state = self.inner.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.pending = Some((seq, Instant::now()));
+        seq.to_be_bytes().to_vec()
+    }
+
+    // Match a pong to the outstanding ping; returns the round trip if it was the answer
+    pub fn pong(&self, payload: &[u8]) -> Option<Duration> {
+        let seq = u64::from_be_bytes(payload.try_into().ok()?);
+        let mut state = self.inner.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let (pending_seq, sent_at) = state.pending?;
+        if pending_seq != seq {
+            return None;
+        }
+        state.pending = None;
+
+        let rtt = sent_at.elapsed();
+        if state.samples.len() == WINDOW {
+            state.samples.pop_front();
+        }
+        state.samples.push_back(rtt);
+        Some(rtt)
+    }
+
+    pub fn summary(&self) -> Option<RttSummary> {
+        let state = self.inner.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let last = state.samples.back()?;
+        let ms = |duration: &Duration| duration.as_secs_f64() * 1000.0;
+        let total: Duration = state.samples.iter().sum();
+        Some(RttSummary {
+            last_ms: ms(last),
+            min_ms: state.samples.iter().min().map(ms).unwrap_or_default(),
+            avg_ms: ms(&total) / state.samples.len() as f64,
+            max_ms: state.samples.iter().max().map(ms).unwrap_or_default(),
+            samples: state.samples.len(),
+        })
+    }
+}
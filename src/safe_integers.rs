@@ -0,0 +1,90 @@
+// Integers JavaScript can't hold exactly.
+
+use serde::Serialize;
+use std::str::FromStr;
+
+use crate::AppState;
+
+// Largest integer a double represents exactly, along with every integer below it
+const MAX_SAFE_INTEGER: u64 = (1 << 53) - 1;
+
+// Paths reported in a warning or rejection
+const MAX_REPORTED: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnsafeIntegerPolicy {
+    // Pass data through untouched
+    Off,
+    Warn,
+    Stringify,
+    Reject,
+}
+
+impl FromStr for UnsafeIntegerPolicy {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "off" => Ok(UnsafeIntegerPolicy::Off),
+            "warn" => Ok(UnsafeIntegerPolicy::Warn),
+            "stringify" => Ok(UnsafeIntegerPolicy::Stringify),
+            "reject" => Ok(UnsafeIntegerPolicy::Reject),
+            _ => Err(()),
+        }
+    }
+}
+
+fn is_unsafe(number: &serde_json::Number) -> bool {
+    match (number.as_u64(), number.as_i64()) {
+        (Some(value), _) => value > MAX_SAFE_INTEGER,
+        (None, Some(value)) => value.unsigned_abs() > MAX_SAFE_INTEGER,
+        _ => false,
+    }
+}
+
+// Collect the paths of unsafe integers under `value`, stringifying them if asked
+fn visit(value: &mut serde_json::Value, path: &mut String, stringify: bool, found: &mut Vec<String>) {
+    match value {
+        serde_json::Value::Number(number) if is_unsafe(number) => {
+            if found.len() < MAX_REPORTED {
+                found.push(path.clone());
+            }
+            if stringify {
+                let exact = number.to_string();
+                *value = serde_json::Value::String(exact);
+            }
+        }
+        serde_json::Value::Array(items) => {
+            for (index, item) in items.iter_mut().enumerate() {
+                let len = path.len();
+                path.push_str(&format!("[{}]", index));
+                visit(item, path, stringify, found);
+                path.truncate(len);
+            }
+        }
+        serde_json::Value::Object(fields) => {
+            for (key, field) in fields.iter_mut() {
+                let len = path.len();
+                path.push('.');
+                path.push_str(key);
+                visit(field, path, stringify, found);
+                path.truncate(len);
+            }
+        }
+        _ => {}
+    }
+}
+
+// Apply the policy to a message's data. Returns the paths of any unsafe integers found
+// (the first few), which under `reject` means the message must be refused.
+pub fn check(state: &AppState, data: &mut Option<serde_json::Value>) -> Vec<String> {
+    let policy = state.config.unsafe_integer_policy;
+    let Some(data) = data.as_mut().filter(|_| policy != UnsafeIntegerPolicy::Off) else {
+        return Vec::new();
+    };
+
+    let mut found = Vec::new();
+    visit(data, &mut String::from("data"), policy == UnsafeIntegerPolicy::Stringify, &mut found);
+    found
+}
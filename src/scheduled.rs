@@ -0,0 +1,405 @@
+// Messages held back for delivery at a later time.
+
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::PathBuf,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    time::Duration,
+};
+use tokio::task::AbortHandle;
+use uuid::Uuid;
+
+use crate::{clock, config::Config, degradation, lifecycle, scheduler::Priority, send_to_channel, AppState, ServerMessage};
+
+// Furthest ahead a message may be scheduled
+const MAX_DELAY: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+// Messages that fell due while the server was down are still sent if no later than this
+const MAX_OVERDUE: Duration = Duration::from_secs(60);
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ScheduledMessage {
+    id: String,
+    channel: String,
+    data: serde_json::Value,
+    // Unix ms
+    deliver_at: i64,
+    scheduled_by: String,
+    // Stable identity of the scheduling client, which may cancel after reconnecting
+    identity: Option<String>,
+    correlation_id: String,
+    priority: Priority,
+    incompressible: bool,
+    #[serde(skip)]
+    timer: Option<AbortHandle>,
+}
+
+// What a client asks to have delivered later
+pub struct Request {
+    pub channel: String,
+    pub data: serde_json::Value,
+    pub deliver_at: i64,
+    pub scheduled_by: String,
+    pub identity: Option<String>,
+    pub correlation_id: String,
+    pub priority: Priority,
+    pub incompressible: bool,
+}
+
+pub enum ScheduleError {
+    // deliver_at is in the past or too far ahead
+    InvalidTime(&'static str),
+    // The channel already holds as many scheduled messages as it may
+    ChannelFull(usize),
+    // The server already holds as many scheduled messages as it may
+    ServerFull(usize),
+}
+
+pub enum CancelError {
+    UnknownSchedule,
+    // Only the scheduling client, or a role that manages the channel, may cancel
+    NotOwner,
+}
+
+pub struct Schedules {
+    // Scheduled messages by channel, then by schedule id
+    channels: DashMap<String, HashMap<String, ScheduledMessage>>,
+    // File to persist them in, if configured
+    store: Option<PathBuf>,
+    dirty: AtomicBool,
+    // Scheduled messages waiting across all channels
+    total: AtomicUsize,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    saved_at: i64,
+    messages: Vec<ScheduledMessage>,
+}
+
+impl Schedules {
+    pub fn new(config: &Config) -> Self {
+        Schedules {
+            channels: DashMap::new(),
+            store: config.schedule_store.as_deref().map(PathBuf::from),
+            dirty: AtomicBool::new(false),
+            total: AtomicUsize::new(0),
+        }
+    }
+
+    // Scheduled messages waiting on a channel
+    pub fn count(&self, channel: &str) -> usize {
+        self.channels.get(channel).map(|messages| messages.len()).unwrap_or(0)
+    }
+
+    // Scheduled messages waiting on all channels
+    pub fn total(&self) -> usize {
+        self.total.load(Ordering::Relaxed)
+    }
+
+    // Take a slot under the server-wide cap, if there is one left
+    fn reserve(&self, limit: usize) -> bool {
+        self.total
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |total| (total < limit).then_some(total + 1))
+            .is_ok()
+    }
+
+    fn release(&self, count: usize) {
+        self.total.fetch_sub(count, Ordering::Relaxed);
+    }
+
+    fn mark_dirty(&self) {
+        if self.store.is_some() {
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+// Hold a message for later delivery, returning its schedule id
+pub fn schedule(state: &AppState, request: Request) -> Result<String, ScheduleError> {
+    let now = clock::now().timestamp_millis();
+    if request.deliver_at <= now {
+        return Err(ScheduleError::InvalidTime("deliver_at must be in the future"));
+    }
+    if request.deliver_at - now > MAX_DELAY.as_millis() as i64 {
+        return Err(ScheduleError::InvalidTime("deliver_at may be at most 7 days ahead"));
+    }
+    if lifecycle::expires_at(state, &request.channel).is_some_and(|expires_at| request.deliver_at >= expires_at * 1000) {
+        return Err(ScheduleError::InvalidTime("deliver_at is after the channel expires"));
+    }
+
+    let limit = state.config.max_scheduled_per_channel;
+    let mut messages = state.scheduled.channels.entry(request.channel.clone()).or_default();
+    if messages.len() >= limit {
+        return Err(ScheduleError::ChannelFull(limit));
+    }
+    let server_limit = state.config.max_scheduled_total;
+    if !state.scheduled.reserve(server_limit) {
+        return Err(ScheduleError::ServerFull(server_limit));
+    }
+
+    let id = Uuid::new_v4().to_string();
+    let message = ScheduledMessage {
+        id: id.clone(),
+        timer: Some(arm(state, &request.channel, &id, request.deliver_at)),
+        channel: request.channel,
+        data: request.data,
+        deliver_at: request.deliver_at,
+        scheduled_by: request.scheduled_by,
+        identity: request.identity,
+        correlation_id: request.correlation_id,
+        priority: request.priority,
+        incompressible: request.incompressible,
+    };
+    messages.insert(id.clone(), message);
+    drop(messages);
+
+    state.scheduled.mark_dirty();
+    Ok(id)
+}
+
+// Withdraw a scheduled message before it goes out
+pub fn cancel(
+    state: &AppState,
+    channel: &str,
+    id: &str,
+    client_id: &str,
+    identity: Option<&str>,
+    may_manage: bool,
+) -> Result<(), CancelError> {
+    let mut messages = state.scheduled.channels.get_mut(channel).ok_or(CancelError::UnknownSchedule)?;
+    let message = messages.get(id).ok_or(CancelError::UnknownSchedule)?;
+
+    let owns = message.scheduled_by == client_id || (identity.is_some() && message.identity.as_deref() == identity);
+    if !owns && !may_manage {
+        return Err(CancelError::NotOwner);
+    }
+
+    if let Some(timer) = messages.remove(id).and_then(|message| message.timer) {
+        timer.abort();
+    }
+    state.scheduled.release(1);
+    let now_empty = messages.is_empty();
+    drop(messages);
+    if now_empty {
+        state.scheduled.channels.remove_if(channel, |_, messages| messages.is_empty());
+    }
+
+    state.scheduled.mark_dirty();
+    Ok(())
+}
+
+// Withdraw every message scheduled on a channel, returning how many there were
+pub fn cancel_channel(state: &AppState, channel: &str) -> usize {
+    let Some((_, messages)) = state.scheduled.channels.remove(channel) else {
+        return 0;
+    };
+    for timer in messages.values().filter_map(|message| message.timer.as_ref()) {
+        timer.abort();
+    }
+    state.scheduled.release(messages.len());
+
+    state.scheduled.mark_dirty();
+    messages.len()
+}
+
+// Sleep until the message is due, then send it if it's still scheduled
+fn arm(state: &AppState, channel: &str, id: &str, deliver_at: i64) -> AbortHandle {
+    let delay = Duration::from_millis((deliver_at - clock::now().timestamp_millis()).max(0) as u64);
+    let state = state.clone();
+    let channel = channel.to_string();
+    let id = id.to_string();
+    tokio::spawn(async move {
+        tokio::time::sleep(delay).await;
+        fire(&state, &channel, &id);
+    })
+    .abort_handle()
+}
+
+fn fire(state: &AppState, channel: &str, id: &str) {
+    let message = {
+        let Some(mut messages) = state.scheduled.channels.get_mut(channel) else {
+            return;
+        };
+        let Some(message) = messages.remove(id) else {
+            return;
+        };
+        let now_empty = messages.is_empty();
+        drop(messages);
+        if now_empty {
+            state.scheduled.channels.remove_if(channel, |_, messages| messages.is_empty());
+        }
+        message
+    };
+    state.scheduled.release(1);
+    state.scheduled.mark_dirty();
+
+    // The same gates a publish passes when it's sent, applied at the time it goes out
+    if state.maintenance.load(Ordering::Relaxed) || state.archived_channels.contains_key(channel) {
+        println!("⏰ Scheduled message {} on channel {} dropped: channel is not accepting publishes", id, channel);
+        return;
+    }
+
+    let msg = ServerMessage {
+        correlation_id: Some(message.correlation_id.clone()),
+        priority: message.priority,
+        incompressible: message.incompressible,
+        ..ServerMessage::new("message", channel, message.data)
+    };
+    if send_to_channel(state, msg, &message.scheduled_by) {
+        println!(
+            "⏰ Scheduled message {} delivered to channel {} (correlation_id {})",
+            id, channel, message.correlation_id
+        );
+    } else {
+        println!("⏰ Scheduled message {} on channel {} reached no subscribers", id, channel);
+    }
+}
+
+fn save(path: &PathBuf, snapshot: &Snapshot) -> io::Result<()> {
+    let json = serde_json::to_vec(snapshot).map_err(io::Error::other)?;
+    let temp = path.with_extension("tmp");
+    fs::write(&temp, json)?;
+    fs::rename(&temp, path)
+}
+
+fn load(path: &PathBuf) -> io::Result<Option<Snapshot>> {
+    match fs::read(path) {
+        Ok(bytes) => serde_json::from_slice(&bytes).map(Some).map_err(io::Error::other),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// Re-arm the messages saved before the last shutdown; those long overdue are dropped
+pub fn restore(state: &AppState) {
+    let Some(path) = &state.scheduled.store else {
+        return;
+    };
+
+    let snapshot = match load(path) {
+        Ok(Some(snapshot)) => snapshot,
+        Ok(None) => return,
+        Err(e) => {
+            eprintln!("❌ Failed to load scheduled messages from {}: {}", path.display(), e);
+            return;
+        }
+    };
+
+    let oldest = clock::now().timestamp_millis() - MAX_OVERDUE.as_millis() as i64;
+    let (mut restored, mut dropped) = (0, 0);
+    for mut message in snapshot.messages {
+        if message.deliver_at < oldest {
+            dropped += 1;
+            continue;
+        }
+        message.timer = Some(arm(state, &message.channel, &message.id, message.deliver_at));
+        state
+            .scheduled
+            .channels
+            .entry(message.channel.clone())
+            .or_default()
+            .insert(message.id.clone(), message);
+        state.scheduled.total.fetch_add(1, Ordering::Relaxed);
+        restored += 1;
+    }
+    if dropped > 0 {
+        state.scheduled.mark_dirty();
+    }
+    println!("♻️ Restored {} scheduled messages ({} too overdue to send)", restored, dropped);
+}
+
+// Write the scheduled messages out whenever they've changed since the last write
+pub fn spawn_flusher(state: AppState) {
+    let Some(path) = state.scheduled.store.clone() else {
+        return;
+    };
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+        loop {
+            interval.tick().await;
+            if !state.scheduled.dirty.swap(false, Ordering::Relaxed) {
+                continue;
+            }
+
+            let messages = state
+                .scheduled
+                .channels
+                .iter()
+                .flat_map(|entry| entry.value().values().cloned().collect::<Vec<_>>())
+                .collect();
+            let snapshot = Snapshot {
+                saved_at: clock::now().timestamp(),
+                messages,
+            };
+
+            let path = path.clone();
+            let result = tokio::task::spawn_blocking(move || save(&path, &snapshot)).await;
+            match result {
+                Ok(Ok(())) => state.degradations.recover(degradation::SCHEDULE_STORE),
+                Ok(Err(e)) => {
+                    eprintln!("❌ Failed to save scheduled messages: {}", e);
+                    state.degradations.fail(degradation::SCHEDULE_STORE, e);
+                    state.scheduled.mark_dirty();
+                }
+                Err(_) => {}
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        clock,
+        testing::{TestClient, TestServer},
+    };
+
+    // The schedule id, or the error code the schedule was refused with
+    async fn schedule(teacher: &mut TestClient, channel: &str) -> Result<String, String> {
+        let deliver_at = clock::now().timestamp_millis() + 60_000;
+        teacher
+            .send(serde_json::json!({ "action": "schedule", "channel": channel, "data": "break time", "deliver_at": deliver_at }))
+            .await;
+        loop {
+            let msg = teacher.next().await.expect("connection closed");
+            match (msg["type"].as_str(), msg["data"]["code"].as_str()) {
+                (Some("info"), Some("scheduled")) => return Ok(msg["data"]["schedule_id"].as_str().unwrap().to_string()),
+                (Some("error"), Some(code)) => return Err(code.to_string()),
+                _ => continue,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn schedules_past_either_limit_are_refused_until_one_is_cancelled() {
+        let server = TestServer::start(|config| {
+            config.max_scheduled_per_channel = 2;
+            config.max_scheduled_total = 3;
+        })
+        .await;
+        let mut teacher = server.connect("").await;
+        for channel in ["lesson", "art", "music"] {
+            teacher.subscribe(channel, serde_json::json!({ "role": "teacher" })).await;
+        }
+
+        let first = schedule(&mut teacher, "lesson").await.expect("first schedule");
+        schedule(&mut teacher, "lesson").await.expect("second schedule");
+        assert_eq!(schedule(&mut teacher, "lesson").await, Err("schedule_limit_exceeded".to_string()));
+        schedule(&mut teacher, "art").await.expect("a schedule on another channel");
+        assert_eq!(schedule(&mut teacher, "music").await, Err("schedule_limit_exceeded".to_string()));
+        assert_eq!(server.state.scheduled.total(), 3);
+
+        // Cancelling frees the slot on the channel and on the server
+        teacher.send(serde_json::json!({ "action": "cancel_schedule", "channel": "lesson", "schedule_id": first })).await;
+        assert_eq!(teacher.expect("info").await["data"]["code"], "schedule_cancelled");
+        assert_eq!(server.state.scheduled.total(), 2);
+        schedule(&mut teacher, "music").await.expect("a schedule once one was cancelled");
+        let (_, metrics) = server.request("GET", "/metrics", None).await;
+        assert!(metrics.contains("rably_scheduled_messages 3"), "{}", metrics);
+    }
+}
@@ -0,0 +1,98 @@
+// Fair fan-in from a connection's channel forwarders into its outgoing queue.
+
+use axum::extract::ws::Message;
+use serde::{Deserialize, Serialize};
+use std::{future::poll_fn, task::Poll};
+use tokio::{sync::mpsc, task::JoinHandle};
+
+use crate::Outgoing;
+
+// Delivery tier for a broadcast. High-priority messages bypass the channel lanes into a
+// separate queue that the sender drains before the normal backlog, so they can overtake
+// earlier normal messages from the same channel.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    #[default]
+    Normal,
+    High,
+}
+
+impl Priority {
+    // User publishes are normal; built-in control and state events are high
+    pub fn for_event_type(event_type: &str) -> Self {
+        match event_type {
+            "message" => Priority::Normal,
+            _ => Priority::High,
+        }
+    }
+}
+
+// Messages buffered per channel lane before its forwarder has to wait
+const LANE_CAPACITY: usize = 64;
+
+// Opens lanes into a connection's scheduler
+pub struct Lanes {
+    register: mpsc::UnboundedSender<mpsc::Receiver<Message>>,
+}
+
+impl Lanes {
+    // A new lane for one subscription; dropping the sender retires the lane once drained
+    pub fn open(&self) -> mpsc::Sender<Message> {
+        let (tx, rx) = mpsc::channel(LANE_CAPACITY);
+        let _ = self.register.send(rx);
+        tx
+    }
+}
+
+// Start a connection's scheduler; it stops when the Lanes handle is dropped
+pub fn spawn(outgoing: Outgoing) -> (Lanes, JoinHandle<()>) {
+    let (register, mut register_rx) = mpsc::unbounded_channel::<mpsc::Receiver<Message>>();
+
+    let handle = tokio::spawn(async move {
+        let mut lanes: Vec<mpsc::Receiver<Message>> = Vec::new();
+        let mut next = 0;
+
+        loop {
+            let msg = poll_fn(|cx| {
+                loop {
+                    match register_rx.poll_recv(cx) {
+                        Poll::Ready(Some(lane)) => lanes.push(lane),
+                        Poll::Ready(None) => return Poll::Ready(None),
+                        Poll::Pending => break,
+                    }
+                }
+
+                // Take one message from the first ready lane after the last one served
+                let mut checked = 0;
+                while checked < lanes.len() {
+                    let index = (next + checked) % lanes.len();
+                    match lanes[index].poll_recv(cx) {
+                        Poll::Ready(Some(msg)) => {
+                            next = index + 1;
+                            return Poll::Ready(Some(msg));
+                        }
+                        Poll::Ready(None) => {
+                            lanes.remove(index);
+                            if index < next {
+                                next -= 1;
+                            }
+                        }
+                        Poll::Pending => checked += 1,
+                    }
+                }
+                Poll::Pending
+            })
+            .await;
+
+            let Some(msg) = msg else {
+                break;
+            };
+            if outgoing.send(msg).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    (Lanes { register }, handle)
+}
@@ -0,0 +1,169 @@
+// Seeding a channel with another channel's messages.
+
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use serde::Deserialize;
+use std::sync::Arc;
+
+use crate::{
+    admin::{self, admin_error},
+    clock, encoding, history, lifecycle, next_seq, retention, AppState, ChannelEvent, ServerMessage,
+};
+
+// Most messages one seed copies
+const MAX_SEED: usize = 1000;
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SeedSource {
+    #[default]
+    History,
+    Transcript,
+}
+
+#[derive(Deserialize)]
+pub struct SeedRequest {
+    source: String,
+    #[serde(default)]
+    from: SeedSource,
+    // Copy only the most recent messages
+    limit: Option<usize>,
+    // Stamp copies with the current time instead of the original
+    #[serde(default)]
+    retimestamp: bool,
+    // Also deliver the copies to current subscribers of the target
+    #[serde(default)]
+    broadcast: bool,
+}
+
+// What is carried over from one source message
+struct Recorded {
+    r#type: String,
+    data: serde_json::Value,
+    timestamp: i64,
+    expires_at: Option<i64>,
+    encrypted: bool,
+}
+
+impl Recorded {
+    fn from_envelope(envelope: &serde_json::Value) -> Option<Self> {
+        Some(Recorded {
+            r#type: envelope.get("type")?.as_str()?.to_string(),
+            data: envelope.get("data").cloned().unwrap_or_default(),
+            timestamp: envelope.get("timestamp").and_then(|timestamp| timestamp.as_i64()).unwrap_or_default(),
+            expires_at: envelope.get("expires_at").and_then(|expires_at| expires_at.as_i64()),
+            encrypted: envelope.get("encrypted").and_then(|encrypted| encrypted.as_bool()).unwrap_or(false),
+        })
+    }
+}
+
+pub async fn seed_channel(
+    Path(channel_id): Path<String>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<SeedRequest>,
+) -> Result<String, (StatusCode, String)> {
+    admin::authorize(&state, &headers)?;
+
+    let target = admin::resolve_channel(&state, &channel_id);
+    let source = admin::resolve_channel(&state, &request.source);
+    if source == target {
+        return Err(admin_error(StatusCode::BAD_REQUEST, "source and target are the same channel"));
+    }
+    if state.archived_channels.contains_key(&target) {
+        return Err(admin_error(StatusCode::CONFLICT, "channel is archived and read-only"));
+    }
+    let limit = request.limit.unwrap_or(MAX_SEED).clamp(1, MAX_SEED);
+
+    let recorded = match request.from {
+        SeedSource::History => history::recent(&state, &source)
+            .iter()
+            .filter_map(|event| serde_json::to_value(&event.msg).ok())
+            .filter_map(|envelope| Recorded::from_envelope(&envelope))
+            .collect::<Vec<_>>(),
+        SeedSource::Transcript => {
+            if !retention::is_retained(&state, &source) {
+                return Err(admin_error(StatusCode::NOT_FOUND, "source channel is not retained"));
+            }
+            let transcript = retention::export(&state, source.clone()).await.map_err(|e| {
+                eprintln!("❌ Failed to read transcript of {}: {}", source, e);
+                admin_error(StatusCode::INTERNAL_SERVER_ERROR, "reading the transcript failed")
+            })?;
+            transcript
+                .unwrap_or_default()
+                .split(|byte| *byte == b'\n')
+                .filter_map(|line| serde_json::from_slice::<serde_json::Value>(line).ok())
+                .filter_map(|envelope| Recorded::from_envelope(&envelope))
+                .collect()
+        }
+    };
+    let copyable: Vec<Recorded> = recorded
+        .into_iter()
+        .filter(|recorded| matches!(recorded.r#type.as_str(), "message" | "slide_change"))
+        .collect();
+    let skipped = copyable.len().saturating_sub(limit);
+
+    let now = clock::now();
+    let tx = state.channels.get(&target).map(|tx| tx.clone());
+    let mut copied = 0;
+    let mut delivered = 0;
+    for recorded in copyable.into_iter().skip(skipped) {
+        let (timestamp, expires_at) = if request.retimestamp {
+            // Keep each message's remaining lifetime relative to its new time
+            let shift = (now.timestamp() - recorded.timestamp) * 1000;
+            (now.timestamp(), recorded.expires_at.map(|expires_at| expires_at.saturating_add(shift)))
+        } else {
+            (recorded.timestamp, recorded.expires_at)
+        };
+        let mut msg = ServerMessage {
+            timestamp,
+            expires_at,
+            encrypted: recorded.encrypted,
+            incompressible: recorded.encrypted,
+            ..ServerMessage::new(&recorded.r#type, &target, recorded.data)
+        };
+        if msg.is_expired() {
+            continue;
+        }
+        let (epoch, seq) = next_seq(&state, &target);
+        msg.epoch = Some(epoch);
+        msg.seq = Some(seq);
+        let Some(json) = msg.to_json() else {
+            continue;
+        };
+        let event = Arc::new(ChannelEvent {
+            msg,
+            origin: "server".to_string(),
+            json,
+            frames: encoding::FrameCache::default(),
+        });
+
+        history::record(&state, &event);
+        retention::record(&state, &event);
+        copied += 1;
+        if request.broadcast && tx.as_ref().is_some_and(|tx| tx.send(event).is_ok()) {
+            delivered += 1;
+        }
+    }
+    lifecycle::touch(&state, &target);
+
+    println!("🌱 Seeded channel {} with {} messages from {}", target, copied, source);
+    state.audit.record(
+        &headers,
+        "seed_channel",
+        Some(&target),
+        serde_json::json!({ "source": source, "copied": copied }),
+    );
+
+    Ok(serde_json::json!({
+        "channel": target,
+        "source": source,
+        "copied": copied,
+        "skipped": skipped,
+        "delivered": delivered,
+    })
+    .to_string())
+}
@@ -0,0 +1,158 @@
+// Phased graceful shutdown on SIGTERM or Ctrl-C: stop accepting, notify (and migrate), flush the
+// outgoing queues, then close with 1001.
+
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use crate::{close::CloseReason, migration, send_direct, AppState};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Default)]
+pub struct Shutdown {
+    draining: AtomicBool,
+}
+
+impl Shutdown {
+    // Whether shutdown has begun, so new traffic should go elsewhere
+    pub fn draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+}
+
+async fn signal() {
+    let ctrl_c = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(_) => std::future::pending().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+// Connections whose outgoing queue still holds messages
+fn unflushed(state: &AppState) -> usize {
+    state
+        .clients
+        .iter()
+        .filter(|client| client.outgoing.capacity() < client.outgoing.max_capacity())
+        .count()
+}
+
+// Poll until the condition holds or the timeout passes; returns whether it held
+async fn wait_for(timeout: Duration, condition: impl Fn() -> bool) -> bool {
+    let deadline = tokio::time::Instant::now() + timeout;
+    loop {
+        if condition() {
+            return true;
+        }
+        if tokio::time::Instant::now() >= deadline {
+            return false;
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+// Resolves once a shutdown signal has arrived and every phase has run; the server stops
+// listening when it does
+pub async fn drain(state: AppState) {
+    signal().await;
+    tokio::spawn(async {
+        signal().await;
+        eprintln!("⚠️ Second shutdown signal; exiting without draining");
+        std::process::exit(1);
+    });
+
+    // Phase 1: stop accepting
+    state.shutdown.draining.store(true, Ordering::Relaxed);
+    println!(
+        "🛑 Shutting down: refusing new connections and reporting not ready for {}s ({} connections open)",
+        state.config.shutdown_unready_secs,
+        state.clients.len()
+    );
+    tokio::time::sleep(Duration::from_secs(state.config.shutdown_unready_secs)).await;
+
+    // Phase 2: tell clients to reconnect elsewhere, spread out so the rest of the fleet isn't stampeded
+    let spread_ms = state.config.shutdown_reconnect_spread_ms;
+    let mut notified: usize = 0;
+    for client in state.clients.iter() {
+        let reconnect_after_ms = if spread_ms > 0 { rand::random_range(0..=spread_ms) } else { 0 };
+        send_direct(
+            &client.outgoing,
+            None,
+            "",
+            "server_shutdown",
+            serde_json::json!({
+                "instance": state.config.instance_id,
+                "reconnect": true,
+                "reconnect_after_ms": reconnect_after_ms,
+            }),
+        );
+        if migration::timeout(&state).is_some() {
+            migration::prepare(&state, &client, CloseReason::ServerShutdown);
+        }
+        notified += 1;
+    }
+    println!("📣 Sent server_shutdown to {} connections", notified);
+
+    if let Some(timeout) = migration::timeout(&state).filter(|_| notified > 0) {
+        let all_ready = wait_for(timeout, || state.clients.is_empty()).await;
+        let remaining = state.clients.len();
+        if all_ready {
+            println!("🤝 All {} connections were ready to migrate", notified);
+        } else {
+            println!(
+                "⏱️ Migration timeout passed; {} connections closed, {} never replied",
+                notified.saturating_sub(remaining),
+                remaining
+            );
+        }
+    }
+
+    // Phase 3: let queued messages, the notice included, reach their clients
+    let flush_timeout = Duration::from_millis(state.config.shutdown_flush_timeout_ms);
+    let flushed = wait_for(flush_timeout, || unflushed(&state) == 0).await;
+    let remaining = state.clients.len();
+    if flushed {
+        println!(
+            "🚰 Outgoing queues flushed; {} connections drained on their own, {} still open",
+            notified.saturating_sub(remaining),
+            remaining
+        );
+    } else {
+        println!(
+            "⏱️ Flush deadline passed with {} connections still behind; {} drained on their own, {} still open",
+            unflushed(&state),
+            notified.saturating_sub(remaining),
+            remaining
+        );
+    }
+
+    // Phase 4: close whoever is left
+    for client in state.clients.iter() {
+        let _ = client.disconnect.send(CloseReason::ServerShutdown);
+    }
+    let close_timeout = Duration::from_millis(state.config.shutdown_close_timeout_ms);
+    wait_for(close_timeout, || state.clients.is_empty()).await;
+    let forced = state.clients.len();
+    println!(
+        "👋 Closed {} connections with 1001; {} forced closed at exit",
+        remaining.saturating_sub(forced),
+        forced
+    );
+}
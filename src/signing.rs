@@ -0,0 +1,35 @@
+// Optional HMAC signatures on server messages.
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use std::sync::OnceLock;
+
+use crate::{auth, config::Config};
+
+// Set once at startup; global so serialization helpers don't need AppState
+static KEY: OnceLock<Option<Vec<u8>>> = OnceLock::new();
+
+pub fn init(config: &Config) {
+    let key = config.message_signing_key.as_ref().map(|key| key.as_bytes().to_vec());
+    if key.is_some() {
+        println!("✍️ Signing server messages with HMAC-SHA256");
+    }
+    let _ = KEY.set(key);
+}
+
+pub fn enabled() -> bool {
+    KEY.get().is_some_and(Option::is_some)
+}
+
+// The serialized message with its signature appended as the last field
+pub fn sign(json: String) -> String {
+    let Some(key) = KEY.get().and_then(Option::as_ref) else {
+        return json;
+    };
+    let Some(body) = json.strip_suffix('}') else {
+        return json;
+    };
+
+    let signature = URL_SAFE_NO_PAD.encode(auth::hmac_sha256(key, json.as_bytes()));
+    let separator = if body.ends_with('{') { "" } else { "," };
+    format!("{}{}\"sig\":\"{}\"}}", body, separator, signature)
+}
@@ -0,0 +1,38 @@
+// Per-connection client clock skew, estimated from time_sync and optionally used to shift client
+// times into server time.
+
+use std::collections::VecDeque;
+
+use crate::AppState;
+
+// Samples kept per connection
+const WINDOW: usize = 8;
+
+// How far ahead of the server a connection's clock runs, by its recent time_syncs
+#[derive(Default)]
+pub struct SkewEstimate {
+    samples: VecDeque<i64>,
+}
+
+impl SkewEstimate {
+    // Record a time_sync's client_time against the server time it arrived at
+    pub fn sample(&mut self, client_time: i64, server_time: i64) {
+        if self.samples.len() == WINDOW {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(client_time.saturating_sub(server_time));
+    }
+
+    // Estimated skew in ms, positive if the client's clock is ahead
+    pub fn ms(&self) -> Option<i64> {
+        self.samples.iter().max().copied()
+    }
+}
+
+// A time the client gave, in server time if skew correction is on
+pub fn to_server_time(state: &AppState, skew: &SkewEstimate, client_time: i64) -> i64 {
+    match skew.ms() {
+        Some(skew) if state.config.clock_skew_correction => client_time.saturating_sub(skew),
+        _ => client_time,
+    }
+}
@@ -0,0 +1,86 @@
+// Current slide state per channel.
+
+use crate::{channel_config, send_to_channel, AppState, ServerMessage};
+
+// Diffs accepted on top of one full slide before a fresh slide_change is required
+const MAX_DIFFS: usize = 256;
+
+#[derive(Default)]
+pub struct SlideState {
+    version: u64,
+    slide: serde_json::Value,
+    diffs: Vec<serde_json::Value>,
+}
+
+// Why a slide change was refused
+pub enum ChangeError {
+    // The slide_index is lower than the current slide's on a monotonic channel
+    Backward { current_index: i64 },
+}
+
+// Why a diff was refused
+pub enum DiffError {
+    // The diff was computed against an older version than the channel's current one
+    Stale { current_version: u64 },
+    // Too many diffs since the last full slide
+    FullSlideRequired,
+}
+
+// Broadcast a full slide and make it the channel's current state.
+// Returns false if nobody was subscribed to receive it.
+pub fn change(state: &AppState, mut msg: ServerMessage, origin: &str, allow_backward: bool) -> Result<bool, ChangeError> {
+    let monotonic = !allow_backward && channel_config::monotonic_slides(state, &msg.channel);
+
+    // Version and broadcast happen under the channel's entry so concurrent changes are
+    // numbered in the order subscribers receive them
+    let mut slide = state.slide_state.entry(msg.channel.clone()).or_default();
+    // Only numbered slides are ordered; anything else passes
+    let backward = slide_index(&slide.slide)
+        .filter(|current| monotonic && slide_index(&msg.data).is_some_and(|index| index < *current));
+    if let Some(current_index) = backward {
+        return Err(ChangeError::Backward { current_index });
+    }
+    slide.version += 1;
+    slide.slide = msg.data.clone();
+    slide.diffs.clear();
+
+    msg.slide_version = Some(slide.version);
+    Ok(send_to_channel(state, msg, origin))
+}
+
+fn slide_index(slide: &serde_json::Value) -> Option<i64> {
+    slide.get("slide_index").and_then(|index| index.as_i64())
+}
+
+// Broadcast a diff if it applies to the channel's current slide.
+// Returns whether anybody was subscribed to receive it.
+pub fn apply_diff(state: &AppState, mut msg: ServerMessage, base_version: u64, origin: &str) -> Result<bool, DiffError> {
+    let mut slide = state.slide_state.entry(msg.channel.clone()).or_default();
+    if slide.version != base_version {
+        return Err(DiffError::Stale { current_version: slide.version });
+    }
+    if slide.diffs.len() >= MAX_DIFFS {
+        return Err(DiffError::FullSlideRequired);
+    }
+
+    slide.version += 1;
+    slide.diffs.push(msg.data.clone());
+
+    msg.slide_version = Some(slide.version);
+    Ok(send_to_channel(state, msg, origin))
+}
+
+// Version of the channel's current slide, without copying the slide
+pub fn version(state: &AppState, channel: &str) -> Option<u64> {
+    state.slide_state.get(channel).map(|slide| slide.version)
+}
+
+// The channel's current version, full slide and the diffs applied since, if any slide was shared
+pub fn current(state: &AppState, channel: &str) -> Option<serde_json::Value> {
+    let slide = state.slide_state.get(channel)?;
+    Some(serde_json::json!({
+        "version": slide.version,
+        "slide": slide.slide,
+        "diffs": slide.diffs,
+    }))
+}
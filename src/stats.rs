@@ -0,0 +1,83 @@
+use std::{
+    sync::atomic::{AtomicI64, AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use crate::{clock, AppState};
+
+// Seconds of broadcasts averaged into the reported message rate
+const RATE_WINDOW_SECS: usize = 10;
+
+// Aggregate counters behind the /stats overview
+pub struct Stats {
+    started_at: Instant,
+    // One bucket per second of the window, tagged with the second it counts
+    bucket_counts: [AtomicU64; RATE_WINDOW_SECS],
+    bucket_secs: [AtomicI64; RATE_WINDOW_SECS],
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Stats {
+            started_at: Instant::now(),
+            bucket_counts: std::array::from_fn(|_| AtomicU64::new(0)),
+            bucket_secs: std::array::from_fn(|_| AtomicI64::new(0)),
+        }
+    }
+}
+
+impl Stats {
+    // Count one channel broadcast
+    pub fn record_message(&self) {
+        let now = clock::now().timestamp();
+        let slot = now.rem_euclid(RATE_WINDOW_SECS as i64) as usize;
+
+        // First broadcast in a new second reclaims the bucket from the previous lap
+        if self.bucket_secs[slot].swap(now, Ordering::Relaxed) != now {
+            self.bucket_counts[slot].store(0, Ordering::Relaxed);
+        }
+        self.bucket_counts[slot].fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Broadcasts per second over the rolling window
+    pub fn messages_per_sec(&self) -> f64 {
+        let cutoff = clock::now().timestamp() - RATE_WINDOW_SECS as i64;
+        let total: u64 = self
+            .bucket_secs
+            .iter()
+            .zip(&self.bucket_counts)
+            .filter(|(sec, _)| sec.load(Ordering::Relaxed) > cutoff)
+            .map(|(_, count)| count.load(Ordering::Relaxed))
+            .sum();
+
+        total as f64 / RATE_WINDOW_SECS as f64
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started_at.elapsed()
+    }
+
+    pub fn uptime_secs(&self) -> u64 {
+        self.uptime().as_secs()
+    }
+}
+
+// Server-wide overview as JSON
+pub fn render(state: &AppState) -> String {
+    let participants: usize = state
+        .channel_presence
+        .iter()
+        .map(|channel_map| channel_map.len())
+        .sum();
+
+    serde_json::json!({
+        "instance": state.config.instance_id,
+        "connections": state.clients.len(),
+        "channels": state.channels.len(),
+        "participants": participants,
+        "messages_per_sec": state.stats.messages_per_sec(),
+        "uptime_secs": state.stats.uptime_secs(),
+        "degraded": state.degradations.report(),
+    })
+    .to_string()
+}
@@ -0,0 +1,45 @@
+// Sticky channel messages for late joiners.
+
+use crate::{clock, send_direct, AppState, Outgoing};
+
+pub struct StickyMessage {
+    data: serde_json::Value,
+    set_by: String,
+    set_at: i64,
+}
+
+// Replace the channel's sticky message
+pub fn set(state: &AppState, channel: &str, data: serde_json::Value, set_by: &str) {
+    state.sticky_messages.insert(
+        channel.to_string(),
+        StickyMessage {
+            data,
+            set_by: set_by.to_string(),
+            set_at: clock::now().timestamp(),
+        },
+    );
+}
+
+// Remove the channel's sticky message, returning whether there was one
+pub fn clear(state: &AppState, channel: &str) -> bool {
+    state.sticky_messages.remove(channel).is_some()
+}
+
+// Hand the sticky message, if any, to a client that just subscribed
+pub fn deliver(state: &AppState, outgoing_tx: &Outgoing, request_id: Option<&str>, channel: &str) {
+    let Some(sticky) = state.sticky_messages.get(channel) else {
+        return;
+    };
+
+    send_direct(
+        outgoing_tx,
+        request_id,
+        channel,
+        "sticky_message",
+        serde_json::json!({
+            "data": sticky.data,
+            "set_by": sticky.set_by,
+            "set_at": sticky.set_at,
+        }),
+    );
+}
@@ -0,0 +1,71 @@
+// Channel firehose for recording and transcription services.
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Path, State,
+    },
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use futures::{sink::SinkExt, stream::StreamExt};
+use tokio::sync::broadcast;
+
+use crate::{admin, lifecycle, AppState, ServerMessage};
+
+pub async fn stream_channel(
+    ws: WebSocketUpgrade,
+    Path(channel_id): Path<String>,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> Response {
+    if let Err(rejection) = admin::authorize(&state, &headers) {
+        return rejection.into_response();
+    }
+
+    let channel = admin::resolve_channel(&state, &channel_id);
+    if !state.channels.contains_key(&channel) {
+        return admin::admin_error(StatusCode::NOT_FOUND, "channel not found").into_response();
+    }
+
+    ws.on_upgrade(move |socket| forward(socket, state, channel)).into_response()
+}
+
+async fn forward(socket: WebSocket, state: AppState, channel: String) {
+    let (mut sender, mut receiver) = socket.split();
+    let mut rx = lifecycle::subscribe(&state, &channel);
+    println!("🎙️ Stream opened on channel {}", channel);
+
+    let mut forwarded: u64 = 0;
+    loop {
+        tokio::select! {
+            event = rx.recv() => {
+                let frame = match event {
+                    Ok(event) => event.json.clone(),
+                    // Tell the recorder exactly how much it missed rather than skipping silently
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        eprintln!("⚠️ Stream on channel {} fell behind by {} messages", channel, skipped);
+                        let gap = ServerMessage::new("stream_gap", &channel, serde_json::json!({ "skipped": skipped }));
+                        match gap.to_json() {
+                            Some(json) => json,
+                            None => continue,
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                if sender.send(Message::Text(frame.into())).await.is_err() {
+                    break;
+                }
+                forwarded += 1;
+            }
+            msg = receiver.next() => match msg {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+
+    drop(rx);
+    lifecycle::release_sender(&state, &channel);
+    println!("🎙️ Stream closed on channel {} after {} messages", channel, forwarded);
+}
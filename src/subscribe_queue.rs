@@ -0,0 +1,54 @@
+// Pacing for subscribe stampedes.
+
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::config::Config;
+
+pub struct SubscribeQueue {
+    // None when subscribes aren't paced
+    semaphore: Option<Arc<Semaphore>>,
+    waiting: Arc<AtomicU64>,
+}
+
+// Counts a subscribe as waiting until it gets its turn or its connection goes away
+struct Waiting(Arc<AtomicU64>);
+
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl SubscribeQueue {
+    pub fn new(config: &Config) -> Self {
+        SubscribeQueue {
+            semaphore: Some(config.subscribe_concurrency)
+                .filter(|concurrency| *concurrency > 0)
+                .map(|concurrency| Arc::new(Semaphore::new(concurrency))),
+            waiting: Arc::default(),
+        }
+    }
+
+    // Wait for a turn to set up a subscribe; the turn ends when the permit is dropped.
+    // None if subscribes aren't paced.
+    pub async fn enter(&self) -> Option<OwnedSemaphorePermit> {
+        let semaphore = self.semaphore.clone()?;
+        if let Ok(permit) = semaphore.clone().try_acquire_owned() {
+            return Some(permit);
+        }
+
+        self.waiting.fetch_add(1, Ordering::Relaxed);
+        let _waiting = Waiting(self.waiting.clone());
+        // The semaphore is never closed
+        semaphore.acquire_owned().await.ok()
+    }
+
+    // Subscribes waiting for their turn
+    pub fn depth(&self) -> u64 {
+        self.waiting.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,95 @@
+// Pluggable tenant isolation.
+
+use futures::future::BoxFuture;
+use serde::Serialize;
+use std::{fmt, str::FromStr};
+
+use crate::{auth::Identity, config::Config};
+
+#[derive(Debug)]
+pub enum TenantError {
+    // The channel belongs to another tenant
+    WrongTenant,
+    // A connection bound to a tenant tried a channel outside every tenant
+    Unscoped,
+}
+
+impl fmt::Display for TenantError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TenantError::WrongTenant => write!(f, "channel belongs to another tenant"),
+            TenantError::Unscoped => write!(f, "channel is outside your tenant"),
+        }
+    }
+}
+
+pub trait TenantResolver: Send + Sync {
+    // The channel's tenant (None for shared channels), if the identity may use it
+    fn resolve<'a>(&'a self, channel: &'a str, identity: &'a Identity) -> BoxFuture<'a, Result<Option<String>, TenantError>>;
+}
+
+// Which built-in resolver to use
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Tenancy {
+    None,
+    Prefix,
+}
+
+impl FromStr for Tenancy {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "none" => Ok(Tenancy::None),
+            "prefix" => Ok(Tenancy::Prefix),
+            _ => Err(()),
+        }
+    }
+}
+
+pub fn from_config(config: &Config) -> Box<dyn TenantResolver> {
+    match config.tenancy {
+        Tenancy::None => Box::new(SingleTenant),
+        Tenancy::Prefix => {
+            println!("🏢 Channels are scoped to tenants by their \"<tenant>{}\" prefix", config.tenant_separator);
+            Box::new(PrefixResolver {
+                separator: config.tenant_separator.clone(),
+            })
+        }
+    }
+}
+
+// Default: no isolation, every channel is shared
+pub struct SingleTenant;
+
+impl TenantResolver for SingleTenant {
+    fn resolve<'a>(&'a self, _channel: &'a str, _identity: &'a Identity) -> BoxFuture<'a, Result<Option<String>, TenantError>> {
+        Box::pin(async { Ok(None) })
+    }
+}
+
+// "<tenant><separator><name>" channels belong to that tenant and are only open to
+// connections authenticated for it. Tenant-bound connections are kept to their own
+// channels; connections without a tenant may only use unprefixed ones.
+pub struct PrefixResolver {
+    separator: String,
+}
+
+impl TenantResolver for PrefixResolver {
+    fn resolve<'a>(&'a self, channel: &'a str, identity: &'a Identity) -> BoxFuture<'a, Result<Option<String>, TenantError>> {
+        Box::pin(async move {
+            let channel_tenant = channel
+                .split_once(self.separator.as_str())
+                .map(|(tenant, _)| tenant)
+                .filter(|tenant| !tenant.is_empty());
+
+            match (channel_tenant, identity.tenant.as_deref()) {
+                (Some(channel_tenant), Some(tenant)) if channel_tenant == tenant => Ok(Some(tenant.to_string())),
+                (Some(_), _) => Err(TenantError::WrongTenant),
+                (None, Some(_)) => Err(TenantError::Unscoped),
+                (None, None) => Ok(None),
+            }
+        })
+    }
+}
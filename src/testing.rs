@@ -0,0 +1,159 @@
+// Shared setup for tests: server state built from the default settings, and a real server
+// for tests that talk to it over WebSockets.
+
+use futures::{SinkExt, StreamExt};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tokio_tungstenite::{
+    tungstenite::{
+        protocol::frame::{
+            coding::{Data, OpCode},
+            Frame,
+        },
+        Message,
+    },
+    MaybeTlsStream, WebSocketStream,
+};
+
+use crate::{config::Config, routers, AppState};
+
+// How long a test waits for something the server should do right away
+const WAIT: Duration = Duration::from_secs(5);
+
+// State for a server with the default settings, adjusted by `configure`
+pub fn state(configure: impl FnOnce(&mut Config)) -> AppState {
+    let mut config = Config::from_env();
+    configure(&mut config);
+    AppState::new(Arc::new(config))
+}
+
+// A server on a free local port, without the background sweepers
+pub struct TestServer {
+    pub state: AppState,
+    addr: SocketAddr,
+}
+
+impl TestServer {
+    pub async fn start(configure: impl FnOnce(&mut Config)) -> TestServer {
+        let state = state(configure);
+        let (app, _) = routers(&state);
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind a local port");
+        let addr = listener.local_addr().expect("bound address");
+        tokio::spawn(async move {
+            let _ = axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await;
+        });
+        TestServer { state, addr }
+    }
+
+    // Open a connection to /ws with the given query string, e.g. "?identity=teacher-1"
+    pub async fn connect(&self, query: &str) -> TestClient {
+        let url = format!("ws://{}/ws{}", self.addr, query);
+        let (ws, _) = tokio_tungstenite::connect_async(url).await.expect("WebSocket upgrade");
+        let mut client = TestClient { ws, client_id: String::new() };
+        let connected = client.expect("connected").await;
+        client.client_id = connected["data"]["client_id"].as_str().expect("client_id").to_string();
+        client
+    }
+
+    // An HTTP request with an optional bearer token, answered with its status and body
+    pub async fn request(&self, method: &str, path: &str, token: Option<&str>) -> (u16, String) {
+        let mut stream = TcpStream::connect(self.addr).await.expect("connect to the server");
+        let authorization = token.map(|token| format!("Authorization: Bearer {}\r\n", token)).unwrap_or_default();
+        let request = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\n{}Content-Length: 0\r\nConnection: close\r\n\r\n",
+            method, path, self.addr, authorization
+        );
+        stream.write_all(request.as_bytes()).await.expect("send the request");
+
+        let mut response = String::new();
+        tokio::time::timeout(WAIT, stream.read_to_string(&mut response))
+            .await
+            .expect("no response from the server")
+            .expect("read the response");
+        let status = response.split(' ').nth(1).and_then(|status| status.parse().ok()).expect("status line");
+        let body = response.split_once("\r\n\r\n").map(|(_, body)| body.to_string()).unwrap_or_default();
+        (status, body)
+    }
+}
+
+// One WebSocket connection to a TestServer
+pub struct TestClient {
+    ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    pub client_id: String,
+}
+
+impl TestClient {
+    pub async fn send(&mut self, msg: serde_json::Value) {
+        self.ws.send(Message::text(msg.to_string())).await.expect("send to the server");
+    }
+
+    // Text as is, such as JSON that doesn't parse
+    pub async fn send_text(&mut self, text: &str) {
+        self.ws.send(Message::text(text)).await.expect("send to the server");
+    }
+
+    // One text message split across frames, the first a text frame and the rest continuations
+    pub async fn send_fragmented(&mut self, text: &str, fragment_size: usize) {
+        let pieces: Vec<&[u8]> = text.as_bytes().chunks(fragment_size).collect();
+        for (index, piece) in pieces.iter().enumerate() {
+            let opcode = if index == 0 { OpCode::Data(Data::Text) } else { OpCode::Data(Data::Continue) };
+            let frame = Frame::message(piece.to_vec(), opcode, index == pieces.len() - 1);
+            self.ws.send(Message::Frame(frame)).await.expect("send a frame to the server");
+        }
+    }
+
+    // The next JSON message, or None once the server has closed the connection
+    pub async fn next(&mut self) -> Option<serde_json::Value> {
+        loop {
+            let frame = tokio::time::timeout(WAIT, self.ws.next()).await.expect("no message from the server");
+            match frame {
+                Some(Ok(Message::Text(text))) => return Some(serde_json::from_str(&text).expect("JSON from the server")),
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return None,
+                Some(Ok(_)) => continue,
+            }
+        }
+    }
+
+    // Close the connection from the client side
+    pub async fn close(&mut self) {
+        self.ws.close(None).await.expect("send a close frame");
+    }
+
+    // The code and reason the server closed the connection with, skipping anything before it
+    pub async fn close_frame(&mut self) -> Option<(u16, String)> {
+        loop {
+            let frame = tokio::time::timeout(WAIT, self.ws.next()).await.expect("the server never closed");
+            match frame {
+                Some(Ok(Message::Close(frame))) => {
+                    return frame.map(|frame| (u16::from(frame.code), frame.reason.to_string()));
+                }
+                Some(Ok(_)) => continue,
+                Some(Err(_)) | None => return None,
+            }
+        }
+    }
+
+    // Skip ahead to the next message of a type
+    pub async fn expect(&mut self, event_type: &str) -> serde_json::Value {
+        loop {
+            match self.next().await {
+                Some(msg) if msg["type"] == event_type => return msg,
+                Some(_) => continue,
+                None => panic!("connection closed while waiting for {}", event_type),
+            }
+        }
+    }
+
+    // Subscribe with the given extra fields, e.g. a role, and wait until it's done
+    pub async fn subscribe(&mut self, channel: &str, extra: serde_json::Value) -> serde_json::Value {
+        let mut msg = serde_json::json!({ "action": "subscribe", "channel": channel });
+        if let (Some(msg), Some(extra)) = (msg.as_object_mut(), extra.as_object()) {
+            msg.extend(extra.clone());
+        }
+        self.send(msg).await;
+        self.expect("subscribed").await
+    }
+}
@@ -0,0 +1,63 @@
+// Per-channel message throughput.
+
+use std::time::{Duration, Instant};
+
+use crate::AppState;
+
+// How far back the rate looks; a burst's weight falls to 1/e after this long
+const WINDOW: Duration = Duration::from_secs(60);
+
+pub struct Rate {
+    // Messages per second as of `updated`
+    per_sec: f64,
+    updated: Instant,
+}
+
+impl Rate {
+    fn decayed(&self, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.per_sec * (-elapsed / WINDOW.as_secs_f64()).exp()
+    }
+
+    fn add(&mut self, now: Instant) {
+        self.per_sec = self.decayed(now) + 1.0 / WINDOW.as_secs_f64();
+        self.updated = now;
+    }
+}
+
+// Count one broadcast on the channel
+pub fn record(state: &AppState, channel: &str) {
+    let now = Instant::now();
+    // Looked up by &str first so only a new channel pays for an owned key
+    if let Some(mut rate) = state.channel_throughput.get_mut(channel) {
+        rate.add(now);
+        return;
+    }
+    state
+        .channel_throughput
+        .entry(channel.to_string())
+        .or_insert(Rate { per_sec: 0.0, updated: now })
+        .add(now);
+}
+
+// The channel's recent messages per second
+pub fn per_sec(state: &AppState, channel: &str) -> f64 {
+    state
+        .channel_throughput
+        .get(channel)
+        .map(|rate| rate.decayed(Instant::now()))
+        .unwrap_or(0.0)
+}
+
+// The busiest channels by recent messages per second, at most `limit` of them
+pub fn busiest(state: &AppState, limit: usize) -> Vec<(String, f64)> {
+    let now = Instant::now();
+    let mut rates: Vec<(String, f64)> = state
+        .channel_throughput
+        .iter()
+        .map(|entry| (entry.key().clone(), entry.value().decayed(now)))
+        .collect();
+    rates.sort_by(|a, b| b.1.total_cmp(&a.1));
+    rates.truncate(limit);
+    rates
+}
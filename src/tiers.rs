@@ -0,0 +1,52 @@
+// Connection priority tiers.
+
+use crate::{auth::Identity, close::CloseReason, AppState};
+
+// The connection's tier, higher being shed later
+pub fn of(state: &AppState, identity: &Identity) -> u8 {
+    identity
+        .role
+        .as_deref()
+        .and_then(|role| {
+            state
+                .config
+                .connection_tiers
+                .iter()
+                .find(|(tiered, _)| tiered == role)
+                .map(|(_, tier)| *tier)
+        })
+        .unwrap_or(0)
+}
+
+// Whether a new connection of the tier should be turned away as the server is overloaded
+pub fn should_shed(state: &AppState, tier: u8) -> bool {
+    if tier == 0 && state.memory.over_limit() {
+        return true;
+    }
+    state.load.should_shed(tier)
+}
+
+// Close up to `count` connections, lowest tier first and, within a tier, those with the
+// most queued, so each one frees as much as it can. Returns how many were asked to close.
+pub fn evict(state: &AppState, count: usize) -> usize {
+    let mut candidates: Vec<(u8, usize, String)> = state
+        .clients
+        .iter()
+        .map(|client| {
+            let queued = client.outgoing.max_capacity() - client.outgoing.capacity();
+            (client.tier, queued, client.key().clone())
+        })
+        .collect();
+    candidates.sort_by(|a, b| a.0.cmp(&b.0).then(b.1.cmp(&a.1)));
+
+    candidates
+        .into_iter()
+        .take(count)
+        .filter(|(_, _, client_id)| {
+            state
+                .clients
+                .get(client_id)
+                .is_some_and(|client| client.disconnect.send(CloseReason::Overloaded).is_ok())
+        })
+        .count()
+}
@@ -0,0 +1,128 @@
+// Client-supplied event times.
+
+use serde::Serialize;
+use std::{fmt, str::FromStr};
+
+use crate::{clock, skew::{self, SkewEstimate}, AppState};
+
+const FIELD: &str = "client_timestamp";
+
+// What to do with a client timestamp outside the accepted window
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TimestampPolicy {
+    // Leave client timestamps in the data, unchecked
+    Off,
+    Clamp,
+    Reject,
+}
+
+impl FromStr for TimestampPolicy {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "off" => Ok(TimestampPolicy::Off),
+            "clamp" => Ok(TimestampPolicy::Clamp),
+            "reject" => Ok(TimestampPolicy::Reject),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum TimestampError {
+    NotANumber,
+    OutOfRange { server_time: i64 },
+}
+
+impl fmt::Display for TimestampError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TimestampError::NotANumber => write!(f, "client_timestamp must be unix milliseconds"),
+            TimestampError::OutOfRange { server_time } => {
+                write!(f, "client_timestamp is too far from server time {}", server_time)
+            }
+        }
+    }
+}
+
+// Take the client timestamp out of a message's data, checked against server time
+pub fn take(state: &AppState, skew: &SkewEstimate, data: &mut Option<serde_json::Value>) -> Result<Option<i64>, TimestampError> {
+    if state.config.client_timestamp_policy == TimestampPolicy::Off {
+        return Ok(None);
+    }
+    let Some(value) = data.as_mut().and_then(|data| data.as_object_mut()).and_then(|data| data.remove(FIELD)) else {
+        return Ok(None);
+    };
+    let client_time = skew::to_server_time(state, skew, value.as_i64().ok_or(TimestampError::NotANumber)?);
+
+    let server_time = clock::now().timestamp_millis();
+    let earliest = server_time.saturating_sub(state.config.client_timestamp_max_past_ms as i64);
+    let latest = server_time.saturating_add(state.config.client_timestamp_max_future_ms as i64);
+    if (earliest..=latest).contains(&client_time) {
+        return Ok(Some(client_time));
+    }
+
+    match state.config.client_timestamp_policy {
+        TimestampPolicy::Reject => Err(TimestampError::OutOfRange { server_time }),
+        _ => Ok(Some(client_time.clamp(earliest, latest))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock::mock::MockClock, testing};
+    use chrono::DateTime;
+    use std::time::Duration;
+
+    const NOW_MS: i64 = 1_700_000_000_000;
+
+    fn with_timestamp(client_time: i64) -> Option<serde_json::Value> {
+        Some(serde_json::json!({ "slide": 1, FIELD: client_time }))
+    }
+
+    #[test]
+    fn client_times_are_checked_against_the_server_clock() {
+        let clock = MockClock::install(DateTime::from_timestamp_millis(NOW_MS).unwrap());
+        let state = testing::state(|config| {
+            config.client_timestamp_policy = TimestampPolicy::Clamp;
+            config.client_timestamp_max_past_ms = 1000;
+            config.client_timestamp_max_future_ms = 100;
+        });
+        let skew = SkewEstimate::default();
+
+        let mut data = with_timestamp(NOW_MS - 1000);
+        assert_eq!(take(&state, &skew, &mut data).unwrap(), Some(NOW_MS - 1000));
+        assert_eq!(data, Some(serde_json::json!({ "slide": 1 })));
+        assert_eq!(take(&state, &skew, &mut with_timestamp(NOW_MS - 1001)).unwrap(), Some(NOW_MS - 1000));
+        assert_eq!(take(&state, &skew, &mut with_timestamp(NOW_MS + 500)).unwrap(), Some(NOW_MS + 100));
+
+        // The window moves with the clock
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(take(&state, &skew, &mut with_timestamp(NOW_MS)).unwrap(), Some(NOW_MS + 9000));
+        assert_eq!(take(&state, &skew, &mut with_timestamp(NOW_MS + 10_050)).unwrap(), Some(NOW_MS + 10_050));
+    }
+
+    #[test]
+    fn reject_refuses_what_clamp_would_move() {
+        let _clock = MockClock::install(DateTime::from_timestamp_millis(NOW_MS).unwrap());
+        let state = testing::state(|config| {
+            config.client_timestamp_policy = TimestampPolicy::Reject;
+            config.client_timestamp_max_past_ms = 1000;
+            config.client_timestamp_max_future_ms = 100;
+        });
+        let skew = SkewEstimate::default();
+
+        assert_eq!(take(&state, &skew, &mut with_timestamp(NOW_MS + 100)).unwrap(), Some(NOW_MS + 100));
+        let Err(TimestampError::OutOfRange { server_time }) = take(&state, &skew, &mut with_timestamp(NOW_MS + 101)) else {
+            panic!("a time past the window was accepted");
+        };
+        assert_eq!(server_time, NOW_MS);
+        assert!(matches!(
+            take(&state, &skew, &mut Some(serde_json::json!({ FIELD: "yesterday" }))),
+            Err(TimestampError::NotANumber)
+        ));
+    }
+}
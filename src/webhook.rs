@@ -0,0 +1,493 @@
+// Channel events POSTed to a webhook endpoint, with failed deliveries retried from a bounded queue.
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    sync::mpsc,
+    time::Instant,
+};
+
+use crate::{
+    config::Config,
+    dead_letter::{DeadLetterLog, DeadLetterReason},
+    degradation::{self, Degradations},
+    metrics::Metrics,
+    AppState,
+};
+
+// Most of a response read looking for its status line
+const MAX_STATUS_LINE: usize = 8 << 10;
+
+// Where deliveries are POSTed, from an http:// URL
+#[derive(Debug, PartialEq)]
+struct Endpoint {
+    // host:port to connect to
+    addr: String,
+    // The Host header
+    host: String,
+    path: String,
+}
+
+fn parse_endpoint(url: &str) -> Result<Endpoint, String> {
+    let Some(rest) = url.strip_prefix("http://") else {
+        return Err(format!("{} is not an http:// URL", url));
+    };
+    let (host, path) = match rest.find('/') {
+        Some(slash) => (&rest[..slash], &rest[slash..]),
+        None => (rest, "/"),
+    };
+    if host.is_empty() {
+        return Err(format!("{} has no host", url));
+    }
+    let addr = if host.rsplit_once(':').is_some_and(|(_, port)| port.parse::<u16>().is_ok()) {
+        host.to_string()
+    } else {
+        format!("{}:80", host)
+    };
+    Ok(Endpoint { addr, host: host.to_string(), path: path.to_string() })
+}
+
+// Why an attempt failed, and whether trying again could help
+enum Failure {
+    Retry(String),
+    Permanent(String),
+}
+
+// One notification on its way to the endpoint
+struct Delivery {
+    channel: String,
+    origin: String,
+    body: String,
+    // Attempts made so far
+    attempts: u32,
+}
+
+#[derive(Default)]
+struct Counters {
+    // Deliveries waiting for their first attempt or a retry
+    queued: AtomicU64,
+    delivered: AtomicU64,
+    // Attempts that failed, retried or not
+    failures: AtomicU64,
+    // Deliveries given up on: out of retries, refused by the endpoint, or with the queue full
+    dead_lettered: AtomicU64,
+}
+
+// How the sender retries
+struct RetryPolicy {
+    timeout: Duration,
+    max_retries: u32,
+    base: Duration,
+    max: Duration,
+}
+
+impl RetryPolicy {
+    // Wait before the next try after `attempts` failed ones: the base, doubled each time, capped
+    fn backoff(&self, attempts: u32) -> Duration {
+        let factor = 1u32.checked_shl(attempts.saturating_sub(1)).unwrap_or(u32::MAX);
+        self.base.saturating_mul(factor).min(self.max)
+    }
+}
+
+pub struct Webhooks {
+    tx: mpsc::UnboundedSender<Delivery>,
+    events: Vec<String>,
+    capacity: u64,
+    counters: Arc<Counters>,
+    dead_letters: Arc<DeadLetterLog>,
+}
+
+impl Webhooks {
+    pub fn new(config: &Config, dead_letters: &Arc<DeadLetterLog>, degradations: &Arc<Degradations>) -> Option<Self> {
+        let url = config.webhook_url.as_deref()?;
+        let endpoint = match parse_endpoint(url) {
+            Ok(endpoint) => endpoint,
+            Err(e) => {
+                eprintln!("❌ Webhooks disabled: {}", e);
+                return None;
+            }
+        };
+
+        println!("🪝 POSTing {} events to {}", config.webhook_events.join(", "), url);
+        let policy = RetryPolicy {
+            timeout: Duration::from_millis(config.webhook_timeout_ms.max(1)),
+            max_retries: config.webhook_max_retries,
+            base: Duration::from_millis(config.webhook_retry_base_ms.max(1)),
+            max: Duration::from_millis(config.webhook_retry_max_ms.max(config.webhook_retry_base_ms)),
+        };
+        let (tx, rx) = mpsc::unbounded_channel();
+        let counters = Arc::new(Counters::default());
+        tokio::spawn(run_sender(endpoint, policy, rx, counters.clone(), dead_letters.clone(), degradations.clone()));
+        Some(Webhooks {
+            tx,
+            events: config.webhook_events.clone(),
+            capacity: config.webhook_queue_size.max(1) as u64,
+            counters,
+            dead_letters: dead_letters.clone(),
+        })
+    }
+
+    pub fn depth(&self) -> u64 {
+        self.counters.queued.load(Ordering::Relaxed)
+    }
+
+    pub fn delivered(&self) -> u64 {
+        self.counters.delivered.load(Ordering::Relaxed)
+    }
+
+    pub fn failures(&self) -> u64 {
+        self.counters.failures.load(Ordering::Relaxed)
+    }
+
+    pub fn dead_lettered(&self) -> u64 {
+        self.counters.dead_lettered.load(Ordering::Relaxed)
+    }
+}
+
+// Queue a broadcast for the endpoint if its type is one it gets. Never waits: with the
+// queue full, the event goes to the dead-letter log instead.
+pub fn notify(state: &AppState, origin: &str, channel: &str, event_type: &str, json: &str) {
+    let Some(webhooks) = &state.webhooks else {
+        return;
+    };
+    if !webhooks.events.iter().any(|event| event == event_type) {
+        return;
+    }
+
+    let counters = &webhooks.counters;
+    let reserved = counters
+        .queued
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |queued| (queued < webhooks.capacity).then_some(queued + 1))
+        .is_ok();
+    if !reserved {
+        Metrics::inc(&counters.dead_lettered);
+        webhooks.dead_letters.record(DeadLetterReason::WebhookFailed, Some(channel), origin, json);
+        return;
+    }
+
+    let delivery = Delivery { channel: channel.to_string(), origin: origin.to_string(), body: json.to_string(), attempts: 0 };
+    if webhooks.tx.send(delivery).is_err() {
+        counters.queued.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Send queued deliveries one at a time, and retries once they're due, on a task of its own
+// so a slow or failing endpoint never holds up connections
+async fn run_sender(
+    endpoint: Endpoint,
+    policy: RetryPolicy,
+    mut rx: mpsc::UnboundedReceiver<Delivery>,
+    counters: Arc<Counters>,
+    dead_letters: Arc<DeadLetterLog>,
+    degradations: Arc<Degradations>,
+) {
+    // Failed deliveries by when they're due again; the number keeps equal times apart
+    let mut retries: BTreeMap<(Instant, u64), Delivery> = BTreeMap::new();
+    let mut scheduled = 0u64;
+
+    loop {
+        let next_due = retries.first_key_value().map(|((due, _), _)| *due);
+        let mut delivery = tokio::select! {
+            delivery = rx.recv() => match delivery {
+                Some(delivery) => delivery,
+                None => return,
+            },
+            _ = tokio::time::sleep_until(next_due.unwrap_or_else(Instant::now)), if next_due.is_some() => {
+                match retries.pop_first() {
+                    Some((_, delivery)) => delivery,
+                    None => continue,
+                }
+            }
+        };
+
+        delivery.attempts += 1;
+        let reason = match post(&endpoint, &delivery.body, policy.timeout).await {
+            Ok(()) => {
+                Metrics::inc(&counters.delivered);
+                counters.queued.fetch_sub(1, Ordering::Relaxed);
+                degradations.recover(degradation::WEBHOOK);
+                continue;
+            }
+            Err(Failure::Retry(reason)) if delivery.attempts <= policy.max_retries => {
+                Metrics::inc(&counters.failures);
+                degradations.fail(degradation::WEBHOOK, &reason);
+                scheduled += 1;
+                retries.insert((Instant::now() + policy.backoff(delivery.attempts), scheduled), delivery);
+                continue;
+            }
+            Err(Failure::Retry(reason) | Failure::Permanent(reason)) => reason,
+        };
+
+        Metrics::inc(&counters.failures);
+        Metrics::inc(&counters.dead_lettered);
+        counters.queued.fetch_sub(1, Ordering::Relaxed);
+        degradations.fail(degradation::WEBHOOK, &reason);
+        println!(
+            "🪝 Giving up on a webhook for channel {} after {} attempts: {}",
+            delivery.channel, delivery.attempts, reason
+        );
+        dead_letters.record(DeadLetterReason::WebhookFailed, Some(&delivery.channel), &delivery.origin, &delivery.body);
+    }
+}
+
+// POST one body and read the response's status. Timeouts, connection errors and 5xx, 408
+// and 429 responses are worth retrying; any other non-2xx answer is final.
+async fn post(endpoint: &Endpoint, body: &str, timeout: Duration) -> Result<(), Failure> {
+    let exchange = async {
+        let mut stream = TcpStream::connect(&endpoint.addr)
+            .await
+            .map_err(|e| Failure::Retry(format!("cannot connect to {}: {}", endpoint.addr, e)))?;
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: rably\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            endpoint.path,
+            endpoint.host,
+            body.len(),
+            body
+        );
+        stream
+            .write_all(request.as_bytes())
+            .await
+            .map_err(|e| Failure::Retry(format!("cannot send to {}: {}", endpoint.addr, e)))?;
+
+        let mut response = Vec::new();
+        let mut buf = [0u8; 1024];
+        while !response.windows(2).any(|pair| pair == b"\r\n") && response.len() < MAX_STATUS_LINE {
+            match stream.read(&mut buf).await {
+                Ok(0) => break,
+                Ok(n) => response.extend_from_slice(&buf[..n]),
+                Err(e) => return Err(Failure::Retry(format!("cannot read the response: {}", e))),
+            }
+        }
+        let status = String::from_utf8_lossy(&response)
+            .split(' ')
+            .nth(1)
+            .and_then(|status| status.parse::<u16>().ok())
+            .ok_or_else(|| Failure::Retry("no HTTP status in the response".to_string()))?;
+        match status {
+            200..=299 => Ok(()),
+            408 | 429 | 500..=599 => Err(Failure::Retry(format!("endpoint answered {}", status))),
+            _ => Err(Failure::Permanent(format!("endpoint answered {}", status))),
+        }
+    };
+    tokio::time::timeout(timeout, exchange)
+        .await
+        .unwrap_or_else(|_| Err(Failure::Retry(format!("no answer within {}ms", timeout.as_millis()))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{send_to_channel, testing, ServerMessage};
+    use tokio::net::TcpListener;
+
+    const WAIT: Duration = Duration::from_secs(5);
+
+    // One request as the endpoint received it, with the start line and headers
+    async fn read_request(stream: &mut TcpStream) -> String {
+        let mut request = Vec::new();
+        let mut buf = [0u8; 4096];
+        loop {
+            let text = String::from_utf8_lossy(&request).to_string();
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                let length = head
+                    .lines()
+                    .find_map(|line| line.strip_prefix("Content-Length: "))
+                    .and_then(|length| length.parse::<usize>().ok())
+                    .unwrap_or(0);
+                if body.len() >= length {
+                    return text;
+                }
+            }
+            match stream.read(&mut buf).await {
+                Ok(0) | Err(_) => return String::from_utf8_lossy(&request).to_string(),
+                Ok(n) => request.extend_from_slice(&buf[..n]),
+            }
+        }
+    }
+
+    // An endpoint answering with `statuses` in turn and 200 after them, reporting each
+    // request it gets and when
+    async fn endpoint(statuses: Vec<u16>) -> (String, mpsc::UnboundedReceiver<(Instant, String)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind a local port");
+        let url = format!("http://{}/hooks/rably", listener.local_addr().expect("bound address"));
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut statuses = statuses.into_iter();
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let request = read_request(&mut stream).await;
+                let _ = tx.send((Instant::now(), request));
+                let status = statuses.next().unwrap_or(200);
+                let response = format!("HTTP/1.1 {} Whatever\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status);
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        (url, rx)
+    }
+
+    async fn next_request(requests: &mut mpsc::UnboundedReceiver<(Instant, String)>) -> (Instant, String) {
+        tokio::time::timeout(WAIT, requests.recv())
+            .await
+            .expect("the endpoint got no request")
+            .expect("the endpoint is gone")
+    }
+
+    // Wait until nothing is left for the sender
+    async fn settled(state: &AppState) -> &Webhooks {
+        let webhooks = state.webhooks.as_deref().expect("webhooks enabled");
+        tokio::time::timeout(WAIT, async {
+            while webhooks.depth() > 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("deliveries still queued");
+        webhooks
+    }
+
+    fn joined(state: &AppState, client_id: &str) {
+        send_to_channel(state, ServerMessage::new("user_joined", "lesson", serde_json::json!({ "id": client_id })), "server");
+    }
+
+    #[test]
+    fn endpoints_are_plain_http_urls() {
+        let endpoint = parse_endpoint("http://hooks.example.com:8080/rably/events").unwrap();
+        assert_eq!(
+            endpoint,
+            Endpoint {
+                addr: "hooks.example.com:8080".to_string(),
+                host: "hooks.example.com:8080".to_string(),
+                path: "/rably/events".to_string(),
+            }
+        );
+        let endpoint = parse_endpoint("http://hooks.example.com").unwrap();
+        assert_eq!((endpoint.addr.as_str(), endpoint.path.as_str()), ("hooks.example.com:80", "/"));
+        assert!(parse_endpoint("https://hooks.example.com/").is_err());
+        assert!(parse_endpoint("http:///path").is_err());
+    }
+
+    #[test]
+    fn retries_back_off_exponentially_up_to_the_cap() {
+        let policy = RetryPolicy {
+            timeout: Duration::from_secs(1),
+            max_retries: 40,
+            base: Duration::from_millis(100),
+            max: Duration::from_secs(1),
+        };
+        let waits: Vec<u128> = (1..=6).map(|attempts| policy.backoff(attempts).as_millis()).collect();
+        assert_eq!(waits, [100, 200, 400, 800, 1000, 1000]);
+        assert_eq!(policy.backoff(40), Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn the_configured_events_are_posted_as_their_envelope() {
+        let (url, mut requests) = endpoint(vec![]).await;
+        let server = testing::TestServer::start(|config| config.webhook_url = Some(url)).await;
+        let mut student = server.connect("").await;
+        student.subscribe("lesson", serde_json::json!({})).await;
+        student.send(serde_json::json!({ "action": "publish", "channel": "lesson", "data": "not an event type" })).await;
+        student.expect("message").await;
+
+        let (_, request) = next_request(&mut requests).await;
+        assert!(request.starts_with("POST /hooks/rably HTTP/1.1\r\n"), "{}", request);
+        assert!(request.contains("\r\nContent-Type: application/json\r\n"), "{}", request);
+        let body: serde_json::Value = serde_json::from_str(request.split_once("\r\n\r\n").unwrap().1).unwrap();
+        assert_eq!(body["type"], "user_joined");
+        assert_eq!(body["channel"], "lesson");
+        assert_eq!(body["data"]["id"], student.client_id.as_str());
+
+        let webhooks = settled(&server.state).await;
+        assert_eq!((webhooks.delivered(), webhooks.failures()), (1, 0));
+        let (_, metrics) = server.request("GET", "/metrics", None).await;
+        assert!(metrics.contains("rably_webhook_queue_depth 0"), "{}", metrics);
+        assert!(metrics.contains("rably_webhook_delivered_total 1"), "{}", metrics);
+        // The publish wasn't sent
+        assert!(tokio::time::timeout(Duration::from_millis(100), requests.recv()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn a_failing_endpoint_is_retried_with_backoff_until_it_accepts() {
+        let (url, mut requests) = endpoint(vec![503, 500]).await;
+        let state = testing::state(|config| {
+            config.webhook_url = Some(url);
+            config.webhook_retry_base_ms = 100;
+        });
+        joined(&state, "a");
+
+        let (first, body) = next_request(&mut requests).await;
+        let (second, retried) = next_request(&mut requests).await;
+        let (third, _) = next_request(&mut requests).await;
+        assert_eq!(body.split_once("\r\n\r\n").map(|(_, body)| body), retried.split_once("\r\n\r\n").map(|(_, body)| body));
+        assert!(second - first >= Duration::from_millis(100), "{:?}", second - first);
+        assert!(third - second >= Duration::from_millis(200), "{:?}", third - second);
+
+        let webhooks = settled(&state).await;
+        assert_eq!((webhooks.delivered(), webhooks.failures(), webhooks.dead_lettered()), (1, 2, 0));
+        assert!(!state.degradations.any());
+    }
+
+    #[tokio::test]
+    async fn a_delivery_out_of_retries_is_dead_lettered() {
+        let (url, mut requests) = endpoint(vec![500; 10]).await;
+        let state = testing::state(|config| {
+            config.webhook_url = Some(url);
+            config.webhook_max_retries = 2;
+            config.webhook_retry_base_ms = 10;
+        });
+        joined(&state, "a");
+
+        for _ in 0..3 {
+            next_request(&mut requests).await;
+        }
+        let webhooks = settled(&state).await;
+        assert_eq!((webhooks.delivered(), webhooks.failures(), webhooks.dead_lettered()), (0, 3, 1));
+        assert_eq!(state.degradations.report()[0]["subsystem"], degradation::WEBHOOK);
+        assert!(tokio::time::timeout(Duration::from_millis(100), requests.recv()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn a_refused_delivery_is_not_retried() {
+        let (url, mut requests) = endpoint(vec![404]).await;
+        let state = testing::state(|config| {
+            config.webhook_url = Some(url);
+            config.webhook_retry_base_ms = 10;
+        });
+        joined(&state, "a");
+
+        next_request(&mut requests).await;
+        let webhooks = settled(&state).await;
+        assert_eq!((webhooks.failures(), webhooks.dead_lettered()), (1, 1));
+        assert!(tokio::time::timeout(Duration::from_millis(100), requests.recv()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn a_full_queue_turns_deliveries_away_without_waiting() {
+        // Accepts connections and never answers
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind a local port");
+        let url = format!("http://{}/", listener.local_addr().expect("bound address"));
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                held.push(stream);
+            }
+        });
+        let state = testing::state(|config| {
+            config.webhook_url = Some(url);
+            config.webhook_queue_size = 2;
+            config.webhook_timeout_ms = 60_000;
+        });
+        for client_id in ["a", "b", "c", "d"] {
+            joined(&state, client_id);
+        }
+
+        let webhooks = state.webhooks.as_deref().unwrap();
+        assert_eq!((webhooks.depth(), webhooks.dead_lettered()), (2, 2));
+    }
+}